@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::path::Path;
-use templify::{TemplateEngine, FileGenerator, ManualSectionManager, ManualSectionConfig};
-use env_logger;
+use templify::{TemplateEngine, FileGenerator, DryRunMode, ManualSectionManager, ManualSectionConfig};
 use std::env;
 use log::{info, error};
 use fs_extra::dir::{copy, CopyOptions, remove};
@@ -19,7 +18,7 @@ fn main() {
     // Initialize components
     let engine = TemplateEngine::new();
     let manual_section_manager = ManualSectionManager::new(ManualSectionConfig::default());
-    let generator = FileGenerator::new(engine, manual_section_manager, false);
+    let generator = FileGenerator::new(engine, manual_section_manager, DryRunMode::Off);
 
     // Define paths for template and output files
     let template_path = Path::new("examples/templates/inject");
@@ -0,0 +1,228 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/// Archive formats supported as an alternative to writing into a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Detects the archive format from the file extension (`.tar.gz`, `.tgz` or `.zip`).
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+enum ArchiveBackend {
+    TarGz(tar::Builder<GzEncoder<File>>),
+    Zip(ZipWriter<File>),
+}
+
+/// ArchiveWriter collects generated files into a tar.gz or zip archive instead of the filesystem.
+/// Entry names are the output paths relative to `root`.
+pub struct ArchiveWriter {
+    root: PathBuf,
+    backend: Mutex<Option<ArchiveBackend>>,
+}
+
+impl ArchiveWriter {
+    /// Creates the archive file at `archive_path`; entries are stored relative to `root`.
+    pub fn create(archive_path: &Path, root: &Path) -> Result<Self, String> {
+        let format = ArchiveFormat::from_path(archive_path).ok_or_else(|| {
+            format!(
+                "Unsupported archive format {:?}: expected .tar.gz, .tgz or .zip",
+                archive_path
+            )
+        })?;
+        if let Some(parent) = archive_path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+        }
+        let file = File::create(archive_path)
+            .map_err(|e| format!("Failed to create archive {:?}: {}", archive_path, e))?;
+        let backend = match format {
+            ArchiveFormat::TarGz => {
+                ArchiveBackend::TarGz(tar::Builder::new(GzEncoder::new(file, Compression::default())))
+            }
+            ArchiveFormat::Zip => ArchiveBackend::Zip(ZipWriter::new(file)),
+        };
+        Ok(Self {
+            root: root.to_path_buf(),
+            backend: Mutex::new(Some(backend)),
+        })
+    }
+
+    /// Returns the archive entry name for an output path, using `/` separators.
+    pub fn entry_name(&self, output_path: &Path) -> String {
+        let relative = output_path.strip_prefix(&self.root).unwrap_or(output_path);
+        relative
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Adds a file entry to the archive.
+    pub fn add_file(&self, output_path: &Path, contents: &[u8]) -> Result<(), String> {
+        let name = self.entry_name(output_path);
+        let mut guard = self.backend.lock().map_err(|e| e.to_string())?;
+        match guard.as_mut() {
+            Some(ArchiveBackend::TarGz(builder)) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder
+                    .append_data(&mut header, &name, contents)
+                    .map_err(|e| format!("Failed to add {} to archive: {}", name, e))
+            }
+            Some(ArchiveBackend::Zip(writer)) => {
+                writer
+                    .start_file(name.as_str(), SimpleFileOptions::default())
+                    .map_err(|e| format!("Failed to add {} to archive: {}", name, e))?;
+                writer
+                    .write_all(contents)
+                    .map_err(|e| format!("Failed to add {} to archive: {}", name, e))
+            }
+            None => Err("Archive has already been finished".to_string()),
+        }
+    }
+
+    /// Flushes and closes the archive. Further `add_file` calls will fail.
+    pub fn finish(&self) -> Result<(), String> {
+        let mut guard = self.backend.lock().map_err(|e| e.to_string())?;
+        match guard.take() {
+            Some(ArchiveBackend::TarGz(builder)) => {
+                let encoder = builder.into_inner().map_err(|e| e.to_string())?;
+                encoder.finish().map_err(|e| e.to_string())?;
+            }
+            Some(ArchiveBackend::Zip(writer)) => {
+                writer.finish().map_err(|e| e.to_string())?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DryRunMode, FileGenerator, ManualSectionConfig, ManualSectionManager, TemplateEngine};
+    use flate2::read::GzDecoder;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn write_templates(dir: &Path) {
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("hello.txt.j2"), "Hello, {{ name }}!").unwrap();
+        std::fs::write(dir.join("sub/{{ name }}.md.j2"), "# {{ name }}").unwrap();
+    }
+
+    fn generate_into(archive: Arc<ArchiveWriter>, templates: &Path, output: &Path) {
+        let generator = FileGenerator::new(
+            TemplateEngine::new(),
+            ManualSectionManager::new(ManualSectionConfig::default()),
+            DryRunMode::Off,
+        )
+        .with_archive(archive.clone());
+        let context = HashMap::from([("name", "World")]);
+        generator.generate(templates, output, &context).unwrap();
+        archive.finish().unwrap();
+    }
+
+    #[test]
+    fn test_tar_gz_archive_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        write_templates(&templates);
+        let output = tmp.path().join("out");
+        let archive_path = tmp.path().join("out.tar.gz");
+
+        let archive = Arc::new(ArchiveWriter::create(&archive_path, &output).unwrap());
+        generate_into(archive, &templates, &output);
+
+        let mut tar = tar::Archive::new(GzDecoder::new(File::open(&archive_path).unwrap()));
+        let mut names: Vec<String> = tar
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["hello.txt", "sub/World.md"]);
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_zip_archive_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        write_templates(&templates);
+        let output = tmp.path().join("out");
+        let archive_path = tmp.path().join("out.zip");
+
+        let archive = Arc::new(ArchiveWriter::create(&archive_path, &output).unwrap());
+        generate_into(archive, &templates, &output);
+
+        let mut zip = zip::ZipArchive::new(File::open(&archive_path).unwrap()).unwrap();
+        let mut names: Vec<String> = zip.file_names().map(|n| n.to_string()).collect();
+        names.sort();
+        assert_eq!(names, vec!["hello.txt", "sub/World.md"]);
+
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut zip.by_name("hello.txt").unwrap(), &mut content)
+            .unwrap();
+        assert_eq!(content, "Hello, World!");
+    }
+
+    #[test]
+    fn test_archive_injects_into_existing_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        std::fs::create_dir_all(&templates).unwrap();
+        let injection = "<!-- injection-pattern: mods -->\n(?P<injection>)// end\n\
+                         <!-- injection-string-start -->\npub mod {{ name }};\n<!-- injection-string-end -->\n";
+        std::fs::write(templates.join("mod.rs.inj"), injection).unwrap();
+        std::fs::write(templates.join("missing.rs.inj"), injection).unwrap();
+        let output = tmp.path().join("out");
+        std::fs::create_dir_all(&output).unwrap();
+        std::fs::write(output.join("mod.rs"), "// end\n").unwrap();
+        let archive_path = tmp.path().join("out.zip");
+
+        let archive = Arc::new(ArchiveWriter::create(&archive_path, &output).unwrap());
+        generate_into(archive, &templates, &output);
+
+        // The existing file is injected into in the archive only; without one the template is skipped
+        let mut zip = zip::ZipArchive::new(File::open(&archive_path).unwrap()).unwrap();
+        assert_eq!(zip.file_names().collect::<Vec<_>>(), vec!["mod.rs"]);
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut zip.by_name("mod.rs").unwrap(), &mut content).unwrap();
+        assert_eq!(content, "\npub mod World;\n// end\n");
+        assert_eq!(std::fs::read_to_string(output.join("mod.rs")).unwrap(), "// end\n");
+    }
+
+    #[test]
+    fn test_unsupported_format() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(ArchiveWriter::create(&tmp.path().join("out.rar"), tmp.path()).is_err());
+    }
+}
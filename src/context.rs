@@ -0,0 +1,480 @@
+use crate::config::{TargetConfig, TemplateConfig};
+use crate::data::DataLoader;
+use crate::engine::TemplateEngine;
+use crate::iteration::LoopPosition;
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Layers of the rendering context, ordered from lowest to highest precedence.
+/// When the same key is provided by several layers, the higher layer wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ContextLayer {
+    /// Top-level keys of the data file (and nested keys down to `flatten_depth`), when
+    /// `flatten_data` is enabled.
+    Flattened,
+    /// The full data file, available as `dd` and under the `--data-key` name.
+    Data,
+    /// Values from the `globals` section of the config, available as `globals`.
+    Globals,
+    /// Values loaded through `extra_data`, each under its configured key.
+    ExtraData,
+    /// The current output target, available as `target`.
+    Target,
+    /// The iteration variable of the current item.
+    Iteration,
+}
+
+/// A key provided by more than one layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextCollision {
+    pub key: String,
+    /// The layer whose value is used.
+    pub winner: ContextLayer,
+    /// The layer whose value is shadowed.
+    pub shadowed: ContextLayer,
+}
+
+/// ContextBuilder assembles the rendering context from named layers with explicit precedence
+/// (see `ContextLayer`) and reports keys that are shadowed by a higher layer.
+#[derive(Debug, Clone, Default)]
+pub struct ContextBuilder {
+    layers: BTreeMap<ContextLayer, BTreeMap<String, Value>>,
+    /// Expressions of the computed globals that `with_computed_globals` hasn't evaluated yet
+    computed_globals: BTreeMap<String, String>,
+}
+
+impl ContextBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a single value into a layer. Within a layer, later inserts replace earlier ones.
+    pub fn insert<T: Serialize>(&mut self, layer: ContextLayer, key: &str, value: T) -> &mut Self {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.layers
+            .entry(layer)
+            .or_default()
+            .insert(key.to_string(), value);
+        self
+    }
+
+    /// Adds the data file as `dd` and, if `flatten` is set, its top-level keys.
+    pub fn with_data(self, data: &Value, flatten: bool) -> Self {
+        self.with_data_flattened(data, if flatten { 1 } else { 0 }, "_")
+    }
+
+    /// Adds the data file as `dd` and its keys down to `depth` levels, nested keys joined
+    /// with `separator`: at depth 2, `database.host` is also available as `database_host`.
+    /// A depth of 0 disables flattening.
+    pub fn with_data_flattened(mut self, data: &Value, depth: usize, separator: &str) -> Self {
+        self.insert(ContextLayer::Data, "dd", data);
+        self.flatten(None, data, depth, separator);
+        self
+    }
+
+    fn flatten(&mut self, prefix: Option<&str>, value: &Value, depth: usize, separator: &str) {
+        let Value::Object(map) = value else {
+            return;
+        };
+        if depth == 0 {
+            return;
+        }
+        for (k, v) in map {
+            let key = match prefix {
+                Some(prefix) => format!("{}{}{}", prefix, separator, k),
+                None => k.clone(),
+            };
+            self.insert(ContextLayer::Flattened, &key, v);
+            self.flatten(Some(&key), v, depth - 1, separator);
+        }
+    }
+
+    /// Adds the data file under `key` as well, like the `dict_name` of the legacy `RenderHelper`.
+    pub fn with_data_key(mut self, key: &str, data: &Value) -> Self {
+        self.insert(ContextLayer::Data, key, data);
+        self
+    }
+
+    /// Adds the config globals as `globals`. String values containing `{{` and values of the
+    /// form `{ expr: "..." }` are computed globals, kept as they are until evaluated by
+    /// `with_computed_globals`.
+    pub fn with_globals(mut self, globals: &HashMap<String, Value>) -> Self {
+        self.insert(ContextLayer::Globals, "globals", globals);
+        self.computed_globals = globals
+            .iter()
+            .filter_map(|(name, value)| Some((name.clone(), computed_template(value)?)))
+            .collect();
+        self
+    }
+
+    /// Evaluates the computed globals against the context built so far. A value that is a
+    /// single `{{ expr }}`, or an `{ expr }` map, becomes the value of the expression, e.g. a
+    /// number for `{{ dd.services | length }}`; other values are rendered as strings. With
+    /// `defer_undefined`, globals reading a variable missing from the context (e.g. an
+    /// iteration variable not bound yet) are left as they are for a later call; without it,
+    /// they fail naming the missing variables.
+    pub fn with_computed_globals(mut self, engine: &TemplateEngine, defer_undefined: bool) -> Result<Self, String> {
+        let pending = std::mem::take(&mut self.computed_globals);
+        if pending.is_empty() {
+            return Ok(self);
+        }
+        let context = self.build();
+        for (name, template) in pending {
+            let undefined: Vec<String> = engine
+                .referenced_variables(&template)
+                .map_err(|e| format!("Invalid computed global '{}': {}", name, e))?
+                .into_iter()
+                .filter(|path| !context.contains_key(path.split('.').next().unwrap_or(path)))
+                .collect();
+            if !undefined.is_empty() {
+                if defer_undefined {
+                    self.computed_globals.insert(name, template);
+                    continue;
+                }
+                return Err(format!(
+                    "Failed to compute global '{}': undefined variable(s) {}",
+                    name,
+                    undefined.join(", ")
+                ));
+            }
+            let expression = template
+                .trim()
+                .strip_prefix("{{")
+                .and_then(|rest| rest.strip_suffix("}}"))
+                .filter(|expr| !expr.contains("{{") && !expr.contains("}}"));
+            let value = match expression {
+                Some(expr) => engine.evaluate_expression(expr, &context),
+                None => engine.render_string(&template, &context).map(Value::String),
+            }
+            .map_err(|e| format!("Failed to compute global '{}': {}", name, e))?;
+            if let Some(Value::Object(globals)) = self
+                .layers
+                .get_mut(&ContextLayer::Globals)
+                .and_then(|layer| layer.get_mut("globals"))
+            {
+                globals.insert(name, value);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Adds an extra data value under `key`.
+    pub fn with_extra_data(mut self, key: &str, value: &Value) -> Self {
+        self.insert(ContextLayer::ExtraData, key, value);
+        self
+    }
+
+    /// Adds the data file and the config globals the way `yagen` does: the data as `dd`,
+    /// flattened according to `flatten_data`, `flatten_depth` and `flatten_separator`.
+    pub fn with_config(self, config: &TemplateConfig, data: &Value) -> Self {
+        let depth = if config.flatten_data { config.flatten_depth } else { 0 };
+        let context = self.with_data_flattened(data, depth, &config.flatten_separator);
+        match config.globals {
+            Some(ref globals) => context.with_globals(globals),
+            None => context,
+        }
+    }
+
+    /// Adds the `extra_data` entries of a config, loading their files or URLs with `loader`
+    /// and resolving relative paths against `base_dir`, usually the directory of the config.
+    /// An optional file that is missing or fails to parse is skipped with a warning.
+    pub fn with_config_extra_data(
+        mut self,
+        config: &TemplateConfig,
+        base_dir: Option<&Path>,
+        loader: &DataLoader,
+    ) -> Result<Self, String> {
+        for extra in &config.extra_data {
+            // `TemplateConfig::load` ensures that either `path` or `value` is set
+            let Some(ref path) = extra.path else {
+                if let Some(ref value) = extra.value {
+                    self = self.with_extra_data(&extra.key, value);
+                }
+                continue;
+            };
+            let extra_path = if DataLoader::is_url(path) {
+                PathBuf::from(path)
+            } else {
+                base_dir.unwrap_or(Path::new(".")).join(path)
+            };
+            match loader.load(path, base_dir) {
+                Ok(value) => self = self.with_extra_data(&extra.key, &value),
+                Err(e) if e.is_parse_error() => {
+                    warn!("Failed to parse extra data from {:?}: {}", extra_path, e);
+                    if extra.required {
+                        return Err(format!(
+                            "Required extra data file failed to parse: {:?}",
+                            extra_path
+                        ));
+                    }
+                }
+                Err(_) if extra.required => {
+                    return Err(format!("Required extra data file not found: {:?}", extra_path));
+                }
+                Err(_) => warn!("Optional extra data file not found: {:?}", extra_path),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Adds an output target as `target`.
+    pub fn with_target(mut self, target: &TargetConfig) -> Self {
+        self.insert(ContextLayer::Target, "target", target);
+        self
+    }
+
+    /// Adds the iteration variable of the current item.
+    pub fn with_iteration_var(mut self, var: &str, item: &Value) -> Self {
+        self.insert(ContextLayer::Iteration, var, item);
+        self
+    }
+
+    /// Adds the loop variables of the current item, given the position of its item at every
+    /// iteration level, outermost first: `loop` for the innermost level (see
+    /// `LoopPosition::to_value`) and `loops.<var>` for each level.
+    pub fn with_loop(mut self, positions: &[(&str, LoopPosition)]) -> Self {
+        let Some((_, innermost)) = positions.last() else {
+            return self;
+        };
+        let loops: serde_json::Map<String, Value> = positions
+            .iter()
+            .map(|(var, position)| (var.to_string(), position.to_value()))
+            .collect();
+        self.insert(ContextLayer::Iteration, "loop", innermost.to_value());
+        self.insert(ContextLayer::Iteration, "loops", Value::Object(loops));
+        self
+    }
+
+    /// Returns all keys that are provided by more than one layer.
+    pub fn collisions(&self) -> Vec<ContextCollision> {
+        let mut owners: BTreeMap<&str, ContextLayer> = BTreeMap::new();
+        let mut collisions = Vec::new();
+        for (layer, values) in &self.layers {
+            for key in values.keys() {
+                if let Some(shadowed) = owners.insert(key, *layer) {
+                    collisions.push(ContextCollision {
+                        key: key.clone(),
+                        winner: *layer,
+                        shadowed,
+                    });
+                }
+            }
+        }
+        collisions
+    }
+
+    /// Builds the context, logging a warning for every collision.
+    pub fn build(&self) -> HashMap<String, Value> {
+        for collision in self.collisions() {
+            warn!(
+                "Context key '{}' from {:?} is shadowed by {:?}",
+                collision.key, collision.shadowed, collision.winner
+            );
+        }
+        let mut context = HashMap::new();
+        for values in self.layers.values() {
+            for (k, v) in values {
+                context.insert(k.clone(), v.clone());
+            }
+        }
+        context
+    }
+
+    /// Builds the context as a JSON object, logging a warning for every collision.
+    pub fn build_value(&self) -> Value {
+        Value::Object(self.build().into_iter().collect())
+    }
+}
+
+/// Returns the expression of a computed global, a map with `expr` as its only key.
+/// The template of a computed global: a string containing `{{`, or `{{ expr }}` for an
+/// `{ expr: "..." }` map.
+fn computed_template(value: &Value) -> Option<String> {
+    match value {
+        Value::String(template) if template.contains("{{") => Some(template.clone()),
+        Value::Object(map) if map.len() == 1 => Some(format!("{{{{ {} }}}}", map.get("expr")?.as_str()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_precedence() {
+        let data = json!({ "service": "flattened", "name": "data" });
+        let globals = HashMap::from([("version".to_string(), json!("1.0"))]);
+        let context = ContextBuilder::new()
+            .with_iteration_var("service", &json!("item"))
+            .with_data(&data, true)
+            .with_globals(&globals)
+            .with_extra_data("name", &json!("extra"))
+            .build();
+
+        assert_eq!(context["service"], json!("item"));
+        assert_eq!(context["name"], json!("extra"));
+        assert_eq!(context["dd"], data);
+        assert_eq!(context["globals"], json!({ "version": "1.0" }));
+    }
+
+    #[test]
+    fn test_computed_globals() {
+        let data = json!({ "services": ["api", "web", "db"] });
+        let globals = HashMap::from([
+            ("service_count".to_string(), json!("{{ dd.services | length }}")),
+            ("summary".to_string(), json!("{{ dd.services | length }} services")),
+            ("first".to_string(), json!({ "expr": "dd.services | first" })),
+            ("label".to_string(), json!("{{ svc | upper }}")),
+            ("version".to_string(), json!("1.0")),
+        ]);
+        let engine = TemplateEngine::new();
+        let set_context = ContextBuilder::new()
+            .with_data(&data, false)
+            .with_globals(&globals)
+            .with_computed_globals(&engine, true)
+            .unwrap();
+        let context = set_context.build();
+        assert_eq!(context["globals"]["service_count"], json!(3));
+        assert_eq!(context["globals"]["summary"], json!("3 services"));
+        assert_eq!(context["globals"]["first"], json!("api"));
+        assert_eq!(context["globals"]["label"], json!("{{ svc | upper }}"));
+
+        let item = set_context
+            .with_iteration_var("svc", &json!("api"))
+            .with_computed_globals(&engine, false)
+            .unwrap()
+            .build();
+        assert_eq!(item["globals"]["label"], json!("API"));
+        assert_eq!(item["globals"]["version"], json!("1.0"));
+
+        let missing = HashMap::from([("broken".to_string(), json!("{{ nothing.here }}"))]);
+        let err = ContextBuilder::new()
+            .with_globals(&missing)
+            .with_computed_globals(&engine, false)
+            .unwrap_err();
+        assert_eq!(err, "Failed to compute global 'broken': undefined variable(s) nothing.here");
+    }
+
+    #[test]
+    fn test_target() {
+        let target: TargetConfig = serde_yaml::from_str("ext: rs
+lang: rust
+module: crate").unwrap();
+        let context = ContextBuilder::new().with_target(&target).build();
+        assert_eq!(
+            context["target"],
+            json!({ "ext": "rs", "lang": "rust", "module": "crate" })
+        );
+    }
+
+    #[test]
+    fn test_flatten_disabled() {
+        let context = ContextBuilder::new()
+            .with_data(&json!({ "name": "data" }), false)
+            .build();
+        assert!(!context.contains_key("name"));
+        assert!(context.contains_key("dd"));
+    }
+
+    #[test]
+    fn test_collisions_reported() {
+        let builder = ContextBuilder::new()
+            .with_data(&json!({ "dd": 1, "service": 2, "other": 3 }), true)
+            .with_iteration_var("service", &json!("item"));
+        assert_eq!(
+            builder.collisions(),
+            vec![
+                ContextCollision {
+                    key: "dd".to_string(),
+                    winner: ContextLayer::Data,
+                    shadowed: ContextLayer::Flattened,
+                },
+                ContextCollision {
+                    key: "service".to_string(),
+                    winner: ContextLayer::Iteration,
+                    shadowed: ContextLayer::Flattened,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_flattening() {
+        let data = json!({ "database": { "host": "db", "auth": { "user": "admin" } }, "name": "app" });
+
+        let context = ContextBuilder::new().with_data_flattened(&data, 2, "_").build();
+        assert_eq!(context["name"], json!("app"));
+        assert_eq!(context["database"]["host"], json!("db"));
+        assert_eq!(context["database_host"], json!("db"));
+        assert_eq!(context["database_auth"], json!({ "user": "admin" }));
+        assert!(!context.contains_key("database_auth_user"));
+
+        let context = ContextBuilder::new().with_data_flattened(&data, 3, "__").build();
+        assert_eq!(context["database__auth__user"], json!("admin"));
+
+        let context = ContextBuilder::new().with_data(&data, true).build();
+        assert!(context.contains_key("database") && !context.contains_key("database_host"));
+        let context = ContextBuilder::new().with_data(&data, false).build();
+        assert_eq!(context.keys().collect::<Vec<_>>(), vec!["dd"]);
+    }
+
+    #[test]
+    fn test_config() {
+        let config: TemplateConfig = serde_yaml::from_str(
+            "flatten_data: true\nflatten_depth: 2\nglobals:\n  version: \"1.0\"\ntemplates: []\n",
+        )
+        .unwrap();
+        let data = json!({ "database": { "host": "db" } });
+        let context = ContextBuilder::new()
+            .with_config(&config, &data)
+            .with_iteration_var("svc", &json!("api"))
+            .build_value();
+        assert_eq!(
+            context,
+            json!({
+                "dd": data,
+                "database": { "host": "db" },
+                "database_host": "db",
+                "globals": { "version": "1.0" },
+                "svc": "api",
+            })
+        );
+
+        let config: TemplateConfig = serde_yaml::from_str("templates: []\n").unwrap();
+        let context = ContextBuilder::new().with_config(&config, &data).build_value();
+        assert_eq!(context, json!({ "dd": data, "database": { "host": "db" } }));
+    }
+
+    #[test]
+    fn test_config_extra_data() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("owners.yaml"), "team: core\n").unwrap();
+        let config: TemplateConfig = serde_yaml::from_str(
+            "templates: []\nextra_data:\n  - { key: owners, path: owners.yaml }\n\
+             \x20 - { key: limits, value: { cpu: 2 } }\n  - { key: optional, path: missing.json }\n",
+        )
+        .unwrap();
+        let context = ContextBuilder::new()
+            .with_config_extra_data(&config, Some(tmp.path()), &DataLoader::new())
+            .unwrap()
+            .build_value();
+        assert_eq!(
+            context,
+            json!({ "owners": { "team": "core" }, "limits": { "cpu": 2 } })
+        );
+
+        let config: TemplateConfig = serde_yaml::from_str(
+            "templates: []\nextra_data:\n  - { key: owners, path: missing.json, required: true }\n",
+        )
+        .unwrap();
+        let err = ContextBuilder::new()
+            .with_config_extra_data(&config, Some(tmp.path()), &DataLoader::new())
+            .unwrap_err();
+        assert!(err.starts_with("Required extra data file not found"), "{}", err);
+    }
+}
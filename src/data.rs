@@ -0,0 +1,309 @@
+use serde_json::Value;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Environment variable holding an optional bearer token for HTTP(S) data sources.
+pub const DATA_TOKEN_ENV: &str = "TEMPLIFY_DATA_TOKEN";
+
+/// Default timeout for fetching HTTP(S) data sources.
+pub const DEFAULT_DATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum DataError {
+    #[error("Failed to read data file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to fetch data: {0}")]
+    Http(String),
+    #[error("Failed to parse JSON data: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Failed to parse YAML data: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("Invalid JSON Schema: {0}")]
+    Schema(String),
+}
+
+impl DataError {
+    /// Returns true if the data was found but could not be parsed.
+    pub fn is_parse_error(&self) -> bool {
+        matches!(self, DataError::Json(_) | DataError::Yaml(_))
+    }
+}
+
+/// Formats supported for data documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    Json,
+    Yaml,
+}
+
+impl FromStr for DataFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(DataFormat::Json),
+            "yaml" | "yml" => Ok(DataFormat::Yaml),
+            _ => Err(format!("Invalid data format '{}': expected json or yaml", s)),
+        }
+    }
+}
+
+impl DataFormat {
+    /// Infers the format from a file path or URL: `.yaml`/`.yml` is YAML, anything else JSON.
+    pub fn from_path(path: &str) -> Self {
+        let path = path.split(['?', '#']).next().unwrap_or(path).to_lowercase();
+        if path.ends_with(".yaml") || path.ends_with(".yml") {
+            DataFormat::Yaml
+        } else {
+            DataFormat::Json
+        }
+    }
+
+    /// Infers the format from an HTTP `Content-Type` header.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim().to_lowercase();
+        if mime.ends_with("json") {
+            Some(DataFormat::Json)
+        } else if mime.ends_with("yaml") || mime.ends_with("yml") {
+            Some(DataFormat::Yaml)
+        } else {
+            None
+        }
+    }
+
+    /// Parses a document in this format.
+    pub fn parse(&self, content: &str) -> Result<Value, DataError> {
+        Ok(match self {
+            DataFormat::Json => serde_json::from_str(content)?,
+            DataFormat::Yaml => serde_yaml::from_str(content)?,
+        })
+    }
+}
+
+/// A value in the data that does not conform to the JSON Schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    /// JSON pointer to the offending value (empty for the document root)
+    pub pointer: String,
+    pub message: String,
+}
+
+/// A compiled JSON Schema, to validate several documents without compiling it again.
+pub struct DataSchema {
+    validator: jsonschema::Validator,
+}
+
+impl DataSchema {
+    pub fn new(schema: &Value) -> Result<Self, DataError> {
+        let validator =
+            jsonschema::validator_for(schema).map_err(|e| DataError::Schema(e.to_string()))?;
+        Ok(Self { validator })
+    }
+
+    /// Validates data against the schema and returns all violations.
+    pub fn validate(&self, data: &Value) -> Vec<SchemaViolation> {
+        self.validator
+            .iter_errors(data)
+            .map(|e| SchemaViolation {
+                pointer: e.instance_path.to_string(),
+                message: e.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// Validates data against a JSON Schema and returns all violations.
+pub fn validate_schema(schema: &Value, data: &Value) -> Result<Vec<SchemaViolation>, DataError> {
+    Ok(DataSchema::new(schema)?.validate(data))
+}
+
+/// DataLoader loads data documents from local files or HTTP(S) URLs.
+#[derive(Debug, Clone)]
+pub struct DataLoader {
+    format: Option<DataFormat>,
+    timeout: Duration,
+    bearer_token: Option<String>,
+}
+
+impl Default for DataLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DataLoader {
+    pub fn new() -> Self {
+        Self {
+            format: None,
+            timeout: DEFAULT_DATA_TIMEOUT,
+            bearer_token: None,
+        }
+    }
+
+    /// Forces the data format instead of inferring it from the content type or extension.
+    pub fn with_format(mut self, format: Option<DataFormat>) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets the timeout for HTTP(S) requests.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends the token as `Authorization: Bearer <token>` with HTTP(S) requests.
+    pub fn with_bearer_token(mut self, token: Option<String>) -> Self {
+        self.bearer_token = token;
+        self
+    }
+
+    /// Returns true if the location is an HTTP(S) URL.
+    pub fn is_url(location: &str) -> bool {
+        location.starts_with("http://") || location.starts_with("https://")
+    }
+
+    /// Loads a document from a URL, or from a local path resolved against `base_dir`.
+    pub fn load(&self, location: &str, base_dir: Option<&Path>) -> Result<Value, DataError> {
+        if Self::is_url(location) {
+            return self.fetch(location);
+        }
+        let path = match base_dir {
+            Some(dir) => dir.join(location),
+            None => Path::new(location).to_path_buf(),
+        };
+        let content = std::fs::read_to_string(path)?;
+        self.format
+            .unwrap_or_else(|| DataFormat::from_path(location))
+            .parse(&content)
+    }
+
+    fn fetch(&self, url: &str) -> Result<Value, DataError> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .map_err(|e| DataError::Http(e.to_string()))?;
+        let mut request = client.get(url);
+        if let Some(ref token) = self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| DataError::Http(e.to_string()))?;
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(DataFormat::from_content_type);
+        let format = self
+            .format
+            .or(content_type)
+            .unwrap_or_else(|| DataFormat::from_path(url));
+        let content = response
+            .text()
+            .map_err(|e| DataError::Http(e.to_string()))?;
+        format.parse(&content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Serves a single HTTP response and sends the received request text back.
+    fn serve_once(content_type: &str, body: &str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/data", listener.local_addr().unwrap());
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        );
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0u8; 4096];
+            let n = stream.read(&mut buffer).unwrap();
+            tx.send(String::from_utf8_lossy(&buffer[..n]).to_string())
+                .unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_fetch_yaml_by_content_type() {
+        let (url, request) = serve_once("application/x-yaml", "name: remote\nitems: [1, 2]\n");
+        let data = DataLoader::new()
+            .with_bearer_token(Some("secret".to_string()))
+            .load(&url, None)
+            .unwrap();
+        assert_eq!(data, json!({ "name": "remote", "items": [1, 2] }));
+        let request = request.recv().unwrap().to_lowercase();
+        assert!(request.contains("authorization: bearer secret"));
+    }
+
+    #[test]
+    fn test_fetch_with_format_override() {
+        let (url, _request) = serve_once("text/plain", "{\"name\": \"remote\"}");
+        let data = DataLoader::new()
+            .with_format(Some(DataFormat::Json))
+            .load(&url, None)
+            .unwrap();
+        assert_eq!(data, json!({ "name": "remote" }));
+    }
+
+    #[test]
+    fn test_load_local_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("data.yml"), "name: local\n").unwrap();
+        let data = DataLoader::new().load("data.yml", Some(tmp.path())).unwrap();
+        assert_eq!(data, json!({ "name": "local" }));
+
+        let err = DataLoader::new().load("missing.json", Some(tmp.path())).unwrap_err();
+        assert!(!err.is_parse_error());
+    }
+
+    #[test]
+    fn test_validate_schema() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "type": "string" },
+                "ports": { "type": "array", "items": { "type": "integer" } }
+            }
+        });
+        assert!(validate_schema(&schema, &json!({ "name": "ok", "ports": [1] }))
+            .unwrap()
+            .is_empty());
+
+        let violations = validate_schema(&schema, &json!({ "ports": [1, "two"] })).unwrap();
+        let pointers: Vec<&str> = violations.iter().map(|v| v.pointer.as_str()).collect();
+        assert_eq!(violations.len(), 2);
+        assert!(pointers.contains(&""));
+        assert!(pointers.contains(&"/ports/1"));
+
+        let err = validate_schema(&json!({ "type": "unknown" }), &json!({})).unwrap_err();
+        assert!(matches!(err, DataError::Schema(_)));
+    }
+
+    #[test]
+    fn test_format_inference() {
+        assert_eq!(DataFormat::from_path("https://host/data.yaml?x=1"), DataFormat::Yaml);
+        assert_eq!(DataFormat::from_path("data.json"), DataFormat::Json);
+        assert_eq!(
+            DataFormat::from_content_type("application/json; charset=utf-8"),
+            Some(DataFormat::Json)
+        );
+        assert_eq!(DataFormat::from_content_type("text/html"), None);
+    }
+}
@@ -1,9 +1,150 @@
-use minijinja::{Environment, UndefinedBehavior};
+use minijinja::value::{Object, ObjectRepr, Value};
+use minijinja::{Environment, Error, ErrorKind, State, Template, UndefinedBehavior};
 use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+#[cfg(feature = "template-cache")]
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::filters::{random_choice_with, shuffle_with, SeededRng};
+use crate::i18n::MessageCatalogs;
+use crate::source::{FileSystemSource, TemplateSource, DEFAULT_MAX_TEMPLATE_SIZE};
+
+/// Default placeholder for undefined variables when missing keys are allowed.
+/// `{}` is replaced by the variable path.
+pub const DEFAULT_MISSING_KEY_PLACEHOLDER: &str = "<<UNDEFINED:{}>>";
+
+/// Name of the shared macros template registered with `set_macros`.
+pub const MACROS_TEMPLATE: &str = "<macros>";
+
+/// Filters and functions a sandboxed engine doesn't register: `debug`/`dump` print to stderr
+/// with `log=true`, minijinja's `debug()` dumps the whole context, and `markdown` passes raw
+/// HTML from its input through as safe with `allow_html=true`.
+pub const SANDBOX_EXCLUDED: &[&str] = &["debug", "dump", "markdown"];
+
+/// Instructions a template may execute per render in a sandboxed engine (minijinja's fuel).
+pub const SANDBOX_FUEL: u64 = 1_000_000;
+
+/// Nesting depth of includes, macro calls and blocks allowed in a sandboxed engine.
+pub const SANDBOX_RECURSION_LIMIT: usize = 50;
+
+/// Key marking a placeholder object in the JSON context before conversion.
+const MISSING_KEY_MARKER: &str = "\u{0}templify_missing";
+
+/// Stand-in for an undefined variable: renders as the placeholder, iterates as empty and is falsy.
+#[derive(Debug)]
+struct MissingValue {
+    placeholder: String,
+}
+
+impl Object for MissingValue {
+    fn repr(self: &Arc<Self>) -> ObjectRepr {
+        ObjectRepr::Seq
+    }
+
+    fn render(self: &Arc<Self>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.placeholder)
+    }
+}
+
+/// Whether a custom entry is registered as a filter or as a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    Filter,
+    Function,
+}
+
+/// A custom filter or function registered by `TemplateEngine::new`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterInfo {
+    pub kind: FilterKind,
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// Declares the custom filters and functions once, generating both their registration and
+/// `CUSTOM_FILTERS`, so the listing can't drift from what is registered.
+macro_rules! custom_filters {
+    ($($(#[$meta:meta])* $kind:ident $name:literal => $func:path, $description:literal;)*) => {
+        /// All custom filters and functions, in registration order.
+        pub const CUSTOM_FILTERS: &[FilterInfo] = &[
+            $($(#[$meta])* FilterInfo {
+                kind: FilterKind::$kind,
+                name: $name,
+                description: $description,
+            },)*
+        ];
+
+        fn register_custom_filters(env: &mut Environment<'static>) {
+            $($(#[$meta])* custom_filters!(@register env, $kind, $name, $func);)*
+        }
+    };
+    (@register $env:ident, Filter, $name:literal, $func:path) => {
+        $env.add_filter($name, $func);
+    };
+    (@register $env:ident, Function, $name:literal, $func:path) => {
+        $env.add_function($name, $func);
+    };
+}
+
+custom_filters! {
+    Filter "camelcase" => crate::filters::filter_camelcase, "Converts to camelCase";
+    Filter "pascalcase" => crate::filters::filter_pascalcase, "Converts to PascalCase";
+    Filter "snakecase" => crate::filters::filter_snakecase, "Converts to snake_case";
+    Filter "kebabcase" => crate::filters::filter_kebabcase, "Converts to kebab-case";
+    Filter "screamingsnakecase" => crate::filters::filter_screamingsnakecase, "Converts to SCREAMING_SNAKE_CASE";
+    Filter "uuid_generate" => crate::filters::filter_uuid_generate, "Deterministic UUID (v5) from the value, or a random UUID (v4) when empty";
+    Filter "default_if_none" => crate::filters::filter_default_if_none, "Replaces only none/null values with the fallback";
+    Filter "mandatory" => crate::filters::filter_mandatory, "Fails rendering with a message when the value is undefined or none";
+    Filter "wordcount" => crate::filters::filter_wordcount, "Number of words in a string";
+    Filter "linecount" => crate::filters::filter_linecount, "Number of lines in a string";
+    Filter "lines" => crate::filters::filter_lines, "Splits a string into a list of lines";
+    Filter "filesizeformat" => crate::filters::filter_filesizeformat, "Human-readable size in decimal or binary units";
+    Filter "ordinal" => crate::filters::filter_ordinal, "Number with its English ordinal suffix (1st, 2nd, ...)";
+    Filter "numberformat" => crate::filters::filter_numberformat, "Number with thousands separators and optional decimals";
+    Filter "contains" => crate::filters::filter_contains, "True if the string contains the argument";
+    Filter "startswith" => crate::filters::filter_startswith, "True if the string starts with the argument";
+    Filter "endswith" => crate::filters::filter_endswith, "True if the string ends with the argument";
+    Filter "count" => crate::filters::filter_count, "Occurrences of the argument in a string, or the length without an argument";
+    Filter "keys" => crate::filters::filter_keys, "Keys of a map, in the order of the data";
+    Filter "values" => crate::filters::filter_values, "Values of a map, in the order of the data";
+    Filter "items" => crate::filters::filter_items, "[key, value] pairs of a map, in the order of the data";
+    Filter "push" => crate::filters::filter_push, "Copy of a sequence with the arguments appended, for accumulating with namespace()";
+    Filter "lookup" => crate::filters::filter_lookup, "Value of the input key in a mapping; default= for missing keys";
+    Filter "get" => crate::filters::filter_get, "Value at a dotted path or JSON pointer, or a default when any segment is missing";
+    Filter "debug" => crate::filters::filter_debug, "Type-annotated description of a value, e.g. Array[2] [Int(1), Int(2)]; log=true prints it to stderr and passes the value through";
+    Filter "dump" => crate::filters::filter_debug, "Alias of debug";
+    Filter "join_with" => crate::filters::filter_join_with, "Joins items with a separator, skipping none and blank items; last= sets the final separator";
+    Filter "random_choice" => crate::filters::filter_random_choice, "Random item of a sequence, reproducible with --seed";
+    Filter "shuffle" => crate::filters::filter_shuffle, "Items of a sequence in random order, reproducible with --seed";
+    Filter "date_add" => crate::filters::filter_date_add, "Adds years/months/weeks/days/hours/minutes/seconds to a date";
+    Filter "date" => crate::filters::filter_date, "Formats a date with a strftime format (default %Y-%m-%d)";
+    Filter "relpath" => crate::filters::filter_relpath, "Path of the argument relative to the directory given as the value";
+    #[cfg(feature = "markdown")]
+    Filter "markdown" => crate::filters::filter_markdown, "Renders markdown to HTML, marked safe; raw HTML is escaped unless allow_html=true";
+    Function "relpath" => crate::filters::filter_relpath, "relpath(from, to): path of `to` relative to the directory `from`";
+    Function "now" => crate::filters::filter_now, "Current UTC date and time as RFC 3339";
+    Function "uuid_generate" => crate::filters::filter_uuid_generate, "Same as the uuid_generate filter";
+    Function "cycle" => crate::filters::filter_cycle, "Returns the argument at loop.index0 % n of the innermost loop";
+    Function "roundrobin" => crate::filters::filter_cycle, "Alias of cycle";
+    Function "trans" => crate::filters::filter_trans, "trans(key, count): message of key in the i18n catalog of the current locale, with {count} replaced";
+}
 
 /// TemplateEngine wraps minijinja::Environment and provides a clean API for rendering templates.
 pub struct TemplateEngine {
     env: Environment<'static>,
+    missing_key_placeholder: Option<String>,
+    missing_keys: Mutex<BTreeSet<String>>,
+    /// Number of templates compiled so far
+    compilations: Arc<AtomicUsize>,
+    /// Statement importing the shared macros, put in front of every template body
+    macros_import: Option<String>,
+    /// Sources of the cached templates by name, read by the environment's loader
+    #[cfg(feature = "template-cache")]
+    sources: Arc<Mutex<HashMap<String, String>>>,
 }
 
 impl TemplateEngine {
@@ -11,56 +152,408 @@ impl TemplateEngine {
     pub fn new() -> Self {
         let mut env = Environment::new();
         env.set_undefined_behavior(UndefinedBehavior::Strict);
-        
-        // Register custom filters
-        env.add_filter("camelcase", crate::filters::filter_camelcase);
-        env.add_filter("pascalcase", crate::filters::filter_pascalcase);
-        env.add_filter("snakecase", crate::filters::filter_snakecase);
-        env.add_filter("kebabcase", crate::filters::filter_kebabcase);
-        env.add_filter("screamingsnakecase", crate::filters::filter_screamingsnakecase);
-        env.add_filter("uuid_generate", crate::filters::filter_uuid_generate);
-        
-        // Register utility functions
-        env.add_function("uuid_generate", crate::filters::filter_uuid_generate);
 
-        Self { env }
+        register_custom_filters(&mut env);
+
+        let compilations = Arc::new(AtomicUsize::new(0));
+        // With the template cache, templates are loaded by name, so that the environment
+        // compiles each one once and keeps it for later renders
+        #[cfg(feature = "template-cache")]
+        let sources: Arc<Mutex<HashMap<String, String>>> = Arc::default();
+        #[cfg(feature = "template-cache")]
+        {
+            let sources = sources.clone();
+            let compilations = compilations.clone();
+            env.set_loader(move |name| {
+                compilations.fetch_add(1, Ordering::Relaxed);
+                Ok(sources.lock().unwrap().get(name).cloned())
+            });
+        }
+
+        Self {
+            env,
+            missing_key_placeholder: None,
+            missing_keys: Mutex::new(BTreeSet::new()),
+            compilations,
+            macros_import: None,
+            #[cfg(feature = "template-cache")]
+            sources,
+        }
+    }
+
+    /// Creates an engine for templates from untrusted sources: the filters and functions in
+    /// `SANDBOX_EXCLUDED` are not available, and every render is limited to `SANDBOX_FUEL`
+    /// instructions and `SANDBOX_RECURSION_LIMIT` levels of recursion.
+    pub fn sandboxed() -> Self {
+        let mut engine = Self::new();
+        for name in SANDBOX_EXCLUDED {
+            engine.env.remove_filter(name);
+            engine.env.remove_global(name);
+        }
+        engine.env.set_fuel(Some(SANDBOX_FUEL));
+        engine.env.set_recursion_limit(SANDBOX_RECURSION_LIMIT);
+        engine
+    }
+
+    /// Returns how many templates were compiled. With the `template-cache` feature, rendering
+    /// the same template string again doesn't compile it again.
+    pub fn compilations(&self) -> usize {
+        self.compilations.load(Ordering::Relaxed)
+    }
+
+    /// Compiles a template string, or returns the cached compilation of the same string.
+    fn template<'a>(&'a self, template_str: &'a str) -> Result<Template<'a, 'a>, Error> {
+        #[cfg(feature = "template-cache")]
+        {
+            use std::hash::{DefaultHasher, Hash, Hasher};
+
+            let mut hasher = DefaultHasher::new();
+            template_str.hash(&mut hasher);
+            let name = format!("<string {:016x}>", hasher.finish());
+            let cached = {
+                let mut sources = self.sources.lock().unwrap();
+                let source = sources
+                    .entry(name.clone())
+                    .or_insert_with(|| template_str.to_string());
+                source == template_str
+            };
+            // On a hash collision, compile without caching
+            if cached {
+                return self.env.get_template(&name);
+            }
+        }
+        self.compilations.fetch_add(1, Ordering::Relaxed);
+        self.env.template_from_str(template_str)
+    }
+
+    /// Returns the template string with the import of the shared macros in front of it. The
+    /// import is on the first line and renders nothing, so line numbers and output are unchanged.
+    fn with_macros<'a>(&self, template_str: &'a str) -> Cow<'a, str> {
+        match self.macros_import {
+            Some(ref import) => Cow::Owned(format!("{}{}", import, template_str)),
+            None => Cow::Borrowed(template_str),
+        }
+    }
+
+    /// Compiles a template string without rendering it, reporting syntax errors. The
+    /// compilation is cached for later renders with the `template-cache` feature.
+    pub fn check_template(&self, template_str: &str) -> Result<(), String> {
+        self.check_source(template_str, template_str)
+    }
+
+    /// Like `check_template`, for the body of a template file, which can call the shared macros.
+    pub fn check_body(&self, template_str: &str) -> Result<(), String> {
+        self.check_source(&self.with_macros(template_str), template_str)
+    }
+
+    fn check_source(&self, source: &str, template_str: &str) -> Result<(), String> {
+        self.template(source)
+            .map(|_| ())
+            .map_err(|e| render_error(&e, template_str))
+    }
+
+    /// Returns the context variables a template string reads, as dotted attribute paths such as
+    /// `svc.name`; a variable used as a whole is its bare name. Variables the template sets
+    /// itself, loop variables, globals and functions are left out.
+    pub fn referenced_variables(&self, template_str: &str) -> Result<BTreeSet<String>, String> {
+        self.source_variables(template_str, template_str)
+    }
+
+    /// Like `referenced_variables`, for the body of a template file, which can call the shared
+    /// macros.
+    pub fn body_variables(&self, template_str: &str) -> Result<BTreeSet<String>, String> {
+        self.source_variables(&self.with_macros(template_str), template_str)
+    }
+
+    fn source_variables(&self, source: &str, template_str: &str) -> Result<BTreeSet<String>, String> {
+        let template = self
+            .template(source)
+            .map_err(|e| render_error(&e, template_str))?;
+        Ok(template
+            .undeclared_variables(true)
+            .into_iter()
+            .filter(|path| !self.is_global(path.split('.').next().unwrap_or(path)))
+            .collect())
+    }
+
+    /// Renders undefined variables as `placeholder` (with `{}` replaced by the variable path)
+    /// instead of failing, and collects their names (see `missing_keys`).
+    pub fn allow_missing_keys(&mut self, placeholder: &str) {
+        let placeholder = placeholder.to_string();
+        let unknown = placeholder.replace("{}", "?");
+        self.env.set_undefined_behavior(UndefinedBehavior::Chainable);
+        self.env.set_formatter(move |out, state, value| {
+            if value.is_undefined() {
+                out.write_str(&unknown)?;
+                Ok(())
+            } else {
+                minijinja::escape_formatter(out, state, value)
+            }
+        });
+        self.missing_key_placeholder = Some(placeholder);
+    }
+
+    /// Returns the undefined variable paths found while rendering with `allow_missing_keys`.
+    pub fn missing_keys(&self) -> Vec<String> {
+        self.missing_keys.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Returns a copy of the context where every undefined variable referenced by the template
+    /// is set to the placeholder, recording the variable paths as missing.
+    fn fill_missing_keys<T: Serialize>(
+        &self,
+        template: &Template,
+        context: &T,
+        placeholder: &str,
+    ) -> Result<Value, String> {
+        let mut ctx = serde_json::to_value(context).map_err(|e| e.to_string())?;
+        if !ctx.is_object() {
+            return Ok(Value::from_serialize(&ctx));
+        }
+        let mut paths: Vec<String> = template.undeclared_variables(true).into_iter().collect();
+        // Longer paths first, so that intermediate objects are created before shorter paths are checked
+        paths.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        for path in paths {
+            let root = path.split('.').next().unwrap_or(&path);
+            if ctx.get(root).is_none() && self.is_global(root) {
+                continue;
+            }
+            if insert_placeholder(&mut ctx, &path, placeholder) {
+                self.missing_keys.lock().unwrap().insert(path);
+            }
+        }
+        Ok(to_template_value(&ctx))
+    }
+
+    /// Returns true if the name is a global variable or function of the environment.
+    fn is_global(&self, name: &str) -> bool {
+        self.env
+            .compile_expression(name)
+            .and_then(|expr| expr.eval(()))
+            .map(|value| !value.is_undefined())
+            .unwrap_or(false)
+    }
+
+    /// Replaces the `trans(key, count)` function with one that looks up `key` in the catalog of
+    /// the locale held by the catalogs' locale variable. An unknown key is an error in strict
+    /// mode and renders as the key itself when missing keys are allowed.
+    pub fn set_message_catalogs(&mut self, catalogs: MessageCatalogs) {
+        self.env.add_function(
+            "trans",
+            move |state: &State, key: String, count: Option<i64>| -> Result<String, Error> {
+                let locale = state
+                    .lookup(catalogs.locale_var())
+                    .and_then(|value| value.as_str().map(str::to_string))
+                    .or_else(|| catalogs.default_locale().map(str::to_string))
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidOperation,
+                            format!("trans: no locale in '{}'", catalogs.locale_var()),
+                        )
+                    })?;
+                match catalogs.lookup(&locale, &key, count) {
+                    Some(message) => Ok(message),
+                    None if state.undefined_behavior() == UndefinedBehavior::Strict => Err(Error::new(
+                        ErrorKind::InvalidOperation,
+                        format!("trans: no message '{}' for locale '{}'", key, locale),
+                    )),
+                    None => Ok(key),
+                }
+            },
+        );
+    }
+
+    /// Makes `random_choice` and `shuffle` draw from one generator seeded with `seed`, so that
+    /// rendering the same templates in the same order gives the same output.
+    pub fn set_seed(&mut self, seed: u64) {
+        let rng = Arc::new(Mutex::new(SeededRng::new(seed)));
+        let choice_rng = rng.clone();
+        self.env.add_filter("random_choice", move |values: Vec<Value>| {
+            random_choice_with(&mut choice_rng.lock().unwrap(), values)
+        });
+        self.env.add_filter("shuffle", move |values: Vec<Value>| {
+            shuffle_with(&mut rng.lock().unwrap(), values)
+        });
+    }
+
+    /// Makes the macros (and top-level variables) defined in `source` callable by name from
+    /// every template body (`render_body`, `check_body`, `body_variables` and `render_to_write`),
+    /// as if each body imported them from `MACROS_TEMPLATE`. File names, conditions and other
+    /// template strings don't import them. Fails if the macros template doesn't compile or
+    /// evaluate.
+    pub fn set_macros(&mut self, source: String) -> Result<(), String> {
+        self.env
+            .add_template_owned(MACROS_TEMPLATE, source.clone())
+            .map_err(|e| render_error(&e, &source))?;
+        let names = self
+            .env
+            .get_template(MACROS_TEMPLATE)
+            .and_then(|template| {
+                let state = template.eval_to_state(())?;
+                Ok(state.exports().join(", "))
+            })
+            .map_err(|e| render_error(&e, &source))?;
+        self.macros_import = (!names.is_empty())
+            .then(|| format!("{{% from \"{}\" import {} %}}", MACROS_TEMPLATE, names));
+        Ok(())
+    }
+
+    /// Sets minijinja's `trim_blocks` (drop the newline after a block tag) and `lstrip_blocks`
+    /// (drop the indentation before a block tag). Both are off by default.
+    pub fn with_whitespace_control(mut self, trim_blocks: bool, lstrip_blocks: bool) -> Self {
+        self.env.set_trim_blocks(trim_blocks);
+        self.env.set_lstrip_blocks(lstrip_blocks);
+        self
     }
 
     /// Registers a global variable in the template environment.
     pub fn add_global<T: Serialize>(&mut self, name: String, value: T) {
-        self.env.add_global(name, minijinja::value::Value::from_serialize(&value));
+        self.env.add_global(name, Value::from_serialize(&value));
     }
 
 
     /// Renders a template string with the given context.
     pub fn render_string<T: Serialize>(&self, template_str: &str, context: &T) -> Result<String, String> {
-        let template = self
-            .env
-            .template_from_str(template_str)
-            .map_err(|e| e.to_string())?;
-        
-        let rendered = template.render(context).map_err(|e| {
-            if let Some(line) = e.line() {
-                let error_line = template_str.lines().nth(line - 1).unwrap_or("");
-                format!("{}\\n{}", e, error_line)
-            } else {
-                format!("{}", e)
-            }
-        })?;
-        
+        self.render_source(template_str, template_str, context)
+    }
+
+    /// Renders the body of a template file with the given context. Unlike `render_string`, the
+    /// body can call the shared macros registered with `set_macros`.
+    pub fn render_body<T: Serialize>(&self, template_str: &str, context: &T) -> Result<String, String> {
+        self.render_source(&self.with_macros(template_str), template_str, context)
+    }
+
+    fn render_source<T: Serialize>(
+        &self,
+        source: &str,
+        template_str: &str,
+        context: &T,
+    ) -> Result<String, String> {
+        let template = self.template(source).map_err(|e| e.to_string())?;
+
+        let rendered = if let Some(placeholder) = &self.missing_key_placeholder {
+            let context = self.fill_missing_keys(&template, context, placeholder)?;
+            template.render(context)
+        } else {
+            template.render(context)
+        };
+        let rendered = rendered.map_err(|e| render_error(&e, template_str))?;
+
         Ok(rendered)
     }
 
+    /// Renders the body of a template file with the given context directly into a writer,
+    /// without building the whole output in memory. Like `render_body`, it can call the shared
+    /// macros.
+    pub fn render_to_write<T: Serialize, W: std::io::Write>(
+        &self,
+        template_str: &str,
+        context: &T,
+        writer: W,
+    ) -> Result<(), String> {
+        let source = self.with_macros(template_str);
+        let template = self.template(&source).map_err(|e| e.to_string())?;
+        let result = if let Some(placeholder) = &self.missing_key_placeholder {
+            let context = self.fill_missing_keys(&template, context, placeholder)?;
+            template.render_to_write(context, writer).map(|_| ())
+        } else {
+            template.render_to_write(context, writer).map(|_| ())
+        };
+        result.map_err(|e| render_error(&e, template_str))
+    }
+
+    /// Evaluates an expression such as `item.enabled and not item.internal` and returns its truthiness.
+    pub fn evaluate_condition<T: Serialize>(&self, expr: &str, context: &T) -> Result<bool, String> {
+        let expression = self
+            .env
+            .compile_expression(expr)
+            .map_err(|e| format!("Invalid condition '{}': {}", expr, e))?;
+        let value = expression
+            .eval(context)
+            .map_err(|e| format!("Failed to evaluate condition '{}': {}", expr, e))?;
+        Ok(value.is_true())
+    }
+
+    /// Evaluates an expression such as `name | lower ~ "_svc"` and returns its value.
+    pub fn evaluate_expression<T: Serialize>(&self, expr: &str, context: &T) -> Result<serde_json::Value, String> {
+        let expression = self
+            .env
+            .compile_expression(expr)
+            .map_err(|e| format!("Invalid expression '{}': {}", expr, e))?;
+        let value = expression
+            .eval(context)
+            .map_err(|e| format!("Failed to evaluate expression '{}': {}", expr, e))?;
+        serde_json::to_value(&value).map_err(|e| format!("Invalid value of expression '{}': {}", expr, e))
+    }
+
     /// Renders a template from a file with the given context.
     pub fn render_file<T: Serialize>(&self, template_path: &std::path::Path, context: &T) -> Result<String, String> {
-        let template_str = std::fs::read_to_string(template_path)
+        let template_str = FileSystemSource
+            .read_template(template_path, DEFAULT_MAX_TEMPLATE_SIZE)
             .map_err(|e| format!("Failed to read template file {:?}: {}", template_path, e))?;
-        
-        self.render_string(&template_str, context)
+
+        self.render_body(&template_str, context)
             .map_err(|e| format!("{:?}, error: {}", template_path, e))
     }
 }
 
+/// Formats a render error, including the template line it occurred on.
+fn render_error(e: &minijinja::Error, template_str: &str) -> String {
+    if let Some(line) = e.line() {
+        let error_line = template_str.lines().nth(line - 1).unwrap_or("");
+        format!("{}\\n{}", e, error_line)
+    } else {
+        format!("{}", e)
+    }
+}
+
+/// Sets the placeholder at a dotted path if the path doesn't resolve, creating intermediate objects.
+/// Returns true if the path was missing.
+fn insert_placeholder(ctx: &mut serde_json::Value, path: &str, placeholder: &str) -> bool {
+    let segments: Vec<&str> = path.split('.').collect();
+    let mut current = ctx;
+    for (i, segment) in segments.iter().enumerate() {
+        let map = match current {
+            serde_json::Value::Object(map) => map,
+            // Attribute of a non-object value, can't be filled in
+            _ => return false,
+        };
+        if !map.contains_key(*segment) {
+            let mut value = serde_json::json!({ MISSING_KEY_MARKER: placeholder.replace("{}", path) });
+            for inner in segments[i + 1..].iter().rev() {
+                let mut obj = serde_json::Map::new();
+                obj.insert(inner.to_string(), value);
+                value = serde_json::Value::Object(obj);
+            }
+            map.insert(segment.to_string(), value);
+            return true;
+        }
+        current = map.get_mut(*segment).unwrap();
+    }
+    false
+}
+
+/// Converts a JSON context into a template value, replacing marked objects with `MissingValue`.
+fn to_template_value(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(serde_json::Value::String(placeholder)) = map.get(MISSING_KEY_MARKER) {
+                return Value::from_object(MissingValue {
+                    placeholder: placeholder.clone(),
+                });
+            }
+            // Collected into minijinja's own map, which keeps the key order of the data
+            map.iter()
+                .map(|(k, v)| (k.as_str(), to_template_value(v)))
+                .collect()
+        }
+        serde_json::Value::Array(items) => {
+            Value::from(items.iter().map(to_template_value).collect::<Vec<_>>())
+        }
+        _ => Value::from_serialize(value),
+    }
+}
+
 impl Default for TemplateEngine {
     fn default() -> Self {
         Self::new()
@@ -72,6 +565,44 @@ mod tests {
     use super::*;
     use std::collections::HashMap;
 
+    #[test]
+    fn test_custom_filters_listed_and_registered() {
+        let names: Vec<&str> = CUSTOM_FILTERS.iter().map(|f| f.name).collect();
+        for name in ["camelcase", "pascalcase", "snakecase", "uuid_generate", "cycle"] {
+            assert!(names.contains(&name), "{} is not listed", name);
+        }
+
+        let engine = TemplateEngine::new();
+        let context: HashMap<&str, &str> = HashMap::new();
+        for info in CUSTOM_FILTERS {
+            match info.kind {
+                FilterKind::Filter => {
+                    let template = format!("{{{{ \"x\" | {} }}}}", info.name);
+                    if let Err(e) = engine.render_string(&template, &context) {
+                        assert!(!e.contains("unknown filter"), "{}: {}", info.name, e);
+                    }
+                }
+                FilterKind::Function => assert!(engine.is_global(info.name), "{}", info.name),
+            }
+        }
+    }
+
+    #[test]
+    fn test_referenced_variables() {
+        let engine = TemplateEngine::new();
+        let variables = engine
+            .referenced_variables(
+                "{% set title = project.name | upper %}{{ title }} {{ now() }}\n\
+                 {% for svc in services %}{{ svc.name }}:{{ svc.port }} {{ defaults.port }}{% endfor %}",
+            )
+            .unwrap();
+        assert_eq!(
+            variables.into_iter().collect::<Vec<_>>(),
+            ["defaults.port", "project.name", "services"]
+        );
+        assert!(engine.referenced_variables("{{ unclosed").is_err());
+    }
+
     #[test]
     fn test_render_string() {
         let engine = TemplateEngine::new();
@@ -80,16 +611,171 @@ mod tests {
         assert_eq!(result, "Hello, World!");
     }
 
+    #[test]
+    fn test_sandbox() {
+        let context = HashMap::from([("items", vec![1, 2])]);
+        let engine = TemplateEngine::new();
+        assert_eq!(
+            engine.render_string("{{ items | dump }}", &context).unwrap(),
+            "Array[2] [Int(1), Int(2)]"
+        );
+        assert!(engine.render_string("{{ debug() }}", &context).unwrap().contains("items"));
+
+        let sandboxed = TemplateEngine::sandboxed();
+        for template in ["{{ items | dump }}", "{{ items | debug }}", "{{ debug() }}"] {
+            assert!(sandboxed.render_string(template, &context).is_err(), "{}", template);
+        }
+        let safe = sandboxed.render_string("{{ items | join('-') | snakecase }}", &context);
+        assert_eq!(safe.unwrap(), "1_2");
+
+        let busy = "{% for i in range(1000) %}{% for j in range(1000) %}{% endfor %}{% endfor %}done";
+        assert_eq!(engine.render_string(busy, &context).unwrap(), "done");
+        assert!(sandboxed.render_string(busy, &context).is_err());
+
+        let recursive =
+            "{% macro down(n) %}{% if n %}{{ down(n - 1) }}{% endif %}{% endmacro %}{{ down(60) }}x";
+        assert_eq!(engine.render_string(recursive, &context).unwrap(), "x");
+        assert!(sandboxed.render_string(recursive, &context).is_err());
+    }
+
+    #[test]
+    fn test_whitespace_control() {
+        let context = HashMap::from([("items", vec!["a", "b"])]);
+        let template = "list:\n  {% for item in items %}\n  - {{ item }}\n  {% endfor %}\nend";
+        let default = TemplateEngine::new().render_string(template, &context).unwrap();
+        assert_eq!(default, "list:\n  \n  - a\n  \n  - b\n  \nend");
+
+        let trimmed = TemplateEngine::new()
+            .with_whitespace_control(true, true)
+            .render_string(template, &context)
+            .unwrap();
+        assert_eq!(trimmed, "list:\n  - a\n  - b\nend");
+
+        let trim_only = TemplateEngine::new()
+            .with_whitespace_control(true, false)
+            .render_string(template, &context)
+            .unwrap();
+        assert_eq!(trim_only, "list:\n    - a\n    - b\n  end");
+    }
+
     #[test]
     fn test_render_string_with_globals() {
         let mut engine = TemplateEngine::new();
         engine.add_global("version".to_string(), "1.0.0");
-        
+
         let context = HashMap::from([("name", "Test")]);
         let result = engine.render_string("{{ name }} v{{ version }}", &context).unwrap();
         assert_eq!(result, "Test v1.0.0");
     }
 
+    #[test]
+    fn test_allow_missing_keys() {
+        let mut engine = TemplateEngine::new();
+        engine.allow_missing_keys(DEFAULT_MISSING_KEY_PLACEHOLDER);
+        let context = serde_json::json!({ "service": { "name": "auth" } });
+        let result = engine
+            .render_string(
+                "{{ service.name }}:{{ service.port }} {{ owner }} {{ range(2) | length }}",
+                &context,
+            )
+            .unwrap();
+        assert_eq!(result, "auth:<<UNDEFINED:service.port>> <<UNDEFINED:owner>> 2");
+
+        let result = engine
+            .render_string("{% for s in services %}x{% endfor %}{{ cfg.a.b }}", &context)
+            .unwrap();
+        assert_eq!(result, "<<UNDEFINED:cfg.a.b>>");
+
+        assert_eq!(
+            engine.missing_keys(),
+            vec!["cfg.a.b", "owner", "service.port", "services"]
+        );
+
+        let context = serde_json::json!({ "ports": { "web": 80, "api": 8080, "db": 5432 } });
+        let result = engine
+            .render_string("{% for name in ports %}{{ name }} {% endfor %}{{ owner }}", &context)
+            .unwrap();
+        assert_eq!(result, "web api db <<UNDEFINED:owner>>");
+    }
+
+    #[test]
+    fn test_evaluate_condition() {
+        let engine = TemplateEngine::new();
+        let context = serde_json::json!({ "item": { "enabled": true, "tags": [] } });
+        assert!(engine.evaluate_condition("item.enabled", &context).unwrap());
+        assert!(!engine.evaluate_condition("item.tags", &context).unwrap());
+        assert!(engine.evaluate_condition("not item.tags and item.enabled", &context).unwrap());
+        assert!(engine.evaluate_condition("item.enabled and", &context).is_err());
+    }
+
+    #[test]
+    fn test_trans() {
+        let catalogs = MessageCatalogs::new("locale")
+            .with_default_locale(Some("en".to_string()))
+            .with_catalog("en", serde_json::json!({ "files": { "one": "1 file", "other": "{count} files" } }))
+            .with_catalog("de", serde_json::json!({ "files": { "one": "1 Datei", "other": "{count} Dateien" } }));
+        let mut engine = TemplateEngine::new();
+        engine.set_message_catalogs(catalogs.clone());
+
+        let template = "{{ trans('files', 1) }}, {{ trans('files', 4) }}";
+        let de = serde_json::json!({ "locale": "de" });
+        assert_eq!(engine.render_string(template, &de).unwrap(), "1 Datei, 4 Dateien");
+        let context: HashMap<String, String> = HashMap::new();
+        assert_eq!(engine.render_string(template, &context).unwrap(), "1 file, 4 files");
+
+        let err = engine.render_string("{{ trans('title') }}", &de).unwrap_err();
+        assert!(err.contains("no message 'title' for locale 'de'"), "{}", err);
+        // Registered without catalogs too, so it is listed
+        let err = TemplateEngine::new().render_string("{{ trans('title') }}", &de).unwrap_err();
+        assert!(err.contains("no message catalogs are configured"), "{}", err);
+
+        let mut engine = TemplateEngine::new();
+        engine.allow_missing_keys(DEFAULT_MISSING_KEY_PLACEHOLDER);
+        engine.set_message_catalogs(catalogs);
+        assert_eq!(engine.render_string("{{ trans('title') }}", &de).unwrap(), "title");
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut engine = TemplateEngine::new();
+        engine
+            .set_macros("{% macro header(t) %}# {{ t }}{% endmacro %}{% set sep = '-' %}".to_string())
+            .unwrap();
+        let context = HashMap::from([("name", "a")]);
+        assert_eq!(engine.render_body("{{ header(name) }}{{ sep }}", &context).unwrap(), "# a-");
+        assert!(engine.check_body("{{ header(name) }}").is_ok());
+        assert_eq!(
+            engine.body_variables("{{ header(name) }}").unwrap(),
+            BTreeSet::from(["name".to_string()])
+        );
+        // Names, conditions and other template strings don't import the macros
+        assert!(engine.render_string("{{ header(name) }}", &context).is_err());
+        assert!(engine.set_macros("{% macro %}".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_check_template() {
+        let engine = TemplateEngine::new();
+        assert!(engine.check_template("{{ name | upper }}").is_ok());
+        let err = engine.check_template("line\n{% if %}").unwrap_err();
+        assert!(err.contains("syntax error"), "{}", err);
+    }
+
+    #[cfg(feature = "template-cache")]
+    #[test]
+    fn test_template_cache() {
+        let engine = TemplateEngine::new();
+        let context = HashMap::from([("name", "World")]);
+        engine.check_template("Hello {{ name }}").unwrap();
+        assert_eq!(engine.compilations(), 1);
+        for _ in 0..3 {
+            assert_eq!(engine.render_string("Hello {{ name }}", &context).unwrap(), "Hello World");
+        }
+        assert_eq!(engine.compilations(), 1);
+        engine.render_string("Bye {{ name }}", &context).unwrap();
+        assert_eq!(engine.compilations(), 2);
+    }
+
     #[test]
     fn test_render_string_undefined_variable() {
         let engine = TemplateEngine::new();
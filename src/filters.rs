@@ -1,4 +1,8 @@
+use chrono::{DateTime, FixedOffset, Months, NaiveDate, NaiveDateTime, SecondsFormat, TimeDelta, Utc};
 use heck::{ToKebabCase, ToLowerCamelCase, ToPascalCase, ToShoutySnakeCase, ToSnakeCase};
+use minijinja::value::{Kwargs, Rest, ValueKind};
+use minijinja::{Error, ErrorKind, State, UndefinedBehavior, Value};
+use std::path::{Component, Path};
 use uuid::Uuid;
 
 // Export individual filter functions
@@ -8,6 +12,36 @@ pub use self::snakecase as filter_snakecase;
 pub use self::kebabcase as filter_kebabcase;
 pub use self::screamingsnakecase as filter_screamingsnakecase;
 pub use self::uuid_generate as filter_uuid_generate;
+pub use self::default_if_none as filter_default_if_none;
+pub use self::mandatory as filter_mandatory;
+pub use self::cycle as filter_cycle;
+pub use self::wordcount as filter_wordcount;
+pub use self::linecount as filter_linecount;
+pub use self::lines as filter_lines;
+pub use self::filesizeformat as filter_filesizeformat;
+pub use self::ordinal as filter_ordinal;
+pub use self::numberformat as filter_numberformat;
+pub use self::relpath as filter_relpath;
+pub use self::contains as filter_contains;
+pub use self::startswith as filter_startswith;
+pub use self::endswith as filter_endswith;
+pub use self::count as filter_count;
+pub use self::keys as filter_keys;
+pub use self::values as filter_values;
+pub use self::items as filter_items;
+pub use self::push as filter_push;
+pub use self::lookup as filter_lookup;
+pub use self::get as filter_get;
+pub use self::debug as filter_debug;
+pub use self::join_with as filter_join_with;
+pub use self::random_choice as filter_random_choice;
+pub use self::shuffle as filter_shuffle;
+pub use self::now as filter_now;
+pub use self::trans as filter_trans;
+pub use self::date_add as filter_date_add;
+pub use self::date as filter_date;
+#[cfg(feature = "markdown")]
+pub use self::markdown as filter_markdown;
 
 /* 
    Note: We assume these match minijinja's Filter signature.
@@ -47,3 +81,1018 @@ pub fn uuid_generate(val: Option<String>) -> String {
         _ => Uuid::new_v4().to_string(),
     }
 }
+
+/// Returns `fallback` only if the value is none (null), unlike `default` which only replaces undefined.
+/// An undefined value is passed through unchanged, so strict mode still reports it as missing.
+pub fn default_if_none(value: Value, fallback: Value) -> Value {
+    if value.is_none() {
+        fallback
+    } else {
+        value
+    }
+}
+
+/// Fails rendering with `message` if the value is undefined or none (null).
+/// Empty strings and other falsy values are accepted.
+pub fn mandatory(value: Value, message: Option<String>) -> Result<Value, Error> {
+    if value.is_undefined() || value.is_none() {
+        let message = message.unwrap_or_else(|| "mandatory value is undefined or none".to_string());
+        Err(Error::new(ErrorKind::InvalidOperation, message))
+    } else {
+        Ok(value)
+    }
+}
+
+/// Returns the argument at `loop.index0 % n` of the innermost `loop`, e.g. `{{ cycle("odd", "even") }}`.
+pub fn cycle(state: &State, values: Rest<Value>) -> Result<Value, Error> {
+    if values.is_empty() {
+        return Err(Error::new(
+            ErrorKind::MissingArgument,
+            "cycle() requires at least one value",
+        ));
+    }
+    let index0 = state
+        .lookup("loop")
+        .and_then(|l| l.get_attr("index0").ok())
+        .and_then(|i| usize::try_from(i).ok())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidOperation,
+                "cycle() can only be used inside a loop",
+            )
+        })?;
+    Ok(values[index0 % values.len()].clone())
+}
+
+/// Joins the items of a sequence with `sep`, skipping none, undefined and blank items, so
+/// conditionally rendered items never leave a doubled or trailing separator. `last=` sets the
+/// separator before the final item, e.g. `{{ names | join_with(", ", last=" and ") }}`.
+pub fn join_with(values: Vec<Value>, sep: String, kwargs: Kwargs) -> Result<String, Error> {
+    let last = kwargs.get::<Option<String>>("last")?;
+    kwargs.assert_all_used()?;
+    let items: Vec<String> = values
+        .iter()
+        .filter(|value| !value.is_none() && !value.is_undefined())
+        .map(|value| value.to_string())
+        .filter(|item| !item.trim().is_empty())
+        .collect();
+    let mut joined = String::new();
+    for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+            match last {
+                Some(ref last) if index == items.len() - 1 => joined.push_str(last),
+                _ => joined.push_str(&sep),
+            }
+        }
+        joined.push_str(item);
+    }
+    Ok(joined)
+}
+
+/// Random number generator behind `random_choice` and `shuffle` (SplitMix64); the same seed
+/// gives the same sequence.
+#[derive(Debug, Clone)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Creates a generator with a random seed.
+    pub fn from_entropy() -> Self {
+        Self::new(Uuid::new_v4().as_u64_pair().0)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a random index below `n`, which must not be 0.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Picks a random item of a sequence, e.g. `{{ ["red", "green"] | random_choice }}`.
+/// Reproducible with `--seed`.
+pub fn random_choice(values: Vec<Value>) -> Result<Value, Error> {
+    random_choice_with(&mut SeededRng::from_entropy(), values)
+}
+
+/// Returns the items of a sequence in random order. Reproducible with `--seed`.
+pub fn shuffle(values: Vec<Value>) -> Vec<Value> {
+    shuffle_with(&mut SeededRng::from_entropy(), values)
+}
+
+/// `random_choice` drawing from the given generator.
+pub fn random_choice_with(rng: &mut SeededRng, values: Vec<Value>) -> Result<Value, Error> {
+    if values.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            "random_choice requires a non-empty sequence",
+        ));
+    }
+    let index = rng.below(values.len());
+    Ok(values[index].clone())
+}
+
+/// `shuffle` drawing from the given generator (Fisher-Yates).
+pub fn shuffle_with(rng: &mut SeededRng, mut values: Vec<Value>) -> Vec<Value> {
+    for i in (1..values.len()).rev() {
+        values.swap(i, rng.below(i + 1));
+    }
+    values
+}
+
+/// Returns true if the string contains `substr`. An empty `substr` is always contained.
+pub fn contains(s: String, substr: String) -> bool {
+    s.contains(&substr)
+}
+
+/// Returns true if the string starts with `prefix`, e.g. `{% if name | startswith("Get") %}`.
+pub fn startswith(s: String, prefix: String) -> bool {
+    s.starts_with(&prefix)
+}
+
+/// Returns true if the string ends with `suffix`, e.g. `{% if name | endswith("Service") %}`.
+pub fn endswith(s: String, suffix: String) -> bool {
+    s.ends_with(&suffix)
+}
+
+/// Returns the keys and values of a map, in the order of the data.
+fn entries(value: &Value, filter: &str) -> Result<Vec<(Value, Value)>, Error> {
+    if value.kind() != ValueKind::Map {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            format!("{} requires a map, got {}", filter, value.kind()),
+        ));
+    }
+    value
+        .try_iter()?
+        .map(|key| Ok((key.clone(), value.get_item(&key)?)))
+        .collect()
+}
+
+/// Keys of a map, e.g. `{{ config | keys | join(", ") }}`.
+pub fn keys(value: Value) -> Result<Value, Error> {
+    Ok(Value::from(entries(&value, "keys")?.into_iter().map(|(k, _)| k).collect::<Vec<_>>()))
+}
+
+/// Values of a map.
+pub fn values(value: Value) -> Result<Value, Error> {
+    Ok(Value::from(entries(&value, "values")?.into_iter().map(|(_, v)| v).collect::<Vec<_>>()))
+}
+
+/// `[key, value]` pairs of a map, e.g. `{% for k, v in config | items %}`.
+pub fn items(value: Value) -> Result<Value, Error> {
+    Ok(Value::from(
+        entries(&value, "items")?
+            .into_iter()
+            .map(|(k, v)| Value::from(vec![k, v]))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// A copy of a sequence with the arguments appended. Templates can't mutate values, so lists are
+/// built across loop iterations by reassigning a namespace attribute:
+/// `{% set ns.names = ns.names | push(item.name) %}`.
+pub fn push(values: Value, items: Rest<Value>) -> Result<Value, Error> {
+    if values.kind() != ValueKind::Seq {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            format!("push requires a sequence, got {}", values.kind()),
+        ));
+    }
+    let mut values: Vec<Value> = values.try_iter()?.collect();
+    values.extend(items.0);
+    Ok(Value::from(values))
+}
+
+/// Value of the input key in a mapping, e.g.
+/// `{{ status | lookup({"ok": "green", "err": "red"}, default="gray") }}`. A key missing from
+/// the mapping gives `default=`; without it, it is an error in strict mode and undefined
+/// otherwise.
+pub fn lookup(state: &State, key: Value, mapping: Value, kwargs: Kwargs) -> Result<Value, Error> {
+    let default = if kwargs.has("default") {
+        Some(kwargs.get::<Value>("default")?)
+    } else {
+        None
+    };
+    kwargs.assert_all_used()?;
+    if mapping.kind() != ValueKind::Map {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            format!("lookup requires a map, got {}", mapping.kind()),
+        ));
+    }
+    let value = mapping.get_item(&key)?;
+    if !value.is_undefined() {
+        return Ok(value);
+    }
+    match default {
+        Some(default) => Ok(default),
+        None if state.undefined_behavior() == UndefinedBehavior::Strict => Err(Error::new(
+            ErrorKind::UndefinedError,
+            format!("lookup: no entry for {} and no default", key),
+        )),
+        None => Ok(Value::UNDEFINED),
+    }
+}
+
+/// Value at a dotted path (`spec.limits.cpu`, `ports.0`) or JSON pointer (`/spec/limits/cpu`)
+/// below the input, or `default` (none if omitted) when any segment is missing, e.g.
+/// `{{ service | get("spec.resources.limits.cpu", "500m") }}`. Numeric segments index into
+/// sequences. Never fails, so optional deep fields work in strict mode.
+pub fn get(value: Value, path: String, default: Option<Value>) -> Value {
+    let segments: Vec<String> = match path.strip_prefix('/') {
+        Some(pointer) => pointer
+            .split('/')
+            .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None if path.is_empty() => Vec::new(),
+        None => path.split('.').map(str::to_string).collect(),
+    };
+    let mut current = value;
+    for segment in segments {
+        let next = match current.kind() {
+            ValueKind::Seq => segment
+                .parse::<i64>()
+                .ok()
+                .and_then(|index| current.get_item(&Value::from(index)).ok()),
+            ValueKind::Map => current.get_item(&Value::from(segment)).ok(),
+            _ => None,
+        };
+        match next {
+            Some(next) if !next.is_undefined() => current = next,
+            _ => return default.unwrap_or(Value::from(())),
+        }
+    }
+    current
+}
+
+/// Type-annotated description of a value for template debugging, e.g.
+/// `Object{"name": String("api"), "ports": Array[2] [Int(80), Int(443)]}`. With `log=true`, the
+/// description is printed to stderr instead and the value passes through unchanged, so
+/// `{{ items | debug(log=true) | join(", ") }}` inspects an intermediate value mid-pipeline.
+pub fn debug(value: Value, kwargs: Kwargs) -> Result<Value, Error> {
+    let log = kwargs.get::<Option<bool>>("log")?.unwrap_or(false);
+    kwargs.assert_all_used()?;
+    if log {
+        eprintln!("[debug] {}", describe(&value));
+        Ok(value)
+    } else {
+        Ok(Value::from(describe(&value)))
+    }
+}
+
+fn describe(value: &Value) -> String {
+    let entries = |value: &Value| {
+        value
+            .try_iter()
+            .map(|iter| iter.collect::<Vec<_>>())
+            .unwrap_or_default()
+    };
+    match value.kind() {
+        ValueKind::Undefined => "Undefined".to_string(),
+        ValueKind::None => "None".to_string(),
+        ValueKind::Bool => format!("Bool({})", value.is_true()),
+        ValueKind::Number if value.is_integer() => format!("Int({})", value),
+        ValueKind::Number => format!("Float({})", value),
+        ValueKind::String => format!("String({:?})", value.as_str().unwrap_or_default()),
+        ValueKind::Seq | ValueKind::Iterable => {
+            let items = entries(value);
+            let items: Vec<String> = items.iter().map(describe).collect();
+            format!("Array[{}] [{}]", items.len(), items.join(", "))
+        }
+        ValueKind::Map => {
+            let entries: Vec<String> = entries(value)
+                .iter()
+                .map(|key| {
+                    let item = value.get_item(key).unwrap_or_default();
+                    match key.as_str() {
+                        Some(key) => format!("{:?}: {}", key, describe(&item)),
+                        None => format!("{}: {}", describe(key), describe(&item)),
+                    }
+                })
+                .collect();
+            format!("Object{{{}}}", entries.join(", "))
+        }
+        kind => format!("{}({})", kind, value),
+    }
+}
+
+/// Counts non-overlapping occurrences of `substr` in a string; an empty `substr` counts 0.
+/// Without an argument this is the built-in `count`, the length of the value.
+pub fn count(value: Value, substr: Option<String>) -> Result<usize, Error> {
+    match substr {
+        Some(substr) => {
+            let s = value.as_str().ok_or_else(|| {
+                Error::new(ErrorKind::InvalidOperation, "count(substr) requires a string")
+            })?;
+            if substr.is_empty() {
+                Ok(0)
+            } else {
+                Ok(s.matches(&substr).count())
+            }
+        }
+        None => value.len().ok_or_else(|| {
+            Error::new(ErrorKind::InvalidOperation, "cannot calculate length of value")
+        }),
+    }
+}
+
+/// Counts whitespace-separated words.
+pub fn wordcount(s: String) -> usize {
+    s.split_whitespace().count()
+}
+
+/// Counts lines. A trailing newline does not start an extra line, and `\r\n` counts as one line break.
+pub fn linecount(s: String) -> usize {
+    s.lines().count()
+}
+
+/// Splits a string into lines, accepting `\n` and `\r\n` line endings.
+/// A trailing newline does not produce an empty last line.
+pub fn lines(s: String) -> Vec<String> {
+    s.lines().map(|line| line.to_string()).collect()
+}
+
+/// Formats a byte count as a human-readable size, e.g. `1.5 kB`.
+/// Uses decimal units (kB, MB, ...) by default and binary units (KiB, MiB, ...) if `binary` is true.
+pub fn filesizeformat(bytes: f64, binary: Option<bool>) -> String {
+    let (base, prefixes) = if binary.unwrap_or(false) {
+        (1024.0, ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB", "ZiB", "YiB"])
+    } else {
+        (1000.0, ["kB", "MB", "GB", "TB", "PB", "EB", "ZB", "YB"])
+    };
+    if bytes == 1.0 {
+        return "1 Byte".to_string();
+    }
+    if bytes < base {
+        return format!("{} Bytes", bytes);
+    }
+    let mut unit = base;
+    for prefix in prefixes.iter() {
+        if bytes < unit * base {
+            return format!("{:.1} {}", bytes / unit, prefix);
+        }
+        unit *= base;
+    }
+    format!("{:.1} {}", bytes / (unit / base), prefixes[prefixes.len() - 1])
+}
+
+/// Appends the English ordinal suffix to a number: 1st, 2nd, 3rd, 4th, 11th, 21st.
+pub fn ordinal(n: i64) -> String {
+    let suffix = match (n.abs() % 10, n.abs() % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+/// Formats a number with `,` as thousands separator, optionally with a fixed number of decimals.
+/// Without `decimals`, whole numbers are printed without a fractional part.
+pub fn numberformat(value: f64, decimals: Option<usize>) -> String {
+    let formatted = match decimals {
+        Some(d) => format!("{:.*}", d, value),
+        None if value.fract() == 0.0 => format!("{:.0}", value),
+        None => value.to_string(),
+    };
+    let (sign, unsigned) = match formatted.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", formatted.as_str()),
+    };
+    let (int_part, frac_part) = match unsigned.split_once('.') {
+        Some((i, f)) => (i, Some(f)),
+        None => (unsigned, None),
+    };
+    let mut grouped = String::new();
+    for (i, c) in int_part.chars().enumerate() {
+        if i > 0 && (int_part.len() - i) % 3 == 0 {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    match frac_part {
+        Some(f) => format!("{}{}.{}", sign, grouped, f),
+        None => format!("{}{}", sign, grouped),
+    }
+}
+
+/// Returns the path of `to` relative to the directory `from`, like `pathdiff`, e.g.
+/// `relpath("src/api", "src/common/mod.rs")` is `../common/mod.rs`. Paths are compared
+/// lexically and must be both relative or both absolute. The result always uses `/`.
+pub fn relpath(from: String, to: String) -> Result<String, Error> {
+    let error = |reason: &str| {
+        Error::new(
+            ErrorKind::InvalidOperation,
+            format!("relpath: cannot relate {:?} to {:?}: {}", to, from, reason),
+        )
+    };
+    if Path::new(&from).has_root() != Path::new(&to).has_root() {
+        return Err(error("one path is absolute and the other is relative"));
+    }
+    let from = normalize_components(&from);
+    let to = normalize_components(&to);
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    if from[common..].iter().any(|c| c == "..") {
+        return Err(error("the base path leaves its starting directory"));
+    }
+    let mut parts: Vec<&str> = vec![".."; from.len() - common];
+    parts.extend(to[common..].iter().map(String::as_str));
+    if parts.is_empty() {
+        return Ok(".".to_string());
+    }
+    Ok(parts.join("/"))
+}
+
+/// Splits a path into its normal components, resolving `.` and `..` lexically.
+fn normalize_components(path: &str) -> Vec<String> {
+    let mut components: Vec<String> = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_string_lossy().to_string()),
+            Component::ParentDir => {
+                if components.last().is_some_and(|last| last != "..") {
+                    components.pop();
+                } else {
+                    components.push("..".to_string());
+                }
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    components
+}
+
+/// `trans(key, count)` of an engine without message catalogs: an error in strict mode, and the
+/// key itself when missing keys are allowed. `TemplateEngine::set_message_catalogs` replaces it.
+pub fn trans(state: &State, key: String, _count: Option<i64>) -> Result<String, Error> {
+    if state.undefined_behavior() == UndefinedBehavior::Strict {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            format!("trans: no message '{}': no message catalogs are configured", key),
+        ));
+    }
+    Ok(key)
+}
+
+/// Returns the current UTC date and time as RFC 3339, e.g. `2024-01-31T12:00:00Z`.
+pub fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Parses an RFC 3339 date-time, a date-time without offset (taken as UTC) or a `YYYY-MM-DD`
+/// date. Returns the date-time and whether the value was a date only.
+fn parse_date(value: &str) -> Result<(DateTime<FixedOffset>, bool), Error> {
+    let value = value.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Ok((dt, false));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok((dt.and_utc().fixed_offset(), false));
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let dt = date.and_hms_opt(0, 0, 0).unwrap().and_utc().fixed_offset();
+        return Ok((dt, true));
+    }
+    Err(Error::new(
+        ErrorKind::InvalidOperation,
+        format!("'{}' is not a date (expected YYYY-MM-DD or RFC 3339)", value),
+    ))
+}
+
+/// Adds `years`, `months`, `weeks`, `days`, `hours`, `minutes` and `seconds` (negative values
+/// subtract) to a date, e.g. `{{ now() | date_add(days=30) }}`. Adding months keeps the day
+/// of the month, clamped to the end of shorter months (Jan 31 + 1 month is Feb 28/29).
+/// A `YYYY-MM-DD` date stays a date; other values are returned as RFC 3339.
+pub fn date_add(value: String, kwargs: Kwargs) -> Result<String, Error> {
+    let (mut dt, date_only) = parse_date(&value)?;
+    let overflow = || {
+        Error::new(
+            ErrorKind::InvalidOperation,
+            format!("date_add: result for '{}' is out of range", value),
+        )
+    };
+
+    let years = kwargs.get::<Option<i64>>("years")?.unwrap_or(0);
+    let months = kwargs.get::<Option<i64>>("months")?.unwrap_or(0);
+    let months = years
+        .checked_mul(12)
+        .and_then(|year_months| year_months.checked_add(months))
+        .ok_or_else(overflow)?;
+    let month_count = Months::new(u32::try_from(months.unsigned_abs()).map_err(|_| overflow())?);
+    dt = if months >= 0 {
+        dt.checked_add_months(month_count)
+    } else {
+        dt.checked_sub_months(month_count)
+    }
+    .ok_or_else(overflow)?;
+
+    let units = [
+        ("weeks", 7 * 24 * 3600),
+        ("days", 24 * 3600),
+        ("hours", 3600),
+        ("minutes", 60),
+        ("seconds", 1),
+    ];
+    for (name, unit_seconds) in units {
+        if let Some(amount) = kwargs.get::<Option<i64>>(name)? {
+            let delta = amount
+                .checked_mul(unit_seconds)
+                .and_then(TimeDelta::try_seconds)
+                .ok_or_else(overflow)?;
+            dt = dt.checked_add_signed(delta).ok_or_else(overflow)?;
+        }
+    }
+    kwargs.assert_all_used()?;
+
+    if date_only {
+        Ok(dt.format("%Y-%m-%d").to_string())
+    } else {
+        Ok(dt.to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+}
+
+/// Formats a date with a strftime-style format, `%Y-%m-%d` by default, e.g. `{{ now() | date("%d.%m.%Y") }}`.
+pub fn date(value: String, format: Option<String>) -> Result<String, Error> {
+    use std::fmt::Write;
+    let (dt, _) = parse_date(&value)?;
+    let format = format.unwrap_or_else(|| "%Y-%m-%d".to_string());
+    let mut out = String::new();
+    write!(out, "{}", dt.format(&format)).map_err(|_| {
+        Error::new(ErrorKind::InvalidOperation, format!("Invalid date format '{}'", format))
+    })?;
+    Ok(out)
+}
+
+/// Renders markdown to HTML, with tables and strikethrough if the `gfm` feature is enabled. The
+/// result is marked safe, so it isn't escaped again when autoescaping is enabled. Raw HTML in
+/// the input is escaped unless `allow_html=true` is passed.
+#[cfg(feature = "markdown")]
+pub fn markdown(s: String, kwargs: Kwargs) -> Result<Value, Error> {
+    use pulldown_cmark::{html, Event, Options, Parser};
+
+    let allow_html = kwargs.get::<Option<bool>>("allow_html")?.unwrap_or(false);
+    kwargs.assert_all_used()?;
+    #[cfg(feature = "gfm")]
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    #[cfg(not(feature = "gfm"))]
+    let options = Options::empty();
+    let events = Parser::new_ext(&s, options).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) if !allow_html => Event::Text(raw),
+        event => event,
+    });
+    let mut output = String::new();
+    html::push_html(&mut output, events);
+    Ok(Value::from_safe_string(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::TemplateEngine;
+    use chrono::{TimeDelta, Utc};
+    use serde_json::json;
+
+    fn render(template: &str) -> Result<String, String> {
+        let context = json!({ "null_value": null, "empty": "", "name": "templify" });
+        TemplateEngine::new().render_string(template, &context)
+    }
+
+    #[test]
+    fn test_default_if_none() {
+        assert_eq!(render("{{ null_value | default_if_none('x') }}").unwrap(), "x");
+        assert_eq!(render("[{{ empty | default_if_none('x') }}]").unwrap(), "[]");
+        assert_eq!(render("{{ name | default_if_none('x') }}").unwrap(), "templify");
+        // Undefined values are not replaced, so strict mode still fails
+        assert!(render("{{ missing | default_if_none('x') }}").is_err());
+    }
+
+    #[test]
+    fn test_keys_values_items() {
+        let context: serde_json::Value = serde_json::from_str(
+            r#"{"config": {"zeta": 1, "alpha": {"port": 80, "host": "x"}}}"#,
+        )
+        .unwrap();
+        let render = |template: &str| TemplateEngine::new().render_string(template, &context);
+        // Insertion order of the data, not sorted
+        assert_eq!(render("{{ config | keys | join(',') }}").unwrap(), "zeta,alpha");
+        assert_eq!(render("{{ config.alpha | keys | join(',') }}").unwrap(), "port,host");
+        assert_eq!(render("{{ config.alpha | values | join(',') }}").unwrap(), "80,x");
+        assert_eq!(
+            render("{% for k, v in config.alpha | items %}{{ k }}={{ v }};{% endfor %}").unwrap(),
+            "port=80;host=x;"
+        );
+        assert_eq!(
+            render("{% for k, v in config | items %}{{ k }}:{{ v | length if v is mapping else v }} {% endfor %}")
+                .unwrap(),
+            "zeta:1 alpha:2 "
+        );
+        assert!(render("{{ [1, 2] | keys }}").is_err());
+    }
+
+    #[test]
+    fn test_namespace_accumulator() {
+        let context = json!({
+            "services": [
+                {"name": "api", "public": true},
+                {"name": "db", "public": false},
+                {"name": "web", "public": true},
+            ],
+        });
+        let render = |template: &str| TemplateEngine::new().render_string(template, &context);
+        assert_eq!(
+            render(
+                "{% set ns = namespace(public=[], count=0) %}\
+                 {% for svc in services %}{% if svc.public %}\
+                 {% set ns.public = ns.public | push(svc.name) %}{% set ns.count = ns.count + 1 %}\
+                 {% endif %}{% endfor %}{{ ns.count }}: {{ ns.public | join(', ') }}"
+            )
+            .unwrap(),
+            "2: api, web"
+        );
+        // A plain `set` computes a value once for the rest of the template
+        assert_eq!(
+            render("{% set names = services | map(attribute='name') | list %}{{ names | length }} {{ names | last }}")
+                .unwrap(),
+            "3 web"
+        );
+        assert_eq!(render("{{ [1] | push(2, 3) }}").unwrap(), "[1, 2, 3]");
+        assert!(render("{{ 'a' | push(1) }}").is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let context = json!({"colors": {"ok": "green", "err": "red"}, "codes": {"1": "one"}});
+        let render = |template: &str, status: &str| {
+            let mut context = context.clone();
+            context["status"] = json!(status);
+            TemplateEngine::new().render_string(template, &context)
+        };
+        assert_eq!(render("{{ status | lookup(colors) }}", "err").unwrap(), "red");
+        assert_eq!(
+            render(r#"{{ status | lookup({"ok": "green"}, default="gray") }}"#, "ok").unwrap(),
+            "green"
+        );
+        assert_eq!(render(r#"{{ status | lookup(colors, default="gray") }}"#, "new").unwrap(), "gray");
+        let err = render("{{ status | lookup(colors) }}", "new").unwrap_err();
+        assert!(err.contains("no entry for new"), "{}", err);
+        assert!(render("{{ status | lookup(['ok']) }}", "ok").is_err());
+
+        let mut lenient = TemplateEngine::new();
+        lenient.allow_missing_keys("?");
+        assert_eq!(lenient.render_string("[{{ 'x' | lookup(codes) }}]", &context).unwrap(), "[?]");
+    }
+
+    #[test]
+    fn test_debug() {
+        let context = json!({
+            "port": 8080,
+            "ratio": 0.5,
+            "name": "api \"v1\"",
+            "enabled": true,
+            "missing": null,
+            "ports": [80, 443],
+            "service": {"name": "api", "tags": [], "limits": {"cpu": 2}},
+        });
+        let render = |template: &str| TemplateEngine::new().render_string(template, &context).unwrap();
+        assert_eq!(render("{{ port | debug }}"), "Int(8080)");
+        assert_eq!(render("{{ ratio | debug }}"), "Float(0.5)");
+        assert_eq!(render("{{ name | debug }}"), r#"String("api \"v1\"")"#);
+        assert_eq!(render("{{ enabled | debug }} {{ missing | debug }}"), "Bool(true) None");
+        assert_eq!(render("{{ ports | debug }}"), "Array[2] [Int(80), Int(443)]");
+        assert_eq!(
+            render("{{ service | debug }}"),
+            r#"Object{"name": String("api"), "tags": Array[0] [], "limits": Object{"cpu": Int(2)}}"#
+        );
+        assert_eq!(render("{{ ports | debug(log=true) | join('-') }}"), "80-443");
+        assert_eq!(render("{{ service | dump(log=true) | length }}"), "3");
+    }
+
+    #[test]
+    fn test_get() {
+        let context = json!({
+            "service": {
+                "spec": {"resources": {"limits": {"cpu": "2"}}, "ports": [{"port": 80}, {"port": 443}]},
+                "a/b": {"~x": 1},
+            },
+        });
+        let render = |template: &str| TemplateEngine::new().render_string(template, &context).unwrap();
+        assert_eq!(render(r#"{{ service | get("spec.resources.limits.cpu", "500m") }}"#), "2");
+        assert_eq!(render(r#"{{ service | get("/spec/resources/limits/cpu") }}"#), "2");
+        // Missing segments, at any depth
+        assert_eq!(render(r#"{{ service | get("spec.resources.requests.cpu", "500m") }}"#), "500m");
+        assert_eq!(render(r#"{{ service | get("status.phase", "unknown") }}"#), "unknown");
+        assert_eq!(render(r#"{{ service | get("spec.resources.limits.cpu.value") is none }}"#), "true");
+        // Array indexes
+        assert_eq!(render(r#"{{ service | get("spec.ports.1.port") }}"#), "443");
+        assert_eq!(render(r#"{{ service | get("/spec/ports/0/port") }}"#), "80");
+        assert_eq!(render(r#"{{ service | get("spec.ports.5.port", 0) }}"#), "0");
+        assert_eq!(render(r#"{{ service | get("spec.ports.x", "-") }}"#), "-");
+        // Escaped JSON pointer segments
+        assert_eq!(render(r#"{{ service | get("/a~1b/~0x") }}"#), "1");
+    }
+
+    #[test]
+    fn test_join_with() {
+        let context = json!({
+            "xs": ["a", "", null, "b", "  ", "c"],
+            "one": ["a"],
+            "names": ["a", "b", "c"]
+        });
+        let render = |template: &str| TemplateEngine::new().render_string(template, &context);
+        assert_eq!(render("{{ xs | join_with(', ') }}").unwrap(), "a, b, c");
+        assert_eq!(render("{{ xs | join_with(', ', last=' and ') }}").unwrap(), "a, b and c");
+        assert_eq!(render("{{ one | join_with(', ', last=' and ') }}").unwrap(), "a");
+        assert_eq!(render("[{{ [] | join_with(', ') }}]").unwrap(), "[]");
+        // A rendered sub-list with conditionally skipped lines
+        assert_eq!(
+            render(
+                "{% set parts %}{% for x in names %}{% if x != 'b' %}{{ x }}{% endif %}\n{% endfor %}{% endset %}\
+                 {{ parts | lines | join_with(', ') }};"
+            )
+            .unwrap(),
+            "a, c;"
+        );
+        assert!(render("{{ xs | join_with(', ', first='x') }}").is_err());
+    }
+
+    #[test]
+    fn test_seeded_random_filters() {
+        let template = "{{ range(20) | shuffle | join(',') }} {{ ['a', 'b', 'c', 'd'] | random_choice }}";
+        let render = |seed: Option<u64>| {
+            let mut engine = TemplateEngine::new();
+            if let Some(seed) = seed {
+                engine.set_seed(seed);
+            }
+            engine.render_string(template, &json!({})).unwrap()
+        };
+        assert_eq!(render(Some(42)), render(Some(42)));
+        assert_ne!(render(Some(1)), render(Some(2)));
+
+        let unseeded = render(None);
+        let (numbers, choice) = unseeded.split_once(' ').unwrap();
+        let mut numbers: Vec<u32> = numbers.split(',').map(|n| n.parse().unwrap()).collect();
+        numbers.sort();
+        assert_eq!(numbers, (0..20).collect::<Vec<_>>());
+        assert!(["a", "b", "c", "d"].contains(&choice));
+        assert!(TemplateEngine::new().render_string("{{ [] | random_choice }}", &json!({})).is_err());
+    }
+
+    #[test]
+    fn test_default_only_replaces_undefined() {
+        assert_eq!(render("{{ missing | default('x') }}").unwrap(), "x");
+        assert_eq!(render("{{ null_value | default('x') }}").unwrap(), "none");
+    }
+
+    #[test]
+    fn test_cycle() {
+        let context = json!({ "items": ["a", "b", "c", "d", "e"] });
+        let result = TemplateEngine::new()
+            .render_string(
+                "{% for item in items %}{{ item }}:{{ cycle('odd', 'even') }} {% endfor %}",
+                &context,
+            )
+            .unwrap();
+        assert_eq!(result, "a:odd b:even c:odd d:even e:odd ");
+
+        let result = TemplateEngine::new()
+            .render_string("{% for item in items %}{{ roundrobin(1, 2, 3) }}{% endfor %}", &context)
+            .unwrap();
+        assert_eq!(result, "12312");
+
+        assert!(render("{{ cycle('odd', 'even') }}").is_err());
+    }
+
+    #[test]
+    fn test_string_predicates() {
+        assert_eq!(render("{{ name | contains('mpl') }}").unwrap(), "true");
+        assert_eq!(render("{{ name | contains('xyz') }}").unwrap(), "false");
+        assert_eq!(render("{{ name | contains('') }}").unwrap(), "true");
+        assert_eq!(render("{{ empty | contains('') }}").unwrap(), "true");
+
+        assert_eq!(render("{{ name | startswith('temp') }}").unwrap(), "true");
+        assert_eq!(render("{{ name | startswith('ify') }}").unwrap(), "false");
+        assert_eq!(render("{{ name | startswith('') }}").unwrap(), "true");
+
+        assert_eq!(
+            render("{% if name | endswith('ify') %}yes{% else %}no{% endif %}").unwrap(),
+            "yes"
+        );
+        assert_eq!(render("{{ name | endswith('temp') }}").unwrap(), "false");
+        assert_eq!(render("{{ empty | endswith('') }}").unwrap(), "true");
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(render("{{ 'a.b.c' | count('.') }}").unwrap(), "2");
+        assert_eq!(render("{{ 'aaaa' | count('aa') }}").unwrap(), "2");
+        assert_eq!(render("{{ name | count('x') }}").unwrap(), "0");
+        assert_eq!(render("{{ name | count('') }}").unwrap(), "0");
+        // Without an argument, count is still the length of the value
+        assert_eq!(render("{{ [1, 2, 3] | count }}").unwrap(), "3");
+        assert_eq!(render("{{ name | count }}").unwrap(), "8");
+    }
+
+    #[test]
+    fn test_date_add_days() {
+        assert_eq!(render("{{ '2024-01-30' | date_add(days=3) }}").unwrap(), "2024-02-02");
+        assert_eq!(render("{{ '2024-02-28' | date_add(days=1) }}").unwrap(), "2024-02-29");
+        assert_eq!(render("{{ '2024-01-01' | date_add(days=-1) }}").unwrap(), "2023-12-31");
+        assert_eq!(render("{{ '2024-01-01' | date_add(weeks=2) }}").unwrap(), "2024-01-15");
+        assert_eq!(
+            render("{{ '2024-12-31T23:30:00Z' | date_add(hours=1) }}").unwrap(),
+            "2025-01-01T00:30:00Z"
+        );
+        assert!(render("{{ '2024-01-01' | date_add(fortnights=1) }}").is_err());
+        assert!(render("{{ 'tomorrow' | date_add(days=1) }}").is_err());
+    }
+
+    #[test]
+    fn test_date_add_months() {
+        assert_eq!(render("{{ '2024-01-31' | date_add(months=1) }}").unwrap(), "2024-02-29");
+        assert_eq!(render("{{ '2023-01-31' | date_add(months=1) }}").unwrap(), "2023-02-28");
+        assert_eq!(render("{{ '2024-03-31' | date_add(months=-1) }}").unwrap(), "2024-02-29");
+        assert_eq!(render("{{ '2024-11-15' | date_add(months=3) }}").unwrap(), "2025-02-15");
+        assert_eq!(render("{{ '2024-02-29' | date_add(years=1) }}").unwrap(), "2025-02-28");
+        let err = render("{{ '2024-01-01' | date_add(years=999999999999999999) }}").unwrap_err();
+        assert!(err.contains("out of range"), "{}", err);
+        assert!(render("{{ '2024-01-01' | date_add(years=1, months=9223372036854775807) }}").is_err());
+    }
+
+    #[test]
+    fn test_now_and_date() {
+        assert_eq!(
+            render("{{ '2024-03-05T10:00:00+02:00' | date('%d.%m.%Y %H:%M') }}").unwrap(),
+            "05.03.2024 10:00"
+        );
+        assert_eq!(render("{{ '2024-03-05' | date }}").unwrap(), "2024-03-05");
+        let before = (Utc::now() + TimeDelta::days(30)).format("%Y-%m-%d").to_string();
+        let expiry = render("{{ now() | date_add(days=30) | date('%Y-%m-%d') }}").unwrap();
+        let after = (Utc::now() + TimeDelta::days(30)).format("%Y-%m-%d").to_string();
+        // Either date is fine if the test runs across midnight
+        assert!(expiry == before || expiry == after, "{}", expiry);
+    }
+
+    #[test]
+    fn test_lines() {
+        let context = json!({
+            "lf": "first\nsecond\n",
+            "crlf": "first\r\nsecond\r\n",
+            "no_trailing": "first\nsecond",
+            "blank_inside": "first\n\nthird",
+        });
+        let engine = TemplateEngine::new();
+        for key in ["lf", "crlf", "no_trailing"] {
+            let template = format!("{{% for line in {} | lines %}}[{{{{ line }}}}]{{% endfor %}}", key);
+            assert_eq!(engine.render_string(&template, &context).unwrap(), "[first][second]");
+        }
+        assert_eq!(
+            engine
+                .render_string("{{ blank_inside | lines | length }}", &context)
+                .unwrap(),
+            "3"
+        );
+    }
+
+    #[test]
+    fn test_linecount_and_wordcount() {
+        let context = json!({
+            "crlf": "one two\r\nthree\r\n",
+            "trailing": "one\n\n",
+            "empty": "",
+        });
+        let engine = TemplateEngine::new();
+        let render = |t: &str| engine.render_string(t, &context).unwrap();
+        assert_eq!(render("{{ crlf | linecount }}"), "2");
+        assert_eq!(render("{{ crlf | wordcount }}"), "3");
+        assert_eq!(render("{{ trailing | linecount }}"), "2");
+        assert_eq!(render("{{ empty | linecount }}"), "0");
+        assert_eq!(render("{{ empty | wordcount }}"), "0");
+    }
+
+    #[test]
+    fn test_filesizeformat() {
+        assert_eq!(super::filesizeformat(0.0, None), "0 Bytes");
+        assert_eq!(super::filesizeformat(1.0, None), "1 Byte");
+        assert_eq!(super::filesizeformat(1023.0, Some(true)), "1023 Bytes");
+        assert_eq!(super::filesizeformat(1024.0, Some(true)), "1.0 KiB");
+        assert_eq!(super::filesizeformat(1023.0, None), "1.0 kB");
+        assert_eq!(super::filesizeformat(1024.0, None), "1.0 kB");
+        assert_eq!(super::filesizeformat(1_500_000.0, None), "1.5 MB");
+        assert_eq!(super::filesizeformat(1_048_576.0, Some(true)), "1.0 MiB");
+        assert_eq!(render("{{ 2048 | filesizeformat(true) }}").unwrap(), "2.0 KiB");
+    }
+
+    #[test]
+    fn test_ordinal() {
+        let cases = [
+            (0, "0th"),
+            (1, "1st"),
+            (2, "2nd"),
+            (3, "3rd"),
+            (4, "4th"),
+            (11, "11th"),
+            (12, "12th"),
+            (13, "13th"),
+            (21, "21st"),
+            (22, "22nd"),
+            (111, "111th"),
+            (101, "101st"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(super::ordinal(n), expected);
+        }
+        assert_eq!(render("{{ 23 | ordinal }}").unwrap(), "23rd");
+    }
+
+    #[test]
+    fn test_numberformat() {
+        assert_eq!(super::numberformat(0.0, None), "0");
+        assert_eq!(super::numberformat(999.0, None), "999");
+        assert_eq!(super::numberformat(1000.0, None), "1,000");
+        assert_eq!(super::numberformat(1234567.0, None), "1,234,567");
+        assert_eq!(super::numberformat(-1234.5, None), "-1,234.5");
+        assert_eq!(super::numberformat(1234.5, Some(2)), "1,234.50");
+        assert_eq!(render("{{ 1234567 | numberformat }}").unwrap(), "1,234,567");
+    }
+
+    #[test]
+    fn test_relpath() {
+        let relpath = |from: &str, to: &str| super::relpath(from.to_string(), to.to_string());
+        // Sibling
+        assert_eq!(relpath("src/api", "src/common/mod.rs").unwrap(), "../common/mod.rs");
+        // Ancestor
+        assert_eq!(relpath("src/api/v1", "src").unwrap(), "../..");
+        // Descendant
+        assert_eq!(relpath("src", "src/common/mod.rs").unwrap(), "common/mod.rs");
+        assert_eq!(relpath("./src/api/", "src/api").unwrap(), ".");
+        assert_eq!(relpath("/a/b", "/a/c/../d").unwrap(), "../d");
+        assert!(relpath("/a", "b").is_err());
+        assert!(relpath("../a", "b").is_err());
+
+        let context = json!({ "target": { "path": "src/api" } });
+        assert_eq!(
+            TemplateEngine::new()
+                .render_string(r#"{{ relpath(target.path, "src/common/mod.rs") }}"#, &context)
+                .unwrap(),
+            "../common/mod.rs"
+        );
+        assert_eq!(render("{{ 'src/a' | relpath('src/b') }}").unwrap(), "../b");
+    }
+
+    #[test]
+    fn test_mandatory() {
+        assert_eq!(render("{{ name | mandatory('name is required') }}").unwrap(), "templify");
+        assert_eq!(render("[{{ empty | mandatory }}]").unwrap(), "[]");
+
+        let err = render("{{ missing | mandatory('missing is required') }}").unwrap_err();
+        assert!(err.contains("missing is required"));
+        let err = render("{{ null_value | mandatory('null_value is required') }}").unwrap_err();
+        assert!(err.contains("null_value is required"));
+    }
+
+    #[cfg(feature = "markdown")]
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            render("{{ '# Title\n\nSome *text* & more' | markdown }}").unwrap(),
+            "<h1>Title</h1>\n<p>Some <em>text</em> &amp; more</p>\n"
+        );
+        // Marked safe, so autoescaping doesn't escape the HTML again
+        assert_eq!(
+            render("{% autoescape true %}{{ '**bold**' | markdown }}{% endautoescape %}").unwrap(),
+            "<p><strong>bold</strong></p>\n"
+        );
+        // Raw HTML is escaped unless allowed
+        assert_eq!(
+            render("{{ 'a <script>x</script>' | markdown }}").unwrap(),
+            "<p>a &lt;script&gt;x&lt;/script&gt;</p>\n"
+        );
+        assert_eq!(
+            render("{{ 'a <b>b</b>' | markdown(allow_html=true) }}").unwrap(),
+            "<p>a <b>b</b></p>\n"
+        );
+    }
+
+    #[cfg(feature = "gfm")]
+    #[test]
+    fn test_markdown_table() {
+        let html = render("{{ '| a | b |\n|---|---|\n| 1 | ~~2~~ |' | markdown }}").unwrap();
+        assert!(html.starts_with("<table>"), "{}", html);
+        assert!(html.contains("<th>a</th>"), "{}", html);
+        assert!(html.contains("<td><del>2</del></td>"), "{}", html);
+    }
+}
@@ -1,12 +1,29 @@
-use crate::config::{FormatConfig, FormatterConfig};
+use crate::config::{matches_file_pattern, FormatConfig, FormatterConfig};
 use crate::manual_sections::ManualSectionManager;
-use log::{debug, error, warn};
-use std::io::Write;
-use std::process::{Command, Stdio};
+use log::{debug, warn};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Result of checking one command of a configured formatter with `check_commands`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandCheck {
+    /// File pattern the formatter is configured for
+    pub pattern: String,
+    pub command: String,
+    /// Where the command was found; None if it is missing
+    pub path: Option<PathBuf>,
+    /// First line printed by `<command> --version`, if requested and successful
+    pub version: Option<String>,
+}
 
 pub struct FormatterManager {
     config: FormatConfig,
     manual_section_manager: ManualSectionManager,
+    commands: RunningCommands,
 }
 
 impl FormatterManager {
@@ -14,17 +31,35 @@ impl FormatterManager {
         Self {
             config,
             manual_section_manager,
+            commands: RunningCommands::default(),
         }
     }
 
+    /// Registers the formatter commands in `commands`, e.g. shared by all formatter
+    /// managers of a run, so that they can be killed together.
+    pub fn with_running_commands(mut self, commands: RunningCommands) -> Self {
+        self.commands = commands;
+        self
+    }
+
     pub fn format_content(&self, content: &str, filename: &str) -> String {
+        self.try_format_content(content, filename).unwrap_or_else(|e| {
+            warn!("{}; keeping the original content of {}", e, filename);
+            // Fallback to original content
+            content.to_string()
+        })
+    }
+
+    /// Like `format_content`, but returns an error instead of the unformatted content when
+    /// the formatter fails.
+    pub fn try_format_content(&self, content: &str, filename: &str) -> Result<String, String> {
         if !self.config.enabled {
-            return content.to_string();
+            return Ok(content.to_string());
         }
 
         if self.should_ignore(filename) {
             debug!("Ignored file for formatting: {}", filename);
-            return content.to_string();
+            return Ok(content.to_string());
         }
 
         if let Some(formatter_config) = self.get_formatter_for_file(filename) {
@@ -38,17 +73,61 @@ impl FormatterManager {
             };
             
             // Format
-            let formatted = self.run_formatter(content, formatter_config, filename);
+            let formatted = self.run_formatter(content, formatter_config, filename)?;
             
             // Restore manual sections
             if let Some(blocks) = blocks {
-                 self.manual_section_manager.restore_blocks(&formatted, &blocks)
+                 Ok(self.manual_section_manager.restore_blocks(&formatted, &blocks))
             } else {
-                 formatted
+                 Ok(formatted)
             }
         } else {
-            content.to_string()
+            Ok(content.to_string())
+        }
+    }
+
+    /// Checks that the `command` and `validate_command` of each enabled `command` formatter
+    /// can be found, sorted by pattern. With `version`, found commands are also run with
+    /// `--version`.
+    pub fn check_commands(&self, version: bool) -> Vec<CommandCheck> {
+        let mut formatters: Vec<_> = self
+            .config
+            .formatters
+            .iter()
+            .filter(|(_, config)| config.enabled && config.formatter_type == "command")
+            .collect();
+        formatters.sort_by_key(|(pattern, _)| pattern.as_str());
+
+        let mut checks = Vec::new();
+        for (pattern, config) in formatters {
+            for command in [&config.command, &config.validate_command].into_iter().flatten() {
+                let path = find_command(command);
+                let version = path.as_ref().filter(|_| version).and_then(|path| {
+                    let output = Command::new(path)
+                        .arg("--version")
+                        .stdin(Stdio::null())
+                        .output()
+                        .ok()?;
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    let first_line = stdout.lines().next()?.trim().to_string();
+                    (output.status.success() && !first_line.is_empty()).then_some(first_line)
+                });
+                checks.push(CommandCheck {
+                    pattern: pattern.clone(),
+                    command: command.clone(),
+                    path,
+                    version,
+                });
+            }
         }
+        checks
+    }
+
+    /// Returns true if `format_content` would run a formatter for this file.
+    pub fn would_format(&self, filename: &str) -> bool {
+        self.config.enabled
+            && !self.should_ignore(filename)
+            && self.get_formatter_for_file(filename).is_some()
     }
 
     fn should_ignore(&self, filename: &str) -> bool {
@@ -68,72 +147,387 @@ impl FormatterManager {
             if !config.enabled {
                 continue;
             }
-            if self.matches_pattern(filename, pattern) {
+            if matches_file_pattern(pattern, Path::new(filename)) {
                 return Some(config);
             }
         }
         None
     }
 
-    fn matches_pattern(&self, filename: &str, pattern: &str) -> bool {
-        if pattern.starts_with("*.") {
-            filename.ends_with(&pattern[1..])
-        } else {
-            filename == pattern || filename.ends_with(pattern)
-        }
-    }
-
-    fn run_formatter(&self, content: &str, config: &FormatterConfig, filename: &str) -> String {
+    fn run_formatter(
+        &self,
+        content: &str,
+        config: &FormatterConfig,
+        filename: &str,
+    ) -> Result<String, String> {
         if config.formatter_type != "command" {
             warn!("Unsupported formatter type: {}", config.formatter_type);
-            return content.to_string();
+            return Ok(content.to_string());
         }
 
         let cmd_str = match &config.command {
             Some(c) => c,
-            None => return content.to_string(),
+            None => return Ok(content.to_string()),
         };
 
-        let mut cmd = Command::new(cmd_str);
-        if let Some(args) = &config.args {
-            cmd.args(args);
-        }
-        
-        // Pass content via stdin
-        cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let args = render_args(config.args.as_deref(), config, filename)?;
+        debug!("Running formatter {} {:?} on {}", cmd_str, args, filename);
+        let output = run_command(&self.commands, cmd_str, args.as_deref(), content, "formatter")?;
 
-        debug!("Running formatter {} on {}", cmd_str, filename);
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("Formatter failed: {}", stderr));
+        }
+        let formatted = String::from_utf8(output.stdout)
+            .map_err(|_| "Formatter output rejected: not valid UTF-8".to_string())?;
+        self.validate_output(content, &formatted, config, filename)?;
+        Ok(formatted)
+    }
 
-        let mut child = match cmd.spawn() {
-            Ok(c) => c,
-            Err(e) => {
-                error!("Failed to spawn formatter: {}", e);
-                return content.to_string();
+    /// Rejects formatter output that is clearly broken even though the formatter succeeded,
+    /// e.g. an empty or truncated file.
+    fn validate_output(
+        &self,
+        content: &str,
+        formatted: &str,
+        config: &FormatterConfig,
+        filename: &str,
+    ) -> Result<(), String> {
+        if config.validate_output {
+            if formatted.trim().is_empty() && !content.trim().is_empty() {
+                return Err("Formatter output rejected: empty output".to_string());
             }
-        };
+            let extension = Path::new(filename).extension().and_then(|ext| ext.to_str());
+            match extension {
+                Some("json") => {
+                    serde_json::from_str::<serde_json::Value>(formatted)
+                        .map_err(|e| format!("Formatter output rejected: invalid JSON: {}", e))?;
+                }
+                Some("yaml" | "yml") => {
+                    serde_yaml::from_str::<serde_yaml::Value>(formatted)
+                        .map_err(|e| format!("Formatter output rejected: invalid YAML: {}", e))?;
+                }
+                _ => {}
+            }
+        }
+        if let Some(ref validate_command) = config.validate_command {
+            debug!("Validating formatter output of {} with {}", filename, validate_command);
+            let args = render_args(config.validate_args.as_deref(), config, filename)?;
+            let output = run_command(
+                &self.commands,
+                validate_command,
+                args.as_deref(),
+                formatted,
+                "validate command",
+            )?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(format!(
+                    "Formatter output rejected by {}: {}",
+                    validate_command,
+                    stderr.trim()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds a command the way spawning it does: a path with a separator is used as is, a bare
+/// name is looked up in the `PATH` directories.
+pub fn find_command(command: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        }
+        #[cfg(not(unix))]
+        {
+            path.is_file()
+        }
+    };
+    let candidates = |path: PathBuf| {
+        let mut candidates = vec![path.clone()];
+        if cfg!(windows) && path.extension().is_none() {
+            candidates.push(path.with_extension("exe"));
+        }
+        candidates
+    };
+    if command.contains(['/', '\\']) {
+        return candidates(PathBuf::from(command)).into_iter().find(|p| is_executable(p));
+    }
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .flat_map(|dir| candidates(dir.join(command)))
+        .find(|p| is_executable(p))
+}
 
-        if let Some(mut stdin) = child.stdin.take() {
-            if let Err(e) = stdin.write_all(content.as_bytes()) {
-                error!("Failed to write to formatter stdin: {}", e);
-                return content.to_string();
+/// Renders formatter arguments as templates with the formatter's `options` and the `file`
+/// being formatted, e.g. `--max-width={{ options.line_width }}`. Arguments that render to an
+/// empty string are dropped, so `{% if options.check %}--check{% endif %}` is conditional.
+fn render_args(
+    args: Option<&[String]>,
+    config: &FormatterConfig,
+    filename: &str,
+) -> Result<Option<Vec<String>>, String> {
+    let Some(args) = args else {
+        return Ok(None);
+    };
+    let env = minijinja::Environment::new();
+    let context = minijinja::context! { options => &config.options, file => filename };
+    let mut rendered = Vec::with_capacity(args.len());
+    for arg in args {
+        if !arg.contains("{{") && !arg.contains("{%") {
+            rendered.push(arg.clone());
+            continue;
+        }
+        let arg = env
+            .render_str(arg, &context)
+            .map_err(|e| format!("Invalid formatter argument '{}': {}", arg, e))?;
+        if !arg.is_empty() {
+            rendered.push(arg);
+        }
+    }
+    Ok(Some(rendered))
+}
+
+/// Formatter commands of a run that haven't exited yet, so that an aborted run can kill them
+/// with `kill`. Clones share the same commands.
+#[derive(Debug, Clone, Default)]
+pub struct RunningCommands {
+    state: Arc<Mutex<RunningState>>,
+}
+
+#[derive(Debug, Default)]
+struct RunningState {
+    children: Vec<Arc<Mutex<Child>>>,
+    killed: bool,
+}
+
+impl RunningCommands {
+    /// Kills the commands that are still running, e.g. when the run timed out. On Unix, each
+    /// command runs in its own process group, which is killed with it, so that e.g. the
+    /// children of `sh -c` don't outlive it. The commands fail with an error in their
+    /// `format_content` call, and commands started later fail without running.
+    pub fn kill(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.killed = true;
+        for child in state.children.drain(..) {
+            let mut child = child.lock().unwrap();
+            // Once reaped, the id of the child may belong to another process
+            if let Ok(None) = child.try_wait() {
+                #[cfg(unix)]
+                // SAFETY: kill has no memory effects; the child is the leader of its group
+                unsafe {
+                    libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                }
+                let _ = child.kill();
             }
         }
+    }
+
+    fn is_killed(&self) -> bool {
+        self.state.lock().unwrap().killed
+    }
+
+    fn spawn(&self, command: &mut Command) -> Result<Arc<Mutex<Child>>, String> {
+        let mut state = self.state.lock().unwrap();
+        if state.killed {
+            return Err("the run was aborted".to_string());
+        }
+        let child = Arc::new(Mutex::new(command.spawn().map_err(|e| e.to_string())?));
+        state.children.push(child.clone());
+        Ok(child)
+    }
+
+    fn remove(&self, child: &Arc<Mutex<Child>>) {
+        self.state
+            .lock()
+            .unwrap()
+            .children
+            .retain(|running| !Arc::ptr_eq(running, child));
+    }
+}
+
+/// Runs a command with `input` on stdin and collects its output. The command is registered
+/// in `commands` while it runs.
+fn run_command(
+    commands: &RunningCommands,
+    program: &str,
+    args: Option<&[String]>,
+    input: &str,
+    what: &str,
+) -> Result<Output, String> {
+    let mut cmd = Command::new(program);
+    if let Some(args) = args {
+        cmd.args(args);
+    }
+    cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    let child = commands
+        .spawn(&mut cmd)
+        .map_err(|e| format!("Failed to spawn {}: {}", what, e))?;
+    let (stdin, stdout, stderr) = {
+        let mut child = child.lock().unwrap();
+        // Read the output on threads, as the child may block on a full pipe before reading
+        // all input
+        (child.stdin.take(), read_pipe(child.stdout.take()), read_pipe(child.stderr.take()))
+    };
 
-        let output = match child.wait_with_output() {
-            Ok(o) => o,
+    let written = stdin.map_or(Ok(()), |mut stdin| stdin.write_all(input.as_bytes()));
+    // Poll instead of blocking in `wait`, so that `kill` can lock the child meanwhile
+    let status = loop {
+        let result = child.lock().unwrap().try_wait();
+        match result {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) => thread::sleep(Duration::from_millis(2)),
             Err(e) => {
-                error!("Failed to wait for formatter: {}", e);
-                return content.to_string();
+                let mut child = child.lock().unwrap();
+                let _ = child.kill();
+                let _ = child.wait();
+                break Err(e);
             }
-        };
+        }
+    };
+    commands.remove(&child);
+    if commands.is_killed() {
+        return Err(format!("{} was killed", what));
+    }
+    let status = status.map_err(|e| format!("Failed to wait for {}: {}", what, e))?;
+    written.map_err(|e| format!("Failed to write to {} stdin: {}", what, e))?;
 
-        if output.status.success() {
-            String::from_utf8_lossy(&output.stdout).to_string()
-        } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            error!("Formatter failed: {}", stderr);
-            // Fallback to original content
-            content.to_string()
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
         }
+        bytes
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FormatConfig, FormatterConfig};
+    use crate::{ManualSectionConfig, ManualSectionManager};
+    use std::collections::HashMap;
+
+    fn manager(pattern: &str, script: &str, validate: Option<&str>) -> FormatterManager {
+        let formatter = FormatterConfig {
+            formatter_type: "command".to_string(),
+            command: Some("sh".to_string()),
+            args: Some(vec!["-c".to_string(), script.to_string()]),
+            options: HashMap::new(),
+            enabled: true,
+            validate_output: true,
+            validate_command: validate.map(|_| "sh".to_string()),
+            validate_args: validate.map(|v| vec!["-c".to_string(), v.to_string()]),
+        };
+        let config = FormatConfig {
+            enabled: true,
+            formatters: HashMap::from([(pattern.to_string(), formatter)]),
+            ..Default::default()
+        };
+        FormatterManager::new(config, ManualSectionManager::new(ManualSectionConfig::default()))
+    }
+
+    #[test]
+    fn test_broken_output_keeps_original() {
+        // Succeeds without printing anything
+        let formatter = manager("*.txt", "cat > /dev/null", None);
+        assert_eq!(formatter.format_content("keep me\n", "a.txt"), "keep me\n");
+        assert!(formatter.try_format_content("keep me\n", "a.txt").is_err());
+
+        let formatter = manager("*.json", "cat > /dev/null; echo '{\"a\": '", None);
+        assert_eq!(formatter.format_content("{\"a\": 1}", "a.json"), "{\"a\": 1}");
+
+        let formatter = manager("*.txt", "tr a-z A-Z", Some("grep -qv BAD"));
+        assert_eq!(formatter.format_content("good\n", "a.txt"), "GOOD\n");
+        assert_eq!(formatter.format_content("bad\n", "a.txt"), "bad\n");
+    }
+
+    #[test]
+    fn test_option_args() {
+        let mut formatter = manager("*.txt", "cat > /dev/null; echo \"$0\" \"$@\"", None);
+        let config = formatter.config.formatters.get_mut("*.txt").unwrap();
+        config.args.as_mut().unwrap().extend(
+            [
+                "--max-width={{ options.line_width }}",
+                "{% if options.check %}--check{% endif %}",
+                "{{ file }}",
+            ]
+            .map(String::from),
+        );
+        config.options.insert("line_width".to_string(), serde_json::json!(80));
+        assert_eq!(
+            formatter.try_format_content("x\n", "a.txt").unwrap(),
+            "--max-width=80 a.txt\n"
+        );
+
+        let config = formatter.config.formatters.get_mut("*.txt").unwrap();
+        config.options.insert("check".to_string(), serde_json::json!(true));
+        assert_eq!(
+            formatter.try_format_content("x\n", "a.txt").unwrap(),
+            "--max-width=80 --check a.txt\n"
+        );
+    }
+
+    #[test]
+    fn test_reindent_manual_sections() {
+        let content = "fn main() {\n\tlet x = 1;\n\t// MANUAL SECTION START: body\n\tif x > 0 {\n\
+                       \t\tprintln!();\n\t}\n\t// MANUAL SECTION END\n}\n";
+        let mut formatter = manager("*.rs", "expand -t 4", None);
+        // The preserved body keeps its tabs
+        assert_eq!(
+            formatter.format_content(content, "main.rs"),
+            "fn main() {\n    let x = 1;\n    // MANUAL SECTION START: body\n\tif x > 0 {\n\
+             \t\tprintln!();\n\t}\n\t// MANUAL SECTION END\n}\n"
+        );
+
+        formatter.manual_section_manager = ManualSectionManager::new(ManualSectionConfig {
+            reindent: true,
+            ..Default::default()
+        });
+        let formatted = formatter.format_content(content, "main.rs");
+        assert_eq!(
+            formatted,
+            "fn main() {\n    let x = 1;\n    // MANUAL SECTION START: body\n    if x > 0 {\n\
+             \x20       println!();\n    }\n    // MANUAL SECTION END\n}\n"
+        );
+        // Preserving the formatted section into a new render follows the new indentation
+        let manager = &formatter.manual_section_manager;
+        assert_eq!(manager.preserve_sections(content, &formatted), content);
+        assert_eq!(formatter.format_content(&formatted, "main.rs"), formatted);
+    }
+
+    #[test]
+    fn test_check_commands() {
+        let mut formatter = manager("*.txt", "cat", None);
+        let mut missing = formatter.config.formatters["*.txt"].clone();
+        missing.command = Some("templify-missing-formatter".to_string());
+        formatter.config.formatters.insert("*.rs".to_string(), missing);
+
+        let checks = formatter.check_commands(false);
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0].pattern, "*.rs");
+        assert_eq!(checks[0].command, "templify-missing-formatter");
+        assert!(checks[0].path.is_none());
+        assert_eq!(checks[1].command, "sh");
+        assert!(checks[1].path.as_ref().is_some_and(|p| p.ends_with("sh")));
+        assert!(checks.iter().all(|check| check.version.is_none()));
     }
 }
@@ -1,43 +1,1452 @@
-use log::{error, info, warn};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use log::{debug, error, info, warn};
 use regex::Regex;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io::{BufWriter, Write};
+use std::path::{Component, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use std::{fs, path::Path};
 
+use crate::archive::ArchiveWriter;
+use crate::config::{
+    glob_matcher, DirectoryManifest, EncodingRule, HeaderCommentConfig, InjectionFrontMatter,
+    PathCase, PermissionRule, RenameRule,
+};
 use crate::engine::TemplateEngine;
-use crate::manual_sections::ManualSectionManager;
+use crate::manual_sections::{ManualSectionManager, SectionOutcome};
 use crate::formatting::FormatterManager;
+use crate::source::{FileSystemSource, TemplateSource, DEFAULT_MAX_TEMPLATE_SIZE};
 
 /// The regex pattern for injection points.
 const INJECTION_PATTERN: &str = r"<!-- injection-pattern: (?P<name>[a-zA-Z0-9_-]+) -->";
 const INJECTION_STRING_START: &str = "<!-- injection-string-start -->";
 const INJECTION_STRING_END: &str = "<!-- injection-string-end -->";
+/// Injection section replacing the content of a managed region of the output file, delimited
+/// by `<!-- injected:<name> -->` and `<!-- /injected:<name> -->`.
+const INJECTION_REGION: &str = r"<!-- injection-region: (?P<name>[a-zA-Z0-9_-]+) -->";
+/// Optional per-directory manifest in template folders; it is never copied to the output.
+const DIRECTORY_MANIFEST: &str = ".templify.yaml";
+
+/// File in an output directory recording the files generated there by each template set
+pub const OUTPUT_MANIFEST: &str = ".templify-manifest.json";
+/// Lines starting with this prefix in the pattern part of `.inj` files are comments.
+const INJECTION_COMMENT_PREFIX: &str = "##";
+
+/// Returns the line comment style for an output file extension or file name (e.g. `Makefile`,
+/// or `gitignore` for `.gitignore`), with `{}` for the comment text; None if the file type has
+/// no known comments.
+pub fn comment_style(extension: &str) -> Option<&'static str> {
+    let style = match extension {
+        "rs" | "c" | "h" | "cc" | "cpp" | "hpp" | "cs" | "java" | "kt" | "kts" | "scala"
+        | "swift" | "go" | "js" | "mjs" | "cjs" | "ts" | "tsx" | "jsx" | "dart" | "proto"
+        | "groovy" | "gradle" | "php" | "zig" | "v" | "sv" => "// {}",
+        "py" | "sh" | "bash" | "zsh" | "fish" | "rb" | "pl" | "r" | "ps1" | "yaml" | "yml"
+        | "toml" | "ini" | "cfg" | "conf" | "cmake" | "tf" | "hcl" | "nix" | "mk" | "Makefile"
+        | "Dockerfile" | "gitignore" | "dockerignore" | "env" => "# {}",
+        "sql" | "lua" | "hs" | "elm" | "ada" | "vhd" | "vhdl" => "-- {}",
+        "html" | "htm" | "xml" | "xsd" | "svg" | "md" | "vue" | "xaml" | "csproj" => "<!-- {} -->",
+        "css" | "scss" | "less" => "/* {} */",
+        "tex" | "erl" | "m" => "% {}",
+        "clj" | "cljs" | "lisp" | "el" | "scm" | "asm" | "s" => ";; {}",
+        "bat" | "cmd" => "REM {}",
+        "vim" => "\" {}",
+        _ => return None,
+    };
+    Some(style)
+}
+
+/// Collects all files below a directory.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir).map_err(|e| format!("{:?}: {}", dir, e))? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Removes comment lines from the pattern part of an injection section.
+fn strip_injection_comments(pattern_part: &str) -> String {
+    pattern_part
+        .lines()
+        .filter(|line| !line.trim_start().starts_with(INJECTION_COMMENT_PREFIX))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Compiles the regex of an injection pattern, which must capture the `injection` group.
+fn injection_regex(pattern_text: &str) -> Result<Regex, String> {
+    let re_injection = Regex::new(pattern_text)
+        .map_err(|e| format!("Invalid regex pattern '{}': {}", pattern_text, e))?;
+    if !pattern_text.contains("(?P<injection>") {
+        return Err(format!(
+            "Invalid regex pattern '{}': no 'injection' named capture group",
+            pattern_text
+        ));
+    }
+    Ok(re_injection)
+}
+
+/// Controls whether files are written and what is reported instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DryRunMode {
+    /// Write files normally.
+    #[default]
+    Off,
+    /// Don't write files, only log which files would be written.
+    List,
+    /// Don't write files, print a unified diff against the existing files.
+    Diff,
+}
+
+impl DryRunMode {
+    /// Returns true if files must not be written.
+    pub fn is_enabled(&self) -> bool {
+        *self != DryRunMode::Off
+    }
+}
+
+impl FromStr for DryRunMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(DryRunMode::Off),
+            "list" => Ok(DryRunMode::List),
+            "diff" => Ok(DryRunMode::Diff),
+            _ => Err(format!(
+                "Invalid dry-run mode '{}': expected off, list or diff",
+                s
+            )),
+        }
+    }
+}
+
+/// How the template tree maps to output paths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    /// Mirror the template directory structure.
+    #[default]
+    Mirror,
+    /// Write every file directly into the output directory; two templates generating the same
+    /// file name are an error.
+    Flat,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mirror" => Ok(OutputMode::Mirror),
+            "flat" => Ok(OutputMode::Flat),
+            _ => Err(format!("Invalid output mode '{}': expected mirror or flat", s)),
+        }
+    }
+}
+
+/// What to do with an existing output file that doesn't look generated by templify: it has no
+/// manual section markers, previous runs didn't record it (see
+/// `FileGenerator::with_previous_outputs`) and its content differs from the new output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Replace the file.
+    #[default]
+    Overwrite,
+    /// Leave the file unchanged.
+    Skip,
+    /// Fail the generation.
+    Error,
+    /// Rename the file to `<name>.bak`, then write the new output.
+    Backup,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "error" => Ok(ConflictPolicy::Error),
+            "backup" => Ok(ConflictPolicy::Backup),
+            _ => Err(format!(
+                "Invalid conflict policy '{}': expected overwrite, skip, error or backup",
+                s
+            )),
+        }
+    }
+}
+
+/// Reads the `OUTPUT_MANIFEST` of an output directory: the report of each set, by set.
+fn read_output_manifest(dir: &Path) -> Result<BTreeMap<String, GenerationReport>, String> {
+    let manifest_path = dir.join(OUTPUT_MANIFEST);
+    match fs::read_to_string(&manifest_path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid manifest {:?}: {}", manifest_path, e)),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
+
+/// Returns the output files that the `OUTPUT_MANIFEST` of `dir` records for `set` (see
+/// `FileGenerator::record_set_outputs`). Fails if an entry could be outside `dir`.
+pub fn recorded_outputs(dir: &Path, set: &str) -> Result<Vec<PathBuf>, String> {
+    let manifest = read_output_manifest(dir)?;
+    manifest
+        .get(set)
+        .into_iter()
+        .flat_map(GenerationReport::output_paths)
+        .map(|file| manifest_entry(dir, file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid manifest {:?}: {}", dir.join(OUTPUT_MANIFEST), e))
+}
+
+/// Resolves a file that a manifest records relative to `dir`. Fails if it could be outside
+/// `dir`: absolute paths, `..` components, or a parent directory that links elsewhere.
+fn manifest_entry(dir: &Path, relative: &Path) -> Result<PathBuf, String> {
+    let is_relative = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !is_relative || relative.file_name().is_none() {
+        return Err(format!("entry {:?} is outside {:?}", relative, dir));
+    }
+    let path = dir.join(relative);
+    let parent = path.parent().map(fs::canonicalize);
+    if let (Some(Ok(parent)), Ok(base)) = (parent, fs::canonicalize(dir)) {
+        if !parent.starts_with(&base) {
+            return Err(format!("entry {:?} links outside {:?}", relative, dir));
+        }
+    }
+    Ok(path)
+}
+
+/// Step of the generation in which an error occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerationPhase {
+    /// Missing template folder or unreadable template directory.
+    Config,
+    /// Rendering a template, a file name or a directory manifest.
+    Render,
+    /// Applying an injection template.
+    Inject,
+    /// Validating manual sections.
+    Validate,
+    /// Running a formatter.
+    Format,
+    /// Encoding, checking the existing file or writing the output.
+    Write,
+}
+
+impl fmt::Display for GenerationPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GenerationPhase::Config => "config",
+            GenerationPhase::Render => "render",
+            GenerationPhase::Inject => "inject",
+            GenerationPhase::Validate => "validate",
+            GenerationPhase::Format => "format",
+            GenerationPhase::Write => "write",
+        })
+    }
+}
+
+/// An error collected with `with_keep_going`, with the template it occurred for.
+#[derive(Debug, Clone)]
+pub struct GenerationError {
+    pub phase: GenerationPhase,
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {:?}: {}", self.phase, self.path, self.message)
+    }
+}
+
+/// Cumulative time spent generating the outputs of one template, collected with `with_profile`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateTiming {
+    pub template: PathBuf,
+    /// Total time of all generations of the template, including formatting and writing
+    pub total: Duration,
+    /// Number of times the template was generated, e.g. once per iteration item
+    pub count: usize,
+}
+
+/// Builds a unified diff between the previous and the new content of a file.
+pub fn unified_diff(prev: &str, new: &str, path: &Path) -> String {
+    let name = path.to_string_lossy();
+    TextDiff::from_lines(prev, new)
+        .unified_diff()
+        .header(&format!("a/{}", name), &format!("b/{}", name))
+        .to_string()
+}
+
+/// An output file in a `GenerationReport`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportedFile {
+    pub path: PathBuf,
+    /// Manual sections of the existing file were carried over into the new content
+    pub manual_sections_merged: bool,
+}
+
+/// What `generate_with_report` did with each output file; in dry-run mode, what it would do.
+/// The same format records a set's outputs in `OUTPUT_MANIFEST`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationReport {
+    /// Files rendered from `.j2` templates, including `generate_concat` outputs
+    pub written: Vec<ReportedFile>,
+    /// Files copied as they are
+    pub copied: Vec<ReportedFile>,
+    /// Existing files `.inj` templates injected into
+    pub injected: Vec<ReportedFile>,
+    /// Generated files left as they are because they are up to date
+    pub skipped: Vec<ReportedFile>,
+    /// Existing files not generated by templify that the conflict policy left unchanged
+    pub conflicts: Vec<ReportedFile>,
+}
+
+impl GenerationReport {
+    /// Appends the files of another report, e.g. of another template set.
+    pub fn extend(&mut self, other: GenerationReport) {
+        self.written.extend(other.written);
+        self.copied.extend(other.copied);
+        self.injected.extend(other.injected);
+        self.skipped.extend(other.skipped);
+        self.conflicts.extend(other.conflicts);
+    }
+
+    /// Returns the paths of the files generated from templates (written, copied or skipped);
+    /// existing files that were only injected into or left unchanged because of a conflict
+    /// are not included.
+    pub fn output_paths(&self) -> impl Iterator<Item = &Path> {
+        self.written
+            .iter()
+            .chain(&self.copied)
+            .chain(&self.skipped)
+            .map(|file| file.path.as_path())
+    }
+
+    /// Returns the report with the files below `dir` only, relative to `dir` and sorted.
+    fn relative_to(&self, dir: &Path) -> GenerationReport {
+        let relative = |files: &[ReportedFile]| {
+            let mut files: Vec<ReportedFile> = files
+                .iter()
+                .filter_map(|file| {
+                    let path = file.path.strip_prefix(dir).ok()?;
+                    Some(ReportedFile {
+                        path: PathBuf::from(path.to_string_lossy().replace('\\', "/")),
+                        manual_sections_merged: file.manual_sections_merged,
+                    })
+                })
+                .collect();
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            files.dedup();
+            files
+        };
+        GenerationReport {
+            written: relative(&self.written),
+            copied: relative(&self.copied),
+            injected: relative(&self.injected),
+            skipped: relative(&self.skipped),
+            conflicts: relative(&self.conflicts),
+        }
+    }
+}
+
+/// What happened to an output file, see `GenerationReport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReportAction {
+    Written,
+    Copied,
+    Injected,
+    Skipped,
+    Conflict,
+}
+
+/// Callback receiving each output path and its final content before it is written. It returns
+/// the content to write, or an error to reject the file.
+pub type PreWriteHook = Arc<dyn Fn(&Path, &str) -> Result<String, String> + Send + Sync>;
 
 pub struct FileGenerator {
     engine: TemplateEngine,
     manual_section_manager: ManualSectionManager,
     formatter_manager: Option<FormatterManager>,
-    dry_run: bool,
+    archive: Option<Arc<ArchiveWriter>>,
+    memory_output: Option<Mutex<BTreeMap<PathBuf, Vec<u8>>>>,
+    source: Arc<dyn TemplateSource>,
+    generated_files: Mutex<Vec<PathBuf>>,
+    /// Files actually written to disk, unlike `generated_files`
+    written_files: Mutex<Vec<PathBuf>>,
+    /// Template files each output was generated from
+    output_templates: Mutex<BTreeMap<PathBuf, BTreeSet<PathBuf>>>,
+    /// What happened to each output file, in generation order, see `GenerationReport`
+    reported_files: Mutex<Vec<(ReportAction, ReportedFile)>>,
+    dry_run: DryRunMode,
+    on_conflict: ConflictPolicy,
+    pre_write_hook: Option<PreWriteHook>,
+    explain: bool,
+    explanations: Mutex<Vec<String>>,
+    /// Unified diffs of the outputs, with `DryRunMode::Diff`
+    dry_run_diffs: Mutex<Vec<String>>,
+    encodings: Vec<EncodingRule>,
+    permissions: Vec<PermissionRule>,
+    header_comment: Option<HeaderCommentConfig>,
+    path_case: Option<PathCase>,
+    rename_map: Vec<(Regex, String)>,
+    /// Output path each renamed path was computed from, to detect collisions
+    renamed_outputs: Mutex<HashMap<PathBuf, PathBuf>>,
+    new_dirs: Mutex<Vec<PathBuf>>,
+    keep_going: bool,
+    errors: Mutex<Vec<GenerationError>>,
+    output_mode: OutputMode,
+    /// Template of each output path, to detect collisions in flat output mode
+    flat_outputs: Mutex<HashMap<PathBuf, PathBuf>>,
+    /// Newest modification time of the inputs besides the templates, when skipping
+    /// up-to-date outputs
+    incremental: Option<SystemTime>,
+    /// Generation time by template, when profiling
+    timings: Option<Mutex<HashMap<PathBuf, TemplateTiming>>>,
+    /// Largest template file that is read, in bytes
+    max_template_size: u64,
+    follow_symlinks: bool,
+    /// Files previous runs generated, which the conflict policy doesn't apply to
+    previous_outputs: HashSet<PathBuf>,
 }
 
 impl FileGenerator {
     pub fn new(
         engine: TemplateEngine,
         manual_section_manager: ManualSectionManager,
-        dry_run: bool,
+        dry_run: DryRunMode,
     ) -> Self {
         Self {
             engine,
             manual_section_manager,
             formatter_manager: None, // Default to None, use with_formatter to set
+            archive: None,
+            memory_output: None,
+            source: Arc::new(FileSystemSource),
+            generated_files: Mutex::new(Vec::new()),
+            written_files: Mutex::new(Vec::new()),
+            output_templates: Mutex::new(BTreeMap::new()),
+            reported_files: Mutex::new(Vec::new()),
             dry_run,
+            on_conflict: ConflictPolicy::default(),
+            pre_write_hook: None,
+            explain: false,
+            explanations: Mutex::new(Vec::new()),
+            dry_run_diffs: Mutex::new(Vec::new()),
+            encodings: Vec::new(),
+            permissions: Vec::new(),
+            header_comment: None,
+            path_case: None,
+            rename_map: Vec::new(),
+            renamed_outputs: Mutex::new(HashMap::new()),
+            new_dirs: Mutex::new(Vec::new()),
+            keep_going: false,
+            errors: Mutex::new(Vec::new()),
+            output_mode: OutputMode::default(),
+            flat_outputs: Mutex::new(HashMap::new()),
+            incremental: None,
+            timings: None,
+            max_template_size: DEFAULT_MAX_TEMPLATE_SIZE,
+            follow_symlinks: false,
+            previous_outputs: HashSet::new(),
         }
     }
-    
+
+    /// Sets the files that previous runs generated, e.g. from `recorded_outputs`. They are
+    /// replaced whatever the conflict policy, even if their content changed.
+    pub fn with_previous_outputs(mut self, outputs: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.previous_outputs = outputs.into_iter().collect();
+        self
+    }
+
+    /// Sets how the template tree maps to output paths.
+    pub fn with_output_mode(mut self, mode: OutputMode) -> Self {
+        self.output_mode = mode;
+        self
+    }
+
+    /// Skips rendering a template when its output file is newer than the template and than
+    /// `inputs_modified`, the newest modification time of the other inputs (data, config).
+    /// Injection templates are always applied.
+    pub fn with_incremental(mut self, inputs_modified: SystemTime) -> Self {
+        self.incremental = Some(inputs_modified);
+        self
+    }
+
+    /// Sets what happens to existing output files that weren't generated by templify.
+    pub fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.on_conflict = policy;
+        self
+    }
+
+    /// Runs `hook` on the final content of every text file, after formatting and before
+    /// writing. The hook can transform the content or reject the file with an error.
+    pub fn with_pre_write_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Path, &str) -> Result<String, String> + Send + Sync + 'static,
+    {
+        self.pre_write_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Writes rendered and injected outputs matching a rule in its encoding instead of UTF-8.
+    /// Existing files are read in the same encoding for manual section preservation.
+    pub fn with_encodings(mut self, encodings: Vec<EncodingRule>) -> Self {
+        self.encodings = encodings;
+        self
+    }
+
+    /// Sets the Unix file modes and owners of written files. For each of mode and owner, the
+    /// first matching rule that sets it applies; files no rule matches keep the defaults.
+    /// Files added to an `--archive` are not affected and get default permissions.
+    pub fn with_permissions(mut self, permissions: Vec<PermissionRule>) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Prepends a comment header to rendered `.j2` outputs (not to copies or injections), in
+    /// the comment style of the output file. Outputs without a known style get no header.
+    pub fn with_header_comment(mut self, header_comment: HeaderCommentConfig) -> Self {
+        self.header_comment = Some(header_comment);
+        self
+    }
+
+    /// Converts rendered output directory names to a case convention; file names are kept.
+    pub fn with_path_case(mut self, path_case: PathCase) -> Self {
+        self.path_case = Some(path_case);
+        self
+    }
+
+    /// Relocates output paths (relative to the output base, with `/` separators) by the first
+    /// matching rule. Fails if a pattern is not a valid regex.
+    pub fn with_rename_map(mut self, rules: &[RenameRule]) -> Result<Self, String> {
+        self.rename_map = rules
+            .iter()
+            .map(|rule| {
+                Regex::new(&rule.pattern)
+                    .map(|re| (re, rule.replacement.clone()))
+                    .map_err(|e| format!("Invalid rename_map pattern '{}': {}", rule.pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(self)
+    }
+
     pub fn with_formatter(mut self, formatter_manager: FormatterManager) -> Self {
         self.formatter_manager = Some(formatter_manager);
         self
     }
 
+    /// Writes generated files into the given archive instead of the filesystem.
+    /// The archive can be shared between several generators and must be finished by the caller.
+    pub fn with_archive(mut self, archive: Arc<ArchiveWriter>) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Keeps generated files in memory instead of writing them (see `memory_output`). Existing
+    /// output files are not checked for conflicts, and only `.inj` templates read them.
+    pub fn with_memory_output(mut self) -> Self {
+        self.memory_output = Some(Mutex::new(BTreeMap::new()));
+        self
+    }
+
+    /// Returns the files generated with `with_memory_output`, by output path.
+    pub fn memory_output(&self) -> BTreeMap<PathBuf, Vec<u8>> {
+        self.memory_output
+            .as_ref()
+            .map(|files| files.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+
+    /// Reads templates from the given source instead of the filesystem.
+    pub fn with_source(mut self, source: Arc<dyn TemplateSource>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Returns the template engine used for rendering.
+    pub fn engine(&self) -> &TemplateEngine {
+        &self.engine
+    }
+
+    /// Returns the output paths of all files generated so far, in generation order.
+    /// In dry-run mode these are the files that would have been written. The list grows with
+    /// every `generate` call, so use a generator per run (the CLI uses one per template set).
+    pub fn generated_files(&self) -> Vec<PathBuf> {
+        self.generated_files.lock().unwrap().clone()
+    }
+
+    /// Returns the number of files generated so far, to pass to `generated_files_since`.
+    pub fn generated_count(&self) -> usize {
+        self.generated_files.lock().unwrap().len()
+    }
+
+    /// Returns the output paths of the files generated after the first `start`, e.g. by one
+    /// iteration item, without copying the earlier ones.
+    pub fn generated_files_since(&self, start: usize) -> Vec<PathBuf> {
+        self.generated_files.lock().unwrap().get(start..).unwrap_or_default().to_vec()
+    }
+
+    /// Returns the template files each generated output was generated from: one template,
+    /// or the fragments of a `generate_concat` output.
+    pub fn output_templates(&self) -> BTreeMap<PathBuf, BTreeSet<PathBuf>> {
+        let generated: HashSet<PathBuf> = self.generated_files().into_iter().collect();
+        let mut outputs = self.output_templates.lock().unwrap().clone();
+        outputs.retain(|output, _| generated.contains(output));
+        outputs
+    }
+
+    /// Returns the files written to disk so far, in write order. Unlike `generated_files`, this
+    /// leaves out files that were skipped (conflicts, `with_incremental`) and output that went
+    /// into an archive or memory.
+    pub fn written_files(&self) -> Vec<PathBuf> {
+        self.written_files.lock().unwrap().clone()
+    }
+
+    /// Records why each template file and directory was generated or skipped
+    /// (see `explanations`).
+    pub fn with_explain(mut self, explain: bool) -> Self {
+        self.explain = explain;
+        self
+    }
+
+    /// Returns the decisions recorded with `with_explain`, in generation order.
+    pub fn explanations(&self) -> Vec<String> {
+        self.explanations.lock().unwrap().clone()
+    }
+
+    /// Returns the unified diff of each output in `DryRunMode::Diff`, in generation order, for
+    /// the caller to print.
+    pub fn dry_run_diffs(&self) -> Vec<String> {
+        self.dry_run_diffs.lock().unwrap().clone()
+    }
+
+    /// Records a decision if explaining is enabled.
+    fn explain(&self, message: impl FnOnce() -> String) {
+        if self.explain {
+            self.explanations.lock().unwrap().push(message());
+        }
+    }
+
+    /// Sets the largest template file that is read, in bytes; larger templates fail to
+    /// generate. The default is `DEFAULT_MAX_TEMPLATE_SIZE`.
+    pub fn with_max_template_size(mut self, max_template_size: u64) -> Self {
+        self.max_template_size = max_template_size;
+        self
+    }
+
+    /// Follows symbolic links to directories inside template folders. A link back to one of
+    /// the directories being traversed is a symlink cycle and fails generating it. By
+    /// default, linked directories are skipped.
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Measures how long generating each template file takes (see `template_timings`).
+    pub fn with_profile(mut self) -> Self {
+        self.timings = Some(Mutex::new(HashMap::new()));
+        self
+    }
+
+    /// Returns the timings measured with `with_profile`, slowest template first.
+    pub fn template_timings(&self) -> Vec<TemplateTiming> {
+        let Some(ref timings) = self.timings else {
+            return Vec::new();
+        };
+        let mut timings: Vec<TemplateTiming> = timings.lock().unwrap().values().cloned().collect();
+        timings.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.template.cmp(&b.template)));
+        timings
+    }
+
+    /// Continues with the next file when a file fails, collecting the errors (see `errors`)
+    /// instead of returning the first one. Formatter failures are collected as well.
+    pub fn with_keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Returns the errors collected with `with_keep_going`.
+    pub fn errors(&self) -> Vec<GenerationError> {
+        self.errors.lock().unwrap().clone()
+    }
+
+    /// Collects the error with `with_keep_going`, or returns its message otherwise.
+    fn handle_error(&self, error: GenerationError) -> Result<(), String> {
+        if !self.keep_going {
+            return Err(error.message);
+        }
+        self.collect_error(error);
+        Ok(())
+    }
+
+    fn collect_error(&self, error: GenerationError) {
+        error!("{}", error);
+        self.errors.lock().unwrap().push(error);
+    }
+
+    /// Returns the directories that would be created, parents first, in dry-run mode.
+    pub fn new_dirs(&self) -> Vec<PathBuf> {
+        self.new_dirs.lock().unwrap().clone()
+    }
+
+    /// Compiles every template and templated file or directory name under `template_path`
+    /// without generating anything, returning the errors found. The compiled templates are
+    /// reused by a later `generate` with the `template-cache` feature.
+    pub fn check_templates(&self, template_path: &Path) -> Result<Vec<String>, String> {
+        let mut errors = Vec::new();
+        self.check_templates_internal(template_path, &mut errors)?;
+        Ok(errors)
+    }
+
+    /// Checks the templates under `template_path` without rendering them: in addition to the
+    /// errors of `check_templates`, reports variables read from outside `variables` (by
+    /// top-level name), manual sections with structural issues, and injection patterns that
+    /// aren't valid regexes. Patterns containing template expressions are only checked when
+    /// rendered.
+    pub fn validate_templates(
+        &self,
+        template_path: &Path,
+        variables: &BTreeSet<String>,
+    ) -> Result<Vec<String>, String> {
+        let mut errors = self.check_templates(template_path)?;
+        // Variables are only known once all templates compile
+        if errors.is_empty() {
+            for (path, used) in self.referenced_variables(template_path)? {
+                let undefined: BTreeSet<&str> = used
+                    .iter()
+                    .map(|path| path.split('.').next().unwrap_or(path))
+                    .filter(|name| !variables.contains(*name))
+                    .collect();
+                for name in undefined {
+                    errors.push(format!("{:?}: undefined variable '{}'", path, name));
+                }
+            }
+        }
+        self.validate_contents(template_path, &mut errors)?;
+        Ok(errors)
+    }
+
+    fn validate_contents(&self, template_path: &Path, errors: &mut Vec<String>) -> Result<(), String> {
+        if !self.source.is_file(template_path) {
+            for path in self.child_paths(template_path)? {
+                match path {
+                    Ok(path) => self.validate_contents(&path, errors)?,
+                    Err(e) => errors.push(e),
+                }
+            }
+            return Ok(());
+        }
+        let name = template_path.file_name().unwrap().to_string_lossy();
+        if !name.ends_with(".j2") && !name.ends_with(".inj") {
+            return Ok(());
+        }
+        let template_str = self.read_template(template_path)?;
+        for issue in self.manual_section_manager.find_issues(&template_str) {
+            errors.push(format!("{:?}:{}: {}", template_path, issue.line, issue.message));
+        }
+        if name.ends_with(".inj") {
+            let template_str = match InjectionFrontMatter::split(&template_str) {
+                Ok((_, template_str)) => template_str,
+                Err(e) => {
+                    errors.push(format!("{:?}: {}", template_path, e));
+                    return Ok(());
+                }
+            };
+            let re_pattern = Regex::new(INJECTION_PATTERN).unwrap();
+            for cap in re_pattern.captures_iter(template_str) {
+                let section_body = &template_str[cap.get(0).unwrap().end()..];
+                let pattern_text = strip_injection_comments(
+                    section_body.split(INJECTION_STRING_START).next().unwrap(),
+                );
+                let pattern_text = pattern_text.trim();
+                if pattern_text.contains("{{") || pattern_text.contains("{%") {
+                    continue;
+                }
+                if let Err(e) = injection_regex(pattern_text) {
+                    errors.push(format!("{:?}: injection '{}': {}", template_path, &cap["name"], e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the context variables (as dotted attribute paths, see
+    /// `TemplateEngine::referenced_variables`) read by each template file and by each templated
+    /// file or directory name under `template_path`, including directory manifest conditions.
+    pub fn referenced_variables(
+        &self,
+        template_path: &Path,
+    ) -> Result<BTreeMap<PathBuf, BTreeSet<String>>, String> {
+        let mut variables = BTreeMap::new();
+        self.referenced_variables_internal(template_path, &mut variables)?;
+        Ok(variables)
+    }
+
+    fn referenced_variables_internal(
+        &self,
+        template_path: &Path,
+        variables: &mut BTreeMap<PathBuf, BTreeSet<String>>,
+    ) -> Result<(), String> {
+        let name = template_path.file_name().unwrap().to_str().unwrap();
+        let in_template = |template_str: &str| {
+            self.engine
+                .referenced_variables(template_str)
+                .map_err(|e| format!("{:?}: {}", template_path, e))
+        };
+        if self.source.is_file(template_path) {
+            let output_name = name
+                .strip_suffix(".j2")
+                .or_else(|| name.strip_suffix(".inj"))
+                .unwrap_or(name);
+            let mut used = in_template(output_name)?;
+            if output_name != name {
+                let body = self.engine.body_variables(&self.read_template(template_path)?);
+                used.extend(body.map_err(|e| format!("{:?}: {}", template_path, e))?);
+            }
+            if output_name != name || !used.is_empty() {
+                variables.insert(template_path.to_path_buf(), used);
+            }
+            return Ok(());
+        }
+
+        let manifest = self.read_manifest(template_path)?;
+        let mut used = in_template(manifest.rename.as_deref().unwrap_or(name))?;
+        let conditions = manifest.when.iter().chain(manifest.files.values());
+        for condition in conditions {
+            used.extend(in_template(&format!("{{{{ {} }}}}", condition))?);
+        }
+        if !used.is_empty() {
+            variables.insert(template_path.to_path_buf(), used);
+        }
+        for path in self.child_paths(template_path)? {
+            self.referenced_variables_internal(&path?, variables)?;
+        }
+        Ok(())
+    }
+
+    fn check_templates_internal(&self, template_path: &Path, errors: &mut Vec<String>) -> Result<(), String> {
+        if !self.source.exists(template_path) {
+            return Err(format!("Template file does not exist: {:?}", template_path));
+        }
+        let name = template_path.file_name().unwrap().to_str().unwrap();
+        // Names are compiled the same way as they are rendered by `generate`
+        let check_name = |name: &str, errors: &mut Vec<String>| {
+            if let Err(e) = self.engine.check_template(name) {
+                errors.push(format!("{:?}: invalid name: {}", template_path, e));
+            }
+        };
+        if self.source.is_file(template_path) {
+            let output_name = name
+                .strip_suffix(".j2")
+                .or_else(|| name.strip_suffix(".inj"))
+                .unwrap_or(name);
+            check_name(output_name, errors);
+            if output_name != name {
+                let template_str = self.read_template(template_path)?;
+                if let Err(e) = self.engine.check_body(&template_str) {
+                    errors.push(format!("{:?}: {}", template_path, e));
+                }
+            }
+            return Ok(());
+        }
+
+        let rename = self.read_manifest(template_path)?.rename;
+        check_name(rename.as_deref().unwrap_or(name), errors);
+        for path in self.child_paths(template_path)? {
+            match path {
+                Ok(path) => self.check_templates_internal(&path, errors)?,
+                Err(e) => errors.push(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the output directories owned by a single iteration item: the resolved names of
+    /// the template folder's sub-directories whose names, or manifest `rename`s, contain
+    /// template expressions. Directories with a fixed name are shared by all items.
+    pub fn item_output_dirs<T: Serialize>(
+        &self,
+        template_path: &Path,
+        output_path: &Path,
+        context: &T,
+    ) -> Result<Vec<PathBuf>, String> {
+        let mut dirs = Vec::new();
+        if self.source.is_file(template_path) || self.output_mode == OutputMode::Flat {
+            return Ok(dirs);
+        }
+        for path in self.child_paths(template_path)? {
+            let path = path?;
+            if self.source.is_file(&path) {
+                continue;
+            }
+            let folder_name = path.file_name().unwrap().to_str().unwrap();
+            let manifest = self.read_manifest(&path)?;
+            if !manifest.rename.as_deref().unwrap_or(folder_name).contains("{{") {
+                continue;
+            }
+            if let Some(name) = self.resolve_directory(&path, context)? {
+                dirs.push(output_path.join(name));
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Returns the output name of a template directory, or None if its manifest condition is false.
+    /// The name is the rendered folder name, or the rendered `rename` of the directory manifest,
+    /// converted to the `path_case`.
+    fn resolve_directory<T: Serialize>(
+        &self,
+        template_path: &Path,
+        context: &T,
+    ) -> Result<Option<String>, String> {
+        let folder_name = template_path.file_name().unwrap().to_str().unwrap();
+        let manifest = self.read_manifest(template_path)?;
+        if let Some(ref when) = manifest.when {
+            if !self.engine.evaluate_condition(when, context)? {
+                return Ok(None);
+            }
+        }
+        let name = manifest.rename.as_deref().unwrap_or(folder_name);
+        let name = self.engine.render_string(name, context)?;
+        Ok(Some(match self.path_case {
+            Some(path_case) => path_case.apply(&name),
+            None => name,
+        }))
+    }
+
+    /// Returns the condition of a file from the `files` of its directory's manifest, if the
+    /// condition evaluates to false.
+    fn unmet_file_condition<T: Serialize>(
+        &self,
+        template_path: &Path,
+        context: &T,
+    ) -> Result<Option<String>, String> {
+        let (Some(dir), Some(name)) = (template_path.parent(), template_path.file_name()) else {
+            return Ok(None);
+        };
+        let manifest = self.read_manifest(dir)?;
+        match manifest.files.get(name.to_string_lossy().as_ref()) {
+            Some(when) if !self.engine.evaluate_condition(when, context)? => Ok(Some(when.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads the manifest of a template directory; a directory without one has the default.
+    fn read_manifest(&self, dir: &Path) -> Result<DirectoryManifest, String> {
+        let manifest_path = dir.join(DIRECTORY_MANIFEST);
+        if !self.source.is_file(&manifest_path) {
+            return Ok(DirectoryManifest::default());
+        }
+        let content = self.source.read_to_string(&manifest_path)?;
+        DirectoryManifest::parse(&content)
+            .map_err(|e| format!("Invalid manifest {:?}: {}", manifest_path, e))
+    }
+
+    /// Removes files below `dir` that are not in `keep`. Files with manual sections
+    /// are preserved. Returns the removed (or, in dry-run mode, the would-be removed) files.
+    pub fn clean_stale_files(
+        &self,
+        dir: &Path,
+        keep: &HashSet<PathBuf>,
+    ) -> Result<Vec<PathBuf>, String> {
+        let mut removed = Vec::new();
+        if dir.is_dir() {
+            self.clean_stale_files_internal(dir, keep, &mut removed)?;
+        }
+        Ok(removed)
+    }
+
+    fn clean_stale_files_internal(
+        &self,
+        dir: &Path,
+        keep: &HashSet<PathBuf>,
+        removed: &mut Vec<PathBuf>,
+    ) -> Result<(), String> {
+        for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.is_dir() {
+                self.clean_stale_files_internal(&path, keep, removed)?;
+                continue;
+            }
+            if keep.contains(&path) {
+                continue;
+            }
+            self.remove_stale_file(path, false, removed)?;
+        }
+        Ok(())
+    }
+
+    /// Removes stale output files that exist, e.g. the ones a previous `GenerationReport`
+    /// lists but the current run no longer generates. Files containing manual sections are
+    /// kept unless `force` is set. Returns the removed (or, in dry-run mode, the would-be
+    /// removed) files.
+    pub fn remove_stale_outputs(
+        &self,
+        paths: impl IntoIterator<Item = PathBuf>,
+        force: bool,
+    ) -> Result<Vec<PathBuf>, String> {
+        let mut removed = Vec::new();
+        for path in paths {
+            if path.is_dir() {
+                warn!("Keeping stale output that is now a directory: {:?}", path);
+            } else if path.symlink_metadata().is_ok() {
+                self.remove_stale_file(path, force, &mut removed)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes a stale file unless it contains manual sections and `force` is not set.
+    fn remove_stale_file(
+        &self,
+        path: PathBuf,
+        force: bool,
+        removed: &mut Vec<PathBuf>,
+    ) -> Result<(), String> {
+        if !force {
+            if let Ok(content) = fs::read(&path) {
+                if self.manual_section_manager.contains_markers(&String::from_utf8_lossy(&content)) {
+                    warn!("Keeping stale file with manual sections: {:?}", path);
+                    return Ok(());
+                }
+            }
+        }
+        if self.dry_run.is_enabled() {
+            info!("[DRY RUN] Would remove stale file: {:?}", path);
+        } else {
+            fs::remove_file(&path).map_err(|e| e.to_string())?;
+            info!("Removed stale file: {:?}", path);
+        }
+        removed.push(path);
+        Ok(())
+    }
+
+    /// Removes the output files that the `OUTPUT_MANIFEST` in `dir` records for `set` but that
+    /// `report` no longer lists (see `GenerationReport::output_paths`), then records the files
+    /// of `report` below `dir` for the next run. Files with manual sections are preserved.
+    /// Returns the removed (or, in dry-run mode, the would-be removed) files; the manifest is
+    /// left unchanged in dry-run mode.
+    pub fn clean_set_outputs(
+        &self,
+        dir: &Path,
+        set: &str,
+        report: &GenerationReport,
+    ) -> Result<Vec<PathBuf>, String> {
+        // Every entry is validated before anything is removed
+        let previous = recorded_outputs(dir, set)?;
+        let keep: HashSet<&Path> = report.output_paths().collect();
+        let stale = previous.into_iter().filter(|path| !keep.contains(path.as_path()));
+        let removed = self.remove_stale_outputs(stale, false)?;
+        self.record_set_outputs(dir, set, report)?;
+        Ok(removed)
+    }
+
+    /// Records the files of `report` below `dir` as the outputs of `set` in the
+    /// `OUTPUT_MANIFEST` of `dir`, for `clean_set_outputs` and `recorded_outputs` of the next
+    /// run. Nothing is recorded in dry-run mode.
+    pub fn record_set_outputs(
+        &self,
+        dir: &Path,
+        set: &str,
+        report: &GenerationReport,
+    ) -> Result<(), String> {
+        if self.dry_run.is_enabled() {
+            return Ok(());
+        }
+        let mut manifest = read_output_manifest(dir)?;
+        manifest.insert(set.to_string(), report.relative_to(dir));
+        let content = serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())?;
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        fs::write(dir.join(OUTPUT_MANIFEST), content + "\n").map_err(|e| e.to_string())
+    }
+
+    /// Returns true if generated files are written to the filesystem.
+    fn writes_to_disk(&self) -> bool {
+        !self.dry_run.is_enabled() && !self.redirects_output()
+    }
+
+    /// Returns true if generated files go into an archive or memory instead of the output
+    /// directory, so existing files there are irrelevant except as injection targets.
+    fn redirects_output(&self) -> bool {
+        self.archive.is_some() || self.memory_output.is_some()
+    }
+
+    /// Reports a file that would be written in dry-run mode.
+    fn report_dry_run(&self, action: &str, output_path: &Path, prev: Option<&str>, new: Option<&str>) {
+        info!("[DRY RUN] Would {}: {:?}", action, output_path);
+        if self.dry_run == DryRunMode::Diff {
+            match new {
+                Some(new) => self
+                    .dry_run_diffs
+                    .lock()
+                    .unwrap()
+                    .push(unified_diff(prev.unwrap_or(""), new, output_path)),
+                None => info!("[DRY RUN] Binary content, no diff: {:?}", output_path),
+            }
+        }
+    }
+
+    /// Returns the encoding of an output file: the first matching rule, or UTF-8.
+    fn encoding_for(&self, output_path: &Path) -> Result<&'static Encoding, String> {
+        match self.encodings.iter().find(|rule| rule.matches(output_path)) {
+            Some(rule) => Encoding::for_label(rule.encoding.trim().as_bytes())
+                .ok_or_else(|| format!("Unknown encoding '{}' for {:?}", rule.encoding, output_path)),
+            None => Ok(UTF_8),
+        }
+    }
+
+    /// Encodes text for an output file. UTF-16 is written with a byte order mark.
+    fn encode_text(&self, output_path: &Path, content: &str) -> Result<Vec<u8>, String> {
+        let encoding = self.encoding_for(output_path)?;
+        if encoding == UTF_8 {
+            return Ok(content.as_bytes().to_vec());
+        }
+        // encoding_rs only decodes UTF-16, so encode it here
+        if encoding == UTF_16LE || encoding == UTF_16BE {
+            let little_endian = encoding == UTF_16LE;
+            let mut bytes = Vec::with_capacity(2 + content.len() * 2);
+            for unit in std::iter::once(0xFEFF).chain(content.encode_utf16()) {
+                let unit_bytes = if little_endian {
+                    unit.to_le_bytes()
+                } else {
+                    unit.to_be_bytes()
+                };
+                bytes.extend_from_slice(&unit_bytes);
+            }
+            return Ok(bytes);
+        }
+        let (bytes, _, had_errors) = encoding.encode(content);
+        if had_errors {
+            return Err(format!(
+                "Content of {:?} cannot be represented in {}",
+                output_path,
+                encoding.name()
+            ));
+        }
+        Ok(bytes.into_owned())
+    }
+
+    /// Reads an existing output file in its configured encoding.
+    fn read_existing(&self, output_path: &Path) -> Result<String, String> {
+        let encoding = self.encoding_for(output_path)?;
+        let bytes = fs::read(output_path).map_err(|e| e.to_string())?;
+        if encoding == UTF_8 {
+            return String::from_utf8(bytes).map_err(|e| e.to_string());
+        }
+        let (content, _, had_errors) = encoding.decode(&bytes);
+        if had_errors {
+            return Err(format!("{:?} is not valid {}", output_path, encoding.name()));
+        }
+        Ok(content.into_owned())
+    }
+
+    /// Writes content to the output path, or into the archive or memory if configured.
+    fn write_output(&self, output_path: &Path, contents: &[u8]) -> Result<(), String> {
+        self.write_file(output_path, contents, None)
+    }
+
+    /// Like `write_output`, but a file written to disk first gets `permissions`, e.g. those of
+    /// the copied source file; matching permission rules still take precedence.
+    fn write_file(
+        &self,
+        output_path: &Path,
+        contents: &[u8],
+        permissions: Option<fs::Permissions>,
+    ) -> Result<(), String> {
+        if let Some(archive) = &self.archive {
+            archive.add_file(output_path, contents)
+        } else if let Some(files) = &self.memory_output {
+            files
+                .lock()
+                .unwrap()
+                .insert(output_path.to_path_buf(), contents.to_vec());
+            Ok(())
+        } else {
+            fs::write(output_path, contents).map_err(|e| e.to_string())?;
+            if let Some(permissions) = permissions {
+                fs::set_permissions(output_path, permissions).map_err(|e| {
+                    format!("Failed to set permissions of {:?}: {}", output_path, e)
+                })?;
+            }
+            self.apply_permissions(output_path)?;
+            self.written_files.lock().unwrap().push(output_path.to_path_buf());
+            Ok(())
+        }
+    }
+
+    /// Sets the mode and owner of a written file from the matching permission rules.
+    fn apply_permissions(&self, output_path: &Path) -> Result<(), String> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let matching = || self.permissions.iter().filter(|rule| rule.matches(output_path));
+            let mode = matching()
+                .find_map(|rule| rule.file_mode().transpose())
+                .transpose()?;
+            let owner = matching()
+                .find_map(|rule| rule.file_owner().transpose())
+                .transpose()?;
+            if let Some(mode) = mode {
+                fs::set_permissions(output_path, fs::Permissions::from_mode(mode))
+                    .map_err(|e| format!("Failed to set mode {:o} of {:?}: {}", mode, output_path, e))?;
+            }
+            if let Some((uid, gid)) = owner {
+                std::os::unix::fs::chown(output_path, Some(uid), gid)
+                    .map_err(|e| format!("Failed to change owner of {:?}: {}", output_path, e))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = output_path;
+        Ok(())
+    }
+
+    /// Copies a non-template file to the output path, or into the archive if one is configured.
+    /// The copy keeps the permissions of the source file, e.g. of an executable script.
+    fn copy_output(&self, template_path: &Path, output_path: &Path, contents: &[u8]) -> Result<(), String> {
+        self.write_file(output_path, contents, self.source.permissions(template_path))
+    }
+
+    /// Reads a non-template file, passing it through the pre-write hook if it is text.
+    fn copy_content(&self, template_path: &Path, output_path: &Path) -> Result<Vec<u8>, String> {
+        let contents = self.source.read(template_path)?;
+        if self.pre_write_hook.is_none() {
+            return Ok(contents);
+        }
+        match String::from_utf8(contents) {
+            Ok(text) => Ok(self.apply_pre_write_hook(output_path, text)?.into_bytes()),
+            Err(e) => Ok(e.into_bytes()),
+        }
+    }
+
+    /// Passes content through the pre-write hook, if one is set.
+    fn apply_pre_write_hook(&self, output_path: &Path, content: String) -> Result<String, String> {
+        match &self.pre_write_hook {
+            Some(hook) => hook(output_path, &content)
+                .map_err(|e| format!("Pre-write hook rejected {:?}: {}", output_path, e)),
+            None => Ok(content),
+        }
+    }
+
+    /// Reads a template body from the template source, within `max_template_size`.
+    fn read_template(&self, template_path: &Path) -> Result<String, String> {
+        self.source.read_template(template_path, self.max_template_size)
+    }
+
+    /// Prepends the configured header comment to rendered content, after a shebang line,
+    /// unless the content already contains it.
+    fn add_header<T: Serialize>(
+        &self,
+        template_path: &Path,
+        output_path: &Path,
+        content: String,
+        context: &T,
+    ) -> Result<String, String> {
+        let Some(ref header_comment) = self.header_comment else {
+            return Ok(content);
+        };
+        // The whole file name first, so dotfiles such as `.gitignore` (which have no extension)
+        // and names such as `Makefile` get a style, then the extension
+        let name = output_path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        let extension = output_path.extension().and_then(|ext| ext.to_str());
+        let kinds = [Some(name), Some(name.trim_start_matches('.')), extension];
+        let Some(style) = kinds.into_iter().flatten().find_map(|kind| {
+            header_comment
+                .styles
+                .get(kind)
+                .map(String::as_str)
+                .or_else(|| comment_style(kind))
+        }) else {
+            debug!("No comment style for {:?}, skipping the header", output_path);
+            return Ok(content);
+        };
+        let text = self.engine.render_string(
+            &header_comment.template,
+            &minijinja::context! {
+                template => template_path.to_string_lossy().replace('\\', "/"),
+                template_name => template_path.file_name().map(|name| name.to_string_lossy()),
+                output => output_path.to_string_lossy().replace('\\', "/"),
+                ..minijinja::Value::from_serialize(context)
+            },
+        )?;
+        let header: String = text
+            .lines()
+            .map(|line| style.replace("{}", line).trim_end().to_string() + "\n")
+            .collect();
+        if content.contains(header.trim_end()) {
+            return Ok(content);
+        }
+        Ok(match content.strip_prefix("#!") {
+            Some(_) => match content.split_once('\n') {
+                Some((shebang, rest)) => format!("{}\n{}{}", shebang, header, rest),
+                None => format!("{}\n{}", content, header),
+            },
+            None => header + &content,
+        })
+    }
+
+    /// Renders a template file read from the template source.
+    fn render_template<T: Serialize>(&self, template_path: &Path, context: &T) -> Result<String, String> {
+        let template_str = self.read_template(template_path).map_err(|e| {
+            format!("Failed to read template file {:?}: {}", template_path, e)
+        })?;
+        self.engine
+            .render_body(&template_str, context)
+            .map_err(|e| format!("{:?}, error: {}", template_path, e))
+    }
+
+    /// Applies the conflict policy before an existing output file is replaced. The file is
+    /// considered generated by templify if a previous run generated it, if it has manual
+    /// section markers, or if it already has the new content. Returns false if the file must
+    /// be left unchanged.
+    fn resolve_conflict(
+        &self,
+        output_path: &Path,
+        new_content: &[u8],
+    ) -> Result<bool, String> {
+        if self.on_conflict == ConflictPolicy::Overwrite
+            || self.redirects_output()
+            || self.previous_outputs.contains(output_path)
+        {
+            return Ok(true);
+        }
+        let existing = match fs::read(output_path) {
+            Ok(existing) => existing,
+            Err(_) => return Ok(true),
+        };
+        let has_markers = std::str::from_utf8(&existing)
+            .is_ok_and(|text| self.manual_section_manager.contains_markers(text));
+        if has_markers || existing == new_content {
+            return Ok(true);
+        }
+        match self.on_conflict {
+            ConflictPolicy::Overwrite => Ok(true),
+            ConflictPolicy::Skip => {
+                warn!("Skipping existing file not generated by templify: {:?}", output_path);
+                self.explain(|| {
+                    format!(
+                        "skip {:?}: existing file not generated by templify (--on-conflict skip)",
+                        output_path
+                    )
+                });
+                Ok(false)
+            }
+            ConflictPolicy::Error => Err(format!(
+                "Existing file was not generated by templify: {:?}",
+                output_path
+            )),
+            ConflictPolicy::Backup => {
+                let mut backup = output_path.as_os_str().to_owned();
+                backup.push(".bak");
+                if self.dry_run.is_enabled() {
+                    info!("[DRY RUN] Would back up {:?} to {:?}", output_path, backup);
+                } else {
+                    fs::rename(output_path, &backup).map_err(|e| e.to_string())?;
+                    warn!("Backed up existing file {:?} to {:?}", output_path, backup);
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    /// Renders a template straight into the output file through a buffered writer, so a large
+    /// output is never held in memory as a whole. Returns false without writing anything if the
+    /// full content is needed: in dry-run or archive mode, when a previous file has to be merged,
+    /// when the template contains manual section markers, when the output is formatted, or
+    /// when a pre-write hook is set.
+    fn stream_template<T: Serialize>(
+        &self,
+        template_path: &Path,
+        output_path: &Path,
+        context: &T,
+        has_prev: bool,
+    ) -> Result<bool, String> {
+        if !self.writes_to_disk()
+            || has_prev
+            || self.pre_write_hook.is_some()
+            || self.header_comment.is_some()
+        {
+            return Ok(false);
+        }
+        if self.encoding_for(output_path)? != UTF_8 {
+            return Ok(false);
+        }
+        if self.on_conflict != ConflictPolicy::Overwrite && output_path.exists() {
+            return Ok(false);
+        }
+        let output_name = output_path.to_str().unwrap_or("");
+        if self
+            .formatter_manager
+            .as_ref()
+            .is_some_and(|fmt| fmt.would_format(output_name))
+        {
+            return Ok(false);
+        }
+        let template_str = self.read_template(template_path).map_err(|e| {
+            format!("Failed to read template file {:?}: {}", template_path, e)
+        })?;
+        if self.manual_section_manager.contains_markers(&template_str) {
+            return Ok(false);
+        }
+
+        let file = fs::File::create(output_path).map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(file);
+        let result = self
+            .engine
+            .render_to_write(&template_str, context, &mut writer)
+            .map_err(|e| format!("{:?}, error: {}", template_path, e))
+            .and_then(|_| writer.flush().map_err(|e| e.to_string()));
+        drop(writer);
+        let result = result.and_then(|_| self.apply_permissions(output_path));
+        if result.is_err() {
+            // Don't leave a partially rendered file behind
+            let _ = fs::remove_file(output_path);
+        } else {
+            self.written_files.lock().unwrap().push(output_path.to_path_buf());
+        }
+        result.map(|_| true)
+    }
+
+    /// Creates a directory if files are written to disk. In dry-run mode, records the
+    /// directories that would be created instead (see `new_dirs`).
+    fn prepare_dir(&self, path: &Path) -> Result<(), String> {
+        if self.writes_to_disk() {
+            return Self::ensure_dir_exists(path);
+        }
+        if self.dry_run.is_enabled() {
+            let missing: Vec<&Path> = path
+                .ancestors()
+                .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+                .collect();
+            let mut new_dirs = self.new_dirs.lock().unwrap();
+            for dir in missing.into_iter().rev() {
+                if !new_dirs.iter().any(|known| known == dir) {
+                    info!("[DRY RUN] Would create directory: {:?}", dir);
+                    new_dirs.push(dir.to_path_buf());
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Ensures that the specified directory exists, creating it if necessary.
     fn ensure_dir_exists(path: &Path) -> Result<(), String> {
         if !path.exists() {
@@ -53,7 +1462,170 @@ impl FileGenerator {
         output_path: &Path,
         context: &T,
     ) -> Result<(), String> {
-        self.generate_internal(template_path, output_path, context, true)
+        self.generate_internal(template_path, output_path, output_path, context, true)
+    }
+
+    /// Like `generate`, but returns what was done with each output file of this call. In
+    /// dry-run mode, the report lists what would be done. Files that other threads generate
+    /// with the same generator at the same time may be included.
+    pub fn generate_with_report<T: Serialize>(
+        &self,
+        template_path: &Path,
+        output_path: &Path,
+        context: &T,
+    ) -> Result<GenerationReport, String> {
+        let start = self.reported_files.lock().unwrap().len();
+        self.generate(template_path, output_path, context)?;
+        Ok(self.report_since(start))
+    }
+
+    /// Returns what was done with every output file generated so far (see
+    /// `generate_with_report`).
+    pub fn report(&self) -> GenerationReport {
+        self.report_since(0)
+    }
+
+    fn report_since(&self, start: usize) -> GenerationReport {
+        let mut report = GenerationReport::default();
+        for (action, file) in self.reported_files.lock().unwrap().iter().skip(start) {
+            let files = match action {
+                ReportAction::Written => &mut report.written,
+                ReportAction::Copied => &mut report.copied,
+                ReportAction::Injected => &mut report.injected,
+                ReportAction::Skipped => &mut report.skipped,
+                ReportAction::Conflict => &mut report.conflicts,
+            };
+            files.push(file.clone());
+        }
+        report
+    }
+
+    fn report_file(&self, action: ReportAction, output_path: &Path, manual_sections_merged: bool) {
+        self.reported_files.lock().unwrap().push((
+            action,
+            ReportedFile {
+                path: output_path.to_path_buf(),
+                manual_sections_merged,
+            },
+        ));
+    }
+
+    /// Renders every file under the template directory in sorted path order and writes the
+    /// results, joined with `separator`, into the single file `output_path`, e.g. one SQL
+    /// migration from many fragments. `.j2` files are rendered and other files are included as
+    /// they are; `.inj` templates are skipped. Directory manifest conditions apply as in
+    /// `generate`, and the output gets the same handling as a rendered file.
+    pub fn generate_concat<T: Serialize>(
+        &self,
+        template_path: &Path,
+        output_path: &Path,
+        separator: &str,
+        context: &T,
+    ) -> Result<(), String> {
+        let fail = |phase| {
+            move |message: String| GenerationError {
+                phase,
+                path: template_path.to_path_buf(),
+                message,
+            }
+        };
+        if !self.source.exists(template_path) {
+            return Err(format!("Template file does not exist: {:?}", template_path));
+        }
+        let mut fragments = Vec::new();
+        if let Err(e) = self.concat_fragments(template_path, context, &mut fragments) {
+            return self.handle_error(e);
+        }
+
+        let prev = if !self.redirects_output() && output_path.exists() {
+            self.read_existing(output_path).ok()
+        } else {
+            None
+        };
+        if let Some(parent) = output_path.parent() {
+            if let Err(e) = self.prepare_dir(parent) {
+                return self.handle_error(fail(GenerationPhase::Write)(e));
+            }
+        }
+        let (paths, fragments): (Vec<PathBuf>, Vec<String>) = fragments.into_iter().unzip();
+        self.output_templates
+            .lock()
+            .unwrap()
+            .entry(output_path.to_path_buf())
+            .or_default()
+            .extend(paths);
+        let content = fragments.join(separator);
+        let content = match self.add_header(template_path, output_path, content, context) {
+            Ok(content) => content,
+            Err(e) => return self.handle_error(fail(GenerationPhase::Render)(e)),
+        };
+        let result = self
+            .write_rendered(template_path, output_path, content, prev.as_deref())
+            .and_then(|_| self.record_generated(output_path).map_err(fail(GenerationPhase::Write)));
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) => self.handle_error(e),
+        }
+    }
+
+    /// Renders the files below `dir` for `generate_concat`, in sorted order.
+    fn concat_fragments<T: Serialize>(
+        &self,
+        dir: &Path,
+        context: &T,
+        fragments: &mut Vec<(PathBuf, String)>,
+    ) -> Result<(), GenerationError> {
+        let fail = |path: &Path, phase| {
+            let path = path.to_path_buf();
+            move |message: String| GenerationError { phase, path, message }
+        };
+        let mut paths = self.child_paths(dir).map_err(fail(dir, GenerationPhase::Config))?;
+        paths.sort();
+        for path in paths {
+            let path = path.map_err(fail(dir, GenerationPhase::Config))?;
+            if !self.source.is_file(&path) {
+                match self.resolve_directory(&path, context) {
+                    Ok(Some(_)) => self.concat_fragments(&path, context, fragments)?,
+                    Ok(None) => {}
+                    Err(e) => return Err(fail(&path, GenerationPhase::Render)(e)),
+                }
+                continue;
+            }
+            if path.file_name().is_some_and(|name| name == DIRECTORY_MANIFEST)
+                || self
+                    .unmet_file_condition(&path, context)
+                    .map_err(fail(&path, GenerationPhase::Render))?
+                    .is_some()
+            {
+                continue;
+            }
+            let fragment = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("j2") => self.render_template(&path, context),
+                Some("inj") => continue,
+                _ => self.source.read_to_string(&path),
+            };
+            let fragment = fragment.map_err(fail(&path, GenerationPhase::Render))?;
+            self.explain(|| format!("concat {:?}", path));
+            fragments.push((path, fragment));
+        }
+        Ok(())
+    }
+
+    /// Generates one template file to the given output file path, with the same handling as
+    /// during a directory traversal (manual sections, formatting, conflicts, ...). Unlike
+    /// `generate`, `output_path` is the path of the file itself and is used as is.
+    pub fn generate_single_file<T: Serialize>(
+        &self,
+        template_path: &Path,
+        output_path: &Path,
+        context: &T,
+    ) -> Result<(), String> {
+        if !self.source.is_file(template_path) {
+            return Err(format!("Template file does not exist: {:?}", template_path));
+        }
+        let output_base = output_path.parent().unwrap_or(Path::new("."));
+        self.generate_file(template_path, output_path, output_base, context)
+            .or_else(|e| self.handle_error(e))
     }
 
     /// Internal method to generate files from the specified template path to the output path.
@@ -61,64 +1633,351 @@ impl FileGenerator {
         &self,
         template_path: &Path,
         output_path: &Path,
+        output_base: &Path,
         context: &T,
         root_path: bool,
     ) -> Result<(), String> {
-        if !template_path.exists() {
+        let fail = |phase| {
+            move |message: String| GenerationError {
+                phase,
+                path: template_path.to_path_buf(),
+                message,
+            }
+        };
+        if !self.source.exists(template_path) {
             error!("Template file does not exist: {:?}", template_path);
-            return Err("Template file does not exist".to_string());
+            return self.handle_error(fail(GenerationPhase::Config)(
+                "Template file does not exist".to_string(),
+            ));
+        }
+
+        if let Err(e) = self.prepare_dir(output_path) {
+            return self.handle_error(fail(GenerationPhase::Write)(e));
+        }
+
+        if self.source.is_file(template_path) {
+            match self.unmet_file_condition(template_path, context) {
+                Ok(Some(when)) => {
+                    info!("Skipping file by manifest condition: {:?}", template_path);
+                    self.explain(|| {
+                        format!(
+                            "skip {:?}: `{}` in {} is false",
+                            template_path, when, DIRECTORY_MANIFEST
+                        )
+                    });
+                    return Ok(());
+                }
+                Ok(None) => {}
+                Err(e) => return self.handle_error(fail(GenerationPhase::Render)(e)),
+            }
+            let filename = template_path.file_name().unwrap().to_str().unwrap();
+            let filename = filename
+                .strip_suffix(".j2")
+                .or_else(|| filename.strip_suffix(".inj"))
+                .unwrap_or(filename);
+            let rendered_filename = match self.engine.render_string(filename, context) {
+                Ok(name) => name,
+                Err(e) => return self.handle_error(fail(GenerationPhase::Render)(e)),
+            };
+            let new_output_path = output_path.join(rendered_filename);
+            let new_output_path = match self.rename_output(output_base, new_output_path) {
+                Ok(path) => path,
+                Err(e) => return self.handle_error(fail(GenerationPhase::Write)(e)),
+            };
+            if let Err(e) = self.check_flat_collision(template_path, &new_output_path) {
+                return self.handle_error(fail(GenerationPhase::Write)(e));
+            }
+            let _log_scope = crate::logging::file_scope(template_path);
+            let started = Instant::now();
+            let result = self.generate_file(template_path, &new_output_path, output_base, context);
+            self.output_templates
+                .lock()
+                .unwrap()
+                .entry(new_output_path)
+                .or_default()
+                .insert(template_path.to_path_buf());
+            if let Some(ref timings) = self.timings {
+                let mut timings = timings.lock().unwrap();
+                let timing = timings
+                    .entry(template_path.to_path_buf())
+                    .or_insert_with(|| TemplateTiming {
+                        template: template_path.to_path_buf(),
+                        total: Duration::ZERO,
+                        count: 0,
+                    });
+                timing.total += started.elapsed();
+                timing.count += 1;
+            }
+            if let Err(e) = result {
+                return self.handle_error(e);
+            }
+        } else {
+            let resolved = match self.resolve_directory(template_path, context) {
+                Ok(resolved) => resolved,
+                Err(e) => return self.handle_error(fail(GenerationPhase::Render)(e)),
+            };
+            let rendered_folder_name = match resolved {
+                Some(name) => name,
+                None => {
+                    info!("Skipping directory by manifest condition: {:?}", template_path);
+                    self.explain(|| {
+                        format!(
+                            "skip {:?}: `when` in {} is false",
+                            template_path, DIRECTORY_MANIFEST
+                        )
+                    });
+                    return Ok(());
+                }
+            };
+            let new_output_path = if root_path || self.output_mode == OutputMode::Flat {
+                output_path.to_path_buf()
+            } else {
+                output_path.join(&rendered_folder_name)
+            };
+            let paths = match self.child_paths(template_path) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    error!("Failed to read directory: {:?}", template_path);
+                    return self.handle_error(fail(GenerationPhase::Config)(e));
+                }
+            };
+            for path in paths {
+                match path {
+                    Ok(path) => {
+                        self.generate_internal(&path, &new_output_path, output_base, context, false)?
+                    }
+                    Err(message) => self.handle_error(fail(GenerationPhase::Config)(message))?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the entries of a template directory that are traversed, without the directory
+    /// manifest. Symlinked directories are left out unless symlinks are followed; one that
+    /// links back to a directory it is reached through is an error entry.
+    fn child_paths(&self, dir: &Path) -> Result<Vec<Result<PathBuf, String>>, String> {
+        let mut paths = Vec::new();
+        for path in self.source.read_dir(dir)? {
+            if path.file_name().is_some_and(|name| name == DIRECTORY_MANIFEST) {
+                continue;
+            }
+            if self.source.is_dir_symlink(&path) {
+                if !self.follow_symlinks {
+                    info!("Skipping symlinked directory: {:?}", path);
+                    self.explain(|| format!("skip {:?}: symlinked directory", path));
+                    continue;
+                }
+                if let Err(message) = self.check_symlink_cycle(&path) {
+                    paths.push(Err(message));
+                    continue;
+                }
+            }
+            paths.push(Ok(path));
+        }
+        Ok(paths)
+    }
+
+    /// Fails if a symlinked directory resolves to one of the directories it is reached
+    /// through, which would be traversed forever.
+    fn check_symlink_cycle(&self, link: &Path) -> Result<(), String> {
+        let target = self.source.canonicalize(link)?;
+        for ancestor in link.ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            if self.source.canonicalize(ancestor)? == target {
+                return Err(format!(
+                    "Symlink cycle: {:?} links back to {:?}",
+                    link, ancestor
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the first matching `rename_map` rule to an output path. Fails if another output
+    /// path was already relocated to (or generated at) the same path.
+    fn rename_output(&self, output_base: &Path, output_path: PathBuf) -> Result<PathBuf, String> {
+        if self.rename_map.is_empty() {
+            return Ok(output_path);
+        }
+        let renamed = match output_path.strip_prefix(output_base) {
+            Ok(relative) => {
+                let relative = relative.to_string_lossy().replace('\\', "/");
+                self.rename_map
+                    .iter()
+                    .find(|(re, _)| re.is_match(&relative))
+                    .map(|(re, replacement)| {
+                        output_base.join(re.replace(&relative, replacement.as_str()).as_ref())
+                    })
+            }
+            Err(_) => None,
+        };
+        let target = renamed.clone().unwrap_or_else(|| output_path.clone());
+        let mut outputs = self.renamed_outputs.lock().unwrap();
+        match outputs.get(&target) {
+            Some(other) if *other != output_path => Err(format!(
+                "{:?} and {:?} both map to {:?} with rename_map",
+                other, output_path, target
+            )),
+            _ => {
+                if renamed.is_some() {
+                    self.explain(|| format!("rename {:?} -> {:?}", output_path, target));
+                }
+                outputs.insert(target.clone(), output_path);
+                Ok(target)
+            }
+        }
+    }
+
+    /// In flat output mode, fails if another template already generated the output path.
+    fn check_flat_collision(&self, template_path: &Path, output_path: &Path) -> Result<(), String> {
+        if self.output_mode != OutputMode::Flat {
+            return Ok(());
+        }
+        let mut outputs = self.flat_outputs.lock().unwrap();
+        match outputs.get(output_path) {
+            Some(other) if other != template_path => Err(format!(
+                "{:?} and {:?} both generate {:?} in flat output mode",
+                other, template_path, output_path
+            )),
+            _ => {
+                outputs.insert(output_path.to_path_buf(), template_path.to_path_buf());
+                Ok(())
+            }
+        }
+    }
+
+    /// Validates, merges manual sections of `prev` into, formats and writes the rendered content
+    /// of a template. Doesn't record the output as generated.
+    fn write_rendered(
+        &self,
+        template_path: &Path,
+        output_path: &Path,
+        rendered_content: String,
+        prev: Option<&str>,
+    ) -> Result<(), GenerationError> {
+        let fail = |phase| {
+            move |message: String| GenerationError {
+                phase,
+                path: template_path.to_path_buf(),
+                message,
+            }
+        };
+        // Validate manual sections
+        self.manual_section_manager.validate_sections(
+            template_path.to_str().unwrap_or("template"),
+            &rendered_content,
+            prev
+        ).map_err(fail(GenerationPhase::Validate))?;
+
+        let mut merged = false;
+        let mut final_content = if let Some(prev) = prev {
+            let (content, outcomes) = self
+                .manual_section_manager
+                .preserve_sections_with_outcomes(&rendered_content, prev);
+            for (id, outcome) in outcomes {
+                debug!("Manual section '{}' in {:?}: {:?}", id, output_path, outcome);
+                merged |= outcome == SectionOutcome::Preserved;
+            }
+            content
+        } else {
+            rendered_content
+        };
+
+        // Format content
+        if let Some(fmt) = &self.formatter_manager {
+            let filename = output_path.to_str().unwrap_or("");
+            final_content = if self.keep_going {
+                match fmt.try_format_content(&final_content, filename) {
+                    Ok(formatted) => formatted,
+                    Err(e) => {
+                        // Collect the failure, but still write the unformatted content
+                        self.collect_error(fail(GenerationPhase::Format)(e));
+                        final_content
+                    }
+                }
+            } else {
+                fmt.format_content(&final_content, filename)
+            };
         }
+        let final_content = self
+            .apply_pre_write_hook(output_path, final_content)
+            .map_err(fail(GenerationPhase::Write))?;
+        let encoded = self
+            .encode_text(output_path, &final_content)
+            .map_err(fail(GenerationPhase::Write))?;
 
-        if !self.dry_run {
-            Self::ensure_dir_exists(output_path)?;
+        if !self
+            .resolve_conflict(output_path, &encoded)
+            .map_err(fail(GenerationPhase::Write))?
+        {
+            self.report_file(ReportAction::Conflict, output_path, false);
+            return Ok(());
         }
+        self.explain(|| format!("render {:?} -> {:?}", template_path, output_path));
 
-        if template_path.is_file() {
-            let filename = template_path.file_name().unwrap().to_str().unwrap();
-            let filename = filename
-                .strip_suffix(".j2")
-                .or_else(|| filename.strip_suffix(".inj"))
-                .unwrap_or(filename);
-            let rendered_filename = self.engine.render_string(filename, context)?;
-            let new_output_path = output_path.join(rendered_filename);
-            self.generate_file(template_path, &new_output_path, context)?;
+        if self.dry_run.is_enabled() {
+            self.report_dry_run("write", output_path, prev, Some(&final_content));
         } else {
-            let folder_name = template_path.file_name().unwrap().to_str().unwrap();
-            let rendered_folder_name = self.engine.render_string(folder_name, context)?;
-            let new_output_path = if root_path {
-                output_path.to_path_buf()
-            } else {
-                output_path.join(&rendered_folder_name)
-            };
-            for entry in fs::read_dir(template_path).map_err(|e| {
-                error!("Failed to read directory: {:?}", template_path);
-                e.to_string()
-            })? {
-                let entry = entry.map_err(|e| {
-                    error!("Failed to read directory entry: {:?}", template_path);
-                    e.to_string()
-                })?;
-                let path = entry.path();
-                self.generate_internal(&path, &new_output_path, context, false)?;
-            }
+            self.write_output(output_path, &encoded).map_err(|e| {
+                error!("Failed to write rendered content to file: {:?}", output_path);
+                fail(GenerationPhase::Write)(e)
+            })?;
+            info!("{:?}", output_path);
         }
+        self.report_file(ReportAction::Written, output_path, merged);
         Ok(())
     }
 
-    /// Generates a file from the specified template path to the output path.
+    /// Generates a file from the specified template path to the output path. `.inj` templates
+    /// with a `target_glob` inject into the matching files under `output_base` instead.
     fn generate_file<T: Serialize>(
         &self,
         template_path: &Path,
         output_path: &Path,
+        output_base: &Path,
         context: &T,
-    ) -> Result<(), String> {
+    ) -> Result<(), GenerationError> {
+        let fail = |phase| {
+            move |message: String| GenerationError {
+                phase,
+                path: template_path.to_path_buf(),
+                message,
+            }
+        };
+        let ext = template_path.extension().and_then(|ext| ext.to_str());
+        if ext == Some("inj") {
+            let template_str = self
+                .read_template(template_path)
+                .map_err(fail(GenerationPhase::Inject))?;
+            let (front_matter, _) = InjectionFrontMatter::split(&template_str)
+                .map_err(|e| fail(GenerationPhase::Config)(e.to_string()))?;
+            if let Some(target_glob) = front_matter.target_glob {
+                return self.inject_matching(template_path, &target_glob, output_base, context);
+            }
+        }
+
         if output_path.file_name().is_none() {
             error!("Output path must have a filename: {:?}", output_path);
-            return Err("Output path must have a filename".to_string());
+            return Err(fail(GenerationPhase::Render)(
+                "Output path must have a filename".to_string(),
+            ));
         }
 
-        let prev_rendered_string = if output_path.exists() {
-            fs::read_to_string(output_path)
+        if ext != Some("inj") && self.is_up_to_date(template_path, output_path) {
+            debug!("Up to date: {:?}", output_path);
+            self.explain(|| {
+                format!("skip {:?}: {:?} is newer than its inputs", template_path, output_path)
+            });
+            self.report_file(ReportAction::Skipped, output_path, false);
+            return self.record_generated(output_path).map_err(fail(GenerationPhase::Write));
+        }
+
+        // `.inj` templates inject into the existing file even if the result goes elsewhere
+        let prev_rendered_string = if (ext == Some("inj") || !self.redirects_output()) && output_path.exists() {
+            self.read_existing(output_path)
                 .map_err(|e| {
                     error!("Failed to read output file: {:?}", output_path);
                     e.to_string()
@@ -127,91 +1986,188 @@ impl FileGenerator {
         } else {
             None
         };
+        if ext == Some("inj") && prev_rendered_string.is_none() && self.redirects_output() {
+            // Packaging the template source instead would put it in the archive as it is
+            warn!("Skipping {:?}: there is no {:?} to inject into", template_path, output_path);
+            self.explain(|| format!("skip {:?}: there is no {:?} to inject into", template_path, output_path));
+            return Ok(());
+        }
 
         if let Some(parent) = output_path.parent() {
-            if !self.dry_run {
-                Self::ensure_dir_exists(parent)?;
-            }
+            self.prepare_dir(parent).map_err(fail(GenerationPhase::Write))?;
         }
 
-        if let Some(ext) = template_path.extension() {
-            if ext == "j2" {
-                let rendered_content = self.engine.render_file(template_path, context)?;
-                
-                // Validate manual sections
-                self.manual_section_manager.validate_sections(
-                    template_path.to_str().unwrap_or("template"), 
-                    &rendered_content, 
-                    prev_rendered_string.as_deref()
-                )?;
-
-                let mut final_content = if let Some(prev) = prev_rendered_string.as_deref() {
-                    self.manual_section_manager.preserve_sections(&rendered_content, prev)
-                } else {
-                    rendered_content
-                };
-                
-                // Format content
-                if let Some(fmt) = &self.formatter_manager {
-                    final_content = fmt.format_content(&final_content, output_path.to_str().unwrap_or(""));
-                }
-
-                if self.dry_run {
-                    info!("[DRY RUN] Would write: {:?}", output_path);
-                } else {
-                    fs::write(output_path, final_content).map_err(|e| {
-                        error!(
-                            "Failed to write rendered content to file: {:?}",
-                            output_path
-                        );
-                        e.to_string()
-                    })?;
-                    info!("{:?}", output_path);
-                }
-            } else if ext == "inj" && prev_rendered_string.is_some() {
-                let injected_content =
-                    self.inject_string(template_path, prev_rendered_string.as_deref(), context)?;
-                
-                if self.dry_run {
-                    info!("[DRY RUN] Would inject: {:?}", output_path);
-                } else {
-                    fs::write(output_path, injected_content).map_err(|e| {
-                        error!(
-                            "Failed to write injected content to file: {:?}",
-                            output_path
-                        );
-                        e.to_string()
-                    })?;
-                    info!("{:?}", output_path);
-                }
-            } else {
-                if self.dry_run {
-                    info!("[DRY RUN] Would copy: {:?}", output_path);
-                } else {
-                    fs::copy(template_path, output_path).map_err(|e| {
-                        error!(
-                            "Failed to copy file from {:?} to {:?}",
-                            template_path, output_path
-                        );
-                        e.to_string()
-                    })?;
-                    info!("{:?}", output_path);
-                }
-            }
+        if ext == Some("j2")
+            && self.stream_template(
+                template_path,
+                output_path,
+                context,
+                prev_rendered_string.is_some(),
+            )
+            .map_err(fail(GenerationPhase::Render))?
+        {
+            self.explain(|| format!("render {:?} -> {:?} (streamed)", template_path, output_path));
+            self.report_file(ReportAction::Written, output_path, false);
+            info!("{:?}", output_path);
+        } else if ext == Some("j2") {
+            let rendered_content = self
+                .render_template(template_path, context)
+                .and_then(|content| self.add_header(template_path, output_path, content, context))
+                .map_err(fail(GenerationPhase::Render))?;
+            self.write_rendered(
+                template_path,
+                output_path,
+                rendered_content,
+                prev_rendered_string.as_deref(),
+            )?;
+        } else if let (Some("inj"), Some(prev)) = (ext, prev_rendered_string.as_deref()) {
+            self.inject_file(template_path, output_path, prev, context)?;
         } else {
-            if self.dry_run {
-                info!("[DRY RUN] Would copy: {:?}", output_path);
+            // Read once, so that the pre-write hook runs once per copied file
+            let contents = self.copy_content(template_path, output_path).map_err(|e| {
+                error!("Failed to read file to copy {:?}", template_path);
+                fail(GenerationPhase::Write)(e)
+            })?;
+            if !self
+                .resolve_conflict(output_path, &contents)
+                .map_err(fail(GenerationPhase::Write))?
+            {
+                self.report_file(ReportAction::Conflict, output_path, false);
+                return self.record_generated(output_path).map_err(fail(GenerationPhase::Write));
+            }
+            self.explain(|| format!("copy {:?} -> {:?}", template_path, output_path));
+            if self.dry_run.is_enabled() {
+                self.report_dry_run(
+                    "copy",
+                    output_path,
+                    prev_rendered_string.as_deref(),
+                    std::str::from_utf8(&contents).ok(),
+                );
             } else {
-                fs::copy(template_path, output_path).map_err(|e| {
+                self.copy_output(template_path, output_path, &contents).map_err(|e| {
                     error!(
                         "Failed to copy file from {:?} to {:?}",
                         template_path, output_path
                     );
-                    e.to_string()
+                    fail(GenerationPhase::Write)(e)
                 })?;
                 info!("{:?}", output_path);
             }
+            self.report_file(ReportAction::Copied, output_path, false);
+        }
+        self.record_generated(output_path)
+            .map_err(fail(GenerationPhase::Write))
+    }
+
+    /// Injects an `.inj` template into the existing content of an output file and writes it.
+    fn inject_file<T: Serialize>(
+        &self,
+        template_path: &Path,
+        output_path: &Path,
+        prev_rendered_string: &str,
+        context: &T,
+    ) -> Result<(), GenerationError> {
+        let fail = |phase| {
+            move |message: String| GenerationError {
+                phase,
+                path: template_path.to_path_buf(),
+                message,
+            }
+        };
+        let injected_content = self
+            .inject_string(template_path, Some(prev_rendered_string), context)
+            .map_err(fail(GenerationPhase::Inject))?;
+        let injected_content = self
+            .apply_pre_write_hook(output_path, injected_content)
+            .map_err(fail(GenerationPhase::Write))?;
+        let encoded = self
+            .encode_text(output_path, &injected_content)
+            .map_err(fail(GenerationPhase::Write))?;
+        self.explain(|| format!("inject {:?} -> {:?}", template_path, output_path));
+
+        if self.dry_run.is_enabled() {
+            self.report_dry_run(
+                "inject",
+                output_path,
+                Some(prev_rendered_string),
+                Some(&injected_content),
+            );
+        } else {
+            self.write_output(output_path, &encoded).map_err(|e| {
+                error!(
+                    "Failed to write injected content to file: {:?}",
+                    output_path
+                );
+                fail(GenerationPhase::Write)(e)
+            })?;
+            info!("{:?}", output_path);
+        }
+        self.report_file(ReportAction::Injected, output_path, false);
+        Ok(())
+    }
+
+    /// Injects an `.inj` template into every existing file under `output_base` whose path
+    /// relative to it matches `target_glob`.
+    fn inject_matching<T: Serialize>(
+        &self,
+        template_path: &Path,
+        target_glob: &str,
+        output_base: &Path,
+        context: &T,
+    ) -> Result<(), GenerationError> {
+        let fail = |phase| {
+            move |message: String| GenerationError {
+                phase,
+                path: template_path.to_path_buf(),
+                message,
+            }
+        };
+        let pattern = glob_matcher(target_glob)
+            .map_err(|e| format!("Invalid target_glob '{}': {}", target_glob, e))
+            .map_err(fail(GenerationPhase::Config))?;
+        let mut targets = Vec::new();
+        collect_files(output_base, &mut targets).map_err(fail(GenerationPhase::Inject))?;
+        targets.retain(|path| {
+            path.strip_prefix(output_base)
+                .is_ok_and(|relative| pattern.is_match(relative.to_string_lossy().replace('\\', "/")))
+        });
+        targets.sort();
+        if targets.is_empty() {
+            warn!("No files under {:?} match target_glob '{}' of {:?}", output_base, target_glob, template_path);
+        }
+        for target in targets {
+            let prev = self
+                .read_existing(&target)
+                .map_err(fail(GenerationPhase::Inject))?;
+            self.inject_file(template_path, &target, &prev, context)?;
+            self.record_generated(&target).map_err(fail(GenerationPhase::Write))?;
+        }
+        Ok(())
+    }
+
+    /// Returns true in incremental mode if the output file is newer than the template and the
+    /// other inputs. Files without a modification time are never up to date.
+    fn is_up_to_date(&self, template_path: &Path, output_path: &Path) -> bool {
+        let Some(inputs_modified) = self.incremental else {
+            return false;
+        };
+        if self.redirects_output() {
+            return false;
+        }
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        match (modified(output_path), modified(template_path)) {
+            (Some(output), Some(template)) => output > template && output > inputs_modified,
+            _ => false,
         }
+    }
+
+    /// Records an output path in `generated_files`. Files skipped because of a conflict are
+    /// recorded too, so stale-file cleaning keeps them.
+    fn record_generated(&self, output_path: &Path) -> Result<(), String> {
+        self.generated_files
+            .lock()
+            .unwrap()
+            .push(output_path.to_path_buf());
         Ok(())
     }
 
@@ -222,30 +2178,23 @@ impl FileGenerator {
         prev_rendered_string: Option<&str>,
         context: &T,
     ) -> Result<String, String> {
-        let template_str = fs::read_to_string(template_path).map_err(|e| {
+        let template_str = self.read_template(template_path).inspect_err(|_| {
             error!("Failed to read template file: {:?}", template_path);
-            e.to_string()
         })?;
-        let rendered_string = self.engine.render_string(&template_str, context)?;
+        let (_, template_str) =
+            InjectionFrontMatter::split(&template_str).map_err(|e| e.to_string())?;
+        let rendered_string = self.engine.render_body(template_str, context)?;
         let re_pattern = Regex::new(INJECTION_PATTERN).unwrap();
         let mut modifications = Vec::new();
 
         for cap in re_pattern.captures_iter(&rendered_string) {
             let name = cap.name("name").unwrap().as_str();
             let section_body = &rendered_string[cap.get(0).unwrap().end()..];
-            let pattern_text = section_body
-                .split(INJECTION_STRING_START)
-                .next()
-                .unwrap()
-                .trim();
-            let re_injection = Regex::new(pattern_text)
-                .map_err(|e| format!("Invalid regex pattern '{}': {}", pattern_text, e))?;
-            if !pattern_text.contains("(?P<injection>") {
-                return Err(format!(
-                    "Invalid regex pattern '{}': no 'injection' named capture group",
-                    pattern_text
-                ));
-            }
+            let pattern_text = strip_injection_comments(
+                section_body.split(INJECTION_STRING_START).next().unwrap(),
+            );
+            let pattern_text = pattern_text.trim();
+            let re_injection = injection_regex(pattern_text)?;
             let injection_string = section_body
                 .split(INJECTION_STRING_START)
                 .nth(1)
@@ -271,6 +2220,33 @@ impl FileGenerator {
             }
         }
 
+        let re_region = Regex::new(INJECTION_REGION).unwrap();
+        for cap in re_region.captures_iter(&rendered_string) {
+            let name = cap.name("name").unwrap().as_str();
+            let section_body = &rendered_string[cap.get(0).unwrap().end()..];
+            let injection_string = section_body
+                .split(INJECTION_STRING_START)
+                .nth(1)
+                .and_then(|rest| rest.split(INJECTION_STRING_END).next())
+                .ok_or_else(|| format!("Injection region '{}' has no injection string", name))?;
+            let start_marker = format!("<!-- injected:{} -->", name);
+            let end_marker = format!("<!-- /injected:{} -->", name);
+            let region = prev_rendered_string.and_then(|prev| {
+                let start = prev.find(&start_marker)? + start_marker.len();
+                let end = start + prev[start..].find(&end_marker)?;
+                Some((start, end))
+            });
+            match region {
+                // The whole region is replaced, so injecting again updates it instead of
+                // adding another copy
+                Some((start, end)) => modifications.push((start, end, injection_string.to_string())),
+                None => warn!(
+                    "Failed to inject '{}': no {} ... {} region",
+                    name, start_marker, end_marker
+                ),
+            }
+        }
+
         if let Some(prev_rendered_string) = prev_rendered_string {
             modifications.sort_by_key(|x| x.0);
             let mut modified_buffer = String::new();
@@ -286,3 +2262,1120 @@ impl FileGenerator {
         Ok(rendered_string)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualSectionConfig, ManualSectionManager, TemplateEngine};
+    use std::collections::HashMap;
+
+    fn generator(mode: DryRunMode) -> FileGenerator {
+        FileGenerator::new(
+            TemplateEngine::new(),
+            ManualSectionManager::new(ManualSectionConfig::default()),
+            mode,
+        )
+    }
+
+    fn generate_with_mode(mode: DryRunMode) -> (tempfile::TempDir, FileGenerator) {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("hello.txt.j2"), "Hello, {{ name }}!\n").unwrap();
+        fs::create_dir_all(tmp.path().join("out")).unwrap();
+        fs::write(tmp.path().join("out/hello.txt"), "Hello, old!\n").unwrap();
+
+        let context = HashMap::from([("name", "World")]);
+        let generator = generator(mode);
+        generator
+            .generate(&templates, &tmp.path().join("out"), &context)
+            .unwrap();
+        (tmp, generator)
+    }
+
+    #[test]
+    fn test_streamed_output_matches_rendered() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        let template = "{% for i in range(count) %}line {{ i }}: {{ name }}\n{% endfor %}";
+        fs::write(templates.join("big.txt.j2"), template).unwrap();
+
+        let generator = generator(DryRunMode::Off);
+        let context = serde_json::json!({ "count": 10000, "name": "World" });
+        let out = tmp.path().join("out");
+        generator.generate(&templates, &out, &context).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join("big.txt")).unwrap(),
+            generator.engine().render_string(template, &context).unwrap()
+        );
+
+        // A failed streamed render leaves no partial file
+        fs::write(templates.join("broken.txt.j2"), "partial {{ missing }}").unwrap();
+        assert!(generator.generate(&templates, &out, &context).is_err());
+        assert!(!out.join("broken.txt").exists());
+    }
+
+    /// Generates `hand.txt` over an existing hand-written file with the given conflict policy.
+    fn generate_over_hand_written(policy: ConflictPolicy) -> (tempfile::TempDir, Result<(), String>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("hand.txt.j2"), "generated").unwrap();
+        fs::write(templates.join("owned.txt.j2"), "generated\n// MANUAL SECTION START: a\n// MANUAL SECTION END").unwrap();
+        let out = tmp.path().join("out");
+        fs::create_dir_all(&out).unwrap();
+        fs::write(out.join("hand.txt"), "hand-written").unwrap();
+        fs::write(out.join("owned.txt"), "old\n// MANUAL SECTION START: a\n// MANUAL SECTION END").unwrap();
+
+        let result = generator(DryRunMode::Off)
+            .with_conflict_policy(policy)
+            .generate(&templates, &out, &HashMap::<String, String>::new());
+        (tmp, result)
+    }
+
+    #[test]
+    fn test_conflict_policy_previous_outputs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("out.txt.j2"), "new").unwrap();
+        let out = tmp.path().join("out");
+        fs::create_dir_all(&out).unwrap();
+        fs::write(out.join("out.txt"), "old").unwrap();
+
+        generator(DryRunMode::Off)
+            .with_conflict_policy(ConflictPolicy::Error)
+            .with_previous_outputs([out.join("out.txt")])
+            .generate(&templates, &out, &HashMap::<String, String>::new())
+            .unwrap();
+        assert_eq!(fs::read_to_string(out.join("out.txt")).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_conflict_policy_skip() {
+        let (tmp, result) = generate_over_hand_written(ConflictPolicy::Skip);
+        result.unwrap();
+        let out = tmp.path().join("out");
+        assert_eq!(fs::read_to_string(out.join("hand.txt")).unwrap(), "hand-written");
+        // Files with manual sections are considered generated and are updated
+        assert!(fs::read_to_string(out.join("owned.txt")).unwrap().starts_with("generated"));
+    }
+
+    #[test]
+    fn test_conflict_policy_error_and_backup() {
+        let (tmp, result) = generate_over_hand_written(ConflictPolicy::Error);
+        assert!(result.unwrap_err().contains("hand.txt"));
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("out/hand.txt")).unwrap(),
+            "hand-written"
+        );
+
+        let (tmp, result) = generate_over_hand_written(ConflictPolicy::Backup);
+        result.unwrap();
+        let out = tmp.path().join("out");
+        assert_eq!(fs::read_to_string(out.join("hand.txt")).unwrap(), "generated");
+        assert_eq!(fs::read_to_string(out.join("hand.txt.bak")).unwrap(), "hand-written");
+        assert!(!out.join("owned.txt.bak").exists());
+    }
+
+    #[test]
+    fn test_pre_write_hook() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("hello.txt.j2"), "Hello, {{ name }}!").unwrap();
+        fs::write(templates.join("static.txt"), "static").unwrap();
+        let out = tmp.path().join("out");
+        let context = HashMap::from([("name", "World")]);
+
+        generator(DryRunMode::Off)
+            .with_pre_write_hook(|_, content| Ok(format!("// License: MIT\n{}", content)))
+            .generate(&templates, &out, &context)
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join("hello.txt")).unwrap(),
+            "// License: MIT\nHello, World!"
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("static.txt")).unwrap(),
+            "// License: MIT\nstatic"
+        );
+
+        let result = generator(DryRunMode::Off)
+            .with_pre_write_hook(|path, _| Err(format!("lint failed for {:?}", path.file_name().unwrap())))
+            .generate(&templates, &out, &context);
+        assert!(result.unwrap_err().contains("lint failed"));
+
+        // A copied file over an existing one is read through the hook once
+        fs::write(out.join("static.txt"), "edited").unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let hook_calls = calls.clone();
+        generator(DryRunMode::Off)
+            .with_conflict_policy(ConflictPolicy::Backup)
+            .with_pre_write_hook(move |path, content| {
+                hook_calls.lock().unwrap().push(path.file_name().unwrap().to_owned());
+                Ok(content.to_string())
+            })
+            .generate(&templates, &out, &context)
+            .unwrap();
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.iter().filter(|name| *name == "static.txt").count(), 1);
+        assert_eq!(fs::read_to_string(out.join("static.txt")).unwrap(), "static");
+    }
+
+    #[test]
+    fn test_explain() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("skipped")).unwrap();
+        fs::write(templates.join("skipped").join(DIRECTORY_MANIFEST), "when: \"false\"").unwrap();
+        fs::write(templates.join("skipped/a.txt.j2"), "a").unwrap();
+        fs::write(templates.join("hello.txt.j2"), "hello").unwrap();
+
+        let generator = generator(DryRunMode::List).with_explain(true);
+        generator
+            .generate(&templates, &tmp.path().join("out"), &HashMap::<String, String>::new())
+            .unwrap();
+        let explanations = generator.explanations();
+        assert_eq!(explanations.len(), 2);
+        assert!(explanations.iter().any(|e| e.starts_with("render") && e.contains("hello.txt")));
+        assert!(explanations.iter().any(|e| e.starts_with("skip") && e.contains("`when`")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("run.sh.j2"), "#!/bin/sh\necho {{ name }}\n").unwrap();
+        fs::write(templates.join("app.conf.j2"), "name={{ name }}\n").unwrap();
+        fs::write(templates.join("static.sh"), "#!/bin/sh\n").unwrap();
+        let rule = |pattern: &str, mode: Option<&str>| PermissionRule {
+            pattern: pattern.to_string(),
+            mode: mode.map(str::to_string),
+            owner: None,
+        };
+        let out = tmp.path().join("out");
+        let permissions = generator(DryRunMode::Off).with_permissions(vec![
+            rule("app.conf", None),
+            rule("*.sh", Some("0755")),
+            rule("*", Some("600")),
+        ]);
+        permissions
+            .generate(&templates, &out, &HashMap::from([("name", "demo")]))
+            .unwrap();
+
+        let mode = |name: &str| fs::metadata(out.join(name)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode("run.sh"), 0o755);
+        assert_eq!(mode("static.sh"), 0o755);
+        assert_eq!(mode("app.conf"), 0o600);
+
+        assert!(rule("*", Some("0999")).file_mode().is_err());
+        let owner = PermissionRule {
+            owner: Some("1000:100".to_string()),
+            ..rule("*", None)
+        };
+        assert_eq!(owner.file_owner().unwrap(), Some((1000, Some(100))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let shared = tmp.path().join("shared");
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(shared.join("common.txt.j2"), "name={{ name }}").unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("app.txt.j2"), "app").unwrap();
+        symlink(&shared, templates.join("shared")).unwrap();
+        let context = HashMap::from([("name", "demo")]);
+
+        let out = tmp.path().join("skipped");
+        generator(DryRunMode::Off).generate(&templates, &out, &context).unwrap();
+        assert!(out.join("app.txt").exists());
+        assert!(!out.join("shared").exists());
+
+        let out = tmp.path().join("followed");
+        let follow = generator(DryRunMode::Off).with_follow_symlinks(true);
+        follow.generate(&templates, &out, &context).unwrap();
+        assert_eq!(fs::read_to_string(out.join("shared/common.txt")).unwrap(), "name=demo");
+
+        // Checks walk the same directories as generation
+        fs::write(shared.join("broken.txt.j2"), "{% if %}").unwrap();
+        assert!(generator(DryRunMode::Off).check_templates(&templates).unwrap().is_empty());
+        assert_eq!(follow.check_templates(&templates).unwrap().len(), 1);
+        fs::remove_file(shared.join("broken.txt.j2")).unwrap();
+
+        symlink(&templates, shared.join("back")).unwrap();
+        let errors = follow.check_templates(&templates).unwrap();
+        assert!(errors.iter().any(|e| e.contains("Symlink cycle")), "{:?}", errors);
+        let err = follow.referenced_variables(&templates).unwrap_err();
+        assert!(err.contains("Symlink cycle"), "{}", err);
+        let errors = follow.validate_templates(&templates, &BTreeSet::from(["name".to_string()]));
+        assert!(errors.unwrap().iter().any(|e| e.contains("Symlink cycle")));
+        let out = tmp.path().join("cycle");
+        let err = generator(DryRunMode::Off)
+            .with_follow_symlinks(true)
+            .generate(&templates, &out, &context)
+            .unwrap_err();
+        assert!(err.contains("Symlink cycle"), "{}", err);
+        assert!(err.contains("shared/back"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_keeps_source_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("run.sh"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(templates.join("run.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+        let out = tmp.path().join("out");
+        let context = HashMap::<String, String>::new();
+        generator(DryRunMode::Off).generate(&templates, &out, &context).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode(&out.join("run.sh")), 0o750);
+
+        // A pre-write hook changes the content, but not the mode
+        generator(DryRunMode::Off)
+            .with_pre_write_hook(|_, content| Ok(content.replace("sh", "bash")))
+            .generate(&templates, &out, &context)
+            .unwrap();
+        assert_eq!(fs::read_to_string(out.join("run.sh")).unwrap(), "#!/bin/bash\n");
+        assert_eq!(mode(&out.join("run.sh")), 0o750);
+    }
+
+    fn encoding_rule(pattern: &str, encoding: &str) -> EncodingRule {
+        EncodingRule {
+            pattern: pattern.to_string(),
+            encoding: encoding.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_latin1_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(
+            templates.join("legacy.txt.j2"),
+            "Café {{ name }}\n// MANUAL SECTION START: notes\n// MANUAL SECTION END",
+        )
+        .unwrap();
+        let out = tmp.path().join("out");
+        let generator = generator(DryRunMode::Off)
+            .with_encodings(vec![encoding_rule("*.txt", "latin1")]);
+        let context = HashMap::from([("name", "Zoë")]);
+        generator.generate(&templates, &out, &context).unwrap();
+
+        let bytes = fs::read(out.join("legacy.txt")).unwrap();
+        assert!(bytes.starts_with(b"Caf\xe9 Zo\xeb\n"));
+
+        // Hand-written Latin-1 content in the manual section survives regeneration
+        let mut with_notes = bytes.clone();
+        let marker = b"notes\n";
+        let pos = with_notes
+            .windows(marker.len())
+            .position(|w| w == marker)
+            .unwrap()
+            + marker.len();
+        with_notes.splice(pos..pos, b"na\xefve\n".iter().copied());
+        fs::write(out.join("legacy.txt"), &with_notes).unwrap();
+        generator.generate(&templates, &out, &context).unwrap();
+        assert_eq!(fs::read(out.join("legacy.txt")).unwrap(), with_notes);
+    }
+
+    #[test]
+    fn test_encoding_errors_and_utf16() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("wide.txt.j2"), "hi").unwrap();
+        let out = tmp.path().join("out");
+
+        generator(DryRunMode::Off)
+            .with_encodings(vec![encoding_rule("wide.txt", "utf-16le")])
+            .generate(&templates, &out, &HashMap::<String, String>::new())
+            .unwrap();
+        assert_eq!(fs::read(out.join("wide.txt")).unwrap(), b"\xff\xfeh\0i\0");
+
+        fs::write(templates.join("wide.txt.j2"), "\u{4e2d}").unwrap();
+        let result = generator(DryRunMode::Off)
+            .with_encodings(vec![encoding_rule("*.txt", "latin1")])
+            .generate(&templates, &out, &HashMap::<String, String>::new());
+        assert!(result.unwrap_err().contains("cannot be represented"));
+
+        let result = generator(DryRunMode::Off)
+            .with_encodings(vec![encoding_rule("*.txt", "klingon")])
+            .generate(&templates, &out, &HashMap::<String, String>::new());
+        assert!(result.unwrap_err().contains("Unknown encoding"));
+    }
+
+    #[test]
+    fn test_dry_run_reports_new_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("{{ name }}/nested")).unwrap();
+        fs::write(templates.join("{{ name }}/nested/file.txt.j2"), "x").unwrap();
+        fs::write(templates.join("top.txt.j2"), "x").unwrap();
+        let out = tmp.path().join("out");
+        fs::create_dir_all(&out).unwrap();
+
+        let generator = generator(DryRunMode::List);
+        generator
+            .generate(&templates, &out, &HashMap::from([("name", "pkg")]))
+            .unwrap();
+        assert_eq!(generator.new_dirs(), vec![out.join("pkg"), out.join("pkg/nested")]);
+        assert!(!out.join("pkg").exists());
+    }
+
+    #[test]
+    fn test_generate_with_report() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(
+            templates.join("a.txt.j2"),
+            "{{ name }}\n// MANUAL SECTION START: notes\n// MANUAL SECTION END\n",
+        )
+        .unwrap();
+        fs::write(templates.join("b.txt.j2"), "{{ name }}").unwrap();
+        fs::write(templates.join("logo.svg"), "<svg/>").unwrap();
+        fs::write(
+            templates.join("mod.rs.inj"),
+            "<!-- injection-pattern: mods -->\n(?P<injection>)// end\n\
+             <!-- injection-string-start -->\npub mod {{ name }};\n<!-- injection-string-end -->\n",
+        )
+        .unwrap();
+        let out = tmp.path().join("out");
+        fs::create_dir_all(&out).unwrap();
+        let existing_a = "old\n// MANUAL SECTION START: notes\nkept\n// MANUAL SECTION END\n";
+        fs::write(out.join("a.txt"), existing_a).unwrap();
+        fs::write(out.join("b.txt"), "hand-written").unwrap();
+        fs::write(out.join("mod.rs"), "// end\n").unwrap();
+        let file = |name: &str, manual_sections_merged| ReportedFile {
+            path: out.join(name),
+            manual_sections_merged,
+        };
+        let expected = GenerationReport {
+            written: vec![file("a.txt", true)],
+            copied: vec![file("logo.svg", false)],
+            injected: vec![file("mod.rs", false)],
+            skipped: vec![],
+            conflicts: vec![file("b.txt", false)],
+        };
+        let context = HashMap::from([("name", "api")]);
+
+        // Dry-run reports the would-be actions without touching the files
+        let dry_run = generator(DryRunMode::List).with_conflict_policy(ConflictPolicy::Skip);
+        let report = dry_run.generate_with_report(&templates, &out, &context).unwrap();
+        assert_eq!(report, expected);
+        assert_eq!(fs::read_to_string(out.join("a.txt")).unwrap(), existing_a);
+        assert!(!out.join("logo.svg").exists());
+
+        let real = generator(DryRunMode::Off).with_conflict_policy(ConflictPolicy::Skip);
+        let report = real.generate_with_report(&templates, &out, &context).unwrap();
+        assert_eq!(report, expected);
+        assert!(fs::read_to_string(out.join("a.txt")).unwrap().contains("kept"));
+        assert_eq!(fs::read_to_string(out.join("b.txt")).unwrap(), "hand-written");
+        // A second call reports only its own files, while `report` has all
+        let report = real.generate_with_report(&templates.join("b.txt.j2"), &out, &context).unwrap();
+        assert_eq!(report.conflicts, vec![file("b.txt", false)]);
+        assert_eq!(real.report().conflicts.len(), 2);
+    }
+
+    #[test]
+    fn test_memory_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("sub")).unwrap();
+        fs::write(templates.join("sub/{{ name }}.txt.j2"), "Hello {{ name }}").unwrap();
+        fs::write(templates.join("static.txt"), "static").unwrap();
+        let out = tmp.path().join("out");
+
+        let generator = generator(DryRunMode::Off).with_memory_output();
+        generator
+            .generate(&templates, &out, &HashMap::from([("name", "World")]))
+            .unwrap();
+        let files = generator.memory_output();
+        assert_eq!(files[&out.join("sub/World.txt")], b"Hello World");
+        assert_eq!(files[&out.join("static.txt")], b"static");
+        assert_eq!(files.len(), 2);
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn test_flat_output_mode() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("a/b")).unwrap();
+        fs::write(templates.join("top.txt.j2"), "top").unwrap();
+        fs::write(templates.join("a/b/{{ name }}.txt.j2"), "nested").unwrap();
+        let out = tmp.path().join("out");
+        let context = HashMap::from([("name", "deep")]);
+
+        generator(DryRunMode::Off)
+            .with_output_mode(OutputMode::Flat)
+            .generate(&templates, &out, &context)
+            .unwrap();
+        assert_eq!(fs::read_to_string(out.join("top.txt")).unwrap(), "top");
+        assert_eq!(fs::read_to_string(out.join("deep.txt")).unwrap(), "nested");
+        assert!(!out.join("a").exists());
+
+        fs::write(templates.join("a/top.txt"), "other").unwrap();
+        let err = generator(DryRunMode::Off)
+            .with_output_mode(OutputMode::Flat)
+            .generate(&templates, &out, &context)
+            .unwrap_err();
+        assert!(err.contains("flat output mode"), "{}", err);
+    }
+
+    #[test]
+    fn test_header_comment() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(
+            templates.join("main.rs.j2"),
+            "fn main() {}\n// MANUAL SECTION START: extra\n// MANUAL SECTION END\n",
+        )
+        .unwrap();
+        fs::write(templates.join("run.sh.j2"), "#!/bin/sh\necho {{ name }}\n").unwrap();
+        fs::write(templates.join("query.sql.j2"), "SELECT 1;\n").unwrap();
+        fs::write(templates.join("data.json.j2"), "{}\n").unwrap();
+        fs::write(templates.join(".gitignore.j2"), "target/\n").unwrap();
+        fs::write(templates.join("copied.rs"), "// copied\n").unwrap();
+        let out = tmp.path().join("out");
+        let context = HashMap::from([("name", "app")]);
+        let headed = || {
+            generator(DryRunMode::Off).with_header_comment(HeaderCommentConfig {
+                template: "AUTO-GENERATED for {{ name }} from {{ template_name }}".to_string(),
+                styles: HashMap::from([("sql".to_string(), "/* {} */".to_string())]),
+            })
+        };
+
+        headed().generate(&templates, &out, &context).unwrap();
+        let main_rs = "// AUTO-GENERATED for app from main.rs.j2\nfn main() {}\n";
+        assert!(fs::read_to_string(out.join("main.rs")).unwrap().starts_with(main_rs));
+        fs::write(
+            out.join("main.rs"),
+            fs::read_to_string(out.join("main.rs"))
+                .unwrap()
+                .replace("START: extra\n", "START: extra\nkept\n"),
+        )
+        .unwrap();
+
+        headed().generate(&templates, &out, &context).unwrap();
+        let main_rs = fs::read_to_string(out.join("main.rs")).unwrap();
+        assert_eq!(main_rs.matches("AUTO-GENERATED").count(), 1, "{}", main_rs);
+        assert!(main_rs.contains("kept\n"));
+        assert_eq!(
+            fs::read_to_string(out.join("run.sh")).unwrap(),
+            "#!/bin/sh\n# AUTO-GENERATED for app from run.sh.j2\necho app"
+        );
+        assert!(fs::read_to_string(out.join("query.sql")).unwrap().starts_with("/* AUTO-GENERATED"));
+        assert_eq!(fs::read_to_string(out.join("data.json")).unwrap(), "{}");
+        assert_eq!(fs::read_to_string(out.join("copied.rs")).unwrap(), "// copied\n");
+        assert_eq!(
+            fs::read_to_string(out.join(".gitignore")).unwrap(),
+            "# AUTO-GENERATED for app from .gitignore.j2\ntarget/"
+        );
+    }
+
+    #[test]
+    fn test_path_case() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("UserProfiles/{{ name }}Api")).unwrap();
+        fs::create_dir_all(templates.join(".github")).unwrap();
+        fs::write(templates.join("UserProfiles/{{ name }}Api/HttpClient.rs.j2"), "client").unwrap();
+        fs::write(templates.join(".github/CODEOWNERS"), "owners").unwrap();
+        let out = tmp.path().join("out");
+        let context = HashMap::from([("name", "Billing")]);
+
+        generator(DryRunMode::Off)
+            .with_path_case(PathCase::Snake)
+            .generate(&templates, &out, &context)
+            .unwrap();
+        assert!(out.join("user_profiles/billing_api/HttpClient.rs").is_file());
+        assert!(out.join(".github/CODEOWNERS").is_file());
+        assert!(!out.join("UserProfiles").exists());
+
+        generator(DryRunMode::Off)
+            .with_path_case(PathCase::Kebab)
+            .generate(&templates, &out, &context)
+            .unwrap();
+        assert!(out.join("user-profiles/billing-api/HttpClient.rs").is_file());
+        assert_eq!(PathCase::Pascal.apply("user_profiles.v2"), "UserProfiles.V2");
+    }
+
+    #[test]
+    fn test_rename_map() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("models/nested")).unwrap();
+        fs::write(templates.join("models/{{ name }}.rs.j2"), "model").unwrap();
+        fs::write(templates.join("models/nested/deep.rs"), "deep").unwrap();
+        fs::write(templates.join("README.md"), "readme").unwrap();
+        let out = tmp.path().join("out");
+        let context = HashMap::from([("name", "user")]);
+        let rule = |pattern: &str, replacement: &str| RenameRule {
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+        };
+
+        let renaming = generator(DryRunMode::Off)
+            .with_rename_map(&[rule("^models/(.*)$", "src/entities/$1")])
+            .unwrap();
+        renaming.generate(&templates, &out, &context).unwrap();
+        assert_eq!(fs::read_to_string(out.join("src/entities/user.rs")).unwrap(), "model");
+        assert_eq!(fs::read_to_string(out.join("src/entities/nested/deep.rs")).unwrap(), "deep");
+        assert_eq!(fs::read_to_string(out.join("README.md")).unwrap(), "readme");
+        assert!(!out.join("models/user.rs").exists());
+
+        let colliding = generator(DryRunMode::Off)
+            .with_rename_map(&[rule("^models/.*$", "README.md")])
+            .unwrap();
+        let err = colliding.generate(&templates, &out, &context).unwrap_err();
+        assert!(err.contains("with rename_map"), "{}", err);
+
+        assert!(generator(DryRunMode::Off).with_rename_map(&[rule("(", "x")]).is_err());
+    }
+
+    #[test]
+    fn test_generate_single_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let template = tmp.path().join("greeting.j2");
+        fs::write(
+            &template,
+            "Hello {{ name }}\n// MANUAL SECTION START: extra\n// MANUAL SECTION END\n",
+        )
+        .unwrap();
+        let output = tmp.path().join("out/nested/hello.txt");
+        fs::create_dir_all(output.parent().unwrap()).unwrap();
+        fs::write(
+            &output,
+            "old\n// MANUAL SECTION START: extra\nkept\n// MANUAL SECTION END\n",
+        )
+        .unwrap();
+
+        let generator = generator(DryRunMode::Off);
+        generator
+            .generate_single_file(&template, &output, &HashMap::from([("name", "World")]))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "Hello World\n// MANUAL SECTION START: extra\nkept\n// MANUAL SECTION END"
+        );
+        assert!(generator
+            .generate_single_file(&tmp.path().join("missing.j2"), &output, &())
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_templates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("{{ name }}")).unwrap();
+        fs::write(templates.join("{{ name }}/a.txt.j2"), "Hello {{ name }}").unwrap();
+        fs::write(templates.join("b.txt.j2"), "{% if %}").unwrap();
+        fs::write(templates.join("{{ broken.txt"), "not a template").unwrap();
+
+        let generator = generator(DryRunMode::Off);
+        let errors = generator.check_templates(&templates).unwrap();
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.iter().any(|e| e.contains("b.txt.j2")));
+        assert!(errors.iter().any(|e| e.contains("invalid name")));
+        assert!(generator.check_templates(&tmp.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_validate_templates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("{{ name }}")).unwrap();
+        fs::write(templates.join("{{ name }}/a.txt.j2"), "{{ name }} {{ user.email }} {{ typo }}").unwrap();
+        fs::write(
+            templates.join("b.txt.j2"),
+            "// MANUAL SECTION START: one\n// MANUAL SECTION START: two\n// MANUAL SECTION END\n",
+        )
+        .unwrap();
+        fs::write(
+            templates.join("c.rs.inj"),
+            "<!-- injection-pattern: bad -->\n(unclosed\n<!-- injection-string-start -->\nx\n<!-- injection-string-end -->\n\
+             <!-- injection-pattern: dynamic -->\n{{ name }}(\n<!-- injection-string-start -->\nx\n<!-- injection-string-end -->\n",
+        )
+        .unwrap();
+
+        let known = BTreeSet::from(["name".to_string(), "user".to_string()]);
+        let errors = generator(DryRunMode::Off).validate_templates(&templates, &known).unwrap();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors[0].contains("a.txt.j2") && errors[0].ends_with("undefined variable 'typo'"));
+        assert!(errors.iter().any(|e| e.contains("b.txt.j2\":2: Nested manual section")));
+        assert!(errors.iter().any(|e| e.contains("injection 'bad': Invalid regex pattern '(unclosed'")));
+        assert!(!tmp.path().join("out").exists());
+    }
+
+    #[cfg(feature = "template-cache")]
+    #[test]
+    fn test_check_then_generate_compiles_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("{{ name }}")).unwrap();
+        fs::write(templates.join("{{ name }}/a.txt.j2"), "Hello {{ name }}").unwrap();
+        fs::write(templates.join("b.txt.j2"), "Bye {{ name }}").unwrap();
+
+        let generator = generator(DryRunMode::Off);
+        assert!(generator.check_templates(&templates).unwrap().is_empty());
+        let compiled = generator.engine().compilations();
+        generator
+            .generate(&templates, &tmp.path().join("out"), &HashMap::from([("name", "x")]))
+            .unwrap();
+        assert_eq!(generator.engine().compilations(), compiled);
+    }
+
+    #[test]
+    fn test_dry_run_mode_from_str() {
+        assert_eq!("off".parse::<DryRunMode>().unwrap(), DryRunMode::Off);
+        assert_eq!("list".parse::<DryRunMode>().unwrap(), DryRunMode::List);
+        assert_eq!("DIFF".parse::<DryRunMode>().unwrap(), DryRunMode::Diff);
+        assert!("maybe".parse::<DryRunMode>().is_err());
+    }
+
+    #[test]
+    fn test_dry_run_off_writes() {
+        let (tmp, _) = generate_with_mode(DryRunMode::Off);
+        let content = fs::read_to_string(tmp.path().join("out/hello.txt")).unwrap();
+        assert_eq!(content, "Hello, World!");
+    }
+
+    #[test]
+    fn test_dry_run_list_does_not_write() {
+        let (tmp, generator) = generate_with_mode(DryRunMode::List);
+        let content = fs::read_to_string(tmp.path().join("out/hello.txt")).unwrap();
+        assert_eq!(content, "Hello, old!\n");
+        assert!(generator.dry_run_diffs().is_empty());
+    }
+
+    #[test]
+    fn test_dry_run_diff_does_not_write() {
+        let (tmp, generator) = generate_with_mode(DryRunMode::Diff);
+        let content = fs::read_to_string(tmp.path().join("out/hello.txt")).unwrap();
+        assert_eq!(content, "Hello, old!\n");
+        let path = tmp.path().join("out/hello.txt").to_string_lossy().into_owned();
+        assert_eq!(
+            generator.dry_run_diffs(),
+            vec![format!(
+                "--- a/{path}\n+++ b/{path}\n@@ -1 +1 @@\n-Hello, old!\n+Hello, World!\n\
+                 \\ No newline at end of file\n"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_inject_with_comments() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(
+            templates.join("main.cpp.inj"),
+            "## Renames the print method to the author name.\n\
+             <!-- injection-pattern: rename -->\n\
+             ## The injection group must match only the method name.\n\
+             (?P<injection>print)\\(\n\
+             <!-- injection-string-start -->{{ name }}<!-- injection-string-end -->\n",
+        )
+        .unwrap();
+        let output = tmp.path().join("out");
+        fs::create_dir_all(&output).unwrap();
+        fs::write(output.join("main.cpp"), "obj.print();\n").unwrap();
+
+        let context = HashMap::from([("name", "show")]);
+        generator(DryRunMode::Off)
+            .generate(&templates, &output, &context)
+            .unwrap();
+        assert_eq!(fs::read_to_string(output.join("main.cpp")).unwrap(), "obj.show();\n");
+    }
+
+    #[test]
+    fn test_inject_region_is_replaced() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(
+            templates.join("routes.txt.inj"),
+            "<!-- injection-region: routes -->\n\
+             <!-- injection-string-start -->\n\
+             {% for r in routes %}{{ r }}\n{% endfor %}<!-- injection-string-end -->\n",
+        )
+        .unwrap();
+        let output = tmp.path().join("out");
+        fs::create_dir_all(&output).unwrap();
+        fs::write(
+            output.join("routes.txt"),
+            "head\n<!-- injected:routes -->\nold\n<!-- /injected:routes -->\ntail\n",
+        )
+        .unwrap();
+
+        let generator = generator(DryRunMode::Off);
+        generator
+            .generate(&templates, &output, &serde_json::json!({ "routes": ["a", "b"] }))
+            .unwrap();
+        generator
+            .generate(&templates, &output, &serde_json::json!({ "routes": ["a", "b", "c"] }))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(output.join("routes.txt")).unwrap(),
+            "head\n<!-- injected:routes -->\na\nb\nc\n<!-- /injected:routes -->\ntail\n"
+        );
+    }
+
+    #[test]
+    fn test_inject_target_glob() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("sub")).unwrap();
+        fs::write(
+            templates.join("sub/register.inj"),
+            "---\n\
+             target_glob: \"**/mod.rs\"\n\
+             ---\n\
+             <!-- injection-pattern: register -->\n\
+             (?P<injection>// modules\\n)\n\
+             <!-- injection-string-start -->// modules\npub mod {{ name }};\n<!-- injection-string-end -->\n",
+        )
+        .unwrap();
+        let output = tmp.path().join("out");
+        for dir in ["a", "b/c"] {
+            fs::create_dir_all(output.join(dir)).unwrap();
+            fs::write(output.join(dir).join("mod.rs"), "// modules\n").unwrap();
+        }
+        fs::write(output.join("a/lib.rs"), "// modules\n").unwrap();
+
+        generator(DryRunMode::Off)
+            .generate(&templates, &output, &HashMap::from([("name", "users")]))
+            .unwrap();
+        for dir in ["a", "b/c"] {
+            assert_eq!(
+                fs::read_to_string(output.join(dir).join("mod.rs")).unwrap(),
+                "// modules\npub mod users;\n"
+            );
+        }
+        assert_eq!(fs::read_to_string(output.join("a/lib.rs")).unwrap(), "// modules\n");
+        // Nothing is written at the mirrored path
+        assert!(!output.join("sub/register").exists());
+    }
+
+    #[test]
+    fn test_incremental_skips_up_to_date_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        let template = templates.join("out.txt.j2");
+        fs::write(&template, "v1").unwrap();
+        let output = tmp.path().join("out");
+        let incremental = || generator(DryRunMode::Off).with_incremental(SystemTime::UNIX_EPOCH);
+        incremental().generate(&templates, &output, &HashMap::<String, String>::new()).unwrap();
+        assert_eq!(fs::read_to_string(output.join("out.txt")).unwrap(), "v1");
+
+        // The output is newer than the changed template, so it is skipped
+        let set_modified = |path: &Path, time: SystemTime| {
+            fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+        };
+        fs::write(&template, "v2").unwrap();
+        let hour = std::time::Duration::from_secs(3600);
+        set_modified(&template, SystemTime::now() - hour);
+        let skipping = incremental();
+        skipping.generate(&templates, &output, &HashMap::<String, String>::new()).unwrap();
+        assert_eq!(fs::read_to_string(output.join("out.txt")).unwrap(), "v1");
+        assert_eq!(skipping.generated_files(), vec![output.join("out.txt")]);
+        assert!(skipping.written_files().is_empty());
+
+        // Newer data or config invalidates it
+        generator(DryRunMode::Off)
+            .with_incremental(SystemTime::now() + hour)
+            .generate(&templates, &output, &HashMap::<String, String>::new())
+            .unwrap();
+        assert_eq!(fs::read_to_string(output.join("out.txt")).unwrap(), "v2");
+    }
+
+    #[test]
+    fn test_clean_stale_item_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("{{ item }}")).unwrap();
+        fs::write(templates.join("{{ item }}/current.txt.j2"), "{{ item }}").unwrap();
+        // A directory renamed per item belongs to the item, a fixed one is shared
+        fs::create_dir_all(templates.join("docs")).unwrap();
+        fs::write(templates.join("docs").join(DIRECTORY_MANIFEST), "rename: \"{{ item }}-docs\"").unwrap();
+        fs::write(templates.join("docs/readme.md.j2"), "{{ item }}").unwrap();
+        fs::create_dir_all(templates.join("shared")).unwrap();
+        fs::write(templates.join("shared/common.txt"), "common").unwrap();
+        let output = tmp.path().join("out");
+        fs::create_dir_all(output.join("a/nested")).unwrap();
+        fs::create_dir_all(output.join("b")).unwrap();
+        fs::write(output.join("a/stale.txt"), "stale").unwrap();
+        fs::write(output.join("a/nested/stale.txt"), "stale").unwrap();
+        fs::write(
+            output.join("a/manual.txt"),
+            "MANUAL SECTION START: keep\nuser code\nMANUAL SECTION END\n",
+        )
+        .unwrap();
+        fs::write(output.join("b/other.txt"), "other item").unwrap();
+
+        let generator = generator(DryRunMode::Off);
+        generator
+            .generate(&templates, &output, &HashMap::from([("item", "b")]))
+            .unwrap();
+        let generated_before = generator.generated_count();
+        let context = HashMap::from([("item", "a")]);
+        generator.generate(&templates, &output, &context).unwrap();
+        let keep: HashSet<PathBuf> =
+            generator.generated_files_since(generated_before).into_iter().collect();
+        assert_eq!(keep.len(), 3);
+        let mut dirs = generator.item_output_dirs(&templates, &output, &context).unwrap();
+        dirs.sort();
+        assert_eq!(dirs, vec![output.join("a"), output.join("a-docs")]);
+        let removed = generator.clean_stale_files(&dirs[0], &keep).unwrap();
+
+        assert_eq!(removed.len(), 2);
+        assert!(output.join("a/current.txt").exists());
+        assert!(!output.join("a/stale.txt").exists());
+        assert!(!output.join("a/nested/stale.txt").exists());
+        assert!(output.join("a/manual.txt").exists());
+        assert!(output.join("b/other.txt").exists());
+    }
+
+    #[test]
+    fn test_template_size_and_encoding() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("big.txt.j2"), "{{ name }}".repeat(10)).unwrap();
+        let output = tmp.path().join("out");
+        let context = serde_json::json!({ "name": "x" });
+
+        let limited = generator(DryRunMode::Off).with_max_template_size(50);
+        let err = limited.generate(&templates, &output, &context).unwrap_err();
+        assert!(err.contains("big.txt.j2"), "{}", err);
+        assert!(err.contains("larger than the template size limit of 50 bytes"), "{}", err);
+        assert!(!output.join("big.txt").exists());
+
+        let sized = generator(DryRunMode::Off).with_max_template_size(100);
+        sized.generate(&templates, &output, &context).unwrap();
+        assert_eq!(fs::read_to_string(output.join("big.txt")).unwrap(), "x".repeat(10));
+
+        fs::remove_file(templates.join("big.txt.j2")).unwrap();
+        fs::write(templates.join("latin1.txt.j2"), b"caf\xe9 {{ name }}").unwrap();
+        let err = sized.generate(&templates, &output, &context).unwrap_err();
+        assert!(err.contains("latin1.txt.j2"), "{}", err);
+        assert!(err.contains("invalid byte at offset 3"), "{}", err);
+    }
+
+    #[test]
+    fn test_clean_set_outputs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let output = tmp.path().join("out");
+        fs::create_dir_all(output.join("b")).unwrap();
+        for file in ["a.txt", "b/b.txt", "manual.txt", "other.txt"] {
+            fs::write(output.join(file), "generated").unwrap();
+        }
+        fs::write(
+            output.join("manual.txt"),
+            "// MANUAL SECTION START: notes\nkept\n// MANUAL SECTION END",
+        )
+        .unwrap();
+        let keep = |files: &[&str]| GenerationReport {
+            written: files
+                .iter()
+                .map(|file| ReportedFile {
+                    path: output.join(file),
+                    manual_sections_merged: false,
+                })
+                .collect(),
+            ..GenerationReport::default()
+        };
+
+        let cleaning = generator(DryRunMode::Off);
+        let first = keep(&["a.txt", "b/b.txt", "manual.txt"]);
+        assert!(cleaning.clean_set_outputs(&output, "api", &first).unwrap().is_empty());
+        // Another set's files are left alone
+        cleaning.clean_set_outputs(&output, "docs", &keep(&["other.txt"])).unwrap();
+
+        let removed = cleaning.clean_set_outputs(&output, "api", &keep(&["a.txt"])).unwrap();
+        assert_eq!(removed, vec![output.join("b/b.txt")]);
+        assert!(output.join("manual.txt").exists());
+        assert!(output.join("other.txt").exists());
+        let manifest = fs::read_to_string(output.join(OUTPUT_MANIFEST)).unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        let recorded = |file: &str| serde_json::json!({
+            "written": [{"path": file, "manual_sections_merged": false}],
+            "copied": [],
+            "injected": [],
+            "skipped": [],
+            "conflicts": [],
+        });
+        assert_eq!(
+            manifest,
+            serde_json::json!({"api": recorded("a.txt"), "docs": recorded("other.txt")})
+        );
+
+        // Existing files a conflict policy left unchanged are never recorded as outputs
+        fs::write(output.join("hand.txt"), "hand-written").unwrap();
+        let mut conflicted = keep(&["a.txt"]);
+        conflicted.conflicts.push(ReportedFile {
+            path: output.join("hand.txt"),
+            manual_sections_merged: false,
+        });
+        cleaning.clean_set_outputs(&output, "api", &conflicted).unwrap();
+        let removed = cleaning.clean_set_outputs(&output, "api", &keep(&[])).unwrap();
+        assert_eq!(removed, vec![output.join("a.txt")]);
+        assert!(output.join("hand.txt").exists());
+
+        // Entries that could point outside the output directory fail before removing anything
+        let outside = tmp.path().join("outside.txt");
+        fs::write(&outside, "hand-written").unwrap();
+        for entry in ["../outside.txt", outside.to_str().unwrap()] {
+            let manifest = serde_json::json!({"api": recorded(entry)});
+            fs::write(output.join(OUTPUT_MANIFEST), manifest.to_string()).unwrap();
+            let err = cleaning.clean_set_outputs(&output, "api", &keep(&[])).unwrap_err();
+            assert!(err.contains("is outside"), "{}", err);
+            assert!(outside.exists());
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(tmp.path(), output.join("up")).unwrap();
+            let manifest = serde_json::json!({"api": recorded("up/outside.txt")});
+            fs::write(output.join(OUTPUT_MANIFEST), manifest.to_string()).unwrap();
+            let err = cleaning.clean_set_outputs(&output, "api", &keep(&[])).unwrap_err();
+            assert!(err.contains("links outside"), "{}", err);
+            assert!(outside.exists());
+        }
+    }
+
+    #[test]
+    fn test_directory_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("docs")).unwrap();
+        fs::create_dir_all(templates.join("src")).unwrap();
+        fs::write(templates.join("docs/.templify.yaml"), "when: features.docs\n").unwrap();
+        fs::write(templates.join("docs/index.md.j2"), "docs").unwrap();
+        fs::write(
+            templates.join("src/.templify.yaml"),
+            "when: features.code\nrename: \"{{ name }}_src\"\n",
+        )
+        .unwrap();
+        fs::write(templates.join("src/main.rs.j2"), "code").unwrap();
+        let output = tmp.path().join("out");
+
+        let context = serde_json::json!({
+            "name": "app",
+            "features": { "docs": false, "code": true },
+        });
+        generator(DryRunMode::Off)
+            .generate(&templates, &output, &context)
+            .unwrap();
+
+        assert!(!output.join("docs").exists());
+        assert!(output.join("app_src/main.rs").exists());
+        assert!(!output.join("app_src/.templify.yaml").exists());
+    }
+
+    #[test]
+    fn test_template_timings() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(templates.join("fast.txt.j2"), "{{ name }}").unwrap();
+        fs::write(
+            templates.join("slow.txt.j2"),
+            "{% for i in range(1000) %}{% for j in range(300) %}{{ j }}{% endfor %}{% endfor %}",
+        )
+        .unwrap();
+        let output = tmp.path().join("out");
+
+        let profiling = generator(DryRunMode::Off).with_profile();
+        for name in ["a", "b"] {
+            profiling
+                .generate(&templates, &output.join(name), &HashMap::from([("name", name)]))
+                .unwrap();
+        }
+        let timings = profiling.template_timings();
+        assert_eq!(timings.len(), 2);
+        assert_eq!(timings[0].template, templates.join("slow.txt.j2"));
+        assert_eq!(timings[0].count, 2);
+        assert!(timings[0].total > timings[1].total);
+        assert!(generator(DryRunMode::Off).template_timings().is_empty());
+    }
+
+    #[test]
+    fn test_referenced_variables() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(templates.join("docs")).unwrap();
+        fs::write(templates.join("docs/.templify.yaml"), "when: features.docs\n").unwrap();
+        fs::write(templates.join("docs/index.md"), "static").unwrap();
+        fs::write(
+            templates.join("{{ svc.name }}.rs.j2"),
+            "struct {{ svc.name | pascalcase }} { port: {{ svc.port }} } // {{ version }}",
+        )
+        .unwrap();
+        fs::write(templates.join("README.md"), "static").unwrap();
+
+        let variables = generator(DryRunMode::Off).referenced_variables(&templates).unwrap();
+        let as_vec = |set: &BTreeSet<String>| set.iter().cloned().collect::<Vec<_>>();
+        assert_eq!(variables.len(), 2);
+        assert_eq!(
+            as_vec(&variables[&templates.join("{{ svc.name }}.rs.j2")]),
+            ["svc.name", "svc.port", "version"]
+        );
+        assert_eq!(as_vec(&variables[&templates.join("docs")]), ["features.docs"]);
+    }
+
+    #[test]
+    fn test_file_conditions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        fs::write(
+            templates.join(".templify.yaml"),
+            "files:\n  logo.png: features.branding\n  favicon.ico: features.icons\n",
+        )
+        .unwrap();
+        fs::write(templates.join("logo.png"), [0x89, b'P', b'N', b'G', 0xff]).unwrap();
+        fs::write(templates.join("favicon.ico"), [0u8, 1, 2]).unwrap();
+        fs::write(templates.join("index.html.j2"), "{{ name }}").unwrap();
+        let output = tmp.path().join("out");
+
+        let context = serde_json::json!({
+            "name": "app",
+            "features": { "branding": false, "icons": true },
+        });
+        generator(DryRunMode::Off)
+            .generate(&templates, &output, &context)
+            .unwrap();
+
+        assert!(!output.join("logo.png").exists());
+        assert_eq!(fs::read(output.join("favicon.ico")).unwrap(), [0u8, 1, 2]);
+        assert_eq!(fs::read_to_string(output.join("index.html")).unwrap(), "app");
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let diff = unified_diff("Hello, old!\n", "Hello, World!\n", Path::new("out/hello.txt"));
+        assert!(diff.contains("--- a/out/hello.txt"));
+        assert!(diff.contains("+++ b/out/hello.txt"));
+        assert!(diff.contains("-Hello, old!"));
+        assert!(diff.contains("+Hello, World!"));
+    }
+}
@@ -0,0 +1,105 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Message catalogs by locale, used by the `trans` template function.
+///
+/// A catalog maps keys (dotted for nested objects) to a message, which is either a string or
+/// an object of plural forms (`zero`, `one`, `other`). `{count}` in a message is replaced by
+/// the count passed to `trans`. Plural forms follow English rules: `one` for a count of 1,
+/// `zero` (if present) for 0 and `other` otherwise.
+#[derive(Debug, Clone)]
+pub struct MessageCatalogs {
+    catalogs: Arc<HashMap<String, Value>>,
+    locale_var: String,
+    default_locale: Option<String>,
+}
+
+impl MessageCatalogs {
+    /// Creates catalogs that select the locale from the context variable `locale_var`.
+    pub fn new(locale_var: &str) -> Self {
+        Self {
+            catalogs: Arc::new(HashMap::new()),
+            locale_var: locale_var.to_string(),
+            default_locale: None,
+        }
+    }
+
+    /// Sets the locale used when the context doesn't define `locale_var`.
+    pub fn with_default_locale(mut self, locale: Option<String>) -> Self {
+        self.default_locale = locale;
+        self
+    }
+
+    /// Adds the catalog of a locale.
+    pub fn with_catalog(mut self, locale: &str, catalog: Value) -> Self {
+        Arc::make_mut(&mut self.catalogs).insert(locale.to_string(), catalog);
+        self
+    }
+
+    /// Returns the name of the context variable holding the locale.
+    pub fn locale_var(&self) -> &str {
+        &self.locale_var
+    }
+
+    /// Returns the default locale, if any.
+    pub fn default_locale(&self) -> Option<&str> {
+        self.default_locale.as_deref()
+    }
+
+    /// Looks up a message, selecting the plural form for `count`.
+    /// Returns None if the locale or the key is unknown.
+    pub fn lookup(&self, locale: &str, key: &str, count: Option<i64>) -> Option<String> {
+        let message = key
+            .split('.')
+            .try_fold(self.catalogs.get(locale)?, |value, part| value.get(part))
+            .or_else(|| self.catalogs.get(locale)?.get(key))?;
+        let text = match message {
+            Value::String(text) => text.as_str(),
+            Value::Object(forms) => {
+                let form = match count {
+                    Some(0) if forms.contains_key("zero") => "zero",
+                    Some(1) => "one",
+                    _ => "other",
+                };
+                forms.get(form).or_else(|| forms.get("other"))?.as_str()?
+            }
+            _ => return None,
+        };
+        Some(match count {
+            Some(count) => text.replace("{count}", &count.to_string()),
+            None => text.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn catalogs() -> MessageCatalogs {
+        MessageCatalogs::new("locale").with_catalog(
+            "en",
+            json!({
+                "greeting": "Hello",
+                "files": { "zero": "No files", "one": "One file", "other": "{count} files" },
+                "errors": { "not_found": "Not found" },
+                "selected": "{count} selected"
+            }),
+        )
+    }
+
+    #[test]
+    fn test_lookup_plural_forms() {
+        let catalogs = catalogs();
+        assert_eq!(catalogs.lookup("en", "greeting", None).unwrap(), "Hello");
+        assert_eq!(catalogs.lookup("en", "files", Some(0)).unwrap(), "No files");
+        assert_eq!(catalogs.lookup("en", "files", Some(1)).unwrap(), "One file");
+        assert_eq!(catalogs.lookup("en", "files", Some(3)).unwrap(), "3 files");
+        assert_eq!(catalogs.lookup("en", "selected", Some(2)).unwrap(), "2 selected");
+        assert_eq!(catalogs.lookup("en", "errors.not_found", None).unwrap(), "Not found");
+        assert!(catalogs.lookup("en", "missing", None).is_none());
+        assert!(catalogs.lookup("de", "greeting", None).is_none());
+    }
+}
@@ -1,3 +1,6 @@
+use crate::engine::TemplateEngine;
+use serde_json::Value;
+use std::collections::HashMap;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,6 +9,8 @@ pub enum IterationError {
     InvalidSyntax(String),
     #[error("Data path not found: {0}")]
     DataPathNotFound(String),
+    #[error("{0}")]
+    Condition(String),
 }
 
 #[derive(Debug, Clone)]
@@ -13,6 +18,34 @@ pub struct IterationInfo {
     pub var: String,
     pub expr: String,
     pub condition: Option<String>,
+    /// The condition comes from `unless`, so items are kept when it is false
+    pub unless: bool,
+}
+
+impl IterationInfo {
+    /// Returns whether an item is kept, given the context of the item: true without a
+    /// condition, otherwise the truthiness of the condition, negated for `unless`.
+    pub fn keeps_item(
+        &self,
+        engine: &TemplateEngine,
+        context: &HashMap<String, Value>,
+    ) -> Result<bool, IterationError> {
+        match self.condition {
+            Some(ref condition) => Ok(IterationEvaluator::evaluate_condition(engine, condition, context)?
+                != self.unless),
+            None => Ok(true),
+        }
+    }
+
+    /// Describes why an item the condition filtered out was skipped.
+    pub fn skip_reason(&self) -> String {
+        let condition = self.condition.as_deref().unwrap_or_default();
+        if self.unless {
+            format!("unless condition `{}` is true", condition)
+        } else {
+            format!("condition `{}` is false", condition)
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -22,18 +55,65 @@ pub enum IterationPattern {
     Array(Vec<IterationPattern>),
 }
 
+/// Items bound by the levels of a nested iteration, outermost first, by variable name.
+pub type IterationBindings = Vec<(String, Value)>;
+
+/// Position of an item among the items its iteration level kept, exposed to templates like
+/// Jinja's loop variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopPosition {
+    pub index0: usize,
+    pub length: usize,
+}
+
+impl LoopPosition {
+    /// Returns the `loop` object of the item: `index` (from 1), `index0` (from 0), `first`,
+    /// `last` and `length`.
+    pub fn to_value(self) -> Value {
+        serde_json::json!({
+            "index": self.index0 + 1,
+            "index0": self.index0,
+            "first": self.index0 == 0,
+            "last": self.index0 + 1 == self.length,
+            "length": self.length,
+        })
+    }
+}
+
+/// One item per level of a nested iteration, with its position within its level.
+#[derive(Debug, Clone)]
+pub struct IterationCombination {
+    pub bindings: IterationBindings,
+    pub positions: Vec<LoopPosition>,
+}
+
+/// Leaf combinations of a nested iteration, see `IterationEvaluator::expand_nested`.
+#[derive(Debug, Default)]
+pub struct NestedExpansion {
+    /// Every combination, in data order
+    pub combinations: Vec<IterationCombination>,
+    /// Inner levels that didn't resolve to an array; their branches were skipped
+    pub errors: Vec<IterationError>,
+}
+
 pub struct IterationEvaluator;
 
 impl IterationEvaluator {
-    /// Parses a simple iteration expression like "item in items" or "item in items if item.enabled"
+    /// Parses a simple iteration expression like "item in items", "item in items if item.enabled"
+    /// or "item in items unless item.deprecated".
     pub fn parse_simple(expr: &str) -> Result<IterationInfo, IterationError> {
-        // Check if there's an "if" condition
-        let (iter_part, condition) = if expr.contains(" if ") {
-            let parts: Vec<&str> = expr.splitn(2, " if ").collect();
-            (parts[0], Some(parts[1].trim().to_string()))
-        } else {
-            (expr, None)
+        // Check if there's an "if" or "unless" condition, whichever comes first
+        let if_pos = expr.find(" if ");
+        let unless_pos = expr.find(" unless ");
+        let (iter_part, condition, unless) = match (if_pos, unless_pos) {
+            (Some(i), Some(u)) if u < i => Self::split_condition(expr, u, " unless "),
+            (Some(i), _) => Self::split_condition(expr, i, " if "),
+            (None, Some(u)) => Self::split_condition(expr, u, " unless "),
+            (None, None) => (expr, None, false),
         };
+        if condition.as_deref() == Some("") {
+            return Err(IterationError::InvalidSyntax(expr.to_string()));
+        }
 
         // Parse "var in expr"
         let parts: Vec<&str> = iter_part.split(" in ").collect();
@@ -45,9 +125,17 @@ impl IterationEvaluator {
             var: parts[0].trim().to_string(),
             expr: parts[1].trim().to_string(),
             condition,
+            unless,
         })
     }
 
+    /// Splits off the condition following `keyword` at `pos`; returns whether it is an
+    /// `unless` condition.
+    fn split_condition<'e>(expr: &'e str, pos: usize, keyword: &str) -> (&'e str, Option<String>, bool) {
+        let condition = expr[pos + keyword.len()..].trim().to_string();
+        (&expr[..pos], Some(condition), keyword == " unless ")
+    }
+
     /// Parses a nested iteration expression like "parent in parents >> child in parent.children"
     pub fn parse_nested(expr: &str) -> Result<Vec<IterationInfo>, IterationError> {
         let parts: Vec<&str> = expr.split(">>").collect();
@@ -70,17 +158,123 @@ impl IterationEvaluator {
         }
     }
 
+    /// Evaluates the `if`/`unless` condition of an iteration against the context of one item,
+    /// which binds the iteration variable and globals. The result follows Jinja truthiness:
+    /// `false`, `none`, `0`, `""`, `[]`, `{}` and missing fields skip the item. Attributes of a
+    /// missing field are an error, as in templates.
+    pub fn evaluate_condition(
+        engine: &TemplateEngine,
+        condition: &str,
+        context: &HashMap<String, Value>,
+    ) -> Result<bool, IterationError> {
+        if condition.trim().is_empty() {
+            return Err(IterationError::InvalidSyntax("empty condition".to_string()));
+        }
+        engine
+            .evaluate_condition(condition, context)
+            .map_err(IterationError::Condition)
+    }
+
+    /// Resolves the data path of an iteration level. A path starting with a variable bound by an
+    /// outer level, like `module.components`, is resolved against that variable's item; any
+    /// other path against the data.
+    pub fn resolve<'a>(expr: &str, bindings: &'a [(String, Value)], data: &'a Value) -> Option<&'a Value> {
+        let expr = expr.trim();
+        let (root, rest) = expr.split_once('.').unwrap_or((expr, ""));
+        match bindings.iter().rev().find(|(var, _)| var == root) {
+            Some((_, item)) if rest.is_empty() => Some(item),
+            Some((_, item)) => item.pointer(&format!("/{}", rest.replace('.', "/"))),
+            None => data.pointer(&Self::evaluate_path(expr)),
+        }
+    }
+
+    /// Expands nested iteration levels, e.g. from `module in modules >> component in
+    /// module.components`, into the combinations of one item per level. For levels with a
+    /// condition, `keep` is called with the level, the item's index and the bindings up to
+    /// the item, and decides whether the item is iterated. Loop positions count the kept items.
+    ///
+    /// The outermost level must resolve to an array. An inner level that doesn't is recorded
+    /// in `errors` and only its branch is skipped.
+    pub fn expand_nested<F>(
+        levels: &[IterationInfo],
+        data: &Value,
+        mut keep: F,
+    ) -> Result<NestedExpansion, IterationError>
+    where
+        F: FnMut(&IterationInfo, usize, &[(String, Value)]) -> Result<bool, IterationError>,
+    {
+        let mut expansion = NestedExpansion::default();
+        let mut combination = IterationCombination {
+            bindings: Vec::new(),
+            positions: Vec::new(),
+        };
+        let mut trail = Vec::new();
+        Self::expand_level(levels, data, &mut combination, &mut trail, &mut keep, &mut expansion)?;
+        Ok(expansion)
+    }
+
+    /// Expands the first of `levels` within the current branch; `trail` names the items bound so
+    /// far, e.g. `module[1]`, for error messages.
+    fn expand_level<F>(
+        levels: &[IterationInfo],
+        data: &Value,
+        combination: &mut IterationCombination,
+        trail: &mut Vec<String>,
+        keep: &mut F,
+        expansion: &mut NestedExpansion,
+    ) -> Result<(), IterationError>
+    where
+        F: FnMut(&IterationInfo, usize, &[(String, Value)]) -> Result<bool, IterationError>,
+    {
+        let Some((level, inner)) = levels.split_first() else {
+            expansion.combinations.push(combination.clone());
+            return Ok(());
+        };
+        let bindings = &mut combination.bindings;
+        let items = match Self::resolve(&level.expr, bindings, data) {
+            Some(Value::Array(items)) => items.clone(),
+            _ if bindings.is_empty() => {
+                return Err(IterationError::DataPathNotFound(level.expr.clone()));
+            }
+            _ => {
+                expansion.errors.push(IterationError::DataPathNotFound(format!(
+                    "'{}' is not an array for {}",
+                    level.expr,
+                    trail.join(", ")
+                )));
+                return Ok(());
+            }
+        };
+        // Filter the whole level first, so that loop positions count the kept items
+        let mut kept = Vec::with_capacity(items.len());
+        for (index, item) in items.into_iter().enumerate() {
+            bindings.push((level.var.clone(), item));
+            let keep_item = level.condition.is_none() || keep(level, index, bindings)?;
+            let (_, item) = bindings.pop().expect("item was pushed");
+            if keep_item {
+                kept.push((index, item));
+            }
+        }
+        let length = kept.len();
+        for (index0, (index, item)) in kept.into_iter().enumerate() {
+            combination.bindings.push((level.var.clone(), item));
+            combination.positions.push(LoopPosition { index0, length });
+            trail.push(format!("{}[{}]", level.var, index));
+            Self::expand_level(inner, data, combination, trail, keep, expansion)?;
+            trail.pop();
+            combination.positions.pop();
+            combination.bindings.pop();
+        }
+        Ok(())
+    }
+
     /// Evaluates a data path expression (e.g., "dd.services" -> "/services")
     pub fn evaluate_path(expr: &str) -> String {
         // Convert dot notation to JSON pointer
         let path = expr.trim();
         
         // Remove "dd." prefix if present
-        let path = if path.starts_with("dd.") {
-            &path[3..]
-        } else {
-            path
-        };
+        let path = path.strip_prefix("dd.").unwrap_or(path);
         
         format!("/{}", path.replace('.', "/"))
     }
@@ -89,6 +283,7 @@ impl IterationEvaluator {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
 
     #[test]
     fn test_parse_simple() {
@@ -106,6 +301,27 @@ mod tests {
         assert_eq!(result.condition, Some("service.enabled".to_string()));
     }
 
+    #[test]
+    fn test_parse_simple_with_unless() {
+        let result = IterationEvaluator::parse_simple("service in services unless service.deprecated").unwrap();
+        assert_eq!(result.var, "service");
+        assert_eq!(result.expr, "services");
+        assert_eq!(result.condition, Some("service.deprecated".to_string()));
+        assert!(result.unless);
+        assert!(IterationEvaluator::parse_simple("service in services unless ").is_err());
+
+        // Undefined values are falsy, so `unless` keeps the item where `if` skips it
+        let engine = TemplateEngine::new();
+        let context = HashMap::from([("service".to_string(), json!({}))]);
+        for condition in ["service.deprecated", "deprecated"] {
+            let unless = IterationEvaluator::parse_simple(&format!("service in services unless {}", condition))
+                .unwrap();
+            assert!(unless.keeps_item(&engine, &context).unwrap(), "{}", condition);
+            let when = IterationEvaluator::parse_simple(&format!("service in services if {}", condition)).unwrap();
+            assert!(!when.keeps_item(&engine, &context).unwrap(), "{}", condition);
+        }
+    }
+
     #[test]
     fn test_parse_nested() {
         let result = IterationEvaluator::parse_nested("module in modules >> component in module.components").unwrap();
@@ -116,6 +332,81 @@ mod tests {
         assert_eq!(result[1].expr, "module.components");
     }
 
+    #[test]
+    fn test_evaluate_condition() {
+        let engine = TemplateEngine::new();
+        let check = |item: Value, condition: &str| {
+            let context = HashMap::from([
+                ("item".to_string(), item),
+                ("region".to_string(), json!("eu")),
+            ]);
+            IterationEvaluator::evaluate_condition(&engine, condition, &context)
+        };
+        assert!(check(json!({"enabled": true}), "item.enabled").unwrap());
+        assert!(check(json!({"enabled": "no"}), "item.enabled").unwrap());
+        for falsy in [json!(false), json!(0), json!(""), json!([]), json!({}), json!(null)] {
+            assert!(!check(json!({ "enabled": falsy }), "item.enabled").unwrap(), "{}", falsy);
+        }
+
+        // Missing fields are undefined, which is falsy; their attributes are an error
+        assert!(!check(json!({}), "item.enabled").unwrap());
+        assert!(check(json!({}), "not item.enabled").unwrap());
+        assert!(!check(json!({}), "item.enabled is defined").unwrap());
+        assert!(matches!(check(json!({}), "item.a.b"), Err(IterationError::Condition(_))));
+
+        assert!(check(json!({}), "region == 'eu'").unwrap());
+        assert!(matches!(check(json!({}), " "), Err(IterationError::InvalidSyntax(_))));
+    }
+
+    #[test]
+    fn test_expand_nested() {
+        let levels = IterationEvaluator::parse_nested(
+            "module in modules >> component in module.components >> port in ports",
+        )
+        .unwrap();
+        let data = json!({
+            "ports": [80, 443],
+            "modules": [
+                {"name": "core", "components": ["parser"]},
+                {"name": "broken", "components": "none"},
+                {"name": "ui", "components": ["button"]}
+            ]
+        });
+        let expansion = IterationEvaluator::expand_nested(&levels, &data, |_, _, _| Ok(true)).unwrap();
+        let leaves: Vec<String> = expansion
+            .combinations
+            .iter()
+            .map(|IterationCombination { bindings, .. }| {
+                let vars: Vec<&str> = bindings.iter().map(|(var, _)| var.as_str()).collect();
+                assert_eq!(vars, ["module", "component", "port"]);
+                format!("{}/{}:{}", bindings[0].1["name"], bindings[1].1, bindings[2].1)
+            })
+            .collect();
+        assert_eq!(
+            leaves,
+            [r#""core"/"parser":80"#, r#""core"/"parser":443"#, r#""ui"/"button":80"#, r#""ui"/"button":443"#]
+        );
+        let positions: Vec<(usize, usize)> = expansion.combinations[3]
+            .positions
+            .iter()
+            .map(|position| (position.index0, position.length))
+            .collect();
+        // The broken module still counts, as it has no condition
+        assert_eq!(positions, [(2, 3), (0, 1), (1, 2)]);
+        assert_eq!(
+            LoopPosition { index0: 0, length: 1 }.to_value(),
+            json!({"index": 1, "index0": 0, "first": true, "last": true, "length": 1})
+        );
+        assert_eq!(expansion.errors.len(), 1);
+        assert_eq!(
+            expansion.errors[0].to_string(),
+            "Data path not found: 'module.components' is not an array for module[1]"
+        );
+
+        let levels = IterationEvaluator::parse_nested("module in missing >> c in module.components").unwrap();
+        assert!(IterationEvaluator::expand_nested(&levels, &data, |_, _, _| Ok(true)).is_err());
+    }
+
     #[test]
     fn test_evaluate_path() {
         assert_eq!(IterationEvaluator::evaluate_path("dd.services"), "/services");
@@ -1,18 +1,32 @@
 // Export public modules
+pub mod archive;
 pub mod config;
+pub mod context;
+pub mod data;
 pub mod engine;
 pub mod generator;
+pub mod i18n;
 pub mod iteration;
+pub mod logging;
 pub mod manual_sections;
 pub mod filters;
 pub mod formatting;
+pub mod source;
+pub mod transform;
 
 // Re-export commonly used types
+pub use archive::ArchiveWriter;
 pub use config::{ManualSectionConfig, TemplateConfig};
+pub use context::{ContextBuilder, ContextLayer};
 pub use engine::TemplateEngine;
-pub use generator::FileGenerator;
+pub use generator::{
+    ConflictPolicy, DryRunMode, FileGenerator, GenerationError, GenerationPhase, GenerationReport,
+    OutputMode, PreWriteHook, ReportedFile, TemplateTiming,
+};
+pub use i18n::MessageCatalogs;
 pub use iteration::{IterationEvaluator, IterationPattern};
-pub use manual_sections::ManualSectionManager;
+pub use manual_sections::{ManualSectionManager, SectionIssue, SectionOutcome};
+pub use source::{FileSystemSource, MemorySource, TemplateSource};
 
 // Legacy compatibility: RenderHelper facade
 use serde::Serialize;
@@ -41,7 +55,7 @@ impl RenderHelper {
 
         let engine = TemplateEngine::new();
         let manual_section_manager = ManualSectionManager::new(ManualSectionConfig::default());
-        let generator = FileGenerator::new(engine, manual_section_manager, false);
+        let generator = FileGenerator::new(engine, manual_section_manager, DryRunMode::Off);
 
         Ok(Self { generator, context })
     }
@@ -0,0 +1,238 @@
+use log::{Log, Metadata, Record};
+use std::cell::RefCell;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Template set and file that log records of the current thread belong to. Scopes order
+/// by set index, then by file; records outside a file come first within their set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogScope {
+    /// Index of the template set in the config, and its label
+    pub set: Option<(usize, String)>,
+    /// Template file being generated
+    pub file: Option<String>,
+}
+
+thread_local! {
+    static SCOPE: RefCell<LogScope> = RefCell::new(LogScope::default());
+}
+
+/// Restores the previous scope of the thread when dropped.
+#[must_use]
+pub struct ScopeGuard {
+    previous: LogScope,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        SCOPE.with(|scope| *scope.borrow_mut() = previous);
+    }
+}
+
+/// Returns the scope of the current thread, e.g. to enter it on worker threads.
+pub fn current_scope() -> LogScope {
+    SCOPE.with(|scope| scope.borrow().clone())
+}
+
+/// Makes `scope` the scope of the current thread until the guard is dropped.
+pub fn enter_scope(scope: LogScope) -> ScopeGuard {
+    let previous = SCOPE.with(|current| std::mem::replace(&mut *current.borrow_mut(), scope));
+    ScopeGuard { previous }
+}
+
+/// Enters the scope of a template set; `index` is its position in the config.
+pub fn set_scope(index: usize, label: &str) -> ScopeGuard {
+    enter_scope(LogScope {
+        set: Some((index, label.to_string())),
+        file: None,
+    })
+}
+
+/// Enters the scope of a template file within the current set.
+pub fn file_scope(template_path: &Path) -> ScopeGuard {
+    let mut scope = current_scope();
+    scope.file = Some(template_path.to_string_lossy().replace('\\', "/"));
+    enter_scope(scope)
+}
+
+/// How `OrderedLogger` keeps log records of concurrently generated sets and files apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogOrder {
+    /// Buffer records within a set and print them grouped by set, then by file, on `flush`.
+    Grouped,
+    /// Log records immediately, prefixed with their set.
+    Prefixed,
+}
+
+impl FromStr for LogOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "grouped" => Ok(LogOrder::Grouped),
+            "prefixed" => Ok(LogOrder::Prefixed),
+            _ => Err(format!("Invalid log order '{}': expected grouped or prefixed", s)),
+        }
+    }
+}
+
+/// Logger wrapping another logger (e.g. `env_logger`) that keeps records legible when items
+/// or sets are generated in parallel. Records outside a template set pass through unchanged.
+pub struct OrderedLogger<L: Log> {
+    inner: L,
+    order: LogOrder,
+    records: Mutex<Vec<(LogScope, String)>>,
+}
+
+impl<L: Log> OrderedLogger<L> {
+    pub fn new(inner: L, order: LogOrder) -> Self {
+        Self {
+            inner,
+            order,
+            records: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Takes the buffered records as lines, grouped by set, then by file, each set starting
+    /// with a `=== <set> ===` line.
+    pub fn take_grouped(&self) -> Vec<String> {
+        let mut records = std::mem::take(&mut *self.records.lock().unwrap());
+        records.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut lines = Vec::new();
+        let mut current_set = None;
+        for (scope, line) in records {
+            if scope.set != current_set {
+                if let Some((_, ref label)) = scope.set {
+                    lines.push(format!("=== {} ===", label));
+                }
+                current_set = scope.set;
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+impl<L: Log> Log for OrderedLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let scope = current_scope();
+        let Some((_, ref label)) = scope.set else {
+            self.inner.log(record);
+            return;
+        };
+        match self.order {
+            LogOrder::Grouped => {
+                let line = format!("[{:<5} {}] {}", record.level(), record.target(), record.args());
+                self.records.lock().unwrap().push((scope, line));
+            }
+            LogOrder::Prefixed => self.inner.log(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", label, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+        }
+    }
+
+    /// Prints the buffered records to stderr, grouped by set and file.
+    fn flush(&self) {
+        for line in self.take_grouped() {
+            eprintln!("{}", line);
+        }
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    /// Logger recording the messages it receives.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Log for Recorder {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Info
+        }
+
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn log(logger: &dyn Log, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(level)
+                .target("templify")
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_grouped_order() {
+        let logger = OrderedLogger::new(Recorder::default(), LogOrder::Grouped);
+        log(&logger, Level::Info, "start");
+        std::thread::scope(|scope| {
+            for (index, set) in [(1, "web"), (0, "api")] {
+                let logger = &logger;
+                scope.spawn(move || {
+                    let _set = set_scope(index, set);
+                    log(logger, Level::Info, &format!("{} begins", set));
+                    for file in ["b.j2", "a.j2"] {
+                        let _file = file_scope(Path::new(file));
+                        for step in 0..3 {
+                            log(logger, Level::Info, &format!("{} {} {}", set, file, step));
+                            std::thread::yield_now();
+                        }
+                    }
+                    log(logger, Level::Debug, "filtered");
+                });
+            }
+        });
+        assert_eq!(*logger.inner.0.lock().unwrap(), vec!["start"]);
+
+        let mut expected = Vec::new();
+        for set in ["api", "web"] {
+            expected.push(format!("=== {} ===", set));
+            expected.push(format!("[INFO  templify] {} begins", set));
+            for file in ["a.j2", "b.j2"] {
+                for step in 0..3 {
+                    expected.push(format!("[INFO  templify] {} {} {}", set, file, step));
+                }
+            }
+        }
+        assert_eq!(logger.take_grouped(), expected);
+        assert!(logger.take_grouped().is_empty());
+        assert_eq!(current_scope(), LogScope::default());
+    }
+
+    #[test]
+    fn test_prefixed() {
+        let logger = OrderedLogger::new(Recorder::default(), LogOrder::Prefixed);
+        {
+            let _set = set_scope(0, "'Env'");
+            let _file = file_scope(Path::new("a.j2"));
+            log(&logger, Level::Warn, "careful");
+        }
+        log(&logger, Level::Info, "done");
+        assert_eq!(*logger.inner.0.lock().unwrap(), vec!["['Env'] careful", "done"]);
+    }
+}
@@ -1,342 +1,4402 @@
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use log::{error, info, warn};
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use templify::config::TemplateConfig;
-use templify::iteration::IterationEvaluator;
-use templify::{FileGenerator, ManualSectionManager, TemplateEngine};
-
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    #[command(subcommand)]
-    command: Option<Commands>,
-
-    /// Path to the YAML configuration file
-    #[arg(short, long, global = true)]
-    config: Option<PathBuf>,
-
-    /// Path to the JSON data file
-    #[arg(short, long, global = true)]
-    data: Option<PathBuf>,
-
-    /// Base output directory (overrides config if provided)
-    #[arg(short, long, global = true)]
-    output: Option<PathBuf>,
-
-    /// Dry run mode - don't write files
-    #[arg(long, global = true)]
-    dry_run: bool,
-
-    /// Include patterns (glob or regex:pattern)
-    #[arg(long, global = true)]
-    include: Vec<String>,
-
-    /// Exclude patterns (glob or regex:pattern)
-    #[arg(long, global = true)]
-    exclude: Vec<String>,
-}
-
-#[derive(Subcommand)]
-enum Commands {
-    /// Initialize a new templify project
-    Init {
-        /// Project directory
-        #[arg(default_value = ".")]
-        path: PathBuf,
-    },
-    /// Generate files from templates (default command)
-    Generate,
-}
-
-fn main() -> Result<()> {
-    env_logger::init();
-
-    let cli = Cli::parse();
-
-    match cli.command {
-        Some(Commands::Init { path }) => {
-            init_project(&path)?;
-        }
-        Some(Commands::Generate) | None => {
-            generate(cli)?;
-        }
-    }
-
-    Ok(())
-}
-
-fn init_project(path: &Path) -> Result<()> {
-    info!("Initializing templify project at {:?}", path);
-
-    // Create directory structure
-    std::fs::create_dir_all(path.join("templates"))?;
-    std::fs::create_dir_all(path.join("output"))?;
-
-    // Create example config.yaml
-    let config_content = r#"globals:
-  version: "1.0.0"
-  project: "MyProject"
-
-manual_sections:
-  start_marker: "MANUAL SECTION START"
-  end_marker: "MANUAL SECTION END"
-
-templates:
-  - name: "Example"
-    folder: "templates"
-    output: "output"
-    enabled: true
-"#;
-    std::fs::write(path.join("config.yaml"), config_content)?;
-
-    // Create example data.json
-    let data_content = r#"{
-  "items": [
-    {"name": "item1", "value": 100},
-    {"name": "item2", "value": 200}
-  ]
-}
-"#;
-    std::fs::write(path.join("data.json"), data_content)?;
-
-    // Create example template
-    let template_content = r#"# {{ item.name }}
-
-Value: {{ item.value }}
-
-MANUAL SECTION START: custom
-# Add your custom content here
-MANUAL SECTION END
-"#;
-    std::fs::write(
-        path.join("templates/_foreach_item_{{ item.name }}.md.j2"),
-        template_content,
-    )?;
-
-    info!("✓ Project initialized successfully!");
-    info!("  Run: yagen -c config.yaml -d data.json");
-
-    Ok(())
-}
-
-fn generate(cli: Cli) -> Result<()> {
-    let config_path = cli
-        .config
-        .ok_or_else(|| anyhow::anyhow!("--config is required"))?;
-    let data_path = cli
-        .data
-        .ok_or_else(|| anyhow::anyhow!("--data is required"))?;
-
-    info!("Loading config from {:?}", config_path);
-    let config = TemplateConfig::load(&config_path).context("Failed to load config")?;
-
-    info!("Loading data from {:?}", data_path);
-    let data_content = std::fs::read_to_string(&data_path).context("Failed to read data file")?;
-    let data: serde_json::Value =
-        serde_json::from_str(&data_content).context("Failed to parse JSON data")?;
-
-    let output_base = cli.output.unwrap_or_else(|| {
-        config_path
-            .parent()
-            .unwrap_or(Path::new("."))
-            .to_path_buf()
-    });
-
-    if cli.dry_run {
-        info!("=== DRY RUN MODE ===");
-    }
-
-    for template_set in config.templates {
-        if !template_set.enabled {
-            continue;
-        }
-
-        // Filter check
-        if let Some(ref name) = template_set.name {
-            if should_filter(name, &cli.include, &cli.exclude) {
-                info!("Skipping template set: {}", name);
-                continue;
-            }
-        }
-
-        let template_folder = config_path
-            .parent()
-            .unwrap_or(Path::new("."))
-            .join(&template_set.folder);
-
-        let set_output_path = if let Some(ref out) = template_set.output {
-            output_base.join(out)
-        } else {
-            output_base.clone()
-        };
-
-        let engine = TemplateEngine::new();
-        let manual_section_manager =
-            ManualSectionManager::new(config.manual_sections.clone());
-            
-        // Initialize formatter
-        let formatter_manager = templify::formatting::FormatterManager::new(
-            config.format.clone(),
-            manual_section_manager.clone(), // Clone needed because FileGenerator takes ownership? No, we need to pass a clone if we need it elsewhere but ManualSectionManager is cheap to clone usually
-        );
-            
-        let generator = FileGenerator::new(engine, manual_section_manager, cli.dry_run)
-            .with_formatter(formatter_manager);
-
-        if let Some(iterate) = template_set.iterate {
-            let info = IterationEvaluator::parse_simple(&iterate)
-                .map_err(|e| anyhow::anyhow!("Failed to parse iteration: {}", e))?;
-            
-            let path = IterationEvaluator::evaluate_path(&info.expr);
-            let items = data.pointer(&path);
-
-            if let Some(serde_json::Value::Array(items)) = items {
-                for item in items.iter() {
-                    // TODO: Check condition if present
-                    let mut context = HashMap::new();
-
-                    // Add globals
-                    if let Some(ref globals) = config.globals {
-                        context.insert(
-                            "globals".to_string(),
-                            serde_json::to_value(globals).unwrap(),
-                        );
-                    }
-
-                    // Add iteration variable
-                    context.insert(info.var.clone(), item.clone());
-
-                    // Add 'dd' (full data)
-                    context.insert("dd".to_string(), data.clone());
-
-                    // Flatten data if enabled
-                    if config.flatten_data {
-                        if let serde_json::Value::Object(map) = &data {
-                            for (k, v) in map {
-                                context.insert(k.clone(), v.clone());
-                            }
-                        }
-                    }
-
-                    generator
-                        .generate(&template_folder, &set_output_path, &context)
-                        .map_err(|e| anyhow::anyhow!(e))?;
-                }
-            } else {
-                error!(
-                    "Iteration expression '{}' did not resolve to an array",
-                    info.expr
-                );
-            }
-        } else {
-            // Static generation
-            let mut context = HashMap::new();
-            
-            // Add globals
-            if let Some(ref globals) = config.globals {
-                context.insert(
-                    "globals".to_string(),
-                    serde_json::to_value(globals).unwrap(),
-                );
-            }
-            
-            // Add 'dd' (full data)
-            context.insert("dd".to_string(), data.clone());
-            
-            // Add extra data
-            for extra in &config.extra_data {
-                let extra_path = config_path.parent().unwrap_or(Path::new(".")).join(&extra.path);
-                match std::fs::read_to_string(&extra_path) {
-                    Ok(content) => {
-                         let val: serde_json::Value = if extra.path.ends_with(".yaml") || extra.path.ends_with(".yml") {
-                             serde_yaml::from_str(&content).unwrap_or(serde_json::Value::Null)
-                         } else {
-                             serde_json::from_str(&content).unwrap_or(serde_json::Value::Null)
-                         };
-                         
-                         // Check valid
-                         if val.is_null() {
-                              warn!("Failed to parse extra data from {:?}", extra_path);
-                              if extra.required {
-                                  return Err(anyhow::anyhow!("Required extra data file failed to parse: {:?}", extra_path));
-                              }
-                         } else {
-                              context.insert(extra.key.clone(), val);
-                         }
-                    },
-                    Err(_) => {
-                        if extra.required {
-                            return Err(anyhow::anyhow!("Required extra data file not found: {:?}", extra_path));
-                        } else {
-                            warn!("Optional extra data file not found: {:?}", extra_path);
-                        }
-                    }
-                }
-            }
-
-            // Flatten data if enabled
-            if config.flatten_data {
-                if let serde_json::Value::Object(map) = &data {
-                    for (k, v) in map {
-                        context.insert(k.clone(), v.clone());
-                    }
-                }
-            }
-
-            generator
-                .generate(&template_folder, &set_output_path, &context)
-                .map_err(|e| anyhow::anyhow!(e))?;
-        }
-    }
-
-    if cli.dry_run {
-        info!("=== DRY RUN COMPLETE ===");
-    }
-
-    Ok(())
-}
-
-fn should_filter(name: &str, include: &[String], exclude: &[String]) -> bool {
-    // If include patterns are specified, name must match at least one
-    if !include.is_empty() {
-        let mut matched = false;
-        for pattern in include {
-            if matches_pattern(name, pattern) {
-                matched = true;
-                break;
-            }
-        }
-        if !matched {
-            return true; // Filter out
-        }
-    }
-
-    // If exclude patterns are specified, name must not match any
-    for pattern in exclude {
-        if matches_pattern(name, pattern) {
-            return true; // Filter out
-        }
-    }
-
-    false // Don't filter
-}
-
-fn matches_pattern(name: &str, pattern: &str) -> bool {
-    if let Some(regex_pattern) = pattern.strip_prefix("regex:") {
-        if let Ok(re) = regex::Regex::new(regex_pattern) {
-            return re.is_match(name);
-        }
-    }
-    
-    // Simple glob-like matching (very basic)
-    if pattern.contains('*') {
-        let parts: Vec<&str> = pattern.split('*').collect();
-        if parts.len() == 2 {
-            return name.starts_with(parts[0]) && name.ends_with(parts[1]);
-        }
-    }
-    
-    name == pattern
-}
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use log::{debug, error, info, warn};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use templify::config::{PermissionRule, TemplateConfig, TemplateSet};
+use templify::context::ContextBuilder;
+use templify::data::{DataFormat, DataLoader, DataSchema, DATA_TOKEN_ENV};
+use templify::engine::{FilterKind, CUSTOM_FILTERS, DEFAULT_MISSING_KEY_PLACEHOLDER};
+use templify::formatting::{FormatterManager, RunningCommands};
+use templify::generator::recorded_outputs;
+use templify::iteration::{
+    IterationError, IterationEvaluator, IterationInfo, IterationPattern, LoopPosition,
+};
+use templify::logging::{LogOrder, OrderedLogger};
+use templify::transform::apply_transforms;
+use templify::{
+    ArchiveWriter, ConflictPolicy, DryRunMode, FileGenerator, GenerationReport, ManualSectionManager,
+    MessageCatalogs, OutputMode, TemplateEngine, TemplateTiming,
+};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Path to the configuration file (YAML, JSON or TOML, by extension)
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Path or HTTP(S) URL of the JSON/YAML data file (can be repeated with --per-data-output)
+    #[arg(short, long, global = true)]
+    data: Vec<PathBuf>,
+
+    /// Generate once per data file into a subdirectory of this directory named after the data file
+    #[arg(long, global = true, value_name = "DIR", conflicts_with = "output")]
+    per_data_output: Option<PathBuf>,
+
+    /// Also expose the whole data under this name, in addition to `dd` (and the top-level keys
+    /// unless `flatten_data` is false)
+    #[arg(long, global = true, value_name = "NAME")]
+    data_key: Option<String>,
+
+    /// Expose the content of a file as a string under a context key (can be repeated)
+    #[arg(long, global = true, value_name = "KEY=PATH", value_parser = parse_set_file)]
+    set_file: Vec<(String, PathBuf)>,
+
+    /// Format of the data (json or yaml), overriding the Content-Type or file extension
+    #[arg(long, global = true, value_name = "FORMAT")]
+    data_format: Option<DataFormat>,
+
+    /// Timeout in seconds for fetching HTTP(S) data
+    #[arg(long, global = true, value_name = "SECONDS", default_value_t = 30)]
+    data_timeout: u64,
+
+    /// Validate the data against the config's `schema` before generating
+    #[arg(long, global = true)]
+    check_data: bool,
+
+    /// Print the effective config, with defaults filled in, as yaml (default) or json and exit
+    #[arg(
+        long,
+        global = true,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "yaml"
+    )]
+    config_check: Option<DataFormat>,
+
+    /// Rewrite the manual section markers OLD_START/OLD_END in the existing outputs to the
+    /// configured markers and exit
+    #[arg(
+        long,
+        global = true,
+        value_name = "OLD_START,OLD_END",
+        value_parser = parse_marker_pair,
+        conflicts_with_all = ["config_check", "template_var_report"]
+    )]
+    migrate_markers: Option<(String, String)>,
+
+    /// Print the context variables each template reads as JSON and exit
+    #[arg(long, global = true, conflicts_with = "config_check")]
+    template_var_report: bool,
+
+    /// Directory that template set folders are resolved against (overrides `template_root`)
+    #[arg(long, global = true, value_name = "DIR")]
+    template_root: Option<PathBuf>,
+
+    /// Base output directory (overrides config if provided)
+    #[arg(short, long, global = true)]
+    output: Option<PathBuf>,
+
+    /// Dry run mode - don't write files: off, list (default when given without a value) or diff
+    #[arg(
+        long,
+        global = true,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_value = "off",
+        default_missing_value = "list"
+    )]
+    dry_run: DryRunMode,
+
+    /// What to do with existing output files not generated by templify: overwrite, skip, error or backup
+    #[arg(long, global = true, value_name = "POLICY", default_value = "overwrite")]
+    on_conflict: ConflictPolicy,
+
+    /// Output layout: mirror the template tree, or flat to write all files into the set output
+    #[arg(long, global = true, value_name = "MODE", default_value = "mirror")]
+    output_mode: OutputMode,
+
+    /// Octal mode of written files, optionally for a pattern such as `*.sh=0755`; takes
+    /// precedence over `permissions` in the config (can be repeated, Unix only)
+    #[arg(long, global = true, value_name = "[PATTERN=]MODE", value_parser = parse_file_mode)]
+    file_mode: Vec<PermissionRule>,
+
+    /// Owner of written files as numeric UID or UID:GID, overriding the config (Unix only)
+    #[arg(long, global = true, value_name = "UID[:GID]", value_parser = parse_file_owner)]
+    file_owner: Option<PermissionRule>,
+
+    /// Render undefined variables as a placeholder instead of failing, and report them at the end
+    #[arg(long, global = true)]
+    allow_missing_data_keys: bool,
+
+    /// Placeholder for undefined variables with --allow-missing-data-keys; `{}` is replaced by the name
+    #[arg(long, global = true, value_name = "TEXT", default_value = DEFAULT_MISSING_KEY_PLACEHOLDER)]
+    missing_key_placeholder: String,
+
+    /// Write generated files into an archive (.tar.gz, .tgz or .zip) instead of the output directory
+    #[arg(long, global = true, conflicts_with = "dry_run")]
+    archive: Option<PathBuf>,
+
+    /// Print why each template set, item and file was generated or skipped
+    #[arg(long, global = true)]
+    explain: bool,
+
+    /// Compile the templates of all template sets before generating any file
+    #[arg(long, global = true)]
+    check_first: bool,
+
+    /// Continue after errors and report all of them at the end
+    #[arg(long, global = true)]
+    keep_going: bool,
+
+    /// Seed for the `random_choice` and `shuffle` filters, to make their output reproducible
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+
+    /// Skip templates whose output file is newer than the template, data and config files
+    #[arg(long, global = true)]
+    incremental: bool,
+
+    /// Print the N slowest templates (10 without a value) with their total generation time and count
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "10"
+    )]
+    profile_template: Option<usize>,
+
+    /// Generate iteration sets with more items than their `max_items`
+    #[arg(long, global = true)]
+    allow_large: bool,
+
+    /// Keep log lines of template sets apart: `grouped` (the default) prints them grouped by
+    /// set and file at the end of the run, `prefixed` streams them prefixed with their set
+    #[arg(
+        long,
+        global = true,
+        value_name = "ORDER",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "grouped"
+    )]
+    parallel_safe_logging: Option<LogOrder>,
+
+    /// Abort the run when it takes longer than this many seconds, killing running formatter
+    /// commands, and exit with code 4
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Formatter commands of the run, killed when it times out
+    #[arg(skip)]
+    commands: RunningCommands,
+
+    /// Render the items of an iteration set on up to N worker threads
+    #[arg(long, global = true, value_name = "N", default_value_t = 1)]
+    jobs: usize,
+
+    /// Stage the files written by the run with `git add`
+    #[arg(long, global = true)]
+    git_add: bool,
+
+    /// Generate in memory and print the absolute path of every file the run would produce,
+    /// one per line, instead of writing them
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = ["dry_run", "archive", "emit_deps", "git_add", "explain", "profile_template"]
+    )]
+    list_outputs: bool,
+
+    /// Generate, then regenerate whenever a template folder, the config or an input file
+    /// changes, until Ctrl-C
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = ["list_outputs", "config_check", "migrate_markers", "template_var_report"]
+    )]
+    watch: bool,
+
+    /// Write a JSON manifest of the files the run wrote, copied, injected or skipped (in
+    /// dry-run mode, would have). `clean` reads the manifest of a previous run instead
+    #[arg(long, global = true, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Write a Makefile-style dependency file listing the inputs of each generated output
+    #[arg(long, global = true, value_name = "FILE")]
+    emit_deps: Option<PathBuf>,
+
+    /// Include patterns (glob or regex:pattern)
+    #[arg(long, global = true)]
+    include: Vec<String>,
+
+    /// Exclude patterns (glob or regex:pattern)
+    #[arg(long, global = true)]
+    exclude: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Initialize a new templify project
+    Init {
+        /// Project directory
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// Generate files from templates (default command)
+    Generate,
+    /// Render a single template read from stdin (or --template) to stdout, with the context of
+    /// --data and --config, without reading or writing any other files
+    Render {
+        /// Template file, or - for stdin
+        #[arg(long, default_value = "-")]
+        template: PathBuf,
+    },
+    /// Check the templates of the enabled sets against the config and data without rendering
+    /// or writing anything: syntax, undefined variables, iteration paths, manual sections and
+    /// injection patterns. Reports all errors and fails if there are any
+    Validate,
+    /// Remove the files that the --manifest of a previous run lists but the current run no
+    /// longer generates. In dry-run mode, only list them
+    Clean {
+        /// Also remove files containing manual sections
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the custom filters and functions available in templates
+    #[command(long_flag = "list-filters")]
+    ListFilters,
+    /// Time repeated in-memory generation runs, without writing files
+    #[command(hide = true)]
+    Bench {
+        /// Number of generation runs
+        #[arg(long, default_value_t = 10)]
+        runs: usize,
+    },
+    /// Verify that manual sections in existing files are structurally valid, without generating
+    VerifyManualSections {
+        /// Files or directories to check (defaults to the configured outputs)
+        paths: Vec<PathBuf>,
+    },
+    /// Check that the commands of the configured formatters are installed
+    Doctor {
+        /// Also run each command with --version and print the result
+        #[arg(long)]
+        version: bool,
+    },
+}
+
+/// Failure categories, each exiting with its own code. Errors without a category are
+/// generation errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// Rendering, injecting or writing failed
+    Generation = 1,
+    /// Invalid arguments, config or inputs (clap also exits with 2 on usage errors)
+    Config = 2,
+    /// Data, templates or manual sections failed a check
+    Validation = 3,
+    /// The run took longer than `--timeout`
+    Timeout = 4,
+}
+
+impl Failure {
+    /// Returns the category an error was tagged with, anywhere in its chain.
+    fn of(error: &anyhow::Error) -> Failure {
+        error
+            .chain()
+            .find_map(|e| e.downcast_ref::<Categorized>())
+            .map_or(Failure::Generation, |c| c.failure)
+    }
+
+    fn exit_code(self) -> ExitCode {
+        ExitCode::from(self as u8)
+    }
+}
+
+/// An error tagged with its failure category; displayed as the error itself.
+#[derive(Debug)]
+struct Categorized {
+    failure: Failure,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Categorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for Categorized {}
+
+/// Tags the error of a result with a failure category.
+trait FailWith<T> {
+    fn fail_with(self, failure: Failure) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> FailWith<T> for std::result::Result<T, E> {
+    fn fail_with(self, failure: Failure) -> Result<T> {
+        self.map_err(|e| anyhow::Error::new(Categorized { failure, error: e.into() }))
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match cli.parallel_safe_logging {
+        Some(order) => {
+            let logger = env_logger::Builder::from_default_env().build();
+            let max_level = logger.filter();
+            if log::set_boxed_logger(Box::new(OrderedLogger::new(logger, order))).is_ok() {
+                log::set_max_level(max_level);
+            }
+        }
+        None => env_logger::init(),
+    }
+
+    let result = run_with_timeout(cli);
+    log::logger().flush();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            Failure::of(&e).exit_code()
+        }
+    }
+}
+
+/// Runs the command. With `--timeout`, it runs on a worker thread that is abandoned when the
+/// timeout passes: the formatter commands of the run are killed and the run fails with
+/// `Failure::Timeout`; returning from `main` then ends the worker.
+fn run_with_timeout(cli: Cli) -> Result<()> {
+    let Some(secs) = cli.timeout else {
+        return run_command(cli);
+    };
+    let commands = cli.commands.clone();
+    let (sender, receiver) = mpsc::channel();
+    let worker = std::thread::spawn(move || {
+        let _ = sender.send(run_command(cli));
+    });
+    match receiver.recv_timeout(Duration::from_secs(secs)) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => {
+            commands.kill();
+            Err(anyhow::anyhow!("The run took longer than the timeout of {}s", secs))
+                .fail_with(Failure::Timeout)
+        }
+        // The worker panicked before sending its result
+        Err(RecvTimeoutError::Disconnected) => match worker.join() {
+            Err(panic) => std::panic::resume_unwind(panic),
+            Ok(()) => unreachable!("the worker sends its result before exiting"),
+        },
+    }
+}
+
+fn run_command(cli: Cli) -> Result<()> {
+    match cli.command {
+        Some(Commands::Init { path }) => {
+            init_project(&path)?;
+        }
+        Some(Commands::ListFilters) => {
+            print!("{}", list_filters());
+        }
+        Some(Commands::Render { ref template }) => {
+            render(&cli, template, std::io::stdin().lock(), std::io::stdout().lock())?;
+        }
+        Some(Commands::Bench { runs }) => {
+            let stats = bench(&cli, runs)?;
+            println!("{}", stats);
+        }
+        Some(Commands::VerifyManualSections { paths }) => {
+            verify_manual_sections(cli.config.as_deref(), cli.output.as_deref(), paths)?;
+        }
+        Some(Commands::Doctor { version }) => {
+            doctor(cli.config.as_deref(), version)?;
+        }
+        Some(Commands::Validate) => {
+            let checked = validate(&cli)?;
+            println!("{} template folder(s) valid", checked);
+        }
+        Some(Commands::Clean { force }) => {
+            let removed = clean(&cli, force)?;
+            if cli.dry_run.is_enabled() {
+                print!("{}", dry_run_preview(&BTreeSet::new(), &removed));
+            } else {
+                println!("{} stale file(s) removed", removed.len());
+            }
+        }
+        Some(Commands::Generate) | None => {
+            if let Some(format) = cli.config_check {
+                print!("{}", config_check(cli.config.as_deref(), format)?);
+            } else if let Some((ref old_start, ref old_end)) = cli.migrate_markers {
+                migrate_markers(&cli, old_start, old_end)?;
+            } else if cli.template_var_report {
+                println!("{}", template_var_report(&cli)?);
+            } else if cli.watch {
+                watch(&cli)?;
+            } else {
+                generate(&cli)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn init_project(path: &Path) -> Result<()> {
+    info!("Initializing templify project at {:?}", path);
+
+    // Create directory structure
+    std::fs::create_dir_all(path.join("templates"))?;
+    std::fs::create_dir_all(path.join("output"))?;
+
+    // Create example config.yaml
+    let config_content = r#"globals:
+  version: "1.0.0"
+  project: "MyProject"
+
+manual_sections:
+  start_marker: "MANUAL SECTION START"
+  end_marker: "MANUAL SECTION END"
+
+templates:
+  - name: "Example"
+    folder: "templates"
+    output: "output"
+    enabled: true
+"#;
+    std::fs::write(path.join("config.yaml"), config_content)?;
+
+    // Create example data.json
+    let data_content = r#"{
+  "items": [
+    {"name": "item1", "value": 100},
+    {"name": "item2", "value": 200}
+  ]
+}
+"#;
+    std::fs::write(path.join("data.json"), data_content)?;
+
+    // Create example template
+    let template_content = r#"# {{ item.name }}
+
+Value: {{ item.value }}
+
+MANUAL SECTION START: custom
+# Add your custom content here
+MANUAL SECTION END
+"#;
+    std::fs::write(
+        path.join("templates/_foreach_item_{{ item.name }}.md.j2"),
+        template_content,
+    )?;
+
+    info!("✓ Project initialized successfully!");
+    info!("  Run: yagen -c config.yaml -d data.json");
+
+    Ok(())
+}
+
+/// Formats the custom filters and functions, one per line.
+fn list_filters() -> String {
+    let width = CUSTOM_FILTERS.iter().map(|f| f.name.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for info in CUSTOM_FILTERS {
+        let kind = match info.kind {
+            FilterKind::Filter => "filter",
+            FilterKind::Function => "function",
+        };
+        out.push_str(&format!(
+            "{:<8}  {:<width$}  {}\n",
+            kind,
+            info.name,
+            info.description,
+            width = width
+        ));
+    }
+    out
+}
+
+/// Returns the config as it will be used, with all defaults filled in.
+fn config_check(config_path: Option<&Path>, format: DataFormat) -> Result<String> {
+    let config_path = config_path
+        .ok_or_else(|| anyhow::anyhow!("--config is required"))
+        .fail_with(Failure::Config)?;
+    let config = TemplateConfig::load(config_path)
+        .context("Failed to load config")
+        .fail_with(Failure::Config)?;
+    Ok(match format {
+        DataFormat::Json => serde_json::to_string_pretty(&config)? + "\n",
+        DataFormat::Yaml => serde_yaml::to_string(&config)?,
+    })
+}
+
+/// Returns a JSON object mapping each template file (and templated file or directory name) of
+/// the config's template sets to the context variables it reads, both as top-level `variables`
+/// and as dotted attribute `paths`.
+fn template_var_report(cli: &Cli) -> Result<String> {
+    let config_path = cli
+        .config
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--config is required"))
+        .fail_with(Failure::Config)?;
+    let config = TemplateConfig::load(config_path)
+        .context("Failed to load config")
+        .fail_with(Failure::Config)?;
+    let template_root = template_root(cli, &config, config_path);
+    let generator = GenerationRun::new(cli, &config, config_path, DataLoader::new(), None, None)
+        .set_generator()?;
+
+    let mut report = serde_json::Map::new();
+    for template_set in &config.templates {
+        let folders = template_set
+            .expand_folder(&template_root)
+            .with_context(|| format!("Failed to expand folder {:?}", template_set.folder))
+            .fail_with(Failure::Config)?;
+        for (template_folder, _) in folders {
+            let variables = generator
+                .referenced_variables(&template_folder)
+                .map_err(|e| anyhow::anyhow!(e))
+                .fail_with(Failure::Validation)?;
+            for (path, paths) in variables {
+                let top_level: BTreeSet<&str> =
+                    paths.iter().map(|path| path.split('.').next().unwrap_or(path)).collect();
+                report.insert(
+                    path.to_string_lossy().into_owned(),
+                    serde_json::json!({ "variables": top_level, "paths": paths }),
+                );
+            }
+        }
+    }
+    Ok(serde_json::to_string_pretty(&report)?)
+}
+
+/// Validates the templates of every enabled and selected template set for each `--data` file
+/// (see `FileGenerator::validate_templates`), and resolves the sets' iteration paths. Returns
+/// the number of template folders checked, or all errors found.
+fn validate(cli: &Cli) -> Result<usize> {
+    let config_path = cli
+        .config
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--config is required"))
+        .fail_with(Failure::Config)?;
+    if cli.data.is_empty() {
+        return Err(anyhow::anyhow!("--data is required")).fail_with(Failure::Config);
+    }
+    let config = TemplateConfig::load(config_path)
+        .context("Failed to load config")
+        .fail_with(Failure::Config)?;
+    let template_root = template_root(cli, &config, config_path);
+    let data_loader = DataLoader::new()
+        .with_timeout(Duration::from_secs(cli.data_timeout))
+        .with_bearer_token(std::env::var(DATA_TOKEN_ENV).ok());
+    let messages =
+        load_message_catalogs(&config, config_path, &data_loader).fail_with(Failure::Config)?;
+    let run = GenerationRun::new(cli, &config, config_path, data_loader, None, messages);
+
+    let mut errors = Vec::new();
+    let mut checked = 0;
+    for data_path in &cli.data {
+        let data = run
+            .data_loader
+            .clone()
+            .with_format(cli.data_format)
+            .load(&data_path.to_string_lossy(), None)
+            .with_context(|| format!("Failed to load data from {:?}", data_path))
+            .fail_with(Failure::Config)?;
+        let data = run.transform_data(&data)?;
+        let base_context = run.base_context(&data)?;
+        for template_set in config.templates.iter().filter(|set| is_selected(set, cli)) {
+            let label = set_label(template_set);
+            let generator = run.set_generator()?;
+            let (set_data, contexts) =
+                run.set_contexts(&generator, template_set, &data, &base_context)?;
+            // A variable of any target counts as defined
+            let mut variables: BTreeSet<String> =
+                contexts.into_iter().flat_map(|context| context.build().into_keys()).collect();
+
+            if let Some(ref iterate) = template_set.iterate {
+                let levels = match IterationEvaluator::parse(iterate) {
+                    Ok(IterationPattern::Simple(info)) => vec![info],
+                    Ok(IterationPattern::Nested(levels)) => levels,
+                    Ok(IterationPattern::Array(_)) => {
+                        errors.push(format!("set {}: unsupported iteration expression '{}'", label, iterate));
+                        continue;
+                    }
+                    Err(e) => {
+                        errors.push(format!("set {}: invalid iteration '{}': {}", label, iterate, e));
+                        continue;
+                    }
+                };
+                for level in &levels {
+                    variables.insert(level.var.clone());
+                    if let Some(ref condition) = level.condition {
+                        if let Err(e) = generator.engine().check_template(&format!("{{{{ {} }}}}", condition)) {
+                            errors.push(format!("set {}: invalid condition `{}`: {}", label, condition, e));
+                        }
+                    }
+                }
+                variables.extend(["loop".to_string(), "loops".to_string()]);
+                match IterationEvaluator::expand_nested(&levels, &set_data, |_, _, _| Ok(true)) {
+                    Ok(expansion) => errors.extend(
+                        expansion.errors.iter().map(|e| format!("set {}: {}", label, e)),
+                    ),
+                    Err(IterationError::DataPathNotFound(expr)) => errors.push(format!(
+                        "set {}: iteration expression '{}' did not resolve to an array",
+                        label, expr
+                    )),
+                    Err(e) => errors.push(format!("set {}: {}", label, e)),
+                }
+            }
+
+            let folders = match template_set.expand_folder(&template_root) {
+                Ok(folders) => folders,
+                Err(e) => {
+                    errors.push(format!("set {}: {}", label, e));
+                    continue;
+                }
+            };
+            for (template_folder, _) in folders {
+                match generator.validate_templates(&template_folder, &variables) {
+                    Ok(found) => errors.extend(found),
+                    Err(e) => errors.push(format!("set {}: {}", label, e)),
+                }
+                checked += 1;
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Validation failed with {} error(s):\n  {}",
+            errors.len(),
+            errors.join("\n  ")
+        ))
+        .fail_with(Failure::Validation);
+    }
+    Ok(checked)
+}
+
+/// Reports whether the command of each configured formatter is installed, and fails if one
+/// is missing.
+fn doctor(config_path: Option<&Path>, version: bool) -> Result<()> {
+    let config_path = config_path
+        .ok_or_else(|| anyhow::anyhow!("--config is required"))
+        .fail_with(Failure::Config)?;
+    let config = TemplateConfig::load(config_path)
+        .context("Failed to load config")
+        .fail_with(Failure::Config)?;
+    if !config.format.enabled {
+        info!("Formatting is disabled in the config (format.enabled: false)");
+    }
+    let manual_section_manager = ManualSectionManager::try_new(config.manual_sections.clone())
+        .map_err(|e| anyhow::anyhow!(e))
+        .fail_with(Failure::Config)?;
+    let formatters = FormatterManager::new(config.format.clone(), manual_section_manager);
+    let checks = formatters.check_commands(version);
+    if checks.is_empty() {
+        println!("No command formatters configured");
+    }
+    for check in &checks {
+        match (&check.path, &check.version) {
+            (Some(path), Some(version)) => {
+                println!("✓ {}: {} ({}, {})", check.pattern, check.command, path.display(), version)
+            }
+            (Some(path), None) => {
+                println!("✓ {}: {} ({})", check.pattern, check.command, path.display())
+            }
+            (None, _) => println!("✗ {}: {} not found", check.pattern, check.command),
+        }
+    }
+    let missing = checks.iter().filter(|check| check.path.is_none()).count();
+    if missing > 0 {
+        return Err(anyhow::anyhow!("{} formatter command(s) not found", missing))
+            .fail_with(Failure::Validation);
+    }
+    Ok(())
+}
+
+fn verify_manual_sections(
+    config_path: Option<&Path>,
+    output: Option<&Path>,
+    mut paths: Vec<PathBuf>,
+) -> Result<()> {
+    let config = match config_path {
+        Some(path) => Some(
+            TemplateConfig::load(path)
+                .context("Failed to load config")
+                .fail_with(Failure::Config)?,
+        ),
+        None => None,
+    };
+
+    if paths.is_empty() {
+        let (config, config_path) = config
+            .as_ref()
+            .zip(config_path)
+            .ok_or_else(|| anyhow::anyhow!("Either paths or --config is required"))
+            .fail_with(Failure::Config)?;
+        paths = configured_outputs(config, config_path, output);
+    }
+
+    let manual_sections = config
+        .map(|c| c.manual_sections)
+        .unwrap_or_default();
+    let manager = ManualSectionManager::try_new(manual_sections)
+        .map_err(|e| anyhow::anyhow!(e))
+        .fail_with(Failure::Config)?;
+    let issues = manager
+        .verify_paths(&paths)
+        .map_err(|e| anyhow::anyhow!(e))
+        .fail_with(Failure::Config)?;
+
+    for (path, issue) in &issues {
+        error!("{}:{}: {}", path.display(), issue.line, issue.message);
+    }
+    if !issues.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} manual section problem(s) found",
+            issues.len()
+        ))
+        .fail_with(Failure::Validation);
+    }
+    info!("✓ Manual sections are valid in {} path(s)", paths.len());
+    Ok(())
+}
+
+/// Returns the output directories of the enabled template sets, below `output` or else the
+/// config file's directory.
+fn configured_outputs(config: &TemplateConfig, config_path: &Path, output: Option<&Path>) -> Vec<PathBuf> {
+    let output_base = output
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| config_path.parent().unwrap_or(Path::new(".")).to_path_buf());
+    let mut paths = Vec::new();
+    for template_set in config.templates.iter().filter(|t| t.enabled) {
+        let set_output = match template_set.output {
+            Some(ref out) => output_base.join(out),
+            None => output_base.clone(),
+        };
+        if !paths.contains(&set_output) {
+            paths.push(set_output);
+        }
+    }
+    paths
+}
+
+/// Rewrites the manual section markers `old_start`/`old_end` in the files of the configured
+/// outputs to the markers of the config, so that their sections survive a change of markers.
+fn migrate_markers(cli: &Cli, old_start: &str, old_end: &str) -> Result<()> {
+    let config_path = cli
+        .config
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--config is required"))
+        .fail_with(Failure::Config)?;
+    let config = TemplateConfig::load(config_path)
+        .context("Failed to load config")
+        .fail_with(Failure::Config)?;
+    let paths: Vec<PathBuf> = configured_outputs(&config, config_path, cli.output.as_deref())
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect();
+    let manager = ManualSectionManager::try_new(config.manual_sections)
+        .map_err(|e| anyhow::anyhow!(e))
+        .fail_with(Failure::Config)?;
+    let migrated = manager
+        .migrate_paths(&paths, old_start, old_end, cli.dry_run.is_enabled())
+        .map_err(|e| anyhow::anyhow!(e))?;
+    for path in &migrated {
+        if cli.dry_run.is_enabled() {
+            info!("[DRY RUN] Would migrate markers in {:?}", path);
+        } else {
+            info!("Migrated markers in {:?}", path);
+        }
+    }
+    info!("✓ {} file(s) with markers to migrate in {} path(s)", migrated.len(), paths.len());
+    Ok(())
+}
+
+/// Checks the --config and --data arguments of a generation run and loads the config.
+/// Returns the config path, the config and the output base.
+fn load_run_config(cli: &Cli) -> Result<(PathBuf, TemplateConfig, PathBuf)> {
+    let config_path = cli
+        .config
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("--config is required"))
+        .fail_with(Failure::Config)?;
+    if cli.data.is_empty() {
+        return Err(anyhow::anyhow!("--data is required")).fail_with(Failure::Config);
+    }
+    if cli.data.len() > 1 && cli.per_data_output.is_none() {
+        return Err(anyhow::anyhow!(
+            "Multiple --data files require --per-data-output"
+        ))
+        .fail_with(Failure::Config);
+    }
+
+    check_jobs(cli)?;
+
+    info!("Loading config from {:?}", config_path);
+    let config = TemplateConfig::load(&config_path)
+        .context("Failed to load config")
+        .fail_with(Failure::Config)?;
+
+    let output_base = cli.per_data_output.clone().or(cli.output.clone()).unwrap_or_else(|| {
+        config_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf()
+    });
+    Ok((config_path, config, output_base))
+}
+
+/// Rejects `--seed` with `--jobs` above 1: the workers would draw from the seeded generator in
+/// the order they happen to render, so the output would no longer be reproducible.
+fn check_jobs(cli: &Cli) -> Result<()> {
+    if cli.jobs > 1 && cli.seed.is_some() {
+        return Err(anyhow::anyhow!("--seed can't be combined with --jobs above 1"))
+            .fail_with(Failure::Config);
+    }
+    Ok(())
+}
+
+fn generate(cli: &Cli) -> Result<()> {
+    let (config_path, config, output_base) = load_run_config(cli)?;
+    let data_loader = DataLoader::new()
+        .with_timeout(Duration::from_secs(cli.data_timeout))
+        .with_bearer_token(std::env::var(DATA_TOKEN_ENV).ok());
+
+    if cli.dry_run.is_enabled() {
+        info!("=== DRY RUN MODE ===");
+    }
+
+    let archive = match cli.archive {
+        Some(ref archive_path) => {
+            info!("Writing generated files into archive {:?}", archive_path);
+            let writer = ArchiveWriter::create(archive_path, &output_base)
+                .map_err(|e| anyhow::anyhow!(e))?;
+            Some(Arc::new(writer))
+        }
+        None => None,
+    };
+
+    let messages =
+        load_message_catalogs(&config, &config_path, &data_loader).fail_with(Failure::Config)?;
+    let mut run = GenerationRun::new(cli, &config, &config_path, data_loader, archive, messages);
+    run.generate_all_data(&output_base)?;
+
+    if !run.missing_keys.is_empty() {
+        warn!("Undefined variables rendered as placeholders:");
+        for key in &run.missing_keys {
+            warn!("  {}", key);
+        }
+    }
+
+    for explanation in &run.explanations {
+        println!("[explain] {}", explanation);
+    }
+
+    if let Some(top) = cli.profile_template {
+        print!("{}", profile_report(run.template_timings.into_values().collect(), top));
+    }
+
+    if let Some(archive) = run.archive {
+        archive.finish().map_err(|e| anyhow::anyhow!(e))?;
+    }
+
+    if !run.errors.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Generation failed with {} error(s):\n  {}",
+            run.errors.len(),
+            run.errors.join("\n  ")
+        ));
+    }
+
+    if cli.git_add {
+        git_add(&run.written_files)?;
+    }
+
+    if let Some(ref deps_path) = cli.emit_deps {
+        if !cli.dry_run.is_enabled() {
+            let inputs: Vec<PathBuf> = run_inputs(cli, &config, &config_path)
+                .into_iter()
+                .filter(|path| !DataLoader::is_url(&path.to_string_lossy()))
+                .collect();
+            std::fs::write(deps_path, deps_file(&run.output_templates, &inputs))
+                .with_context(|| format!("Failed to write dependency file {:?}", deps_path))?;
+        }
+    }
+
+    if cli.list_outputs {
+        print!("{}", output_list(&run.output_files)?);
+    }
+
+    if let Some(ref manifest_path) = cli.manifest {
+        std::fs::write(manifest_path, serde_json::to_string_pretty(&run.report)? + "\n")
+            .with_context(|| format!("Failed to write manifest {:?}", manifest_path))?;
+    }
+
+    if cli.dry_run.is_enabled() {
+        for diff in &run.dry_run_diffs {
+            print!("{}", diff);
+        }
+        print!("{}", dry_run_preview(&run.dry_run_files, &run.pruned_files));
+        if !run.new_dirs.is_empty() {
+            info!("[DRY RUN] {} new directories would be created:", run.new_dirs.len());
+            for dir in &run.new_dirs {
+                info!("  {:?}", dir);
+            }
+        }
+        info!("=== DRY RUN COMPLETE ===");
+    }
+
+    Ok(())
+}
+
+/// Removes the output files listed in the `--manifest` of a previous run that the current
+/// run, generated in memory, no longer produces. Files containing manual sections are kept
+/// unless `force` is set, and nothing is removed if a listed file resolves outside the output
+/// base. Returns the removed (in dry-run mode, the would-be removed) files.
+fn clean(cli: &Cli, force: bool) -> Result<BTreeSet<PathBuf>> {
+    let manifest_path = cli
+        .manifest
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("--manifest is required"))
+        .fail_with(Failure::Config)?;
+    let previous: GenerationReport = std::fs::read_to_string(manifest_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| Ok(serde_json::from_str(&content)?))
+        .with_context(|| format!("Failed to read manifest {:?}", manifest_path))
+        .fail_with(Failure::Config)?;
+
+    let (config_path, config, output_base) = load_run_config(cli)?;
+    let data_loader = DataLoader::new()
+        .with_timeout(Duration::from_secs(cli.data_timeout))
+        .with_bearer_token(std::env::var(DATA_TOKEN_ENV).ok());
+    let messages =
+        load_message_catalogs(&config, &config_path, &data_loader).fail_with(Failure::Config)?;
+    let mut run = GenerationRun::new(cli, &config, &config_path, data_loader, None, messages);
+    run.in_memory = true;
+    run.generate_all_data(&output_base)?;
+    if !run.errors.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Generation failed with {} error(s), nothing removed:\n  {}",
+            run.errors.len(),
+            run.errors.join("\n  ")
+        ));
+    }
+
+    let base = std::fs::canonicalize(&output_base)
+        .with_context(|| format!("Failed to resolve output base {:?}", output_base))
+        .fail_with(Failure::Config)?;
+    let keep: HashSet<PathBuf> = run.output_files.iter().map(|path| resolve_output(path)).collect();
+    let mut stale = BTreeSet::new();
+    for path in previous.output_paths() {
+        // Files that no longer exist need no cleaning, wherever they were
+        if path.symlink_metadata().is_err() {
+            continue;
+        }
+        let resolved = resolve_output(path);
+        if path.file_name().is_none() || !resolved.starts_with(&base) {
+            return Err(anyhow::anyhow!(
+                "Manifest entry {:?} is outside the output base {:?}, nothing removed",
+                path,
+                output_base
+            ))
+            .fail_with(Failure::Config);
+        }
+        if !keep.contains(&resolved) {
+            stale.insert(path.to_path_buf());
+        }
+    }
+
+    let removed = run
+        .set_generator()?
+        .remove_stale_outputs(stale, force)
+        .map_err(|e| anyhow::anyhow!(e))?;
+    Ok(removed.into_iter().collect())
+}
+
+/// Resolves an output path for comparison: its directory with all links and `..` resolved,
+/// joined with its file name. Paths whose directory doesn't exist are made absolute as they are.
+fn resolve_output(path: &Path) -> PathBuf {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    let resolved_parent = match parent {
+        Some(parent) => std::fs::canonicalize(parent).ok(),
+        None => std::env::current_dir().ok(),
+    };
+    match (resolved_parent, path.file_name()) {
+        (Some(parent), Some(name)) => parent.join(name),
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+/// How long `--watch` waits for further changes before regenerating.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Message to the `--watch` loop: a file system event, or Ctrl-C.
+enum WatchMessage {
+    Event(notify::Result<notify::Event>),
+    Stop,
+}
+
+/// Files and folders whose changes trigger a regeneration with `--watch`, as absolute paths.
+#[derive(Debug, Default, PartialEq)]
+struct WatchSet {
+    /// The config and the other inputs of the run (see `run_inputs`)
+    files: BTreeSet<PathBuf>,
+    /// Template folders, watched recursively
+    folders: BTreeSet<PathBuf>,
+}
+
+impl WatchSet {
+    /// Collects the inputs of a run with the config at `config_path`. A config that doesn't
+    /// load only contributes itself and the data files.
+    fn new(cli: &Cli, config_path: &Path) -> Self {
+        let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let is_local = |path: &&PathBuf| !DataLoader::is_url(&path.to_string_lossy());
+        let mut watch_set = WatchSet::default();
+        watch_set.files.insert(absolute(config_path));
+        watch_set.files.extend(cli.data.iter().filter(is_local).map(|path| absolute(path)));
+        let Ok(config) = TemplateConfig::load(config_path) else {
+            return watch_set;
+        };
+        let inputs = run_inputs(cli, &config, config_path);
+        watch_set.files.extend(inputs.iter().filter(is_local).map(|path| absolute(path)));
+        let root = template_root(cli, &config, config_path);
+        for template_set in &config.templates {
+            // A folder glob is watched from the directory before its first wildcard
+            let folder: PathBuf = Path::new(&template_set.folder)
+                .components()
+                .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+                .collect();
+            watch_set.folders.insert(absolute(&root.join(folder)));
+        }
+        watch_set
+    }
+
+    /// Returns true if a change of `path` affects the run.
+    fn contains(&self, path: &Path) -> bool {
+        self.files.contains(path) || self.folders.iter().any(|folder| path.starts_with(folder))
+    }
+
+    /// Returns the paths to watch: the folders recursively, and the directories of the files
+    /// and folders, so that files and folders that are deleted and recreated (as many editors
+    /// save files) keep being noticed.
+    fn watches(&self) -> BTreeMap<PathBuf, RecursiveMode> {
+        let mut watches: BTreeMap<PathBuf, RecursiveMode> = BTreeMap::new();
+        for folder in &self.folders {
+            if !self.folders.iter().any(|other| other != folder && folder.starts_with(other)) {
+                watches.insert(folder.clone(), RecursiveMode::Recursive);
+            }
+        }
+        let parents = self.files.iter().chain(&self.folders).filter_map(|path| path.parent());
+        for parent in parents {
+            if !self.folders.iter().any(|folder| parent.starts_with(folder)) {
+                watches.insert(parent.to_path_buf(), RecursiveMode::NonRecursive);
+            }
+        }
+        watches
+    }
+
+    /// Watches the paths of `watches` with `watcher`, replacing the `previous` watches, and
+    /// returns them. A watch is renewed even if it didn't change, as watches of deleted
+    /// folders are dropped; paths that don't exist are skipped until the next call.
+    fn register(
+        &self,
+        watcher: &mut dyn Watcher,
+        previous: &BTreeMap<PathBuf, RecursiveMode>,
+    ) -> BTreeMap<PathBuf, RecursiveMode> {
+        for path in previous.keys() {
+            let _ = watcher.unwatch(path);
+        }
+        let watches = self.watches();
+        for (path, mode) in &watches {
+            if let Err(e) = watcher.watch(path, *mode) {
+                debug!("Not watching {:?}: {}", path, e);
+            }
+        }
+        watches
+    }
+}
+
+/// Waits for a change of a path in `watch_set` and returns that path once no further event
+/// followed within `debounce`, so that a burst of changes regenerates once. Returns None on
+/// Ctrl-C.
+fn next_change(receiver: &Receiver<WatchMessage>, watch_set: &WatchSet, debounce: Duration) -> Option<PathBuf> {
+    let mut changed: Option<PathBuf> = None;
+    loop {
+        let message = if changed.is_none() {
+            receiver.recv().ok()?
+        } else {
+            match receiver.recv_timeout(debounce) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => return changed,
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        };
+        match message {
+            WatchMessage::Stop => return None,
+            WatchMessage::Event(Err(e)) => warn!("Failed to watch for changes: {}", e),
+            // Generating reads the templates
+            WatchMessage::Event(Ok(event)) if matches!(event.kind, EventKind::Access(_)) => {}
+            WatchMessage::Event(Ok(event)) => {
+                if let Some(path) = event.paths.into_iter().find(|path| watch_set.contains(path)) {
+                    changed.get_or_insert(path);
+                }
+            }
+        }
+    }
+}
+
+/// Generates, then regenerates with the same arguments on every change of the run's inputs
+/// until Ctrl-C. Failed runs are logged and don't end watching.
+fn watch(cli: &Cli) -> Result<()> {
+    let config_path = cli
+        .config
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("--config is required"))
+        .fail_with(Failure::Config)?;
+    let (sender, receiver) = mpsc::channel();
+    let events = sender.clone();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = events.send(WatchMessage::Event(event));
+    })
+    .context("Failed to watch for changes")?;
+    ctrlc::set_handler(move || {
+        let _ = sender.send(WatchMessage::Stop);
+    })
+    .context("Failed to handle Ctrl-C")?;
+
+    let mut watches = BTreeMap::new();
+    loop {
+        if let Err(e) = generate(cli) {
+            error!("Generation failed: {:?}", e);
+        }
+        log::logger().flush();
+        // The config may have changed the template folders
+        let watch_set = WatchSet::new(cli, &config_path);
+        watches = watch_set.register(&mut watcher, &watches);
+        info!("Watching for changes, Ctrl-C to stop");
+        match next_change(&receiver, &watch_set, WATCH_DEBOUNCE) {
+            Some(path) => info!("{:?} changed, regenerating", path),
+            None => break,
+        }
+    }
+    info!("Stopped watching");
+    Ok(())
+}
+
+/// Lists the absolute paths of the given files, one per line, for `--list-outputs`.
+fn output_list(files: &BTreeSet<PathBuf>) -> Result<String> {
+    let mut list = String::new();
+    for file in files {
+        let path = std::path::absolute(file)
+            .with_context(|| format!("Failed to resolve output path {:?}", file))?;
+        list.push_str(&format!("{}\n", path.display()));
+    }
+    Ok(list)
+}
+
+/// Lists the files a dry run would write, separately from the orphaned files that cleaning
+/// would delete.
+fn dry_run_preview(written: &BTreeSet<PathBuf>, pruned: &BTreeSet<PathBuf>) -> String {
+    let mut preview = String::new();
+    for (title, files) in [("would be written", written), ("would be removed as orphans", pruned)] {
+        if files.is_empty() {
+            continue;
+        }
+        preview.push_str(&format!("[DRY RUN] {} file(s) {}:\n", files.len(), title));
+        for file in files {
+            preview.push_str(&format!("  {}\n", file.display()));
+        }
+    }
+    preview
+}
+
+/// Lists the `top` slowest templates with their total generation time and how often they were
+/// generated.
+fn profile_report(mut timings: Vec<TemplateTiming>, top: usize) -> String {
+    timings.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.template.cmp(&b.template)));
+    let mut report = format!("Slowest templates ({} of {}):\n", top.min(timings.len()), timings.len());
+    for timing in timings.iter().take(top) {
+        report.push_str(&format!(
+            "  {:>10.3?}  {:>5}x  {}\n",
+            timing.total,
+            timing.count,
+            timing.template.display()
+        ));
+    }
+    report
+}
+
+/// Stages `files` with `git add`, grouped by the repository they belong to. Files outside a git
+/// repository or ignored by it are skipped with a warning.
+fn git_add(files: &[PathBuf]) -> Result<()> {
+    let mut repos: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    let mut toplevels: HashMap<PathBuf, Option<PathBuf>> = HashMap::new();
+    for file in files {
+        let file = file.canonicalize().unwrap_or_else(|_| file.clone());
+        let Some(dir) = file.parent().map(Path::to_path_buf) else {
+            continue;
+        };
+        let toplevel = toplevels.entry(dir.clone()).or_insert_with(|| {
+            let output = Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(["rev-parse", "--show-toplevel"])
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+                .filter(|output| output.status.success())?;
+            Some(PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()))
+        });
+        match toplevel {
+            Some(toplevel) => repos.entry(toplevel.clone()).or_default().push(file),
+            None => warn!("Not staging {:?}: not inside a git repository", file),
+        }
+    }
+
+    for (toplevel, files) in repos {
+        // git add refuses ignored files, failing for all the others as well
+        let output = git_with_paths(&toplevel, &["check-ignore", "--stdin", "-z"], &files)?;
+        // check-ignore exits with 1 if none of the files is ignored
+        if !matches!(output.status.code(), Some(0 | 1)) {
+            anyhow::bail!("git check-ignore failed in {:?} ({})", toplevel, output.status);
+        }
+        let ignored: HashSet<&[u8]> = output.stdout.split(|&b| b == 0).collect();
+        let (ignored, files): (Vec<PathBuf>, Vec<PathBuf>) = files
+            .into_iter()
+            .partition(|file| ignored.contains(file.to_string_lossy().as_bytes()));
+        for file in ignored {
+            warn!("Not staging {:?}: ignored by git", file);
+        }
+        if files.is_empty() {
+            continue;
+        }
+
+        info!("Staging {} file(s) in {:?}", files.len(), toplevel);
+        // Literal pathspecs, so that names containing `*` or `[` don't match other files
+        let args = ["--literal-pathspecs", "add", "--pathspec-from-file=-", "--pathspec-file-nul"];
+        let output = git_with_paths(&toplevel, &args, &files)?;
+        if !output.status.success() {
+            anyhow::bail!("git add failed in {:?} ({})", toplevel, output.status);
+        }
+    }
+    Ok(())
+}
+
+/// Runs git in `toplevel` with `files` passed on stdin, separated by NUL, and returns its
+/// output.
+fn git_with_paths(toplevel: &Path, args: &[&str], files: &[PathBuf]) -> Result<std::process::Output> {
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(toplevel)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run git")?;
+    let mut paths = Vec::new();
+    for file in files {
+        paths.extend_from_slice(file.to_string_lossy().as_bytes());
+        paths.push(0);
+    }
+    // git may write output before it has read all paths, so feed them from another thread
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = std::thread::spawn(move || stdin.write_all(&paths));
+    let output = child.wait_with_output().context("Failed to run git")?;
+    writer
+        .join()
+        .expect("writing to git doesn't panic")
+        .context("Failed to pass files to git")?;
+    Ok(output)
+}
+
+/// Timing of repeated generation runs.
+struct BenchStats {
+    runs: usize,
+    files_per_run: usize,
+    min: Duration,
+    median: Duration,
+    max: Duration,
+}
+
+impl BenchStats {
+    /// Generated files per second, based on the median run.
+    fn files_per_second(&self) -> f64 {
+        self.files_per_run as f64 / self.median.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl std::fmt::Display for BenchStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} runs, {} files per run: min {:?}, median {:?}, max {:?}, {:.1} files/s",
+            self.runs,
+            self.files_per_run,
+            self.min,
+            self.median,
+            self.max,
+            self.files_per_second()
+        )
+    }
+}
+
+/// Renders the template at `template` (or read from `input` for `-`) with the context a set of
+/// the config would get, and writes it to `output`. Without `--config`, the config defaults
+/// apply; without `--data`, the data is empty.
+fn render(cli: &Cli, template: &Path, mut input: impl std::io::Read, mut output: impl Write) -> Result<()> {
+    if cli.data.len() > 1 {
+        return Err(anyhow::anyhow!("render takes at most one --data file")).fail_with(Failure::Config);
+    }
+    let (config, config_path) = match cli.config {
+        Some(ref config_path) => (
+            TemplateConfig::load(config_path)
+                .context("Failed to load config")
+                .fail_with(Failure::Config)?,
+            config_path.clone(),
+        ),
+        None => (
+            serde_yaml::from_str("templates: []").expect("empty config is valid"),
+            PathBuf::from("."),
+        ),
+    };
+    let data_loader = DataLoader::new()
+        .with_timeout(Duration::from_secs(cli.data_timeout))
+        .with_bearer_token(std::env::var(DATA_TOKEN_ENV).ok());
+    let data = match cli.data.first() {
+        Some(data_path) => data_loader
+            .clone()
+            .with_format(cli.data_format)
+            .load(&data_path.to_string_lossy(), None)
+            .with_context(|| format!("Failed to load data from {:?}", data_path))
+            .fail_with(Failure::Config)?,
+        None => serde_json::json!({}),
+    };
+
+    let template_str = if template == Path::new("-") {
+        let mut template_str = String::new();
+        input
+            .read_to_string(&mut template_str)
+            .context("Failed to read template from stdin")?;
+        template_str
+    } else {
+        std::fs::read_to_string(template)
+            .with_context(|| format!("Failed to read template {:?}", template))
+            .fail_with(Failure::Config)?
+    };
+
+    let messages =
+        load_message_catalogs(&config, &config_path, &data_loader).fail_with(Failure::Config)?;
+    let run = GenerationRun::new(cli, &config, &config_path, data_loader, None, messages);
+    let data = run.transform_data(&data)?;
+    let generator = run.set_generator()?;
+    let context = run
+        .base_context(&data)?
+        .with_config_extra_data(&config, config_path.parent(), &run.data_loader)
+        .map_err(|e| anyhow::anyhow!(e))
+        .fail_with(Failure::Config)?;
+    let context = compute_globals(context, &generator, false)?.build();
+    let mut rendered = generator
+        .engine()
+        .render_body(&template_str, &context)
+        .map_err(|e| anyhow::anyhow!(e))?;
+    // minijinja drops the final newline of the template; keep it for shell pipelines
+    if template_str.ends_with('\n') {
+        rendered.push('\n');
+    }
+    output.write_all(rendered.as_bytes())?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Generates the config `runs` times for the first `--data` file, keeping the outputs in
+/// memory so that disk writes don't distort the timings.
+fn bench(cli: &Cli, runs: usize) -> Result<BenchStats> {
+    if runs == 0 {
+        return Err(anyhow::anyhow!("--runs must be at least 1")).fail_with(Failure::Config);
+    }
+    check_jobs(cli)?;
+    let config_path = cli
+        .config
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("--config is required"))
+        .fail_with(Failure::Config)?;
+    let data_path = cli
+        .data
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("--data is required"))
+        .fail_with(Failure::Config)?;
+    let config = TemplateConfig::load(&config_path)
+        .context("Failed to load config")
+        .fail_with(Failure::Config)?;
+    let data_loader = DataLoader::new()
+        .with_timeout(Duration::from_secs(cli.data_timeout))
+        .with_bearer_token(std::env::var(DATA_TOKEN_ENV).ok());
+    let data = data_loader
+        .clone()
+        .with_format(cli.data_format)
+        .load(&data_path.to_string_lossy(), None)
+        .with_context(|| format!("Failed to load data from {:?}", data_path))
+        .fail_with(Failure::Config)?;
+    let messages =
+        load_message_catalogs(&config, &config_path, &data_loader).fail_with(Failure::Config)?;
+    let output_base = cli.output.clone().unwrap_or_else(|| {
+        config_path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf()
+    });
+
+    let mut durations = Vec::with_capacity(runs);
+    let mut files_per_run = 0;
+    for _ in 0..runs {
+        let mut run = GenerationRun::new(
+            cli,
+            &config,
+            &config_path,
+            data_loader.clone(),
+            None,
+            messages.clone(),
+        );
+        run.in_memory = true;
+        let start = Instant::now();
+        run.generate_for_data(&data, &output_base)?;
+        durations.push(start.elapsed());
+        files_per_run = run.generated_files;
+    }
+    durations.sort();
+    Ok(BenchStats {
+        runs,
+        files_per_run,
+        min: durations[0],
+        median: durations[runs / 2],
+        max: durations[runs - 1],
+    })
+}
+
+/// Loads and compiles the config's JSON Schema. Returns it with its path.
+fn load_schema<'c>(
+    config: &'c TemplateConfig,
+    config_path: &Path,
+    data_loader: &DataLoader,
+) -> Result<(DataSchema, &'c str)> {
+    let schema_path = config
+        .schema
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("--check-data requires a `schema` in the config"))
+        .fail_with(Failure::Config)?;
+    let schema = data_loader
+        .load(schema_path, config_path.parent())
+        .with_context(|| format!("Failed to load schema from {:?}", schema_path))
+        .fail_with(Failure::Config)?;
+    let schema = DataSchema::new(&schema).fail_with(Failure::Config)?;
+    Ok((schema, schema_path))
+}
+
+/// Validates data against the config's JSON Schema, logging every violation.
+fn check_data(
+    schema: &DataSchema,
+    schema_path: &str,
+    data: &serde_json::Value,
+    data_path: &Path,
+) -> Result<()> {
+    let violations = schema.validate(data);
+    if violations.is_empty() {
+        info!("Data {:?} conforms to schema {:?}", data_path, schema_path);
+        return Ok(());
+    }
+    for violation in &violations {
+        let pointer = if violation.pointer.is_empty() {
+            "(root)"
+        } else {
+            &violation.pointer
+        };
+        error!("{:?} {}: {}", data_path, pointer, violation.message);
+    }
+    Err(anyhow::anyhow!(
+        "Data {:?} does not conform to schema {:?}: {} violation(s)",
+        data_path,
+        schema_path,
+        violations.len()
+    ))
+    .fail_with(Failure::Validation)
+}
+
+/// Parses a `--migrate-markers` value of the form `OLD_START,OLD_END`.
+fn parse_marker_pair(value: &str) -> std::result::Result<(String, String), String> {
+    match value.split_once(',') {
+        Some((start, end)) if !start.trim().is_empty() && !end.trim().is_empty() => {
+            Ok((start.trim().to_string(), end.trim().to_string()))
+        }
+        _ => Err(format!("expected OLD_START,OLD_END, got '{}'", value)),
+    }
+}
+
+/// Parses a `--set-file` value of the form `KEY=PATH`.
+fn parse_set_file(value: &str) -> std::result::Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((key, path)) if !key.is_empty() && !path.is_empty() => {
+            Ok((key.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(format!("expected KEY=PATH, got '{}'", value)),
+    }
+}
+
+fn parse_file_mode(value: &str) -> std::result::Result<PermissionRule, String> {
+    let (pattern, mode) = match value.rsplit_once('=') {
+        Some((pattern, mode)) if !pattern.is_empty() => (pattern, mode),
+        Some(_) => return Err(format!("expected [PATTERN=]MODE, got '{}'", value)),
+        None => ("*", value),
+    };
+    let rule = PermissionRule {
+        pattern: pattern.to_string(),
+        mode: Some(mode.to_string()),
+        owner: None,
+    };
+    rule.file_mode()?;
+    Ok(rule)
+}
+
+fn parse_file_owner(value: &str) -> std::result::Result<PermissionRule, String> {
+    let rule = PermissionRule {
+        pattern: "*".to_string(),
+        mode: None,
+        owner: Some(value.to_string()),
+    };
+    rule.file_owner()?;
+    Ok(rule)
+}
+
+/// Returns the name of the per-data output directory: the data file name without its last
+/// extension.
+fn data_stem(data_path: &Path) -> Result<String> {
+    let location = data_path.to_string_lossy();
+    let name = location
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(&location)
+        .trim_end_matches('/')
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default();
+    match Path::new(name).file_stem() {
+        Some(stem) => Ok(stem.to_string_lossy().into_owned()),
+        None => Err(anyhow::anyhow!(
+            "Cannot derive an output directory name from {:?}",
+            data_path
+        )),
+    }
+}
+
+/// Returns the per-data output directory name of each data file, failing if two data files
+/// would share a directory.
+fn data_stems(data_paths: &[PathBuf]) -> Result<Vec<String>> {
+    let mut seen = HashMap::new();
+    let mut stems = Vec::with_capacity(data_paths.len());
+    for data_path in data_paths {
+        let stem = data_stem(data_path)?;
+        if let Some(other) = seen.insert(stem.clone(), data_path) {
+            return Err(anyhow::anyhow!(
+                "Data files {:?} and {:?} would both generate into {:?}",
+                other,
+                data_path,
+                stem
+            ));
+        }
+        stems.push(stem);
+    }
+    Ok(stems)
+}
+
+/// Returns the directory template set folders are resolved against: `--template-root`, else
+/// `template_root` relative to the config file, else the config file's directory.
+fn template_root(cli: &Cli, config: &TemplateConfig, config_path: &Path) -> PathBuf {
+    let config_dir = config_path.parent().unwrap_or(Path::new("."));
+    match (&cli.template_root, &config.template_root) {
+        (Some(root), _) => root.clone(),
+        (None, Some(root)) => config_dir.join(root),
+        (None, None) => config_dir.to_path_buf(),
+    }
+}
+
+/// Returns the newest modification time of the config, data, extra data and message catalog
+/// files, or None if one of them has none (e.g. a URL).
+fn inputs_modified(cli: &Cli, config: &TemplateConfig, config_path: &Path) -> Option<SystemTime> {
+    run_inputs(cli, config, config_path)
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .try_fold(SystemTime::UNIX_EPOCH, |newest, modified| Some(newest.max(modified?)))
+}
+
+/// Returns the inputs of a run besides the templates: the config, data, `--set-file`,
+/// `extra_data`, set data, message catalog and macros files.
+fn run_inputs(cli: &Cli, config: &TemplateConfig, config_path: &Path) -> Vec<PathBuf> {
+    let config_dir = config_path.parent().unwrap_or(Path::new("."));
+    // URLs are kept as they are, paths are relative to the config file
+    let resolve = |location: &String| {
+        if DataLoader::is_url(location) {
+            PathBuf::from(location)
+        } else {
+            config_dir.join(location)
+        }
+    };
+    let mut inputs = vec![config_path.to_path_buf()];
+    inputs.extend(cli.data.iter().cloned());
+    inputs.extend(cli.set_file.iter().map(|(_, path)| path.clone()));
+    inputs.extend(
+        config
+            .extra_data
+            .iter()
+            .filter_map(|extra| extra.path.as_ref())
+            .map(resolve),
+    );
+    inputs.extend(config.templates.iter().filter_map(|set| set.data.as_ref()).map(resolve));
+    if let Some(ref i18n) = config.i18n {
+        inputs.extend(i18n.catalogs.values().map(resolve));
+    }
+    inputs.extend(config.macros.iter().map(|path| config_dir.join(path)));
+    inputs
+}
+
+/// Makefile rules listing, for each generated output, its templates and the run's inputs.
+fn deps_file(
+    output_templates: &BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+    inputs: &[PathBuf],
+) -> String {
+    // Spaces, `#` and `$` are special in make rules
+    let escape = |path: &Path| {
+        path.to_string_lossy()
+            .replace('\\', "/")
+            .replace(' ', "\\ ")
+            .replace('#', "\\#")
+            .replace('$', "$$")
+    };
+    let mut deps = String::new();
+    for (output, templates) in output_templates {
+        deps.push_str(&escape(output));
+        deps.push(':');
+        for input in templates.iter().chain(inputs) {
+            deps.push_str(" \\\n  ");
+            deps.push_str(&escape(input));
+        }
+        deps.push('\n');
+    }
+    deps
+}
+
+/// Loads the message catalogs listed under `i18n` in the config.
+fn load_message_catalogs(
+    config: &TemplateConfig,
+    config_path: &Path,
+    data_loader: &DataLoader,
+) -> Result<Option<MessageCatalogs>> {
+    let Some(ref i18n) = config.i18n else {
+        return Ok(None);
+    };
+    let mut catalogs = MessageCatalogs::new(&i18n.locale_var)
+        .with_default_locale(i18n.default_locale.clone());
+    for (locale, path) in &i18n.catalogs {
+        let catalog = data_loader
+            .load(path, config_path.parent())
+            .with_context(|| format!("Failed to load message catalog {:?}", path))?;
+        catalogs = catalogs.with_catalog(locale, catalog);
+    }
+    Ok(Some(catalogs))
+}
+
+/// State shared by the generation of all template sets in one invocation.
+struct GenerationRun<'a> {
+    cli: &'a Cli,
+    config: &'a TemplateConfig,
+    config_path: &'a Path,
+    data_loader: DataLoader,
+    archive: Option<Arc<ArchiveWriter>>,
+    messages: Option<MessageCatalogs>,
+    missing_keys: BTreeSet<String>,
+    explanations: Vec<String>,
+    /// Directories that would be created, in dry-run mode
+    new_dirs: BTreeSet<PathBuf>,
+    /// Errors collected with `--keep-going`
+    errors: Vec<String>,
+    /// Keep generated files in memory instead of writing them
+    in_memory: bool,
+    /// Number of files generated so far
+    generated_files: usize,
+    /// Files written to disk so far
+    written_files: Vec<PathBuf>,
+    /// Files that would be written, in dry-run mode
+    dry_run_files: BTreeSet<PathBuf>,
+    /// Unified diffs of the outputs, with `--dry-run=diff`
+    dry_run_diffs: Vec<String>,
+    /// Files generated in memory, with `--list-outputs` or `clean`
+    output_files: BTreeSet<PathBuf>,
+    /// What was done with each output file, with `--manifest`
+    report: GenerationReport,
+    /// Template files of each generated output, with `--emit-deps`
+    output_templates: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+    /// Orphaned files removed by `clean_output`/`clean_item_outputs`, or that would be removed
+    pruned_files: BTreeSet<PathBuf>,
+    /// Generation time by template, with `--profile-template`
+    template_timings: HashMap<PathBuf, TemplateTiming>,
+    /// Source of the config's macros file, read by the first `set_generator`
+    macros: OnceLock<String>,
+}
+
+impl<'a> GenerationRun<'a> {
+    fn new(
+        cli: &'a Cli,
+        config: &'a TemplateConfig,
+        config_path: &'a Path,
+        data_loader: DataLoader,
+        archive: Option<Arc<ArchiveWriter>>,
+        messages: Option<MessageCatalogs>,
+    ) -> Self {
+        Self {
+            cli,
+            config,
+            config_path,
+            data_loader,
+            archive,
+            messages,
+            missing_keys: BTreeSet::new(),
+            explanations: Vec::new(),
+            new_dirs: BTreeSet::new(),
+            errors: Vec::new(),
+            in_memory: cli.list_outputs,
+            generated_files: 0,
+            written_files: Vec::new(),
+            dry_run_files: BTreeSet::new(),
+            dry_run_diffs: Vec::new(),
+            output_files: BTreeSet::new(),
+            report: GenerationReport::default(),
+            output_templates: BTreeMap::new(),
+            pruned_files: BTreeSet::new(),
+            template_timings: HashMap::new(),
+            macros: OnceLock::new(),
+        }
+    }
+
+    /// Generates for each --data file into `output_base`, or with --per-data-output into a
+    /// folder per data file below it.
+    fn generate_all_data(&mut self, output_base: &Path) -> Result<()> {
+        let cli = self.cli;
+        let stems = if cli.per_data_output.is_some() {
+            Some(data_stems(&cli.data).fail_with(Failure::Config)?)
+        } else {
+            None
+        };
+        // Compiled once for all data files
+        let schema = if cli.check_data {
+            Some(load_schema(self.config, self.config_path, &self.data_loader)?)
+        } else {
+            None
+        };
+        for (index, data_path) in cli.data.iter().enumerate() {
+            info!("Loading data from {:?}", data_path);
+            let data = self
+                .data_loader
+                .clone()
+                .with_format(cli.data_format)
+                .load(&data_path.to_string_lossy(), None)
+                .with_context(|| format!("Failed to load data from {:?}", data_path))
+                .fail_with(Failure::Config)?;
+            if let Some((ref schema, schema_path)) = schema {
+                check_data(schema, schema_path, &data, data_path)?;
+            }
+
+            let data_output = match stems {
+                Some(ref stems) => {
+                    let stem = &stems[index];
+                    info!("Generating for data {:?} into {:?}", data_path, output_base.join(stem));
+                    output_base.join(stem)
+                }
+                None => output_base.to_path_buf(),
+            };
+            self.generate_for_data(&data, &data_output)?;
+        }
+        Ok(())
+    }
+
+    /// Generates all enabled template sets for one data document into `output_base`.
+    fn generate_for_data(&mut self, data: &serde_json::Value, output_base: &Path) -> Result<()> {
+        let cli = self.cli;
+        let config = self.config;
+        let config_path = self.config_path;
+
+        let template_root = template_root(cli, config, config_path);
+
+        let data = self.transform_data(data)?;
+        let data = data.as_ref();
+        let base_context = self.base_context(data)?;
+
+        // With --check-first, all sets are compiled before anything is generated. The
+        // generators are kept, so that generation reuses the compiled templates.
+        let mut checked: HashMap<usize, FileGenerator> = HashMap::new();
+        if cli.check_first {
+            let mut errors = Vec::new();
+            for (index, template_set) in config.templates.iter().enumerate() {
+                if !is_selected(template_set, cli) {
+                    continue;
+                }
+                let generator = self.set_generator()?;
+                let folders =
+                    template_set.expand_folder(&template_root).fail_with(Failure::Config)?;
+                for (template_folder, _) in folders {
+                    errors.extend(
+                        generator
+                            .check_templates(&template_folder)
+                            .map_err(|e| anyhow::anyhow!(e))?,
+                    );
+                }
+                checked.insert(index, generator);
+            }
+            if !errors.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Template check failed with {} error(s):\n  {}",
+                    errors.len(),
+                    errors.join("\n  ")
+                ))
+                .fail_with(Failure::Validation);
+            }
+        }
+
+        for (index, template_set) in config.templates.iter().enumerate() {
+            let label = set_label(template_set);
+            if !template_set.enabled {
+                self.explain(|| format!("skip set {}: disabled (enabled: false)", label));
+                continue;
+            }
+
+            // Filter check
+            if let Some(ref name) = template_set.name {
+                if let Some(reason) = filter_reason(name, &cli.include, &cli.exclude) {
+                    info!("Skipping template set: {}", name);
+                    self.explain(|| format!("skip set {}: {}", label, reason));
+                    continue;
+                }
+            }
+            self.explain(|| format!("generate set {} from {:?}", label, template_set.folder));
+            let _log_scope = templify::logging::set_scope(index, &label);
+
+            let set_output_path = if let Some(ref out) = template_set.output {
+                output_base.join(out)
+            } else {
+                output_base.to_path_buf()
+            };
+
+            // A folder glob generates each matching folder into the output subdirectory
+            // named after the wildcard matches
+            let folders = template_set
+                .expand_folder(&template_root)
+                .with_context(|| format!("Failed to expand folder {:?}", template_set.folder))
+                .fail_with(Failure::Config)?;
+            if folders.is_empty() {
+                warn!("Template set {}: folder {:?} matched nothing", label, template_set.folder);
+            }
+
+            let mut generator = match checked.remove(&index) {
+                Some(generator) => generator,
+                None => self.set_generator()?,
+            };
+            // Outputs are recorded for the conflict policy to tell them from hand-written files
+            let set_key = template_set.name.as_deref().unwrap_or(&template_set.folder);
+            let records_outputs = (template_set.clean_output
+                || cli.on_conflict != ConflictPolicy::Overwrite)
+                && self.archive.is_none()
+                && !self.in_memory;
+            if records_outputs && cli.on_conflict != ConflictPolicy::Overwrite {
+                let previous = recorded_outputs(&set_output_path, set_key)
+                    .map_err(|e| anyhow::anyhow!(e))
+                    .fail_with(Failure::Config)?;
+                generator = generator.with_previous_outputs(previous);
+            }
+
+            let (set_data, set_contexts) =
+                self.set_contexts(&generator, template_set, data, &base_context)?;
+            for (template_folder, captures) in &folders {
+                let folder_output_path = captures
+                    .iter()
+                    .fold(set_output_path.clone(), |path, capture| path.join(capture));
+                if template_set.is_folder_glob() {
+                    self.explain(|| {
+                        format!("expand {:?} -> {:?}", template_folder, folder_output_path)
+                    });
+                }
+                for set_context in &set_contexts {
+                    let result = self.generate_set(
+                        &generator,
+                        template_set,
+                        template_folder,
+                        &folder_output_path,
+                        set_context.clone(),
+                        &set_data,
+                    );
+                    match result {
+                        Err(e) if cli.keep_going => {
+                            error!("Template set {} failed: {:#}", label, e);
+                            self.errors.push(format!("[config] set {}: {:#}", label, e));
+                        }
+                        result => result?,
+                    }
+                }
+            }
+
+            // Files that failed with --keep-going are not orphans, so don't clean or record
+            // after errors
+            if records_outputs && generator.errors().is_empty() {
+                let report = generator.report();
+                if template_set.clean_output {
+                    let removed = generator
+                        .clean_set_outputs(&set_output_path, set_key, &report)
+                        .map_err(|e| anyhow::anyhow!(e))?;
+                    self.explain(|| format!("clean set {}: removed {:?}", label, removed));
+                    self.pruned_files.extend(removed);
+                } else {
+                    generator
+                        .record_set_outputs(&set_output_path, set_key, &report)
+                        .map_err(|e| anyhow::anyhow!(e))?;
+                }
+            }
+
+            self.missing_keys.extend(generator.engine().missing_keys());
+            self.explanations.extend(generator.explanations());
+            self.new_dirs.extend(generator.new_dirs());
+            self.generated_files += generator.generated_files().len();
+            self.written_files.extend(generator.written_files());
+            if cli.dry_run.is_enabled() {
+                self.dry_run_files.extend(generator.generated_files());
+                self.dry_run_diffs.extend(generator.dry_run_diffs());
+            }
+            if self.in_memory {
+                self.output_files.extend(generator.generated_files());
+            }
+            if cli.manifest.is_some() {
+                self.report.extend(generator.report());
+            }
+            if cli.emit_deps.is_some() {
+                for (output, templates) in generator.output_templates() {
+                    self.output_templates.entry(output).or_default().extend(templates);
+                }
+            }
+            for timing in generator.template_timings() {
+                self.template_timings
+                    .entry(timing.template.clone())
+                    .and_modify(|total| {
+                        total.total += timing.total;
+                        total.count += timing.count;
+                    })
+                    .or_insert(timing);
+            }
+            self.errors
+                .extend(generator.errors().iter().map(|e| e.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Applies the config's `transforms` to the data.
+    fn transform_data<'d>(&self, data: &'d serde_json::Value) -> Result<Cow<'d, serde_json::Value>> {
+        if self.config.transforms.is_empty() {
+            return Ok(Cow::Borrowed(data));
+        }
+        let mut value = data.clone();
+        apply_transforms(&mut value, &self.config.transforms, &TemplateEngine::new())
+            .map_err(|e| anyhow::anyhow!("Failed to transform data: {}", e))
+            .fail_with(Failure::Config)?;
+        Ok(Cow::Owned(value))
+    }
+
+    /// Builds the context layers shared by all template sets using the given data.
+    fn base_context(&self, data: &serde_json::Value) -> Result<ContextBuilder> {
+        let cli = self.cli;
+        let config = self.config;
+        let mut context = ContextBuilder::new().with_config(config, data);
+        if let Some(ref key) = cli.data_key {
+            context = context.with_data_key(key, data);
+        }
+        for (key, path) in &cli.set_file {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read --set-file {}={:?}", key, path))
+                .fail_with(Failure::Config)?;
+            context = context.with_extra_data(key, &serde_json::Value::String(content));
+        }
+        Ok(context)
+    }
+
+    /// Builds the contexts of a template set, one per target or a single one without targets:
+    /// the set's own `data` or the run's `data`, set-level globals, the config's `extra_data`
+    /// (for sets without `iterate`) and the computed globals that don't read iteration variables. Returns them with the
+    /// data the set iterates over. Used by both generation and validation.
+    fn set_contexts<'d>(
+        &self,
+        generator: &FileGenerator,
+        template_set: &'d TemplateSet,
+        data: &'d serde_json::Value,
+        base_context: &ContextBuilder,
+    ) -> Result<(Cow<'d, serde_json::Value>, Vec<ContextBuilder>)> {
+        let config = self.config;
+        let config_path = self.config_path;
+
+        // A set with its own data file uses it instead of the run's data
+        let (data, mut context) = match template_set.data {
+            Some(ref path) => {
+                let set_data = self
+                    .data_loader
+                    .load(path, config_path.parent())
+                    .with_context(|| {
+                        format!(
+                            "Failed to load data of set {} from {:?}",
+                            set_label(template_set),
+                            path
+                        )
+                    })
+                    .fail_with(Failure::Config)?;
+                let set_data = self.transform_data(&set_data)?.into_owned();
+                let context = self.base_context(&set_data)?;
+                (Cow::Owned(set_data), context)
+            }
+            None => (Cow::Borrowed(data), base_context.clone()),
+        };
+
+        // Set-level globals override top-level globals for this set only
+        if let Some(ref set_globals) = template_set.globals {
+            let mut globals = config.globals.clone().unwrap_or_default();
+            globals.extend(set_globals.clone());
+            context = context.with_globals(&globals);
+        }
+        // Only sets without `iterate` get the extra data
+        if template_set.iterate.is_none() {
+            context = context
+                .with_config_extra_data(config, config_path.parent(), &self.data_loader)
+                .map_err(|e| anyhow::anyhow!(e))
+                .fail_with(Failure::Config)?;
+        }
+
+        // Render the set once per target, or once if no targets are configured
+        let contexts = if template_set.targets.is_empty() {
+            vec![context]
+        } else {
+            template_set
+                .targets
+                .iter()
+                .map(|target| context.clone().with_target(target))
+                .collect()
+        };
+        // Globals computed from iteration variables are evaluated per item
+        let contexts = contexts
+            .into_iter()
+            .map(|context| compute_globals(context, generator, template_set.iterate.is_some()))
+            .collect::<Result<_>>()?;
+        Ok((data, contexts))
+    }
+
+    /// Creates the generator for one template set, configured from the command line and config.
+    fn set_generator(&self) -> Result<FileGenerator> {
+        let cli = self.cli;
+        let config = self.config;
+
+        let engine = if config.sandbox {
+            TemplateEngine::sandboxed()
+        } else {
+            TemplateEngine::new()
+        };
+        let mut engine = engine.with_whitespace_control(
+            config.whitespace.trim_blocks,
+            config.whitespace.lstrip_blocks,
+        );
+        if cli.allow_missing_data_keys {
+            engine.allow_missing_keys(&cli.missing_key_placeholder);
+        }
+        if let Some(ref messages) = self.messages {
+            engine.set_message_catalogs(messages.clone());
+        }
+        if let Some(seed) = cli.seed {
+            engine.set_seed(seed);
+        }
+        if let Some(ref macros) = config.macros {
+            let path = self.config_path.parent().unwrap_or(Path::new(".")).join(macros);
+            let source = match self.macros.get() {
+                Some(source) => source,
+                None => {
+                    let source = std::fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read macros from {:?}", path))
+                        .fail_with(Failure::Config)?;
+                    self.macros.get_or_init(|| source)
+                }
+            };
+            engine
+                .set_macros(source.clone())
+                .map_err(|e| anyhow::anyhow!("Invalid macros {:?}: {}", path, e))
+                .fail_with(Failure::Config)?;
+        }
+        let manual_section_manager = ManualSectionManager::try_new(config.manual_sections.clone())
+            .map_err(|e| anyhow::anyhow!(e))
+            .fail_with(Failure::Config)?;
+
+        // Initialize formatter
+        let formatter_manager = FormatterManager::new(
+            config.format.clone(),
+            manual_section_manager.clone(),
+        )
+        .with_running_commands(cli.commands.clone());
+
+        let mut generator = FileGenerator::new(engine, manual_section_manager, cli.dry_run)
+            .with_conflict_policy(cli.on_conflict)
+            .with_output_mode(cli.output_mode)
+            .with_explain(cli.explain)
+            .with_keep_going(cli.keep_going)
+            .with_encodings(config.encodings.clone())
+            .with_permissions(
+                cli.file_mode
+                    .iter()
+                    .chain(&cli.file_owner)
+                    .chain(&config.permissions)
+                    .cloned()
+                    .collect(),
+            )
+            .with_max_template_size(config.max_template_size)
+            .with_follow_symlinks(config.follow_symlinks)
+            .with_rename_map(&config.rename_map)
+            .map_err(|e| anyhow::anyhow!(e))
+            .fail_with(Failure::Config)?
+            .with_formatter(formatter_manager);
+        if let Some(path_case) = config.path_case {
+            generator = generator.with_path_case(path_case);
+        }
+        if let Some(ref header_comment) = config.header_comment {
+            generator = generator.with_header_comment(header_comment.clone());
+        }
+        if let Some(ref archive) = self.archive {
+            generator = generator.with_archive(archive.clone());
+        }
+        if self.in_memory {
+            generator = generator.with_memory_output();
+        }
+        if cli.profile_template.is_some() {
+            generator = generator.with_profile();
+        }
+        if cli.incremental {
+            match inputs_modified(cli, config, self.config_path) {
+                Some(modified) => generator = generator.with_incremental(modified),
+                None => warn!("--incremental ignored: data or config without a modification time"),
+            }
+        }
+        Ok(generator)
+    }
+
+    /// Records a decision for `--explain`.
+    fn explain(&mut self, message: impl FnOnce() -> String) {
+        if self.cli.explain {
+            self.explanations.push(message());
+        }
+    }
+
+    /// Generates one template set, once per iteration item or once for static sets.
+    fn generate_set(
+        &mut self,
+        generator: &FileGenerator,
+        template_set: &TemplateSet,
+        template_folder: &Path,
+        set_output_path: &Path,
+        set_context: ContextBuilder,
+        data: &serde_json::Value,
+    ) -> Result<()> {
+        let config = self.config;
+
+        if let Some(ref iterate) = template_set.iterate {
+            let pattern = IterationEvaluator::parse(iterate)
+                .map_err(|e| anyhow::anyhow!("Failed to parse iteration: {}", e))
+                .fail_with(Failure::Config)?;
+            let info = match pattern {
+                IterationPattern::Simple(info) => info,
+                IterationPattern::Nested(levels) => {
+                    let contexts =
+                        self.nested_item_contexts(generator, template_set, &levels, &set_context, data)?;
+                    return self.generate_items(
+                        generator,
+                        template_set,
+                        template_folder,
+                        set_output_path,
+                        &contexts,
+                    );
+                }
+                IterationPattern::Array(_) => {
+                    return Err(anyhow::anyhow!("Unsupported iteration expression '{}'", iterate))
+                        .fail_with(Failure::Config);
+                }
+            };
+
+            let path = IterationEvaluator::evaluate_path(&info.expr);
+            let items = data.pointer(&path);
+
+            if let Some(serde_json::Value::Array(items)) = items {
+                let max_items = template_set.max_items.or(config.max_items);
+                if let Some(max_items) =
+                    max_items.filter(|&max| items.len() > max && !self.cli.allow_large)
+                {
+                    return Err(anyhow::anyhow!(
+                        "Iteration expression '{}' has {} items, more than max_items ({}); \
+                         raise max_items or pass --allow-large",
+                        info.expr,
+                        items.len(),
+                        max_items
+                    ))
+                    .fail_with(Failure::Config);
+                }
+                let mut kept = Vec::with_capacity(items.len());
+                for (index, item) in items.iter().enumerate() {
+                    let item_context = set_context.clone().with_iteration_var(&info.var, item);
+
+                    // Skip items filtered out by an `if`/`unless` condition
+                    if info.condition.is_some()
+                        && !info
+                            .keeps_item(generator.engine(), &item_context.build())
+                            .map_err(|e| anyhow::anyhow!(e))?
+                    {
+                        self.explain(|| {
+                            format!("skip item {} of {}: {}", index, info.expr, info.skip_reason())
+                        });
+                        continue;
+                    }
+                    kept.push(item_context);
+                }
+                // `loop` counts the kept items, as in a Jinja loop with a filter
+                let length = kept.len();
+                let contexts = kept
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index0, item_context)| {
+                        let position = LoopPosition { index0, length };
+                        let item_context = item_context.with_loop(&[(&info.var, position)]);
+                        Ok(compute_globals(item_context, generator, false)?.build())
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                self.generate_items(
+                    generator,
+                    template_set,
+                    template_folder,
+                    set_output_path,
+                    &contexts,
+                )?;
+            } else {
+                return Err(anyhow::anyhow!(
+                    "Iteration expression '{}' did not resolve to an array",
+                    info.expr
+                ))
+                .fail_with(Failure::Config);
+            }
+        } else {
+            // Static generation
+            generate_folder(
+                generator,
+                template_set,
+                template_folder,
+                set_output_path,
+                &set_context.build(),
+            )
+            .map_err(|e| anyhow::anyhow!(e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Builds the contexts of a nested iteration such as `module in modules >> component in
+    /// module.components`, one per combination of items, binding the variable of every level.
+    /// A branch whose inner level doesn't resolve to an array is logged as an error and skipped.
+    fn nested_item_contexts(
+        &mut self,
+        generator: &FileGenerator,
+        template_set: &TemplateSet,
+        levels: &[IterationInfo],
+        set_context: &ContextBuilder,
+        data: &serde_json::Value,
+    ) -> Result<Vec<HashMap<String, serde_json::Value>>> {
+        let bind = |bindings: &[(String, serde_json::Value)]| {
+            bindings
+                .iter()
+                .fold(set_context.clone(), |context, (var, item)| {
+                    context.with_iteration_var(var, item)
+                })
+        };
+        let mut skipped = Vec::new();
+        let expansion = IterationEvaluator::expand_nested(levels, data, |level, index, bindings| {
+            let keep = level.keeps_item(generator.engine(), &bind(bindings).build())?;
+            if !keep {
+                skipped.push(format!("skip item {} of {}: {}", index, level.expr, level.skip_reason()));
+            }
+            Ok(keep)
+        });
+        for message in skipped {
+            self.explain(|| message);
+        }
+        let expansion = match expansion {
+            Ok(expansion) => expansion,
+            Err(IterationError::DataPathNotFound(expr)) => {
+                return Err(anyhow::anyhow!(
+                    "Iteration expression '{}' did not resolve to an array",
+                    expr
+                ))
+                .fail_with(Failure::Config);
+            }
+            Err(e) => return Err(anyhow::anyhow!(e)),
+        };
+        let iterate = template_set.iterate.as_deref().unwrap_or_default();
+        for e in &expansion.errors {
+            error!("Skipping a branch of iteration '{}': {}", iterate, e);
+        }
+
+        let max_items = template_set.max_items.or(self.config.max_items);
+        if let Some(max_items) =
+            max_items.filter(|&max| expansion.combinations.len() > max && !self.cli.allow_large)
+        {
+            return Err(anyhow::anyhow!(
+                "Iteration expression '{}' has {} item combinations, more than max_items ({}); \
+                 raise max_items or pass --allow-large",
+                iterate,
+                expansion.combinations.len(),
+                max_items
+            ))
+            .fail_with(Failure::Config);
+        }
+        expansion
+            .combinations
+            .iter()
+            .map(|combination| {
+                let positions: Vec<(&str, LoopPosition)> = combination
+                    .bindings
+                    .iter()
+                    .zip(&combination.positions)
+                    .map(|((var, _), position)| (var.as_str(), *position))
+                    .collect();
+                let context = bind(&combination.bindings).with_loop(&positions);
+                Ok(compute_globals(context, generator, false)?.build())
+            })
+            .collect()
+    }
+
+    /// Generates the template set once per item context, on `--jobs` threads if possible.
+    fn generate_items(
+        &mut self,
+        generator: &FileGenerator,
+        template_set: &TemplateSet,
+        template_folder: &Path,
+        set_output_path: &Path,
+        contexts: &[HashMap<String, serde_json::Value>],
+    ) -> Result<()> {
+        // Per-item cleaning needs the files of each item, so it stays serial
+        let jobs = self.cli.jobs.min(contexts.len());
+        if jobs > 1 && !template_set.clean_item_outputs {
+            return generate_items_parallel(
+                generator,
+                template_set,
+                template_folder,
+                set_output_path,
+                contexts,
+                jobs,
+            )
+            .map_err(|e| anyhow::anyhow!(e));
+        }
+
+        for context in contexts {
+            let generated_before = generator.generated_count();
+            let errors_before = generator.errors().len();
+            generate_folder(generator, template_set, template_folder, set_output_path, context)
+                .map_err(|e| anyhow::anyhow!(e))?;
+
+            // A file that failed with --keep-going is not stale, so don't clean after errors
+            if template_set.clean_item_outputs
+                && self.archive.is_none()
+                && !self.in_memory
+                && generator.errors().len() == errors_before
+            {
+                let keep: HashSet<PathBuf> =
+                    generator.generated_files_since(generated_before).into_iter().collect();
+                for dir in generator
+                    .item_output_dirs(template_folder, set_output_path, context)
+                    .map_err(|e| anyhow::anyhow!(e))?
+                {
+                    let removed = generator
+                        .clean_stale_files(&dir, &keep)
+                        .map_err(|e| anyhow::anyhow!(e))?;
+                    self.pruned_files.extend(removed);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Generates a template folder into `output_path`, or into the single file named by the set's
+/// `concat_output` (a template string) below `output_path`.
+fn generate_folder<T: Serialize>(
+    generator: &FileGenerator,
+    template_set: &TemplateSet,
+    template_folder: &Path,
+    output_path: &Path,
+    context: &T,
+) -> Result<(), String> {
+    let Some(ref concat_output) = template_set.concat_output else {
+        return generator.generate(template_folder, output_path, context);
+    };
+    let file_name = generator.engine().render_string(concat_output, context)?;
+    let separator = template_set.concat_separator.as_deref().unwrap_or("");
+    generator.generate_concat(template_folder, &output_path.join(file_name), separator, context)
+}
+
+/// Evaluates the computed globals of a context (see `ContextBuilder::with_computed_globals`).
+fn compute_globals(
+    context: ContextBuilder,
+    generator: &FileGenerator,
+    defer_undefined: bool,
+) -> Result<ContextBuilder> {
+    context
+        .with_computed_globals(generator.engine(), defer_undefined)
+        .map_err(|e| anyhow::anyhow!(e))
+        .fail_with(Failure::Config)
+}
+
+/// Generates the items of an iteration set on `jobs` scoped threads sharing one generator.
+///
+/// Workers pick items in order from a shared counter and stop picking once any item fails;
+/// the error of the earliest failed item is returned, as in a serial run.
+fn generate_items_parallel<T: Serialize + Sync>(
+    generator: &FileGenerator,
+    template_set: &TemplateSet,
+    template_folder: &Path,
+    output_path: &Path,
+    contexts: &[T],
+    jobs: usize,
+) -> Result<(), String> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let errors: Mutex<Vec<(usize, String)>> = Mutex::new(Vec::new());
+    let log_scope = templify::logging::current_scope();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                let _log_scope = templify::logging::enter_scope(log_scope.clone());
+                while !failed.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(context) = contexts.get(index) else {
+                        break;
+                    };
+                    if let Err(e) =
+                        generate_folder(generator, template_set, template_folder, output_path, context)
+                    {
+                        failed.store(true, Ordering::Relaxed);
+                        errors.lock().unwrap().push((index, e));
+                    }
+                }
+            });
+        }
+    });
+    match errors.into_inner().unwrap().into_iter().min_by_key(|(index, _)| *index) {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Returns true if a template set is enabled and not filtered out by --include/--exclude.
+fn is_selected(template_set: &TemplateSet, cli: &Cli) -> bool {
+    template_set.enabled
+        && template_set
+            .name
+            .as_ref()
+            .is_none_or(|name| filter_reason(name, &cli.include, &cli.exclude).is_none())
+}
+
+/// Returns why a template set is filtered out by the include/exclude patterns, if it is.
+fn filter_reason(name: &str, include: &[String], exclude: &[String]) -> Option<String> {
+    // If include patterns are specified, name must match at least one
+    if !include.is_empty() && !include.iter().any(|pattern| matches_pattern(name, pattern)) {
+        return Some(format!("not matched by any --include pattern {:?}", include));
+    }
+
+    // If exclude patterns are specified, name must not match any
+    exclude
+        .iter()
+        .find(|pattern| matches_pattern(name, pattern))
+        .map(|pattern| format!("matched --exclude pattern '{}'", pattern))
+}
+
+/// Returns the name of a template set for messages, or its folder if it has no name.
+fn set_label(template_set: &TemplateSet) -> String {
+    match template_set.name {
+        Some(ref name) => format!("'{}'", name),
+        None => format!("{:?}", template_set.folder),
+    }
+}
+
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    if let Some(regex_pattern) = pattern.strip_prefix("regex:") {
+        if let Ok(re) = regex::Regex::new(regex_pattern) {
+            return re.is_match(name);
+        }
+    }
+
+    // Simple glob-like matching (very basic)
+    if pattern.contains('*') {
+        let parts: Vec<&str> = pattern.split('*').collect();
+        if parts.len() == 2 {
+            return name.starts_with(parts[0]) && name.ends_with(parts[1]);
+        }
+    }
+
+    name == pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a config with one template set rendering `{{ env }}.txt` into the given directory.
+    fn write_project(dir: &Path) -> PathBuf {
+        std::fs::create_dir_all(dir.join("templates")).unwrap();
+        std::fs::write(
+            dir.join("templates/{{ env }}.txt.j2"),
+            "env={{ env }} port={{ dd.port }}",
+        )
+        .unwrap();
+        let config_path = dir.join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Env\"\n    folder: \"templates\"\n",
+        )
+        .unwrap();
+        config_path
+    }
+
+    fn run(args: &[&str]) -> Result<()> {
+        let cli = Cli::try_parse_from(std::iter::once("yagen").chain(args.iter().copied()))?;
+        generate(&cli)
+    }
+
+    #[test]
+    fn test_per_data_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let dev = tmp.path().join("dev.json");
+        let prod = tmp.path().join("prod.yaml");
+        std::fs::write(&dev, r#"{"env": "dev", "port": 1}"#).unwrap();
+        std::fs::write(&prod, "env: prod\nport: 2\n").unwrap();
+        let out = tmp.path().join("envs");
+
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            dev.to_str().unwrap(),
+            "-d",
+            prod.to_str().unwrap(),
+            "--per-data-output",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(out.join("dev/dev.txt")).unwrap(),
+            "env=dev port=1"
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("prod/prod.txt")).unwrap(),
+            "env=prod port=2"
+        );
+    }
+
+    #[test]
+    fn test_multiple_data_requires_per_data_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let result = run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            "a.json",
+            "-d",
+            "b.json",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_targets_fan_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("models")).unwrap();
+        std::fs::write(
+            tmp.path().join("models/model.{{ target.ext }}.j2"),
+            "{% if target.lang == \"rust\" %}pub struct {{ name }};{% else %}export class {{ name }} {}{% endif %}",
+        )
+        .unwrap();
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "templates:\n  - folder: \"models\"\n    targets:\n      - { ext: rs, lang: rust }\n      - { ext: ts, lang: typescript }\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"name": "User"}"#).unwrap();
+        let out = tmp.path().join("out");
+
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(out.join("model.rs")).unwrap(),
+            "pub struct User;"
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("model.ts")).unwrap(),
+            "export class User {}"
+        );
+    }
+
+    #[test]
+    fn test_check_data_rejects_schema_violation() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let mut config = std::fs::read_to_string(&config_path).unwrap();
+        config.push_str("schema: \"schema.yaml\"\n");
+        std::fs::write(&config_path, config).unwrap();
+        std::fs::write(
+            tmp.path().join("schema.yaml"),
+            "type: object\nrequired: [env, port]\nproperties:\n  port: { type: integer }\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "dev", "port": "80"}"#).unwrap();
+        let out = tmp.path().join("out");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ];
+
+        let err = run(&[&args[..], &["--check-data"]].concat()).unwrap_err();
+        assert!(err.to_string().contains("1 violation(s)"));
+        assert!(!out.join("dev.txt").exists());
+
+        // Without --check-data the schema is not enforced
+        run(&args).unwrap();
+        assert!(out.join("dev.txt").exists());
+    }
+
+    #[test]
+    fn test_list_filters() {
+        let cli = Cli::try_parse_from(["yagen", "--list-filters"]).unwrap();
+        assert!(matches!(cli.command, Some(Commands::ListFilters)));
+        let listing = list_filters();
+        assert!(listing.contains("filter    camelcase"));
+        assert!(listing.lines().any(|l| l.starts_with("function") && l.contains("uuid_generate")));
+    }
+
+    #[test]
+    fn test_iteration_unless() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("services")).unwrap();
+        std::fs::write(
+            tmp.path().join("services/{{ service.name }}.txt.j2"),
+            "{{ service.name }}",
+        )
+        .unwrap();
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "templates:\n  - folder: \"services\"\n    iterate: \"service in services unless service.deprecated\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(
+            &data,
+            r#"{"services": [{"name": "api", "deprecated": false}, {"name": "legacy", "deprecated": true}]}"#,
+        )
+        .unwrap();
+        let out = tmp.path().join("out");
+
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert!(out.join("api.txt").exists());
+        assert!(!out.join("legacy.txt").exists());
+    }
+
+    #[test]
+    fn test_set_level_globals() {
+        let tmp = tempfile::tempdir().unwrap();
+        for set in ["a", "b"] {
+            std::fs::create_dir_all(tmp.path().join(set)).unwrap();
+            std::fs::write(
+                tmp.path().join(set).join(format!("{}.txt.j2", set)),
+                "{{ globals.lang }}/{{ globals.version }}",
+            )
+            .unwrap();
+        }
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "globals:\n  lang: \"en\"\n  version: \"1.0\"\ntemplates:\n  - folder: \"a\"\n    globals:\n      lang: \"de\"\n  - folder: \"b\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, "{}").unwrap();
+        let out = tmp.path().join("out");
+
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(out.join("a.txt")).unwrap(), "de/1.0");
+        assert_eq!(std::fs::read_to_string(out.join("b.txt")).unwrap(), "en/1.0");
+    }
+
+    #[test]
+    fn test_explain_names_exclude_pattern() {
+        let exclude = vec!["legacy*".to_string()];
+        assert_eq!(
+            filter_reason("legacy-api", &[], &exclude).unwrap(),
+            "matched --exclude pattern 'legacy*'"
+        );
+        assert!(filter_reason("api", &[], &exclude).is_none());
+        assert!(filter_reason("api", &["web".to_string()], &[]).unwrap().contains("--include"));
+
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let cli = Cli::try_parse_from([
+            "yagen",
+            "-c",
+            config_path.to_str().unwrap(),
+            "--explain",
+            "--exclude",
+            "E*",
+        ])
+        .unwrap();
+        let config = TemplateConfig::load(&config_path).unwrap();
+        let mut run = GenerationRun::new(&cli, &config, &config_path, DataLoader::new(), None, None);
+        let data = serde_json::json!({ "env": "dev", "port": 1 });
+        run.generate_for_data(&data, &tmp.path().join("out")).unwrap();
+        assert_eq!(
+            run.explanations,
+            vec!["skip set 'Env': matched --exclude pattern 'E*'".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_keep_going_reports_all_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let templates = tmp.path().join("templates");
+        std::fs::create_dir_all(&templates).unwrap();
+        std::fs::write(templates.join("good.txt.j2"), "ok").unwrap();
+        std::fs::write(templates.join("broken.txt.j2"), "{{ missing }}").unwrap();
+        std::fs::write(
+            templates.join("sections.txt.j2"),
+            "// MANUAL SECTION START: a\n// MANUAL SECTION START: b\n// MANUAL SECTION END\n",
+        )
+        .unwrap();
+        std::fs::write(
+            templates.join("existing.txt.inj"),
+            "<!-- injection-pattern: p -->\n(unclosed\n<!-- injection-string-start -->\nx\n<!-- injection-string-end -->\n",
+        )
+        .unwrap();
+        std::fs::write(templates.join("fmt.fail.j2"), "x").unwrap();
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Missing\"\n    folder: \"nowhere\"\n  - name: \"Main\"\n    folder: \"templates\"\n\
+             format:\n  enabled: true\n  formatters:\n    \"*.fail\":\n      type: command\n      command: \"false\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, "{}").unwrap();
+        let out = tmp.path().join("out");
+        std::fs::create_dir_all(&out).unwrap();
+        std::fs::write(out.join("existing.txt"), "existing").unwrap();
+
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ];
+        assert!(run(&args).is_err());
+        assert!(!out.join("good.txt").exists(), "the first error aborts without --keep-going");
+
+        let err = run(&[&args[..], &["--keep-going"]].concat()).unwrap_err().to_string();
+        assert!(err.contains("5 error(s)"), "{}", err);
+        for expected in ["[config]", "[render]", "[validate]", "[inject]", "[format]", "nowhere"] {
+            assert!(err.contains(expected), "{} not in {}", expected, err);
+        }
+        assert_eq!(std::fs::read_to_string(out.join("good.txt")).unwrap(), "ok");
+        // A failing formatter still leaves the unformatted output
+        assert_eq!(std::fs::read_to_string(out.join("fmt.fail")).unwrap(), "x");
+    }
+
+    #[test]
+    fn test_template_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let shared = tmp.path().join("shared");
+        std::fs::create_dir_all(shared.join("models")).unwrap();
+        std::fs::write(shared.join("models/model.txt.j2"), "shared {{ name }}").unwrap();
+        let config_dir = tmp.path().join("configs");
+        std::fs::create_dir_all(config_dir.join("models")).unwrap();
+        std::fs::write(config_dir.join("models/model.txt.j2"), "local {{ name }}").unwrap();
+        let config_path = config_dir.join("config.yaml");
+        std::fs::write(&config_path, "templates:\n  - folder: \"models\"\n").unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"name": "User"}"#).unwrap();
+        let out = tmp.path().join("out");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ];
+
+        run(&args).unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("model.txt")).unwrap(), "local User");
+
+        run(&[&args[..], &["--template-root", shared.to_str().unwrap()]].concat()).unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("model.txt")).unwrap(), "shared User");
+
+        // `template_root` in the config is relative to the config file
+        std::fs::write(
+            &config_path,
+            "template_root: \"../shared\"\ntemplates:\n  - folder: \"models\"\n",
+        )
+        .unwrap();
+        std::fs::remove_file(out.join("model.txt")).unwrap();
+        run(&args).unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("model.txt")).unwrap(), "shared User");
+    }
+
+    #[test]
+    fn test_bench() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(tmp.path().join("templates/README.md.j2"), "# {{ env }}").unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "prod", "port": 8080}"#).unwrap();
+        let out = tmp.path().join("out");
+
+        let cli = Cli::try_parse_from([
+            "yagen",
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+        let stats = bench(&cli, 5).unwrap();
+        assert_eq!(stats.runs, 5);
+        assert_eq!(stats.files_per_run, 2);
+        assert!(stats.min <= stats.median && stats.median <= stats.max);
+        assert!(stats.files_per_second() > 0.0);
+        assert!(!out.exists(), "bench must not write files");
+        assert!(bench(&cli, 0).is_err());
+    }
+
+    #[test]
+    fn test_folder_glob() {
+        let tmp = tempfile::tempdir().unwrap();
+        for domain in ["billing", "users"] {
+            let templates = tmp.path().join("domains").join(domain).join("templates");
+            std::fs::create_dir_all(&templates).unwrap();
+            std::fs::write(templates.join("mod.txt.j2"), format!("{} for {{{{ app }}}}", domain))
+                .unwrap();
+        }
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "templates:\n  - folder: \"domains/*/templates\"\n    output: \"gen\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"app": "shop"}"#).unwrap();
+        let out = tmp.path().join("out");
+
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(out.join("gen/billing/mod.txt")).unwrap(),
+            "billing for shop"
+        );
+        assert_eq!(
+            std::fs::read_to_string(out.join("gen/users/mod.txt")).unwrap(),
+            "users for shop"
+        );
+    }
+
+    #[test]
+    fn test_data_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("templates")).unwrap();
+        std::fs::write(
+            tmp.path().join("templates/app.txt.j2"),
+            "{{ context.name }} {{ dd.name }}{% if name is defined %} {{ name }}{% endif %}",
+        )
+        .unwrap();
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(&config_path, "templates:\n  - folder: \"templates\"\n").unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"name": "shop"}"#).unwrap();
+        let out = tmp.path().join("out");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--data-key",
+            "context",
+        ];
+
+        run(&args).unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("app.txt")).unwrap(), "shop shop shop");
+
+        // Without flattening, the data is only available under the key and `dd`
+        std::fs::write(
+            &config_path,
+            "flatten_data: false\ntemplates:\n  - folder: \"templates\"\n",
+        )
+        .unwrap();
+        run(&args).unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("app.txt")).unwrap(), "shop shop");
+    }
+
+    #[test]
+    fn test_check_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        for (folder, template) in [("first", "ok {{ name }}"), ("second", "{% if %}")] {
+            std::fs::create_dir_all(tmp.path().join(folder)).unwrap();
+            std::fs::write(tmp.path().join(folder).join("out.txt.j2"), template).unwrap();
+        }
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "templates:\n  - folder: \"first\"\n    output: \"first\"\n  - folder: \"second\"\n    output: \"second\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"name": "x"}"#).unwrap();
+        let out = tmp.path().join("out");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ];
+
+        let err = run(&[&args[..], &["--check-first"]].concat()).unwrap_err().to_string();
+        assert!(err.contains("Template check failed with 1 error(s)"), "{}", err);
+        assert!(!out.join("first/out.txt").exists());
+
+        // Without --check-first, the first set is generated before the second one fails
+        assert!(run(&args).is_err());
+        assert!(out.join("first/out.txt").exists());
+
+        std::fs::write(tmp.path().join("second/out.txt.j2"), "fixed").unwrap();
+        run(&[&args[..], &["--check-first"]].concat()).unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("second/out.txt")).unwrap(), "fixed");
+    }
+
+    #[test]
+    fn test_data_transforms() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("templates")).unwrap();
+        std::fs::write(
+            tmp.path().join("templates/services.txt.j2"),
+            "{% for s in services %}{{ s.id }} {% endfor %}",
+        )
+        .unwrap();
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "templates:\n  - folder: \"templates\"\ntransforms:\n  - { type: sort_arrays_by, path: services, key: name }\n  - { type: add_field, path: services, name: id, expr: \"name | upper\" }\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"services": [{"name": "b"}, {"name": "a"}]}"#).unwrap();
+        let out = tmp.path().join("out");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("services.txt")).unwrap(), "A B ");
+    }
+
+    #[test]
+    fn test_set_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(
+            tmp.path().join("templates/{{ env }}.txt.j2"),
+            "{{ license }}env={{ env }}",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "dev"}"#).unwrap();
+        let license = tmp.path().join("LICENSE");
+        std::fs::write(&license, "// Copyright ACME\n// All rights reserved\n").unwrap();
+        let out = tmp.path().join("out");
+        let set_file = format!("license={}", license.display());
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--set-file",
+            &set_file,
+        ])
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("dev.txt")).unwrap(),
+            "// Copyright ACME\n// All rights reserved\nenv=dev"
+        );
+        assert!(parse_set_file("license").is_err());
+    }
+
+    #[test]
+    fn test_shared_macros() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(
+            &config_path,
+            "macros: \"macros.j2\"\ntemplates:\n  - name: \"Env\"\n    folder: \"templates\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp.path().join("macros.j2"),
+            "{% macro header(title) %}# {{ title | upper }}{% endmacro %}\n",
+        )
+        .unwrap();
+        std::fs::write(tmp.path().join("templates/a.txt.j2"), "{{ header('a') }} env={{ env }}")
+            .unwrap();
+        std::fs::write(tmp.path().join("templates/b.txt.j2"), "{{ header(env) }}").unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "dev", "port": 1}"#).unwrap();
+        let out = tmp.path().join("out");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("a.txt")).unwrap(), "# A env=dev");
+        assert_eq!(std::fs::read_to_string(out.join("b.txt")).unwrap(), "# DEV");
+        assert_eq!(std::fs::read_to_string(out.join("dev.txt")).unwrap(), "env=dev port=1");
+    }
+
+    #[test]
+    fn test_inline_extra_data() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(
+            &config_path,
+            "extra_data:\n  - key: limits\n    value:\n      cpu: 2\n      memory: \"1Gi\"\n\
+             templates:\n  - name: \"Env\"\n    folder: \"templates\"\n\
+             \x20 - name: \"Services\"\n    folder: \"services\"\n    iterate: \"svc in services\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp.path().join("templates/{{ env }}.txt.j2"),
+            "env={{ env }} cpu={{ limits.cpu }} memory={{ limits.memory }}",
+        )
+        .unwrap();
+        std::fs::create_dir_all(tmp.path().join("services")).unwrap();
+        std::fs::write(
+            tmp.path().join("services/{{ svc }}.txt.j2"),
+            "{{ svc }} cpu={{ limits is defined }}",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "dev", "services": ["api"]}"#).unwrap();
+        let out = tmp.path().join("out");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("dev.txt")).unwrap(),
+            "env=dev cpu=2 memory=1Gi"
+        );
+        // Iteration sets don't get extra_data
+        assert_eq!(std::fs::read_to_string(out.join("api.txt")).unwrap(), "api cpu=false");
+    }
+
+    #[test]
+    fn test_git_add() {
+        let tmp = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            let output = Command::new("git").arg("-C").arg(tmp.path()).args(args).output().unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            String::from_utf8(output.stdout).unwrap()
+        };
+        git(&["init", "-q"]);
+        let config_path = write_project(tmp.path());
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "dev", "port": 1}"#).unwrap();
+        std::fs::write(tmp.path().join("notes.txt"), "unrelated").unwrap();
+        // An ignored output is skipped, and a name with glob characters matches only itself
+        std::fs::write(tmp.path().join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(tmp.path().join("templates/debug.log.j2"), "{{ env }}").unwrap();
+        std::fs::write(tmp.path().join("templates/[ab].txt.j2"), "{{ env }}").unwrap();
+        std::fs::create_dir_all(tmp.path().join("out")).unwrap();
+        std::fs::write(tmp.path().join("out/a.txt"), "unrelated").unwrap();
+        let out = tmp.path().join("out");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--git-add",
+        ])
+        .unwrap();
+        assert!(out.join("debug.log").exists());
+        assert_eq!(git(&["diff", "--cached", "--name-only"]), "out/[ab].txt\nout/dev.txt\n");
+    }
+
+    #[test]
+    fn test_profile_report() {
+        let timing = |name: &str, millis: u64, count: usize| TemplateTiming {
+            template: PathBuf::from(name),
+            total: Duration::from_millis(millis),
+            count,
+        };
+        let report = profile_report(
+            vec![timing("fast.j2", 1, 1), timing("slow.j2", 50, 20), timing("mid.j2", 5, 1)],
+            2,
+        );
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "Slowest templates (2 of 3):");
+        assert!(lines[1].ends_with("20x  slow.j2"), "{}", lines[1]);
+        assert!(lines[2].ends_with("1x  mid.j2"), "{}", lines[2]);
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn test_failure_categories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "dev", "port": 1}"#).unwrap();
+        let out = tmp.path().join("out");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ];
+        let failure = |args: &[&str]| Failure::of(&run(args).unwrap_err());
+
+        // Config and usage errors
+        assert_eq!(failure(&["-d", data.to_str().unwrap()]), Failure::Config);
+        assert_eq!(failure(&[&args[..4], &["-d", "missing.json"]].concat()), Failure::Config);
+        std::fs::write(
+            &config_path,
+            "templates:\n  - folder: \"templates\"\n    iterate: \"s in services\"\n",
+        )
+        .unwrap();
+        assert_eq!(failure(&args), Failure::Config);
+
+        // Validation failures
+        std::fs::write(
+            &config_path,
+            "schema: \"schema.yaml\"\ntemplates:\n  - folder: \"templates\"\n",
+        )
+        .unwrap();
+        std::fs::write(tmp.path().join("schema.yaml"), "required: [name]\n").unwrap();
+        assert_eq!(failure(&[&args[..], &["--check-data"]].concat()), Failure::Validation);
+        std::fs::write(tmp.path().join("templates/{{ env }}.txt.j2"), "{% if %}").unwrap();
+        assert_eq!(failure(&[&args[..], &["--check-first"]].concat()), Failure::Validation);
+
+        // Generation errors
+        assert_eq!(failure(&args), Failure::Generation);
+        assert_eq!(Failure::Config.exit_code(), ExitCode::from(2));
+    }
+
+    #[test]
+    fn test_set_data_override() {
+        let tmp = tempfile::tempdir().unwrap();
+        for set in ["api", "ui"] {
+            std::fs::create_dir_all(tmp.path().join(set)).unwrap();
+            std::fs::write(
+                tmp.path().join(set).join("out.txt.j2"),
+                "{{ name }} {{ dd.name }}",
+            )
+            .unwrap();
+        }
+        std::fs::create_dir_all(tmp.path().join("data")).unwrap();
+        std::fs::write(tmp.path().join("data/api.yaml"), "name: api-data\n").unwrap();
+        std::fs::write(tmp.path().join("data/ui.json"), r#"{"name": "ui-data"}"#).unwrap();
+        let config_path = tmp.path().join("config.yaml");
+        std::fs::write(
+            &config_path,
+            "templates:\n\
+             \x20 - folder: \"api\"\n    output: \"api\"\n    data: \"data/api.yaml\"\n\
+             \x20 - folder: \"ui\"\n    output: \"ui\"\n    data: \"data/ui.json\"\n\
+             \x20 - folder: \"ui\"\n    output: \"default\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"name": "run-data"}"#).unwrap();
+        let out = tmp.path().join("out");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+        let read = |path: &str| std::fs::read_to_string(out.join(path)).unwrap();
+        assert_eq!(read("api/out.txt"), "api-data api-data");
+        assert_eq!(read("ui/out.txt"), "ui-data ui-data");
+        assert_eq!(read("default/out.txt"), "run-data run-data");
+    }
+
+    #[test]
+    fn test_clean_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::remove_file(tmp.path().join("templates/{{ env }}.txt.j2")).unwrap();
+        std::fs::write(tmp.path().join("templates/{{ svc.name }}.txt.j2"), "{{ svc.name }}")
+            .unwrap();
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Services\"\n    folder: \"templates\"\n\
+             \x20   iterate: \"svc in services\"\n    clean_output: true\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        let out = tmp.path().join("out");
+        let generate_for = |services: &str| {
+            std::fs::write(&data, format!(r#"{{"services": {}}}"#, services)).unwrap();
+            run(&[
+                "-c",
+                config_path.to_str().unwrap(),
+                "-d",
+                data.to_str().unwrap(),
+                "-o",
+                out.to_str().unwrap(),
+            ])
+            .unwrap();
+        };
+
+        generate_for(r#"[{"name": "api"}, {"name": "worker"}]"#);
+        std::fs::write(out.join("notes.txt"), "not generated").unwrap();
+        assert!(out.join("worker.txt").exists());
+
+        generate_for(r#"[{"name": "api"}]"#);
+        assert!(out.join("api.txt").exists());
+        assert!(!out.join("worker.txt").exists());
+        assert!(out.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn test_watch_set() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(
+            &config_path,
+            "templates:\n  - folder: \"templates\"\n  - folder: \"templates/nested\"\n  - folder: \"services/*/tpl\"\n",
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "yagen",
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            tmp.path().join("data.json").to_str().unwrap(),
+            "-d",
+            "https://host/data.json",
+            "--watch",
+        ])
+        .unwrap();
+        let watch_set = WatchSet::new(&cli, &config_path);
+        let dir = tmp.path();
+        assert_eq!(watch_set.files, BTreeSet::from([config_path.clone(), dir.join("data.json")]));
+        assert_eq!(
+            watch_set.folders,
+            BTreeSet::from([dir.join("services"), dir.join("templates"), dir.join("templates/nested")])
+        );
+        assert!(watch_set.contains(&dir.join("templates/nested/a.j2")));
+        assert!(watch_set.contains(&dir.join("services/api/tpl/b.j2")));
+        assert!(!watch_set.contains(&dir.join("out.txt")));
+        assert_eq!(
+            watch_set.watches(),
+            BTreeMap::from([
+                (dir.to_path_buf(), RecursiveMode::NonRecursive),
+                (dir.join("services"), RecursiveMode::Recursive),
+                (dir.join("templates"), RecursiveMode::Recursive),
+            ])
+        );
+
+        // A burst of changes is one change; other files and reads are ignored
+        let (sender, receiver) = mpsc::channel();
+        let event = |kind, path: PathBuf| WatchMessage::Event(Ok(notify::Event::new(kind).add_path(path)));
+        let modify = EventKind::Modify(notify::event::ModifyKind::Any);
+        let read = EventKind::Access(notify::event::AccessKind::Any);
+        sender.send(event(read, config_path.clone())).unwrap();
+        sender.send(event(modify, dir.join("out.txt"))).unwrap();
+        sender.send(event(modify, dir.join("templates/a.j2"))).unwrap();
+        sender.send(event(modify, config_path.clone())).unwrap();
+        let debounce = Duration::from_millis(10);
+        assert_eq!(next_change(&receiver, &watch_set, debounce), Some(dir.join("templates/a.j2")));
+        sender.send(WatchMessage::Stop).unwrap();
+        assert_eq!(next_change(&receiver, &watch_set, debounce), None);
+    }
+
+    /// Records the watches of a `WatchSet` instead of asking the OS for notifications. Like a
+    /// real watcher, it can't watch a path that doesn't exist.
+    #[derive(Default)]
+    struct FakeWatcher {
+        watches: BTreeMap<PathBuf, RecursiveMode>,
+    }
+
+    impl Watcher for FakeWatcher {
+        fn new<F: notify::EventHandler>(_: F, _: notify::Config) -> notify::Result<Self> {
+            Ok(Self::default())
+        }
+
+        fn watch(&mut self, path: &Path, recursive_mode: RecursiveMode) -> notify::Result<()> {
+            if !path.exists() {
+                return Err(notify::Error::path_not_found());
+            }
+            self.watches.insert(path.to_path_buf(), recursive_mode);
+            Ok(())
+        }
+
+        fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+            self.watches.remove(path).map(|_| ()).ok_or_else(notify::Error::watch_not_found)
+        }
+
+        fn kind() -> notify::WatcherKind {
+            notify::WatcherKind::NullWatcher
+        }
+    }
+
+    #[test]
+    fn test_watch_recreated_folder() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let cli = Cli::try_parse_from(["yagen", "-c", config_path.to_str().unwrap(), "--watch"]).unwrap();
+        let watch_set = WatchSet::new(&cli, &config_path);
+        let mut watcher = FakeWatcher::default();
+        let (sender, receiver) = mpsc::channel();
+        let event = |kind, path: &Path| WatchMessage::Event(Ok(notify::Event::new(kind).add_path(path.into())));
+        let debounce = Duration::from_millis(10);
+        let dir = tmp.path();
+        let templates = dir.join("templates");
+
+        let watches = watch_set.register(&mut watcher, &BTreeMap::new());
+        assert_eq!(watcher.watches.get(&templates), Some(&RecursiveMode::Recursive));
+
+        // The deleted folder can't be watched, but its parent still reports its recreation
+        std::fs::remove_dir_all(&templates).unwrap();
+        sender.send(event(EventKind::Remove(notify::event::RemoveKind::Folder), &templates)).unwrap();
+        assert_eq!(next_change(&receiver, &watch_set, debounce), Some(templates.clone()));
+        let watches = watch_set.register(&mut watcher, &watches);
+        assert_eq!(watcher.watches, BTreeMap::from([(dir.to_path_buf(), RecursiveMode::NonRecursive)]));
+
+        std::fs::create_dir(&templates).unwrap();
+        sender.send(event(EventKind::Create(notify::event::CreateKind::Folder), &templates)).unwrap();
+        assert_eq!(next_change(&receiver, &watch_set, debounce), Some(templates.clone()));
+        watch_set.register(&mut watcher, &watches);
+        assert_eq!(watcher.watches.get(&templates), Some(&RecursiveMode::Recursive));
+
+        let new_template = templates.join("new.txt.j2");
+        sender.send(event(EventKind::Create(notify::event::CreateKind::File), &new_template)).unwrap();
+        assert_eq!(next_change(&receiver, &watch_set, debounce), Some(new_template));
+    }
+
+    #[test]
+    fn test_conflict_policy_tracks_outputs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let data = tmp.path().join("dev.json");
+        let out = tmp.path().join("out");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--on-conflict",
+            "error",
+        ];
+        for port in [1, 2] {
+            std::fs::write(&data, format!(r#"{{"env": "dev", "port": {}}}"#, port)).unwrap();
+            run(&args).unwrap();
+            let content = std::fs::read_to_string(out.join("dev.txt")).unwrap();
+            assert_eq!(content, format!("env=dev port={}", port));
+        }
+        assert!(out.join(templify::generator::OUTPUT_MANIFEST).exists());
+
+        // A file that no run generated is still a conflict
+        std::fs::remove_file(out.join(templify::generator::OUTPUT_MANIFEST)).unwrap();
+        std::fs::write(&data, r#"{"env": "dev", "port": 3}"#).unwrap();
+        let err = run(&args).unwrap_err();
+        assert!(format!("{:#}", err).contains("not generated by templify"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::create_dir_all(tmp.path().join("other")).unwrap();
+        std::fs::write(tmp.path().join("other/notes.md"), "notes").unwrap();
+        std::fs::write(
+            &config_path,
+            "templates:\n  - folder: \"templates\"\n  - folder: \"other\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("dev.json");
+        std::fs::write(&data, r#"{"env": "dev", "port": 1}"#).unwrap();
+        let out = tmp.path().join("out");
+        let manifest = tmp.path().join("manifest.json");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--manifest",
+            manifest.to_str().unwrap(),
+        ];
+        let expected = serde_json::json!({
+            "written": [{"path": out.join("dev.txt"), "manual_sections_merged": false}],
+            "copied": [{"path": out.join("notes.md"), "manual_sections_merged": false}],
+            "injected": [],
+            "skipped": [],
+            "conflicts": [],
+        });
+
+        run(&[&args[..], &["--dry-run"]].concat()).unwrap();
+        assert!(!out.exists());
+        let read_manifest = || -> serde_json::Value {
+            serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap()
+        };
+        assert_eq!(read_manifest(), expected);
+        run(&args).unwrap();
+        assert_eq!(read_manifest(), expected);
+    }
+
+    #[test]
+    fn test_clean() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let data = |env: &str| {
+            let path = tmp.path().join(format!("{}.json", env));
+            std::fs::write(&path, format!(r#"{{"env": "{}", "port": 1}}"#, env)).unwrap();
+            path
+        };
+        let (dev, prod) = (data("dev"), data("prod"));
+        let out = tmp.path().join("out");
+        let manifest = tmp.path().join("manifest.json");
+        let args = |data: &Path| {
+            vec![
+                "-c".to_string(),
+                config_path.to_string_lossy().into_owned(),
+                "-d".to_string(),
+                data.to_string_lossy().into_owned(),
+                "-o".to_string(),
+                out.to_string_lossy().into_owned(),
+                "--manifest".to_string(),
+                manifest.to_string_lossy().into_owned(),
+            ]
+        };
+        let clean_with = |extra: &[&str]| {
+            let args = args(&prod);
+            let cli = Cli::try_parse_from(
+                ["yagen", "clean"]
+                    .into_iter()
+                    .chain(args.iter().map(String::as_str))
+                    .chain(extra.iter().copied()),
+            )
+            .unwrap();
+            let Some(Commands::Clean { force }) = cli.command else {
+                panic!("expected clean");
+            };
+            clean(&cli, force)
+        };
+        let dev_args = args(&dev);
+        run(&dev_args.iter().map(String::as_str).collect::<Vec<_>>()).unwrap();
+
+        // A file with manual sections listed in the manifest, and a current output
+        let manual = out.join("manual.txt");
+        std::fs::write(&manual, "MANUAL SECTION START: a\nmine\nMANUAL SECTION END\n").unwrap();
+        std::fs::write(out.join("prod.txt"), "env=prod port=1").unwrap();
+        let mut report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+        for path in [&manual, &out.join("prod.txt")] {
+            report["written"]
+                .as_array_mut()
+                .unwrap()
+                .push(serde_json::json!({"path": path, "manual_sections_merged": false}));
+        }
+        // A hand-written file that --on-conflict skip left alone is not an output
+        let hand_written = out.join("hand.txt");
+        std::fs::write(&hand_written, "hand-written").unwrap();
+        report["conflicts"] = serde_json::json!([{"path": hand_written, "manual_sections_merged": false}]);
+        std::fs::write(&manifest, report.to_string()).unwrap();
+
+        let removed = clean_with(&["--dry-run"]).unwrap();
+        assert_eq!(removed, BTreeSet::from([out.join("dev.txt")]));
+        assert!(out.join("dev.txt").exists());
+
+        assert_eq!(clean_with(&[]).unwrap(), BTreeSet::from([out.join("dev.txt")]));
+        assert!(!out.join("dev.txt").exists());
+        assert!(manual.exists() && out.join("prod.txt").exists() && hand_written.exists());
+        assert_eq!(clean_with(&["--force"]).unwrap(), BTreeSet::from([manual.clone()]));
+        assert!(!manual.exists() && out.join("prod.txt").exists());
+
+        // Entries resolving outside the output base abort the clean
+        let outside = tmp.path().join("outside.txt");
+        std::fs::write(&outside, "keep").unwrap();
+        report["written"] = serde_json::json!([
+            {"path": out.join("../outside.txt"), "manual_sections_merged": false},
+        ]);
+        std::fs::write(&manifest, report.to_string()).unwrap();
+        let err = clean_with(&["--force"]).unwrap_err();
+        assert!(format!("{:#}", err).contains("outside the output base"), "{:#}", err);
+        assert_eq!(Failure::of(&err), Failure::Config);
+        assert!(outside.exists());
+    }
+
+    #[test]
+    fn test_list_outputs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let templates = tmp.path().join("templates");
+        std::fs::create_dir_all(templates.join("assets")).unwrap();
+        std::fs::write(templates.join("assets/logo.svg"), "<svg/>").unwrap();
+        std::fs::write(
+            templates.join("mod.rs.inj"),
+            "<!-- injection-pattern: mods -->\n(?P<injection>)// end\n\
+             <!-- injection-string-start -->\npub mod {{ env }};\n<!-- injection-string-end -->\n",
+        )
+        .unwrap();
+        std::fs::write(
+            templates.join("all.inj"),
+            "---\ntarget_glob: \"src/*.rs\"\n---\n<!-- injection-pattern: uses -->\n(?P<injection>)// uses\n\
+             <!-- injection-string-start -->\nuse {{ env }};\n<!-- injection-string-end -->\n",
+        )
+        .unwrap();
+        let out = tmp.path().join("out");
+        std::fs::create_dir_all(out.join("src")).unwrap();
+        std::fs::write(out.join("mod.rs"), "// end\n").unwrap();
+        std::fs::write(out.join("src/lib.rs"), "// uses\n").unwrap();
+
+        let cli = Cli::try_parse_from([
+            "yagen",
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            "data.json",
+            "-o",
+            out.to_str().unwrap(),
+            "--list-outputs",
+            "--manifest",
+            tmp.path().join("manifest.json").to_str().unwrap(),
+        ])
+        .unwrap();
+        let config = TemplateConfig::load(&config_path).unwrap();
+        let mut run = GenerationRun::new(&cli, &config, &config_path, DataLoader::new(), None, None);
+        run.generate_for_data(&serde_json::json!({"env": "dev", "port": 1}), &out).unwrap();
+
+        let out = std::path::absolute(&out).unwrap();
+        assert_eq!(
+            output_list(&run.output_files).unwrap(),
+            format!(
+                "{}\n{}\n{}\n{}\n",
+                out.join("assets/logo.svg").display(),
+                out.join("dev.txt").display(),
+                out.join("mod.rs").display(),
+                out.join("src/lib.rs").display()
+            )
+        );
+        // Injection targets are read from the output directory and reported as injected
+        let mut injected: Vec<_> = run.report.injected.iter().map(|file| file.path.clone()).collect();
+        injected.sort();
+        assert_eq!(injected, [out.join("mod.rs"), out.join("src/lib.rs")]);
+        // Nothing was written
+        assert!(!out.join("dev.txt").exists() && !out.join("assets").exists());
+        assert_eq!(std::fs::read_to_string(out.join("mod.rs")).unwrap(), "// end\n");
+        assert_eq!(std::fs::read_to_string(out.join("src/lib.rs")).unwrap(), "// uses\n");
+        assert!(Cli::try_parse_from(["yagen", "--list-outputs", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_archive_conflicts_with_dry_run() {
+        assert!(Cli::try_parse_from(["yagen", "--archive", "out.tar.gz", "--dry-run"]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--archive", "out.tar.gz"]).is_ok());
+    }
+
+    #[test]
+    fn test_mode_flags_conflict() {
+        assert!(Cli::try_parse_from(["yagen", "--config-check", "--template-var-report"]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--template-var-report"]).is_ok());
+        assert!(Cli::try_parse_from(["yagen", "--migrate-markers", "a,b", "--template-var-report"]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--migrate-markers", "a,b", "--config-check"]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--watch", "--config-check"]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--watch", "--migrate-markers", "a,b"]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--watch", "--template-var-report"]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--list-outputs", "--explain"]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--list-outputs", "--profile-template"]).is_err());
+    }
+
+    #[test]
+    fn test_dry_run_prune_preview() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::remove_file(tmp.path().join("templates/{{ env }}.txt.j2")).unwrap();
+        std::fs::write(tmp.path().join("templates/{{ svc }}.txt.j2"), "{{ svc }}").unwrap();
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Services\"\n    folder: \"templates\"\n\
+             \x20   iterate: \"svc in services\"\n    clean_output: true\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        let out = tmp.path().join("out");
+        let args = [
+            "yagen",
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ];
+        std::fs::write(&data, r#"{"services": ["api", "worker"]}"#).unwrap();
+        run(&args[1..]).unwrap();
+
+        let cli = Cli::try_parse_from([&args[..], &["--dry-run"]].concat()).unwrap();
+        let config = TemplateConfig::load(&config_path).unwrap();
+        let mut dry_run =
+            GenerationRun::new(&cli, &config, &config_path, DataLoader::new(), None, None);
+        dry_run
+            .generate_for_data(&serde_json::json!({"services": ["api", "web"]}), &out)
+            .unwrap();
+
+        assert_eq!(
+            dry_run.dry_run_files,
+            BTreeSet::from([out.join("api.txt"), out.join("web.txt")])
+        );
+        assert_eq!(dry_run.pruned_files, BTreeSet::from([out.join("worker.txt")]));
+        let preview = dry_run_preview(&dry_run.dry_run_files, &dry_run.pruned_files);
+        assert!(
+            preview.contains(&format!(
+                "[DRY RUN] 1 file(s) would be removed as orphans:\n  {}\n",
+                out.join("worker.txt").display()
+            )),
+            "{}",
+            preview
+        );
+        assert!(out.join("worker.txt").exists());
+        assert!(!out.join("web.txt").exists());
+    }
+
+    #[test]
+    fn test_run_inputs_urls() {
+        let config: TemplateConfig = serde_yaml::from_str(
+            "extra_data:\n  - { key: a, path: \"https://host/a.json\" }\n  - { key: b, path: b.json }\n\
+             templates:\n  - { folder: t, data: \"http://host/set.yaml\" }\n",
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from(["yagen"]).unwrap();
+        let inputs = run_inputs(&cli, &config, Path::new("conf/config.yaml"));
+        let expected: Vec<PathBuf> =
+            ["conf/config.yaml", "https://host/a.json", "conf/b.json", "http://host/set.yaml"]
+                .iter()
+                .map(PathBuf::from)
+                .collect();
+        assert_eq!(inputs, expected);
+    }
+
+    #[test]
+    fn test_emit_deps() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(tmp.path().join("owners.json"), r#"{"team": "core"}"#).unwrap();
+        std::fs::write(tmp.path().join("templates/static.txt"), "static").unwrap();
+        std::fs::write(
+            &config_path,
+            std::fs::read_to_string(&config_path).unwrap()
+                + "extra_data:\n  - { key: owners, path: owners.json }\n  - { key: limits, value: 1 }\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("my data.json");
+        std::fs::write(&data, r#"{"env": "dev", "port": 1}"#).unwrap();
+        let license = tmp.path().join("LICENSE");
+        std::fs::write(&license, "MIT").unwrap();
+        let out = tmp.path().join("out");
+        let deps = tmp.path().join("yagen.d");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--emit-deps",
+            deps.to_str().unwrap(),
+            "--set-file",
+            &format!("license={}", license.display()),
+        ])
+        .unwrap();
+
+        let deps = std::fs::read_to_string(deps).unwrap();
+        let inputs = format!(
+            " \\\n  {} \\\n  {} \\\n  {} \\\n  {}\n",
+            config_path.display(),
+            data.display().to_string().replace(' ', "\\ "),
+            license.display(),
+            tmp.path().join("owners.json").display()
+        );
+        assert_eq!(
+            deps,
+            format!(
+                "{}: \\\n  {}{}{}: \\\n  {}{}",
+                out.join("dev.txt").display(),
+                tmp.path()
+                    .join("templates/{{ env }}.txt.j2")
+                    .display()
+                    .to_string()
+                    .replace(' ', "\\ "),
+                inputs,
+                out.join("static.txt").display(),
+                tmp.path().join("templates/static.txt").display(),
+                inputs
+            )
+        );
+    }
+
+    #[test]
+    fn test_max_items() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(tmp.path().join("templates/{{ svc }}.txt.j2"), "{{ svc }}").unwrap();
+        std::fs::remove_file(tmp.path().join("templates/{{ env }}.txt.j2")).unwrap();
+        std::fs::write(
+            &config_path,
+            "max_items: 100\ntemplates:\n  - name: \"Services\"\n    folder: \"templates\"\n\
+             \x20   iterate: \"svc in services\"\n    max_items: 2\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"services": ["a", "b", "c"]}"#).unwrap();
+        let out = tmp.path().join("out");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ];
+
+        let err = run(&args).unwrap_err();
+        assert_eq!(Failure::of(&err), Failure::Config);
+        let message = format!("{:#}", err);
+        assert!(message.contains("'services' has 3 items, more than max_items (2)"), "{}", message);
+        assert!(!out.join("a.txt").exists());
+
+        run(&[&args[..], &["--allow-large"]].concat()).unwrap();
+        assert!(out.join("c.txt").exists());
+
+        // Without max_items, the number of items is unlimited
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Services\"\n    folder: \"templates\"\n    iterate: \"svc in services\"\n",
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&out).unwrap();
+        run(&args).unwrap();
+        assert!(out.join("c.txt").exists());
+    }
+
+    #[test]
+    fn test_render_stdin() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(&config_path, "globals:\n  owner: ops\ntemplates: []\n").unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"services": [{"name": "api"}, {"name": "web"}]}"#).unwrap();
+        let render_stdin = |args: &[&str], template: &str| {
+            let cli = Cli::try_parse_from(["yagen", "render"].iter().chain(args)).unwrap();
+            let Some(Commands::Render { template: ref template_path }) = cli.command else {
+                unreachable!()
+            };
+            let mut output = Vec::new();
+            render(&cli, template_path, template.as_bytes(), &mut output)
+                .map(|_| String::from_utf8(output).unwrap())
+        };
+
+        let template =
+            "{% for svc in services %}{{ svc.name | upper }}\n{% endfor %}owner={{ globals.owner }}\n";
+        let config_args = ["-c", config_path.to_str().unwrap(), "-d", data.to_str().unwrap()];
+        assert_eq!(render_stdin(&config_args, template).unwrap(), "API\nWEB\nowner=ops\n");
+
+        // Without a config, the data is still flattened
+        let data_args = ["--data", data.to_str().unwrap()];
+        assert_eq!(render_stdin(&data_args, "{{ services | length }}").unwrap(), "2");
+        assert_eq!(render_stdin(&[], "plain {{ 1 + 1 }}\n").unwrap(), "plain 2\n");
+
+        let err = render_stdin(&data_args, "{{ missing }}").unwrap_err();
+        assert_eq!(Failure::of(&err), Failure::Generation);
+
+        let template_file = tmp.path().join("one.j2");
+        std::fs::write(&template_file, "{{ dd.services[1].name }}").unwrap();
+        let file_args = ["--template", template_file.to_str().unwrap(), "-d", data.to_str().unwrap()];
+        assert_eq!(render_stdin(&file_args, "ignored").unwrap(), "web");
+    }
+
+    #[test]
+    fn test_nested_iteration() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::remove_file(tmp.path().join("templates/{{ env }}.txt.j2")).unwrap();
+        std::fs::create_dir_all(tmp.path().join("templates/{{ module.name }}")).unwrap();
+        std::fs::write(
+            tmp.path().join("templates/{{ module.name }}/{{ component.name }}.txt.j2"),
+            "{{ module.name }}/{{ component.name }} v{{ globals.version }}",
+        )
+        .unwrap();
+        std::fs::write(
+            &config_path,
+            "globals:\n  version: 2\ntemplates:\n  - name: \"Components\"\n    folder: \"templates\"\n\
+             \x20   iterate: \"module in modules >> component in module.components if not component.disabled\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(
+            &data,
+            r#"{"modules": [
+                {"name": "core", "components": [{"name": "parser"}, {"name": "old", "disabled": true}]},
+                {"name": "broken"},
+                {"name": "ui", "components": [{"name": "button"}, {"name": "menu"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let out = tmp.path().join("out");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        let read = |path: &str| std::fs::read_to_string(out.join(path)).unwrap();
+        assert_eq!(read("core/parser.txt"), "core/parser v2");
+        assert_eq!(read("ui/button.txt"), "ui/button v2");
+        assert_eq!(read("ui/menu.txt"), "ui/menu v2");
+        assert!(!out.join("core/old.txt").exists());
+        assert!(!out.join("broken").exists());
+
+        std::fs::write(&data, r#"{"other": []}"#).unwrap();
+        let err = run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap_err();
+        assert_eq!(Failure::of(&err), Failure::Config);
+    }
+
+    #[test]
+    fn test_loop_variable() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::remove_file(tmp.path().join("templates/{{ env }}.txt.j2")).unwrap();
+        std::fs::write(
+            tmp.path().join("templates/{{ loop.index }}-{{ svc }}.txt.j2"),
+            "{{ loop.index0 }}/{{ loop.length }} first={{ loop.first }} last={{ loop.last }}",
+        )
+        .unwrap();
+        std::fs::create_dir_all(tmp.path().join("nested")).unwrap();
+        std::fs::write(
+            tmp.path().join("nested/{{ m.name }}-{{ c }}.txt.j2"),
+            "{{ loops.m.index }}.{{ loop.index }} of {{ loops.m.length }}.{{ loop.length }}",
+        )
+        .unwrap();
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Services\"\n    folder: \"templates\"\n\
+             \x20   iterate: \"svc in services if svc != 'skip'\"\n    output: \"services\"\n\
+             \x20 - name: \"Single\"\n    folder: \"templates\"\n\
+             \x20   iterate: \"svc in single\"\n    output: \"single\"\n\
+             \x20 - name: \"Empty\"\n    folder: \"templates\"\n\
+             \x20   iterate: \"svc in empty\"\n    output: \"empty\"\n\
+             \x20 - name: \"Nested\"\n    folder: \"nested\"\n\
+             \x20   iterate: \"m in modules >> c in m.parts\"\n    output: \"nested\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(
+            &data,
+            r#"{"services": ["api", "skip", "web", "db"], "single": ["only"], "empty": [],
+                "modules": [{"name": "a", "parts": ["x", "y"]}, {"name": "b", "parts": ["z"]}]}"#,
+        )
+        .unwrap();
+        let out = tmp.path().join("out");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        let read = |path: &str| std::fs::read_to_string(out.join(path)).unwrap();
+        // Filtered items don't count
+        assert_eq!(read("services/1-api.txt"), "0/3 first=true last=false");
+        assert_eq!(read("services/2-web.txt"), "1/3 first=false last=false");
+        assert_eq!(read("services/3-db.txt"), "2/3 first=false last=true");
+        assert_eq!(read("single/1-only.txt"), "0/1 first=true last=true");
+        assert!(!out.join("empty").exists());
+        assert_eq!(read("nested/a-x.txt"), "1.1 of 2.2");
+        assert_eq!(read("nested/a-y.txt"), "1.2 of 2.2");
+        assert_eq!(read("nested/b-z.txt"), "2.1 of 2.1");
+    }
+
+    #[test]
+    fn test_parallel_items() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(
+            tmp.path().join("templates/{{ svc.name }}.txt.j2"),
+            "{{ svc.name }}:{{ svc.port }} {% for i in range(svc.port % 7) %}{{ i }}{% endfor %}",
+        )
+        .unwrap();
+        std::fs::remove_file(tmp.path().join("templates/{{ env }}.txt.j2")).unwrap();
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Services\"\n    folder: \"templates\"\n\
+             \x20   iterate: \"svc in services if svc.port > 8010\"\n",
+        )
+        .unwrap();
+        let services: Vec<_> = (0..40)
+            .map(|i| serde_json::json!({"name": format!("svc{}", i), "port": 8000 + i}))
+            .collect();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, serde_json::json!({ "services": services }).to_string()).unwrap();
+
+        let generate = |out: &Path, jobs: &str| {
+            run(&[
+                "-c",
+                config_path.to_str().unwrap(),
+                "-d",
+                data.to_str().unwrap(),
+                "-o",
+                out.to_str().unwrap(),
+                "--jobs",
+                jobs,
+            ])
+            .unwrap();
+            let mut files = BTreeMap::new();
+            for entry in std::fs::read_dir(out).unwrap() {
+                let path = entry.unwrap().path();
+                files.insert(
+                    path.file_name().unwrap().to_owned(),
+                    std::fs::read_to_string(&path).unwrap(),
+                );
+            }
+            files
+        };
+        let serial = generate(&tmp.path().join("serial"), "1");
+        let parallel = generate(&tmp.path().join("parallel"), "4");
+        assert_eq!(serial.len(), 29);
+        assert_eq!(serial, parallel);
+
+        let err = run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "--jobs",
+            "4",
+            "--seed",
+            "1",
+        ])
+        .unwrap_err();
+        assert_eq!(Failure::of(&err), Failure::Config);
+    }
+
+    #[test]
+    fn test_template_var_report() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(
+            tmp.path().join("templates/ports.txt.j2"),
+            "{% for p in dd.ports %}{{ p }}{% endfor %} {{ owner.email }}",
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "yagen",
+            "-c",
+            config_path.to_str().unwrap(),
+            "--template-var-report",
+        ])
+        .unwrap();
+        let report: serde_json::Value =
+            serde_json::from_str(&template_var_report(&cli).unwrap()).unwrap();
+        let templates = tmp.path().join("templates");
+        let key = |name: &str| templates.join(name).to_string_lossy().into_owned();
+        assert_eq!(
+            report[key("{{ env }}.txt.j2")],
+            serde_json::json!({ "variables": ["dd", "env"], "paths": ["dd.port", "env"] })
+        );
+        assert_eq!(
+            report[key("ports.txt.j2")],
+            serde_json::json!({ "variables": ["dd", "owner"], "paths": ["dd.ports", "owner.email"] })
+        );
+    }
+
+    #[test]
+    fn test_concat_output() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let migrations = tmp.path().join("migrations");
+        std::fs::create_dir_all(migrations.join("02_data")).unwrap();
+        std::fs::write(migrations.join("01_schema.sql.j2"), "CREATE SCHEMA {{ env }};").unwrap();
+        std::fs::write(migrations.join("02_data/users.sql"), "INSERT INTO users VALUES (1);")
+            .unwrap();
+        std::fs::write(migrations.join("03_grants.sql.j2"), "GRANT ALL TO {{ env }}_admin;")
+            .unwrap();
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Migration\"\n    folder: \"migrations\"\n\
+             \x20   concat_output: \"{{ env }}.sql\"\n    concat_separator: \"\\n\\n\"\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "dev"}"#).unwrap();
+        let out = tmp.path().join("out");
+        run(&[
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(out.join("dev.sql")).unwrap(),
+            "CREATE SCHEMA dev;\n\nINSERT INTO users VALUES (1);\n\nGRANT ALL TO dev_admin;"
+        );
+        assert!(!out.join("02_data").exists());
+    }
+
+    #[test]
+    fn test_migrate_markers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let template = tmp.path().join("templates/{{ env }}.txt.j2");
+        std::fs::write(&template, "env={{ env }}\n// MANUAL SECTION START: notes\n// MANUAL SECTION END\n")
+            .unwrap();
+        let data = tmp.path().join("data.json");
+        std::fs::write(&data, r#"{"env": "dev"}"#).unwrap();
+        let out = tmp.path().join("out");
+        let args = [
+            "-c",
+            config_path.to_str().unwrap(),
+            "-d",
+            data.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+        ];
+        run(&args).unwrap();
+        let output = out.join("dev.txt");
+        std::fs::write(
+            &output,
+            "env=dev\n// MANUAL SECTION START: notes\nhand-written\n// MANUAL SECTION END\n",
+        )
+        .unwrap();
+
+        // Switch to new markers, migrate the existing output and generate again
+        std::fs::write(
+            &config_path,
+            "manual_sections:\n  start_marker: \"USER CODE BEGIN\"\n  end_marker: \"USER CODE END\"\n\
+             templates:\n  - name: \"Env\"\n    folder: \"templates\"\n",
+        )
+        .unwrap();
+        std::fs::write(&template, "env={{ env }}\n// USER CODE BEGIN: notes\n// USER CODE END\n").unwrap();
+        let migrate = ["--migrate-markers=MANUAL SECTION START,MANUAL SECTION END"];
+        let cli = Cli::try_parse_from(std::iter::once("yagen").chain([&args[..], &migrate].concat()))
+            .unwrap();
+        run_command(cli).unwrap();
+        assert!(std::fs::read_to_string(&output).unwrap().contains("USER CODE BEGIN: notes\nhand-written"));
+        run(&args).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "env=dev\n// USER CODE BEGIN: notes\nhand-written\n// USER CODE END"
+        );
+        assert!(parse_marker_pair("START").is_err());
+    }
+
+    #[test]
+    fn test_config_check() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(
+            &config_path,
+            "flatten_data: false\ntemplates:\n  - name: \"Env\"\n    folder: \"templates\"\n",
+        )
+        .unwrap();
+
+        let yaml = config_check(Some(&config_path), DataFormat::Yaml).unwrap();
+        let dumped: serde_json::Value = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(dumped["flatten_data"], false);
+        assert_eq!(dumped["flatten_depth"], 1);
+        assert_eq!(dumped["manual_sections"]["start_marker"], "MANUAL SECTION START");
+        assert_eq!(dumped["templates"][0]["enabled"], true);
+        // The dump loads back as the same config
+        let reloaded: TemplateConfig = serde_yaml::from_str(&yaml).unwrap();
+        assert!(!reloaded.flatten_data);
+
+        let json = config_check(Some(&config_path), DataFormat::Json).unwrap();
+        let dumped: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(dumped["flatten_separator"], "_");
+    }
+
+    #[test]
+    fn test_doctor() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = tmp.path().join("config.yaml");
+        let config = "templates: []\nformat:\n  enabled: true\n  formatters:\n    \"*.txt\":\n      type: command\n      command: sh\n";
+        std::fs::write(&config_path, config).unwrap();
+        doctor(Some(&config_path), false).unwrap();
+
+        std::fs::write(
+            &config_path,
+            format!("{}    \"*.rs\":\n      type: command\n      command: templify-missing-formatter\n", config),
+        )
+        .unwrap();
+        let err = doctor(Some(&config_path), false).unwrap_err();
+        assert_eq!(Failure::of(&err), Failure::Validation);
+        assert!(err.to_string().contains("1 formatter command(s) not found"), "{}", err);
+    }
+
+    #[test]
+    fn test_computed_globals() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        std::fs::write(
+            tmp.path().join("templates/{{ env }}.txt.j2"),
+            "{{ globals.service_count }} services",
+        )
+        .unwrap();
+        let services = tmp.path().join("services");
+        std::fs::create_dir_all(&services).unwrap();
+        std::fs::write(services.join("{{ svc }}.txt.j2"), "{{ globals.title }} of {{ globals.service_count }}")
+            .unwrap();
+        std::fs::write(
+            &config_path,
+            "globals:\n  service_count: \"{{ dd.services | length }}\"\n\
+             templates:\n  - folder: \"templates\"\n  - folder: \"services\"\n    iterate: \"svc in services\"\n\
+             \x20   globals:\n      title: { expr: \"loop.index ~ ': ' ~ svc | upper\" }\n",
+        )
+        .unwrap();
+        let data = tmp.path().join("dev.json");
+        std::fs::write(&data, r#"{"env": "dev", "services": ["api", "web", "db"]}"#).unwrap();
+        let out = tmp.path().join("out");
+
+        run(&["-c", config_path.to_str().unwrap(), "-d", data.to_str().unwrap(), "-o", out.to_str().unwrap()])
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("dev.txt")).unwrap(), "3 services");
+        assert_eq!(std::fs::read_to_string(out.join("web.txt")).unwrap(), "2: WEB of 3");
+
+        // A computed global reading a variable no set binds is an error, not left as written
+        std::fs::write(
+            &config_path,
+            "globals:\n  count: \"{{ servces | length }}\"\ntemplates:\n  - folder: \"templates\"\n",
+        )
+        .unwrap();
+        let err = run(&["-c", config_path.to_str().unwrap(), "-d", data.to_str().unwrap(), "-o", out.to_str().unwrap()])
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("undefined variable(s) servces"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_validate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let data = tmp.path().join("dev.json");
+        std::fs::write(&data, r#"{"env": "dev", "port": 1, "services": [{"name": "api"}]}"#).unwrap();
+        let validate_args = |args: &[&str]| {
+            let cli = Cli::try_parse_from(
+                ["yagen", "validate", "-c", config_path.to_str().unwrap(), "-d", data.to_str().unwrap()]
+                    .iter()
+                    .chain(args),
+            )
+            .unwrap();
+            validate(&cli)
+        };
+        assert_eq!(validate_args(&[]).unwrap(), 1);
+
+        let services = tmp.path().join("services");
+        std::fs::create_dir_all(&services).unwrap();
+        std::fs::write(services.join("{{ svc.name }}.txt.j2"), "{{ svc.name }} {{ loop.index }} {{ svc_typo }}")
+            .unwrap();
+        std::fs::write(services.join("broken.txt.j2"), "{% if %}").unwrap();
+        std::fs::write(
+            &config_path,
+            "templates:\n  - name: \"Env\"\n    folder: \"templates\"\n\
+             \x20 - name: \"Services\"\n    folder: \"services\"\n    iterate: \"svc in services if svc.name !=\"\n\
+             \x20 - name: \"Missing\"\n    folder: \"services\"\n    iterate: \"svc in nothing\"\n\
+             \x20 - name: \"Disabled\"\n    folder: \"nowhere\"\n    enabled: false\n",
+        )
+        .unwrap();
+        let err = validate_args(&[]).unwrap_err();
+        assert_eq!(Failure::of(&err), Failure::Validation);
+        let message = err.to_string();
+        assert!(message.contains("Validation failed with 4 error(s)"), "{}", message);
+        assert!(message.contains("set 'Services': invalid condition `svc.name !=`"), "{}", message);
+        assert!(message.contains("broken.txt.j2"), "{}", message);
+        assert!(message.contains("set 'Missing': iteration expression 'nothing' did not resolve"), "{}", message);
+        assert!(!message.contains("nowhere"), "{}", message);
+        // Undefined variables are reported once the templates compile
+        std::fs::remove_file(services.join("broken.txt.j2")).unwrap();
+        let message = validate_args(&["--include", "Services"]).unwrap_err().to_string();
+        assert!(message.contains("undefined variable 'svc_typo'"), "{}", message);
+        assert!(!message.contains("'loop'") && !message.contains("'Missing'"), "{}", message);
+        assert!(!tmp.path().join("dev.txt").exists());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let tmp = tempfile::tempdir().unwrap();
+        let config_path = write_project(tmp.path());
+        let data = tmp.path().join("dev.json");
+        std::fs::write(&data, r#"{"env": "dev", "port": 1}"#).unwrap();
+        let out = tmp.path().join("out");
+        let cli = |timeout: &str| {
+            Cli::try_parse_from([
+                "yagen",
+                "-c",
+                config_path.to_str().unwrap(),
+                "-d",
+                data.to_str().unwrap(),
+                "-o",
+                out.to_str().unwrap(),
+                "--timeout",
+                timeout,
+            ])
+            .unwrap()
+        };
+
+        // A run finishing in time
+        run_with_timeout(cli("30")).unwrap();
+        assert_eq!(std::fs::read_to_string(out.join("dev.txt")).unwrap(), "env=dev port=1");
+
+        // A hanging formatter is killed with the commands it started
+        let marker = tmp.path().join("marker");
+        std::fs::write(
+            &config_path,
+            format!(
+                "templates:\n  - name: \"Env\"\n    folder: \"templates\"\n\
+                 format:\n  enabled: true\n  formatters:\n    \"*.txt\":\n      type: command\n      command: sh\n      args: [\"-c\", \"(sleep 2; touch {}) & sleep 30; cat\"]\n",
+                marker.display()
+            ),
+        )
+        .unwrap();
+        let started = Instant::now();
+        let err = run_with_timeout(cli("1")).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(10), "the slow formatter was killed");
+        assert_eq!(Failure::of(&err), Failure::Timeout);
+        assert_eq!(Failure::Timeout as u8, 4);
+        assert!(err.to_string().contains("timeout of 1s"), "{}", err);
+        std::thread::sleep(Duration::from_secs(2));
+        assert!(!marker.exists(), "the background child of the formatter was killed");
+    }
+
+    #[test]
+    fn test_data_stem() {
+        assert_eq!(data_stem(Path::new("envs/dev.json")).unwrap(), "dev");
+        assert_eq!(data_stem(Path::new("https://host/api/prod.yaml?x=1")).unwrap(), "prod");
+        assert_eq!(data_stem(Path::new("envs/my.env.json")).unwrap(), "my.env");
+        assert!(data_stems(&[PathBuf::from("a/dev.json"), PathBuf::from("b/dev.yaml")]).is_err());
+        assert!(Cli::try_parse_from(["yagen", "--per-data-output", "envs", "-o", "out"]).is_err());
+    }
+}
@@ -2,50 +2,152 @@
 // But I can try to add print statements for debugging since this is dev/test.
 // Or actually adduse regex::Regex;
 use regex::Regex;
-use crate::config::ManualSectionConfig;
+use crate::config::{ManualSectionConfig, MarkerSet, SectionPolicy};
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Outcome of a manual section when merging new output with the previous file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionOutcome {
+    /// The section content was carried over from the previous file.
+    Preserved,
+    /// The section did not exist before and the newly rendered content was used.
+    Created,
+    /// The section belongs to a marker set with the `reset` policy, so the newly rendered
+    /// content was used.
+    Reset,
+}
+
+/// A structural problem with the manual sections of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionIssue {
+    /// 1-based line number of the marker causing the problem.
+    pub line: usize,
+    pub message: String,
+}
 
-/// The regex pattern for manual section IDs.
-const MANUAL_SECTION_ID: &str = "[a-zA-Z0-9_-]+";
+/// A manual section located in content, found by the nesting-aware parser.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SectionSpan {
+    id: String,
+    /// Byte offset of the start marker.
+    start: usize,
+    /// Byte offset just after the end marker.
+    end: usize,
+    /// Number of enclosing sections.
+    depth: usize,
+}
 
 #[derive(Clone)]
 pub struct ManualSectionManager {
     config: ManualSectionConfig,
+    /// Matches a whole section, from the start marker to the first end marker
+    section_re: Regex,
+    /// Matches a start marker with its ID
+    start_re: Regex,
+    /// Same as `start_re`, anchored at the beginning of the searched text
+    start_at_re: Regex,
+    /// Managers for the configured `marker_sets`, each handling only its own markers
+    marker_sets: Vec<(MarkerSet, ManualSectionManager)>,
 }
 
 impl ManualSectionManager {
+    /// Creates a manager for the given config.
+    ///
+    /// # Panics
+    /// If `id_pattern` is not a valid regex. `TemplateConfig::load` rejects such configs; use
+    /// `try_new` for configs from elsewhere.
     pub fn new(config: ManualSectionConfig) -> Self {
-        Self { config }
+        Self::try_new(config).unwrap_or_else(|e| panic!("{}", e))
     }
 
-    pub fn preserve_sections(&self, new_rendered: &str, prev_rendered: &str) -> String {
-        // Build the regex pattern dynamically
-        let manual_section_pattern = format!(
-            r"{}:\s*({})(?:\s|$)(?s)(.*?){}",
-            regex::escape(&self.config.start_marker),
-            MANUAL_SECTION_ID,
-            regex::escape(&self.config.end_marker)
+    /// Creates a manager for the given config, failing if `id_pattern` is not a valid regex.
+    pub fn try_new(config: ManualSectionConfig) -> Result<Self, String> {
+        let marker_sets = config
+            .marker_sets
+            .iter()
+            .map(|set| {
+                let manager = ManualSectionManager::try_new(ManualSectionConfig {
+                    start_marker: set.start_marker.clone(),
+                    end_marker: set.end_marker.clone(),
+                    marker_sets: Vec::new(),
+                    ..config.clone()
+                })?;
+                Ok((set.clone(), manager))
+            })
+            .collect::<Result<_, String>>()?;
+        let start = format!(
+            r"{}:\s*{}(?:\s|$)",
+            regex::escape(&config.start_marker),
+            id_group(&config.id_pattern)
         );
-        
-        // We also need a pattern to find starts without matching the full block necessarily, 
-        // but for preservation we iterate over full blocks in new_rendered.
-        
-        let re = match Regex::new(&manual_section_pattern) {
-            Ok(r) => r,
-            Err(e) => {
-                eprintln!("Failed to compile regex for manual sections: {}", e);
-                return new_rendered.to_string();
-            }
+        let compile = |pattern: &str| {
+            Regex::new(pattern).map_err(|e| format!("Invalid manual section id_pattern: {}", e))
         };
+        let section = format!(r"{}(?s).*?{}", start, regex::escape(&config.end_marker));
+        Ok(Self {
+            section_re: compile(&section)?,
+            start_re: compile(&start)?,
+            start_at_re: compile(&format!("^{}", start))?,
+            config,
+            marker_sets,
+        })
+    }
 
+    pub fn preserve_sections(&self, new_rendered: &str, prev_rendered: &str) -> String {
+        self.preserve_sections_with_outcomes(new_rendered, prev_rendered).0
+    }
+
+    /// Same as `preserve_sections`, but also returns which sections were preserved or created.
+    /// Sections of marker sets with the `reset` policy keep the newly rendered content.
+    pub fn preserve_sections_with_outcomes(
+        &self,
+        new_rendered: &str,
+        prev_rendered: &str,
+    ) -> (String, Vec<(String, SectionOutcome)>) {
+        let (mut content, mut outcomes) = self.merge_sections(new_rendered, prev_rendered);
+        for (set, manager) in &self.marker_sets {
+            match set.policy {
+                SectionPolicy::Preserve => {
+                    let (merged, set_outcomes) = manager.merge_sections(&content, prev_rendered);
+                    content = merged;
+                    outcomes.extend(set_outcomes);
+                }
+                SectionPolicy::Reset => outcomes.extend(
+                    manager
+                        .extract_section_ids(&content)
+                        .into_iter()
+                        .map(|id| (id, SectionOutcome::Reset)),
+                ),
+            }
+        }
+        (content, outcomes)
+    }
+
+    /// Merges the sections of this manager's markers from `prev_rendered` into `new_rendered`.
+    fn merge_sections(
+        &self,
+        new_rendered: &str,
+        prev_rendered: &str,
+    ) -> (String, Vec<(String, SectionOutcome)>) {
+        if self.config.allow_nested_sections {
+            let blocks = self.extract_blocks(prev_rendered);
+            let mut outcomes = Vec::new();
+            let content = self.merge_nested(new_rendered, &blocks, &mut outcomes);
+            return (content, outcomes);
+        }
+
+        let re = &self.section_re;
         let mut preserved = String::new();
+        let mut outcomes = Vec::new();
         let mut last_end = 0;
 
         for cap in re.captures_iter(new_rendered) {
             let start = cap.get(0).unwrap().start();
             let end = cap.get(0).unwrap().end();
-            let id = cap.get(1).unwrap().as_str();
+            let id = &cap["id"];
 
             preserved.push_str(&new_rendered[last_end..start]);
 
@@ -55,49 +157,40 @@ impl ManualSectionManager {
             // But keeping it simple for now to match logic structure.
             let prev_cap = re
                 .captures_iter(prev_rendered)
-                .find(|c| c.get(1).unwrap().as_str() == id);
+                .find(|c| &c["id"] == id);
 
             if let Some(prev_cap) = prev_cap {
-                preserved.push_str(prev_cap.get(0).unwrap().as_str());
+                let block = prev_cap.get(0).unwrap().as_str();
+                preserved.push_str(&self.place_block(id, block, new_rendered, start));
+                outcomes.push((id.to_string(), SectionOutcome::Preserved));
             } else {
                 preserved.push_str(&new_rendered[start..end]);
+                outcomes.push((id.to_string(), SectionOutcome::Created));
             }
 
             last_end = end;
         }
 
         preserved.push_str(&new_rendered[last_end..]);
-        preserved
+        (preserved, outcomes)
     }
 
     /// Extract all section IDs from content
     pub fn extract_section_ids(&self, content: &str) -> Vec<String> {
-        let pattern = format!(
-            r"{}:\s*({})(?:\s|$)",
-            regex::escape(&self.config.start_marker),
-            MANUAL_SECTION_ID
-        );
-        let re = Regex::new(&pattern).unwrap(); // Should be safe if markers are safe
-        re.captures_iter(content)
-            .map(|cap| cap.get(1).unwrap().as_str().to_string())
+        self.start_re
+            .captures_iter(content)
+            .map(|cap| cap["id"].to_string())
             .collect()
     }
 
     /// Check for duplicate IDs
     pub fn check_duplicates(&self, content: &str, filename: &str) -> Result<(), String> {
-        let ids = self.extract_section_ids(content);
-        let mut seen = HashSet::new();
-        let mut duplicates = Vec::new();
-
-        for id in ids {
-            if !seen.insert(id.clone()) {
-                duplicates.push(id);
-            }
-        }
-
+        let duplicates: Vec<String> = self
+            .duplicate_ids(content)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
         if !duplicates.is_empty() {
-            // Find line number of first duplicate
-            // This is a bit rough, assuming we just need to return error
             return Err(format!("Duplicate manual section IDs in {:?}: {:?}", filename, duplicates));
         }
         Ok(())
@@ -105,42 +198,200 @@ impl ManualSectionManager {
 
     /// Check for nested sections and unclosed sections
     pub fn check_structure(&self, content: &str, filename: &str) -> Result<(), String> {
-        let start_marker = &self.config.start_marker;
-        let end_marker = &self.config.end_marker;
-
-        let start_count = content.matches(start_marker).count();
-        let end_count = content.matches(end_marker).count();
+        match self.structure_issues(content).first() {
+            Some(issue) => Err(format!("{} in {:?} at line {}", issue.message, filename, issue.line)),
+            None => Ok(()),
+        }
+    }
 
-        if start_count != end_count {
-            return Err(format!(
-                "Mismatched manual section markers in {:?}: {} starts, {} ends",
-                filename, start_count, end_count
-            ));
+    /// Finds all structural problems (unbalanced, nested or duplicate sections) with line numbers.
+    /// This is the reporting counterpart of `check_structure` and `check_duplicates`.
+    pub fn find_issues(&self, content: &str) -> Vec<SectionIssue> {
+        let mut issues = self.structure_issues(content);
+        issues.extend(self.duplicate_ids(content).into_iter().map(|(_, issue)| issue));
+        for (_, manager) in &self.marker_sets {
+            issues.extend(manager.find_issues(content));
         }
+        issues.sort_by_key(|issue| issue.line);
+        issues
+    }
 
-        // Check for nesting
-        // A simple way is to track depth. 
-        // We find all start and end indices and sort them.
+    /// Finds unbalanced sections and, unless nesting is allowed, nested sections of this
+    /// manager's markers, in content order.
+    fn structure_issues(&self, content: &str) -> Vec<SectionIssue> {
         let mut events = Vec::new();
-        for (i, _) in content.match_indices(start_marker) {
-            events.push((i, 1)); // 1 for start
+        for (i, _) in content.match_indices(&self.config.start_marker) {
+            events.push((i, true));
         }
-        for (i, _) in content.match_indices(end_marker) {
-            events.push((i, -1)); // -1 for end
+        for (i, _) in content.match_indices(&self.config.end_marker) {
+            events.push((i, false));
         }
         events.sort_by_key(|k| k.0);
 
-        let mut depth = 0;
-        for (_, change) in events {
-            depth += change;
-            if depth > 1 {
-                return Err(format!("Nested manual sections detected in {:?}", filename));
+        let mut issues = Vec::new();
+        // Lines of the open sections; without nesting, a new start replaces the open section
+        let mut open: Vec<usize> = Vec::new();
+        for (index, is_start) in events {
+            let line = line_of(content, index);
+            if is_start {
+                if !self.config.allow_nested_sections {
+                    if let Some(open_line) = open.pop() {
+                        issues.push(SectionIssue {
+                            line,
+                            message: format!("Nested manual section inside section started at line {}", open_line),
+                        });
+                    }
+                }
+                open.push(line);
+            } else if open.pop().is_none() {
+                issues.push(SectionIssue {
+                    line,
+                    message: "Manual section end without start".to_string(),
+                });
+            }
+        }
+        for open_line in open {
+            issues.push(SectionIssue {
+                line: open_line,
+                message: "Manual section is not closed".to_string(),
+            });
+        }
+        issues
+    }
+
+    /// Finds the repeated section IDs of this manager's markers, each with the issue to report
+    /// at the repeated start marker.
+    fn duplicate_ids(&self, content: &str) -> Vec<(String, SectionIssue)> {
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        let mut duplicates = Vec::new();
+        for cap in self.start_re.captures_iter(content) {
+            let id = cap["id"].to_string();
+            let line = line_of(content, cap.get(0).unwrap().start());
+            match seen.get(&id) {
+                Some(first_line) => {
+                    let message =
+                        format!("Duplicate manual section ID '{}' (first at line {})", id, first_line);
+                    duplicates.push((id, SectionIssue { line, message }));
+                }
+                None => {
+                    seen.insert(id, line);
+                }
+            }
+        }
+        duplicates
+    }
+
+    /// Rewrites the manual section markers `old_start`/`old_end` of content written with earlier
+    /// settings to the configured markers, keeping everything else, including the section
+    /// content, as it is. Only start markers followed by a section ID are rewritten, and only
+    /// end markers closing such a section. Returns None if there was nothing to rewrite.
+    pub fn migrate_markers(
+        &self,
+        content: &str,
+        old_start: &str,
+        old_end: &str,
+    ) -> Result<Option<String>, String> {
+        let start_re = Regex::new(&format!(
+            r"{}:\s*{}",
+            regex::escape(old_start),
+            id_group(&self.config.id_pattern)
+        ))
+        .map_err(|e| format!("Invalid manual section id_pattern: {}", e))?;
+        let starts: Vec<usize> = start_re.find_iter(content).map(|m| m.start()).collect();
+        // (offset, length, replacement) of each marker, in content order
+        let mut events: Vec<(usize, usize, Option<&str>)> = starts
+            .iter()
+            .map(|&i| (i, old_start.len(), Some(self.config.start_marker.as_str())))
+            .collect();
+        // Skip end markers inside a start marker, e.g. `SECTION` in `SECTION START`. Both are
+        // found in content order, so one pass over the start markers is enough.
+        let mut next_start = 0;
+        for (i, _) in content.match_indices(old_end) {
+            while starts.get(next_start).is_some_and(|&start| start + old_start.len() <= i) {
+                next_start += 1;
             }
-            if depth < 0 {
-                return Err(format!("Manual section end before start in {:?}", filename));
+            if starts.get(next_start).is_none_or(|&start| start > i) {
+                events.push((i, old_end.len(), None));
             }
         }
+        events.sort_by_key(|event| event.0);
+
+        let mut migrated = String::with_capacity(content.len());
+        let mut last = 0;
+        let mut open = 0;
+        for (offset, len, start) in events {
+            let replacement = match start {
+                Some(marker) => {
+                    open += 1;
+                    marker
+                }
+                // An end marker that doesn't close a section is left alone
+                None if open == 0 => continue,
+                None => {
+                    open -= 1;
+                    self.config.end_marker.as_str()
+                }
+            };
+            migrated.push_str(&content[last..offset]);
+            migrated.push_str(replacement);
+            last = offset + len;
+        }
+        migrated.push_str(&content[last..]);
+        Ok((migrated != content).then_some(migrated))
+    }
 
+    /// Rewrites the markers of all text files below the given paths with `migrate_markers`.
+    /// Returns the files that were (or, if `dry_run` is set, would be) changed.
+    pub fn migrate_paths(
+        &self,
+        paths: &[PathBuf],
+        old_start: &str,
+        old_end: &str,
+        dry_run: bool,
+    ) -> Result<Vec<PathBuf>, String> {
+        let mut files = Vec::new();
+        for path in paths {
+            collect_text_files(path, &mut files)?;
+        }
+        let mut migrated = Vec::new();
+        for (path, content) in files {
+            if let Some(content) = self.migrate_markers(&content, old_start, old_end)? {
+                if !dry_run {
+                    std::fs::write(&path, content).map_err(|e| format!("{:?}: {}", path, e))?;
+                }
+                migrated.push(path);
+            }
+        }
+        Ok(migrated)
+    }
+
+    /// Checks all text files below the given paths and returns the problems found per file.
+    /// Files that are not valid UTF-8 are skipped.
+    pub fn verify_paths(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, SectionIssue)>, String> {
+        let mut issues = Vec::new();
+        for path in paths {
+            self.verify_path(path, &mut issues)?;
+        }
+        Ok(issues)
+    }
+
+    fn verify_path(&self, path: &Path, issues: &mut Vec<(PathBuf, SectionIssue)>) -> Result<(), String> {
+        if path.is_dir() {
+            let mut entries = Vec::new();
+            for entry in std::fs::read_dir(path).map_err(|e| format!("{:?}: {}", path, e))? {
+                entries.push(entry.map_err(|e| e.to_string())?.path());
+            }
+            entries.sort();
+            for entry in entries {
+                self.verify_path(&entry, issues)?;
+            }
+        } else if let Ok(content) = std::fs::read_to_string(path) {
+            for issue in self.find_issues(&content) {
+                issues.push((path.to_path_buf(), issue));
+            }
+        } else if !path.exists() {
+            return Err(format!("Path does not exist: {:?}", path));
+        }
         Ok(())
     }
 
@@ -164,7 +415,7 @@ impl ManualSectionManager {
              let prev_ids = self.extract_section_ids(prev);
 
              for id in prev_ids {
-                 if !curr_ids.contains(&id) {
+                 if !curr_ids.contains(&id) && !self.config.optional_sections.contains(&id) {
                      return Err(format!(
                          "Manual section '{}' from existing file is missing in new template output for {:?}", 
                          id, template_path
@@ -173,50 +424,104 @@ impl ManualSectionManager {
              }
         }
 
+        // Sections that are reset on regeneration can't be lost
+        for (set, manager) in &self.marker_sets {
+            let prev = prev_rendered.filter(|_| set.policy == SectionPolicy::Preserve);
+            manager.validate_sections(template_path, rendered, prev)?;
+        }
         Ok(())
     }
 
-    /// Extract all section blocks (complete with markers) from content
+    /// Returns true if the content contains a start or end marker.
+    pub fn contains_markers(&self, content: &str) -> bool {
+        content.contains(&self.config.start_marker)
+            || content.contains(&self.config.end_marker)
+            || self
+                .marker_sets
+                .iter()
+                .any(|(_, manager)| manager.contains_markers(content))
+    }
+
+    /// Returns true if any manual section in content has a non-blank body; sections of marker
+    /// sets with the `reset` policy don't count.
+    /// The remainder of the start marker line and the prefix of the end marker line are ignored.
+    pub fn has_non_empty_sections(&self, content: &str) -> bool {
+        self.has_own_non_empty_sections(content)
+            || self.marker_sets.iter().any(|(set, manager)| {
+                set.policy == SectionPolicy::Preserve && manager.has_own_non_empty_sections(content)
+            })
+    }
+
+    fn has_own_non_empty_sections(&self, content: &str) -> bool {
+        let has_content = self.section_re.find_iter(content).any(|m| {
+            let lines: Vec<&str> = m.as_str().lines().collect();
+            lines.len() > 2 && lines[1..lines.len() - 1].iter().any(|line| !line.trim().is_empty())
+        });
+        has_content
+    }
+
+    /// Extract all section blocks (complete with markers) from content. Blocks of marker sets
+    /// are keyed `<set name>:<id>`.
     pub fn extract_blocks(&self, content: &str) -> HashMap<String, String> {
-        let pattern = format!(
-            r"({}:\s*({})(?:\s|$)(?s)(.*?){})",
-            regex::escape(&self.config.start_marker),
-            MANUAL_SECTION_ID,
-            regex::escape(&self.config.end_marker)
-        );
-        let re = Regex::new(&pattern).unwrap();
-        
+        let mut blocks = self.extract_own_blocks(content);
+        for (set, manager) in &self.marker_sets {
+            for (id, block) in manager.extract_own_blocks(content) {
+                blocks.insert(format!("{}:{}", set.name, id), block);
+            }
+        }
+        blocks
+    }
+
+    fn extract_own_blocks(&self, content: &str) -> HashMap<String, String> {
+        if self.config.allow_nested_sections {
+            return self
+                .parse_nested(content)
+                .into_iter()
+                .map(|span| (span.id, content[span.start..span.end].to_string()))
+                .collect();
+        }
+
         let mut blocks = HashMap::new();
-        for cap in re.captures_iter(content) {
-            let full_block = cap.get(1).unwrap().as_str().to_string();
-            let id = cap.get(2).unwrap().as_str().to_string();
-            blocks.insert(id, full_block);
+        for cap in self.section_re.captures_iter(content) {
+            let full_block = cap.get(0).unwrap().as_str().to_string();
+            blocks.insert(cap["id"].to_string(), full_block);
         }
         blocks
     }
 
-    /// Restore blocks into content
+    /// Restore blocks returned by `extract_blocks` into content
     pub fn restore_blocks(&self, content: &str, blocks: &HashMap<String, String>) -> String {
-        let pattern = format!(
-            r"{}:\s*({})(?:\s|$)(?s)(.*?){}",
-            regex::escape(&self.config.start_marker),
-            MANUAL_SECTION_ID,
-            regex::escape(&self.config.end_marker)
-        );
-        let re = Regex::new(&pattern).unwrap();
+        let mut content = self.restore_own_blocks(content, blocks);
+        for (set, manager) in &self.marker_sets {
+            let prefix = format!("{}:", set.name);
+            let set_blocks: HashMap<String, String> = blocks
+                .iter()
+                .filter_map(|(key, block)| {
+                    Some((key.strip_prefix(&prefix)?.to_string(), block.clone()))
+                })
+                .collect();
+            content = manager.restore_own_blocks(&content, &set_blocks);
+        }
+        content
+    }
+
+    fn restore_own_blocks(&self, content: &str, blocks: &HashMap<String, String>) -> String {
+        if self.config.allow_nested_sections {
+            return self.merge_nested(content, blocks, &mut Vec::new());
+        }
 
         let mut result = String::new();
         let mut last_end = 0;
 
-        for cap in re.captures_iter(content) {
+        for cap in self.section_re.captures_iter(content) {
             let start = cap.get(0).unwrap().start();
             let end = cap.get(0).unwrap().end();
-            let id = cap.get(1).unwrap().as_str();
+            let id = &cap["id"];
 
             result.push_str(&content[last_end..start]);
 
             if let Some(original_block) = blocks.get(id) {
-                result.push_str(original_block);
+                result.push_str(&self.place_block(id, original_block, content, start));
             } else {
                 result.push_str(&content[start..end]);
             }
@@ -225,6 +530,178 @@ impl ManualSectionManager {
         result.push_str(&content[last_end..]);
         result
     }
+
+    /// Returns a preserved block to put at `start` of `content`. The indentation of the block's
+    /// end marker line is taken as the indentation of the block; when it differs from the
+    /// indentation of the line at `start`, the block lines are moved to the new indentation if
+    /// `reindent` is set, converting tabs to spaces or back (e.g. one tab per four spaces).
+    /// Otherwise a change between tabs and spaces is reported, as it makes formatters and
+    /// preserved sections fight over the indentation on every run.
+    fn place_block<'a>(&self, id: &str, block: &'a str, content: &str, start: usize) -> Cow<'a, str> {
+        let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+        let new_base = leading_whitespace(&content[line_start..start]);
+        let Some((_, end_line)) = block.rsplit_once('\n') else {
+            return Cow::Borrowed(block);
+        };
+        let old_base = leading_whitespace(end_line);
+        if old_base == new_base {
+            return Cow::Borrowed(block);
+        }
+        if !self.config.reindent {
+            if old_base.contains('\t') != new_base.contains('\t')
+                && !old_base.is_empty()
+                && !new_base.is_empty()
+            {
+                log::warn!(
+                    "Manual section '{}' mixes tabs and spaces with the surrounding code; set \
+                     manual_sections.reindent to re-indent it",
+                    id
+                );
+            }
+            return Cow::Borrowed(block);
+        }
+
+        // Runs of the old indentation character are scaled to the new one, e.g. a tab to four
+        // spaces or four spaces to a tab; other whitespace is kept
+        let uniform = |base: &str| base.chars().all(|c| base.starts_with(c));
+        let unit = match (old_base.chars().next(), new_base.chars().next()) {
+            (Some(old_char), Some(new_char)) if uniform(old_base) && uniform(new_base) => {
+                Some((old_char, new_char))
+            }
+            _ => None,
+        };
+        let mut lines = block.split('\n');
+        let mut result = lines.next().unwrap_or_default().to_string();
+        for line in lines {
+            result.push('\n');
+            let Some(rest) = line.strip_prefix(old_base) else {
+                result.push_str(line);
+                continue;
+            };
+            result.push_str(new_base);
+            let indent = leading_whitespace(rest);
+            let mut chars = indent.chars().peekable();
+            while let Some(c) = chars.next() {
+                let Some((old_char, new_char)) = unit.filter(|(old_char, _)| *old_char == c) else {
+                    result.push(c);
+                    continue;
+                };
+                let mut run = 1;
+                while chars.next_if_eq(&old_char).is_some() {
+                    run += 1;
+                }
+                let width = run * new_base.len();
+                if width.is_multiple_of(old_base.len()) {
+                    result.extend(std::iter::repeat_n(new_char, width / old_base.len()));
+                } else {
+                    result.extend(std::iter::repeat_n(old_char, run));
+                }
+            }
+            result.push_str(&rest[indent.len()..]);
+        }
+        Cow::Owned(result)
+    }
+
+    /// Finds all matched sections, including nested ones, in order of their start markers.
+    /// Unmatched markers are ignored; `find_issues` reports them.
+    fn parse_nested(&self, content: &str) -> Vec<SectionSpan> {
+        let mut events = Vec::new();
+        for (i, _) in content.match_indices(&self.config.start_marker) {
+            events.push((i, true));
+        }
+        for (i, _) in content.match_indices(&self.config.end_marker) {
+            events.push((i, false));
+        }
+        events.sort_by_key(|k| k.0);
+
+        let mut spans = Vec::new();
+        let mut stack: Vec<(usize, Option<String>)> = Vec::new();
+        for (index, is_start) in events {
+            if is_start {
+                let id = self
+                    .start_at_re
+                    .captures(&content[index..])
+                    .map(|cap| cap["id"].to_string());
+                stack.push((index, id));
+            } else if let Some((start, Some(id))) = stack.pop() {
+                spans.push(SectionSpan {
+                    id,
+                    start,
+                    end: index + self.config.end_marker.len(),
+                    depth: stack.len(),
+                });
+            }
+        }
+        spans.sort_by_key(|span| span.start);
+        spans
+    }
+
+    /// Replaces each outermost section of `content` with the block of the same ID. Sections
+    /// without a block keep their content, but their inner sections are merged the same way.
+    fn merge_nested(
+        &self,
+        content: &str,
+        blocks: &HashMap<String, String>,
+        outcomes: &mut Vec<(String, SectionOutcome)>,
+    ) -> String {
+        let mut result = String::new();
+        let mut last_end = 0;
+        for span in self.parse_nested(content) {
+            if span.depth > 0 {
+                continue;
+            }
+            result.push_str(&content[last_end..span.start]);
+            if let Some(block) = blocks.get(&span.id) {
+                result.push_str(&self.place_block(&span.id, block, content, span.start));
+                outcomes.push((span.id, SectionOutcome::Preserved));
+            } else {
+                outcomes.push((span.id.clone(), SectionOutcome::Created));
+                let inner_start = span.start + self.config.start_marker.len();
+                let inner_end = span.end - self.config.end_marker.len();
+                result.push_str(&content[span.start..inner_start]);
+                result.push_str(&self.merge_nested(&content[inner_start..inner_end], blocks, outcomes));
+                result.push_str(&content[inner_end..span.end]);
+            }
+            last_end = span.end;
+        }
+        result.push_str(&content[last_end..]);
+        result
+    }
+}
+
+/// Wraps the section ID pattern in the named group `id`, so groups of its own don't shift the
+/// groups of the patterns it is embedded in.
+fn id_group(id_pattern: &str) -> String {
+    format!("(?P<id>{})", id_pattern)
+}
+
+/// Returns the 1-based line number of a byte offset in content.
+fn line_of(content: &str, index: usize) -> usize {
+    content[..index].matches('\n').count() + 1
+}
+
+/// Returns the leading spaces and tabs of a line.
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Collects the UTF-8 files below `path` (or `path` itself) with their content, in sorted order.
+fn collect_text_files(path: &Path, files: &mut Vec<(PathBuf, String)>) -> Result<(), String> {
+    if path.is_dir() {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(path).map_err(|e| format!("{:?}: {}", path, e))? {
+            entries.push(entry.map_err(|e| e.to_string())?.path());
+        }
+        entries.sort();
+        for entry in entries {
+            collect_text_files(&entry, files)?;
+        }
+    } else if let Ok(content) = std::fs::read_to_string(path) {
+        files.push((path.to_path_buf(), content));
+    } else if !path.exists() {
+        return Err(format!("Path does not exist: {:?}", path));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -257,6 +734,127 @@ mod tests {
         assert!(manager.check_structure(content, "test").is_err());
     }
 
+    #[test]
+    fn test_preserve_sections_outcomes() {
+        let manager = ManualSectionManager::new(ManualSectionConfig::default());
+        let old = "MANUAL SECTION START: kept\nuser code\nMANUAL SECTION END\n";
+        let new = "MANUAL SECTION START: kept\nMANUAL SECTION END\nMANUAL SECTION START: added\ndefault\nMANUAL SECTION END\n";
+        let (content, outcomes) = manager.preserve_sections_with_outcomes(new, old);
+        assert!(content.contains("user code"));
+        assert!(content.contains("default"));
+        assert_eq!(
+            outcomes,
+            vec![
+                ("kept".to_string(), SectionOutcome::Preserved),
+                ("added".to_string(), SectionOutcome::Created),
+            ]
+        );
+    }
+
+    fn nested_manager() -> ManualSectionManager {
+        ManualSectionManager::new(ManualSectionConfig {
+            allow_nested_sections: true,
+            ..ManualSectionConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_nesting_allowed() {
+        let manager = nested_manager();
+        let content = "MANUAL SECTION START: outer\nMANUAL SECTION START: inner\nMANUAL SECTION END\nMANUAL SECTION END\n";
+        assert!(manager.check_structure(content, "test").is_ok());
+        assert!(manager.find_issues(content).is_empty());
+
+        let unclosed = "MANUAL SECTION START: outer\nMANUAL SECTION START: inner\nMANUAL SECTION END\n";
+        let issues = manager.find_issues(unclosed);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].line, 1);
+    }
+
+    #[test]
+    fn test_nested_round_trip() {
+        let manager = nested_manager();
+        let old = "head\nMANUAL SECTION START: outer\nuser outer\nMANUAL SECTION START: inner\nuser inner\nMANUAL SECTION END\nMANUAL SECTION END\ntail\n";
+        let new = "new head\nMANUAL SECTION START: outer\ndefault outer\nMANUAL SECTION START: inner\ndefault inner\nMANUAL SECTION END\nMANUAL SECTION END\nnew tail\n";
+        let (content, outcomes) = manager.preserve_sections_with_outcomes(new, old);
+        assert_eq!(
+            content,
+            "new head\nMANUAL SECTION START: outer\nuser outer\nMANUAL SECTION START: inner\nuser inner\nMANUAL SECTION END\nMANUAL SECTION END\nnew tail\n"
+        );
+        assert_eq!(outcomes, vec![("outer".to_string(), SectionOutcome::Preserved)]);
+
+        // An inner section moved into a new outer section keeps its content
+        let moved = "MANUAL SECTION START: wrapper\nMANUAL SECTION START: inner\ndefault\nMANUAL SECTION END\nMANUAL SECTION END\n";
+        let (content, outcomes) = manager.preserve_sections_with_outcomes(moved, old);
+        assert_eq!(
+            content,
+            "MANUAL SECTION START: wrapper\nMANUAL SECTION START: inner\nuser inner\nMANUAL SECTION END\nMANUAL SECTION END\n"
+        );
+        assert_eq!(
+            outcomes,
+            vec![
+                ("wrapper".to_string(), SectionOutcome::Created),
+                ("inner".to_string(), SectionOutcome::Preserved),
+            ]
+        );
+
+        // extract_blocks/restore_blocks (used around formatting) round-trip nested blocks
+        let blocks = manager.extract_blocks(old);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(manager.restore_blocks(new, &blocks), manager.preserve_sections(new, old));
+    }
+
+    #[test]
+    fn test_has_non_empty_sections() {
+        let manager = ManualSectionManager::new(ManualSectionConfig::default());
+        let empty = "// MANUAL SECTION START: a\n    \n// MANUAL SECTION END\n";
+        let filled = "// MANUAL SECTION START: a\nx = 1;\n// MANUAL SECTION END\n";
+        assert!(!manager.has_non_empty_sections(empty));
+        assert!(manager.has_non_empty_sections(filled));
+        assert!(!manager.has_non_empty_sections("no sections"));
+    }
+
+    #[test]
+    fn test_find_issues() {
+        let manager = ManualSectionManager::new(ManualSectionConfig::default());
+        let content = "MANUAL SECTION START: a\nMANUAL SECTION END\nMANUAL SECTION START: a\nMANUAL SECTION START: b\nMANUAL SECTION END\nMANUAL SECTION END\n";
+        let issues = manager.find_issues(content);
+        assert_eq!(issues.len(), 3);
+        assert_eq!(issues[0].line, 3);
+        assert!(issues[0].message.contains("Duplicate"));
+        assert_eq!(issues[1].line, 4);
+        assert!(issues[1].message.contains("Nested"));
+        assert_eq!(issues[2].line, 6);
+        assert!(issues[2].message.contains("without start"));
+        // The checks report the same problems
+        assert_eq!(
+            manager.check_structure(content, "test").unwrap_err(),
+            "Nested manual section inside section started at line 3 in \"test\" at line 4"
+        );
+        assert!(manager.check_duplicates(content, "test").unwrap_err().contains("[\"a\"]"));
+    }
+
+    #[test]
+    fn test_verify_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("sub")).unwrap();
+        std::fs::write(
+            tmp.path().join("good.txt"),
+            "MANUAL SECTION START: a\nMANUAL SECTION END\n",
+        )
+        .unwrap();
+        std::fs::write(
+            tmp.path().join("sub/bad.txt"),
+            "line\nMANUAL SECTION START: a\n",
+        )
+        .unwrap();
+        let manager = ManualSectionManager::new(ManualSectionConfig::default());
+        let issues = manager.verify_paths(&[tmp.path().to_path_buf()]).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].0, tmp.path().join("sub/bad.txt"));
+        assert_eq!(issues[0].1.line, 2);
+    }
+
     #[test]
     fn test_missing_section() {
         let config = ManualSectionConfig::default();
@@ -265,4 +863,147 @@ mod tests {
         let new = "MANUAL SECTION START: other\nMANUAL SECTION END";
         assert!(manager.validate_sections("test", new, Some(old)).is_err());
     }
+
+    #[test]
+    fn test_optional_section() {
+        let manager = ManualSectionManager::new(ManualSectionConfig {
+            optional_sections: vec!["debug".to_string()],
+            ..ManualSectionConfig::default()
+        });
+        let old = "MANUAL SECTION START: keep_me\nMANUAL SECTION END\nMANUAL SECTION START: debug\nMANUAL SECTION END";
+        let new = "MANUAL SECTION START: keep_me\nMANUAL SECTION END";
+        assert!(manager.validate_sections("test", new, Some(old)).is_ok());
+        // Sections that aren't optional are still required
+        let new = "MANUAL SECTION START: debug\nMANUAL SECTION END";
+        assert!(manager.validate_sections("test", new, Some(old)).is_err());
+    }
+
+    #[test]
+    fn test_marker_sets() {
+        let set = |name: &str, marker: &str, policy| MarkerSet {
+            name: name.to_string(),
+            start_marker: format!("{} START", marker),
+            end_marker: format!("{} END", marker),
+            policy,
+        };
+        let manager = ManualSectionManager::new(ManualSectionConfig {
+            marker_sets: vec![
+                set("review", "REVIEW", SectionPolicy::Reset),
+                set("ops", "OPS", SectionPolicy::Preserve),
+            ],
+            ..ManualSectionConfig::default()
+        });
+        let template = |user: &str, review: &str, ops: &str| {
+            format!(
+                "MANUAL SECTION START: user\n{}\nMANUAL SECTION END\n\
+                 REVIEW START: notes\n{}\nREVIEW END\n\
+                 OPS START: limits\n{}\nOPS END\n",
+                user, review, ops
+            )
+        };
+        let new = template("default", "pending review", "none");
+        let prev = template("user edit", "reviewer edit", "ops edit");
+        assert!(manager.validate_sections("test", &new, Some(&prev)).is_ok());
+
+        let (merged, outcomes) = manager.preserve_sections_with_outcomes(&new, &prev);
+        assert_eq!(merged, template("user edit", "pending review", "ops edit"));
+        assert_eq!(
+            outcomes,
+            vec![
+                ("user".to_string(), SectionOutcome::Preserved),
+                ("notes".to_string(), SectionOutcome::Reset),
+                ("limits".to_string(), SectionOutcome::Preserved),
+            ]
+        );
+
+        // A reset section may disappear, a preserved one may not
+        let without_review = new.replace("REVIEW START: notes\npending review\nREVIEW END\n", "");
+        assert!(manager.validate_sections("test", &without_review, Some(&prev)).is_ok());
+        let without_ops = new.replace("OPS START: limits\nnone\nOPS END\n", "");
+        assert!(manager.validate_sections("test", &without_ops, Some(&prev)).is_err());
+        assert!(manager.validate_sections("test", "REVIEW START: a\n", None).is_err());
+
+        // Formatting round trip keeps every set's blocks apart
+        let blocks = manager.extract_blocks(&prev);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(manager.restore_blocks(&new, &blocks), prev);
+
+        assert!(!manager.has_non_empty_sections(&template("", "reviewer edit", "")));
+        assert!(manager.has_non_empty_sections(&template("", "", "ops edit")));
+    }
+
+    #[test]
+    fn test_migrate_markers() {
+        let manager = ManualSectionManager::new(ManualSectionConfig {
+            start_marker: "BEGIN USER CODE".to_string(),
+            end_marker: "END USER CODE".to_string(),
+            ..ManualSectionConfig::default()
+        });
+        let old = "fn main() {\n\
+                   // MANUAL SECTION START: body\n\
+                   println!(\"user code\");\n\
+                   // MANUAL SECTION END\n\
+                   }\n\
+                   // MANUAL SECTION END without a start\n";
+        let migrated = manager
+            .migrate_markers(old, "MANUAL SECTION START", "MANUAL SECTION END")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            migrated,
+            "fn main() {\n\
+             // BEGIN USER CODE: body\n\
+             println!(\"user code\");\n\
+             // END USER CODE\n\
+             }\n\
+             // MANUAL SECTION END without a start\n"
+        );
+        // The next generation finds the section under the new markers
+        let new = "fn main() {\n// BEGIN USER CODE: body\n// END USER CODE\n}\n";
+        assert!(manager.preserve_sections(new, &migrated).contains("println!(\"user code\");"));
+
+        assert!(manager
+            .migrate_markers(&migrated, "MANUAL SECTION START", "MANUAL SECTION END")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_custom_id_pattern() {
+        let manager = ManualSectionManager::new(ManualSectionConfig {
+            id_pattern: r"[\w./-]+".to_string(),
+            ..ManualSectionConfig::default()
+        });
+        let old = "MANUAL SECTION START: auth.v2\nold auth\nMANUAL SECTION END\nMANUAL SECTION START: 模块1\nold module\nMANUAL SECTION END";
+        let new = "MANUAL SECTION START: auth.v2\nMANUAL SECTION END\nMANUAL SECTION START: 模块1\nMANUAL SECTION END";
+        assert_eq!(manager.extract_section_ids(old), vec!["auth.v2", "模块1"]);
+        assert_eq!(manager.preserve_sections(new, old), old);
+        assert!(manager.validate_sections("test", new, Some(old)).is_ok());
+
+        let blocks = manager.extract_blocks(old);
+        assert_eq!(blocks["模块1"], "MANUAL SECTION START: 模块1\nold module\nMANUAL SECTION END");
+        assert_eq!(manager.restore_blocks(new, &blocks), old);
+
+        // The default pattern stops at the dot
+        let manager = ManualSectionManager::new(ManualSectionConfig::default());
+        assert_eq!(manager.extract_section_ids(old), Vec::<String>::new());
+
+        // Groups of the pattern don't shift the ID or the block
+        let manager = ManualSectionManager::new(ManualSectionConfig {
+            id_pattern: r"(auth|db)\.(v\d)".to_string(),
+            ..ManualSectionConfig::default()
+        });
+        let old = "MANUAL SECTION START: auth.v2\nold auth\nMANUAL SECTION END";
+        let new = "MANUAL SECTION START: auth.v2\nMANUAL SECTION END";
+        assert_eq!(manager.extract_section_ids(old), vec!["auth.v2"]);
+        assert_eq!(manager.preserve_sections(new, old), old);
+        assert_eq!(manager.extract_blocks(old)["auth.v2"], old);
+        assert!(manager.find_issues(old).is_empty());
+
+        assert!(ManualSectionManager::try_new(ManualSectionConfig {
+            id_pattern: "(".to_string(),
+            ..ManualSectionConfig::default()
+        })
+        .is_err());
+    }
 }
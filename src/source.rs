@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Default limit for the size of a template file, in bytes.
+pub const DEFAULT_MAX_TEMPLATE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// TemplateSource abstracts where template files are read from, so `FileGenerator` can
+/// render templates from the filesystem or from an embedded/in-memory set of files.
+pub trait TemplateSource: Send + Sync {
+    /// Returns true if the path is a file or a directory in this source.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Returns true if the path is a file in this source.
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// Lists the direct children of a directory.
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, String>;
+
+    /// Reads the content of a file.
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String>;
+
+    /// Returns true if the path is a symbolic link to a directory. Sources without links
+    /// return false.
+    fn is_dir_symlink(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// Returns the path with all links resolved, used to detect symlink cycles.
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, String> {
+        Ok(path.to_path_buf())
+    }
+
+    /// Returns the permissions of a file, which copies of it keep. Sources without
+    /// permissions return None.
+    fn permissions(&self, _path: &Path) -> Option<fs::Permissions> {
+        None
+    }
+
+    /// Reads the content of a file as UTF-8 text.
+    fn read_to_string(&self, path: &Path) -> Result<String, String> {
+        decode_utf8(path, self.read(path)?)
+    }
+
+    /// Reads the content of a file, failing if it is larger than `limit` bytes.
+    fn read_limited(&self, path: &Path, limit: u64) -> Result<Vec<u8>, String> {
+        let bytes = self.read(path)?;
+        if bytes.len() as u64 > limit {
+            return Err(too_large(path, limit));
+        }
+        Ok(bytes)
+    }
+
+    /// Reads a template body as UTF-8 text, failing if it is larger than `limit` bytes.
+    fn read_template(&self, path: &Path, limit: u64) -> Result<String, String> {
+        decode_utf8(path, self.read_limited(path, limit)?)
+    }
+}
+
+/// Decodes UTF-8 text, naming the file and the byte offset of the first invalid byte.
+fn decode_utf8(path: &Path, bytes: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(bytes).map_err(|e| {
+        format!(
+            "{:?} is not valid UTF-8: invalid byte at offset {}",
+            path,
+            e.utf8_error().valid_up_to()
+        )
+    })
+}
+
+fn too_large(path: &Path, limit: u64) -> String {
+    format!("{:?} is larger than the template size limit of {} bytes", path, limit)
+}
+
+/// Reads templates from the filesystem. This is the default source.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileSystemSource;
+
+impl TemplateSource for FileSystemSource {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, String> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(path).map_err(|e| e.to_string())? {
+            paths.push(entry.map_err(|e| e.to_string())?.path());
+        }
+        Ok(paths)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        fs::read(path).map_err(|e| e.to_string())
+    }
+
+    fn is_dir_symlink(&self, path: &Path) -> bool {
+        path.is_symlink() && path.is_dir()
+    }
+
+    fn permissions(&self, path: &Path) -> Option<fs::Permissions> {
+        fs::metadata(path).ok().map(|metadata| metadata.permissions())
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, String> {
+        fs::canonicalize(path).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    /// Reads at most `limit + 1` bytes, so a huge file is never loaded completely.
+    fn read_limited(&self, path: &Path, limit: u64) -> Result<Vec<u8>, String> {
+        let file = fs::File::open(path).map_err(|e| e.to_string())?;
+        let mut bytes = Vec::new();
+        BufReader::new(file)
+            .take(limit.saturating_add(1))
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())?;
+        if bytes.len() as u64 > limit {
+            return Err(too_large(path, limit));
+        }
+        Ok(bytes)
+    }
+}
+
+/// Reads templates from memory, e.g. files compiled into the binary with `include_bytes!`
+/// or `rust-embed`. Directories are implied by the file paths.
+#[derive(Debug, Default, Clone)]
+pub struct MemorySource {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file to the source.
+    pub fn insert(&mut self, path: impl Into<PathBuf>, content: impl Into<Vec<u8>>) {
+        self.files.insert(path.into(), content.into());
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files
+            .keys()
+            .any(|file| file != path && file.starts_with(path))
+    }
+}
+
+impl<P: Into<PathBuf>, C: Into<Vec<u8>>> FromIterator<(P, C)> for MemorySource {
+    fn from_iter<I: IntoIterator<Item = (P, C)>>(iter: I) -> Self {
+        let mut source = MemorySource::new();
+        for (path, content) in iter {
+            source.insert(path, content);
+        }
+        source
+    }
+}
+
+impl TemplateSource for MemorySource {
+    fn exists(&self, path: &Path) -> bool {
+        self.is_file(path) || self.is_dir(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, String> {
+        if !self.is_dir(path) {
+            return Err(format!("Directory not found: {:?}", path));
+        }
+        let children: BTreeSet<PathBuf> = self
+            .files
+            .keys()
+            .filter_map(|file| file.strip_prefix(path).ok())
+            .filter_map(|rest| rest.components().next())
+            .map(|child| path.join(child))
+            .collect();
+        Ok(children.into_iter().collect())
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, String> {
+        self.files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| format!("File not found: {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DryRunMode, FileGenerator, ManualSectionConfig, ManualSectionManager, TemplateEngine};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_memory_source_traversal() {
+        let source: MemorySource = [
+            ("templates/a.j2", "a"),
+            ("templates/sub/b.j2", "b"),
+        ]
+        .into_iter()
+        .collect();
+        assert!(source.exists(Path::new("templates")));
+        assert!(!source.is_file(Path::new("templates")));
+        assert!(source.is_file(Path::new("templates/a.j2")));
+        assert!(!source.exists(Path::new("other")));
+        assert_eq!(
+            source.read_dir(Path::new("templates")).unwrap(),
+            vec![PathBuf::from("templates/a.j2"), PathBuf::from("templates/sub")]
+        );
+    }
+
+    #[test]
+    fn test_read_template_limits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let big = tmp.path().join("big.j2");
+        fs::write(&big, "x".repeat(100)).unwrap();
+        let invalid = tmp.path().join("invalid.j2");
+        fs::write(&invalid, b"ok \xffbad").unwrap();
+
+        let sources: [&dyn TemplateSource; 2] = [
+            &FileSystemSource,
+            &[(&big, fs::read(&big).unwrap()), (&invalid, fs::read(&invalid).unwrap())]
+                .into_iter()
+                .collect::<MemorySource>(),
+        ];
+        for source in sources {
+            assert_eq!(source.read_template(&big, 100).unwrap().len(), 100);
+            let err = source.read_template(&big, 99).unwrap_err();
+            assert!(err.ends_with("is larger than the template size limit of 99 bytes"), "{}", err);
+            let err = source.read_template(&invalid, 100).unwrap_err();
+            assert!(err.ends_with("is not valid UTF-8: invalid byte at offset 3"), "{}", err);
+            assert!(err.contains("invalid.j2"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_generate_from_memory_source() {
+        let source: MemorySource = [
+            ("templates/hello.txt.j2", "Hello, {{ name }}!"),
+            ("templates/{{ name }}/notes.md.j2", "# {{ name }}"),
+            ("templates/static.txt", "static"),
+        ]
+        .into_iter()
+        .collect();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let output = tmp.path().join("out");
+        let generator = FileGenerator::new(
+            TemplateEngine::new(),
+            ManualSectionManager::new(ManualSectionConfig::default()),
+            DryRunMode::Off,
+        )
+        .with_source(Arc::new(source));
+        let context = HashMap::from([("name", "World")]);
+        generator
+            .generate(Path::new("templates"), &output, &context)
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(output.join("hello.txt")).unwrap(), "Hello, World!");
+        assert_eq!(fs::read_to_string(output.join("World/notes.md")).unwrap(), "# World");
+        assert_eq!(fs::read_to_string(output.join("static.txt")).unwrap(), "static");
+    }
+}
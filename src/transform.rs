@@ -0,0 +1,135 @@
+use crate::config::DataTransform;
+use crate::engine::TemplateEngine;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+
+/// Applies the transforms in order to the data. Expressions of `add_field` are evaluated by
+/// `engine`, so they can use its filters.
+pub fn apply_transforms(
+    data: &mut Value,
+    transforms: &[DataTransform],
+    engine: &TemplateEngine,
+) -> Result<(), String> {
+    for transform in transforms {
+        match transform {
+            DataTransform::SortArraysBy { path, key, descending } => {
+                let items = value_at(data, Some(path))?
+                    .as_array_mut()
+                    .ok_or_else(|| format!("sort_arrays_by: '{}' is not an array", path))?;
+                items.sort_by(|a, b| {
+                    let ordering = compare_fields(a.get(key), b.get(key));
+                    if *descending && a.get(key).is_some() && b.get(key).is_some() {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                });
+            }
+            DataTransform::LowercaseKeys { path } => {
+                lowercase_keys(value_at(data, path.as_deref())?);
+            }
+            DataTransform::AddField { path, name, expr } => {
+                let target = value_at(data, path.as_deref())?;
+                let objects: Vec<&mut Map<String, Value>> = match target {
+                    Value::Array(items) => items.iter_mut().filter_map(Value::as_object_mut).collect(),
+                    Value::Object(object) => vec![object],
+                    _ => {
+                        return Err(format!(
+                            "add_field: '{}' is not an object or array",
+                            path.as_deref().unwrap_or("")
+                        ))
+                    }
+                };
+                for object in objects {
+                    let value = engine.evaluate_expression(expr, &*object)?;
+                    object.insert(name.clone(), value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a dotted path; None or an empty path is the whole value.
+fn value_at<'a>(data: &'a mut Value, path: Option<&str>) -> Result<&'a mut Value, String> {
+    let path = path.unwrap_or("");
+    if path.is_empty() {
+        return Ok(data);
+    }
+    path.split('.').try_fold(data, |value, key| {
+        value
+            .get_mut(key)
+            .ok_or_else(|| format!("Data has no value at '{}'", path))
+    })
+}
+
+/// Orders numbers numerically and anything else by its text; missing fields go last.
+fn compare_fields(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        (Some(a), Some(b)) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+fn lowercase_keys(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            *object = std::mem::take(object)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    lowercase_keys(&mut value);
+                    (key.to_lowercase(), value)
+                })
+                .collect();
+        }
+        Value::Array(items) => items.iter_mut().for_each(lowercase_keys),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn transforms(yaml: &str) -> Vec<DataTransform> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_sort_and_add_field() {
+        let mut data = json!({
+            "services": [
+                { "name": "Orders", "port": 8082 },
+                { "name": "Auth", "port": 8081 },
+                { "name": "Legacy" }
+            ]
+        });
+        let transforms = transforms(
+            "- { type: sort_arrays_by, path: services, key: port }\n\
+             - { type: add_field, path: services, name: slug, expr: \"name | lower ~ '-svc'\" }\n",
+        );
+        apply_transforms(&mut data, &transforms, &TemplateEngine::new()).unwrap();
+        let names: Vec<_> = data["services"].as_array().unwrap().iter().map(|s| s["name"].clone()).collect();
+        assert_eq!(names, vec!["Auth", "Orders", "Legacy"]);
+        assert_eq!(data["services"][0]["slug"], "auth-svc");
+        assert_eq!(data["services"][2]["slug"], "legacy-svc");
+
+        let transforms = self::transforms(
+            "- type: lowercase_keys\n- { type: sort_arrays_by, path: services, key: name, descending: true }\n",
+        );
+        let mut data = json!({ "Services": [{ "Name": "a" }, { "Name": "b" }] });
+        apply_transforms(&mut data, &transforms, &TemplateEngine::new()).unwrap();
+        assert_eq!(data, json!({ "services": [{ "name": "b" }, { "name": "a" }] }));
+
+        let transforms = self::transforms("- { type: sort_arrays_by, path: missing, key: name }\n");
+        assert!(apply_transforms(&mut data, &transforms, &TemplateEngine::new()).is_err());
+    }
+}